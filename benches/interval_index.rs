@@ -0,0 +1,123 @@
+//! Compare the `SortedIntervals` overlap index with bio's `IntervalTree` on
+//! the features of an annotation.
+//!
+//! Run with `cargo bench --bench interval_index -- <annotation file> [filetype]`,
+//! for example on a GENCODE GTF of the human genome.
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow};
+use bio::data_structures::interval_tree::IntervalTree;
+use cassette_reannotation::indexed_annotation::IndexedAnnotation;
+use cassette_reannotation::interval_index::{IntervalIndex, SortedIntervals};
+
+fn time<R>(f: impl FnOnce() -> R) -> (R, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+fn report(name: &str, tree: Duration, sorted: Duration) {
+    println!("{:<24}{:>12.1?}{:>12.1?}{:>9.1}x",
+        name, tree, sorted, tree.as_secs_f64() / sorted.as_secs_f64().max(1e-9));
+}
+
+// count the overlaps of every query with the indexes of its sequence
+fn count_overlaps<I: IntervalIndex<usize>>(indexes: &HashMap<String, I>, queries: &[(String, Range<u64>)]) -> usize {
+    let mut count = 0;
+    for (seqname, range) in queries {
+        if let Some(index) = indexes.get(seqname) {
+            count += index.find(range.clone()).count();
+        }
+    }
+    count
+}
+
+fn run() -> Result<()> {
+    let args = std::env::args().skip(1).filter(|a| !a.starts_with("--")).collect::<Vec<_>>();
+    let annotfile = args.get(0).ok_or(anyhow!("Usage: interval_index <annotation file> [filetype]"))?;
+    let filetype = args.get(1).map(|f| f.as_str()).unwrap_or("gtf");
+    let annot = IndexedAnnotation::from_file(annotfile, filetype, "gene", "transcript", &None, &None)?;
+
+    // empty features never overlap anything, and IntervalTree also leaves
+    // them out of its ordered iteration
+    let mut ranges = HashMap::<String, Vec<(Range<u64>, usize)>>::new();
+    for (row, record) in annot.rows.iter().enumerate() {
+        let range = record.range()?;
+        if !range.is_empty() {
+            ranges.entry(record.seqname.to_string()).or_insert_with(Vec::new).push((range, row));
+        }
+    }
+    println!("{} features on {} sequences", annot.rows.len(), ranges.len());
+    println!("{:<24}{:>12}{:>12}{:>10}", "", "IntervalTree", "Sorted", "speedup");
+
+    let (trees, tree_time) = time(|| ranges.iter().map(|(seqname, ranges)| {
+        let mut tree = IntervalTree::new();
+        for (range, row) in ranges {
+            tree.insert(range.clone(), *row);
+        }
+        (seqname.clone(), tree)
+    }).collect::<HashMap<_,_>>());
+    let (sorted, sorted_time) = time(|| ranges.iter().
+        map(|(seqname, ranges)| (seqname.clone(), SortedIntervals::new(ranges.clone()))).
+        collect::<HashMap<_,_>>());
+    report("build", tree_time, sorted_time);
+
+    // the overlaps of every feature, as when looking up the exons or
+    // transcripts around each feature
+    let queries = annot.rows.iter().map(|r| (r.seqname.to_string(), r.start-1..r.end)).collect::<Vec<_>>();
+    let ((tree_count, sorted_count), (tree_time, sorted_time)) = compare(&trees, &sorted, &queries);
+    if tree_count != sorted_count {
+        return Err(anyhow!("Overlap counts differ: {} != {}", tree_count, sorted_count));
+    }
+    report("feature overlaps", tree_time, sorted_time);
+
+    // random 1kb windows, as when looking up the features under reads
+    let mut seqnames = ranges.iter().
+        map(|(seqname, ranges)| (seqname.clone(), ranges.iter().map(|(r, _)| r.end).max().unwrap_or(0))).
+        collect::<Vec<_>>();
+    seqnames.sort();
+    let mut seed = 0x2545f4914f6cdd1du64;
+    let mut random = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        seed >> 33
+    };
+    let queries = (0..1_000_000).map(|_| {
+        let (seqname, end) = &seqnames[random() as usize % seqnames.len()];
+        let start = random() % (end + 1);
+        (seqname.clone(), start..start+1000)
+    }).collect::<Vec<_>>();
+    let ((tree_count, sorted_count), (tree_time, sorted_time)) = compare(&trees, &sorted, &queries);
+    if tree_count != sorted_count {
+        return Err(anyhow!("Overlap counts differ: {} != {}", tree_count, sorted_count));
+    }
+    report("random 1kb windows", tree_time, sorted_time);
+
+    // all features of each sequence in order of position
+    let (tree_rows, tree_time) = time(|| trees.values().map(|tree| {
+        let mut entries = tree.find(0..u64::MAX).map(|e| (e.interval().start, *e.data())).collect::<Vec<_>>();
+        entries.sort();
+        entries.len()
+    }).sum::<usize>());
+    let (sorted_rows, sorted_time) = time(|| sorted.values().map(|index| index.iter().count()).sum::<usize>());
+    if tree_rows != sorted_rows {
+        return Err(anyhow!("Feature counts differ: {} != {}", tree_rows, sorted_rows));
+    }
+    report("ordered iteration", tree_time, sorted_time);
+    Ok(())
+}
+
+fn compare(
+    trees: &HashMap<String, IntervalTree<u64, usize>>,
+    sorted: &HashMap<String, SortedIntervals<usize>>,
+    queries: &[(String, Range<u64>)])
+    -> ((usize, usize), (Duration, Duration))
+{
+    let (tree_count, tree_time) = time(|| count_overlaps(trees, queries));
+    let (sorted_count, sorted_time) = time(|| count_overlaps(sorted, queries));
+    ((tree_count, sorted_count), (tree_time, sorted_time))
+}
+
+fn main() -> Result<()> {
+    run()
+}
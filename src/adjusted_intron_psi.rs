@@ -12,6 +12,9 @@ use serde::{Serialize, Deserialize};
 
 use cassette_reannotation::*;
 use cassette_reannotation::indexed_annotation::*;
+use cassette_reannotation::annotation_formats::annotation_format;
+use cassette_reannotation::chrom_alias::ChromAliases;
+use cassette_reannotation::feature_filter::Filter;
 
 use rust_htslib::bam::Read;
 use rust_htslib::bam::IndexedReader;
@@ -25,26 +28,44 @@ use futures_cpupool::CpuPool;
 #[structopt(name = "adjusted_intron_cov", about = "Compute adjusted intron coverage and PSI values from spladder output")]
 struct Options {
     // input files
-    #[structopt(long="gff", help = "A genome annotation file in gff3 format", name="ANNOT_GFF_FILE")]
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
     annotfile_gff: Option<String>,
-    #[structopt(long="gtf", help = "A genome annotation file in gtf format", name="ANNOT_GTF_FILE")]
+    #[structopt(long="gtf", help = "A genome annotation file in gtf format, optionally gzipped, or - for stdin", name="ANNOT_GTF_FILE")]
     annotfile_gtf: Option<String>,
+    #[structopt(long="annot", help = "A genome annotation file in gff3, gtf, bed12, bigbed, genepred or refflat format, optionally gzipped, or - for stdin", name="ANNOT_FILE")]
+    annotfile: Option<String>,
+    #[structopt(long="annot_format", help = "The format of the --annot file: gff, gtf, bed, bigbed, genepred or refflat. Guessed from the file extension if not given.", name="ANNOT_FORMAT")]
+    annot_format: Option<String>,
     #[structopt(long="bam1", short="1", help = "The set of stranded .bam files to analyze where read1 indicates strand", name="BAMFILE1")]
     bam1: Vec<String>,
     #[structopt(long="bam2", short="2", help = "The set of stranded .bam files to analyze where read2 indicates strand", name="BAMFILE2")]
     bam2: Vec<String>,
-    #[structopt(long="bam", short="u", help = "The set of unstranded .bam files to analyze", name="BAMFILE")]
+    #[structopt(long="bam", short="u", help = "The set of unstranded .bam files to analyze, or of unknown strandedness with --autostrand", name="BAMFILE")]
     bam: Vec<String>,
+    #[structopt(long="autostrand", help = "Infer the strandedness of the --bam files from the annotation")]
+    autostrand: bool,
+    #[structopt(long="strand_report", help = "Write the --autostrand report to a .json or tab-delimited file", name="STRAND_REPORT_FILE")]
+    strand_report: Option<String>,
+    #[structopt(long="strand_threshold", help = "The fraction of the --autostrand reads that must agree to call a library stranded, above 0.5 and at most 1", name="STRAND_THRESHOLD", default_value="0.8")]
+    strand_threshold: f64,
+    #[structopt(long="strand_sample_reads", help = "The number of strand-informative reads that --autostrand samples", name="STRAND_SAMPLE_READS", default_value="200000")]
+    strand_sample_reads: u64,
     #[structopt(long="chrmap", help = "Optional tab-delimited chr name mapping file", name="CHRMAP_FILE")]
     chrmap_file: Option<String>,
     #[structopt(long="vizchrmap", help = "Optional tab-delimited chr name mapping file for bigwig/bigbed exports", name="VIZCHRMAP_FILE")]
     vizchrmap_file: Option<String>,
-    #[structopt(long="sizes", help = "Optional chr sizes file", name="SIZES_FILE")]
+    #[structopt(long="chromalias", help = "Optional UCSC chromAlias.txt file or other tab-delimited table of equivalent chr names", name="CHROM_ALIAS_FILE")]
+    chromalias_file: Option<String>,
+    #[structopt(long="strict", help = "Fail on malformed annotation lines instead of skipping them")]
+    strict: bool,
+    #[structopt(long="no_annotation_cache", help = "Always parse the annotation file instead of reading or writing its cache")]
+    no_annotation_cache: bool,
+    #[structopt(long="sizes", help = "Optional chr sizes file, such as a FASTA .fai index", name="SIZES_FILE")]
     sizes_file: Option<String>,
     // output file
     #[structopt(long="out", short="o", help = "Output file", name="OUT_FILE", default_value="-")]
     outfile: String,
-    #[structopt(long="input", short="i", help = "Spladder input file", name="INPUT", default_value="-")]
+    #[structopt(long="input", short="i", help = "Spladder input file, optionally gzipped", name="INPUT", default_value="-")]
     input: String,
     // feature types filter
     #[structopt(long="exon_type", help = "The exon type(s) to search for", name="EXON_TYPE")]
@@ -53,9 +74,13 @@ struct Options {
     transcript_type: Vec<String>,
     #[structopt(long="gene_type", help = "The gene type(s) to search for", name="GENE_TYPE")]
     gene_type: Vec<String>,
+    #[structopt(long="filter", help = "Only use the annotation features that pass a filter expression, such as \"gene_biotype=protein_coding and tag contains basic\"", name="FILTER")]
+    filter: Option<Filter>,
     // flags
     #[structopt(long="cpu_threads", short="t", help = "How many threads to use for processing", default_value="0")]
     cpu_threads: usize,
+    #[structopt(long="read_count", help = "Which alignments count as reads for RPKM: mapped, primary, unique or fragments", name="READ_COUNT", default_value="mapped")]
+    read_count: ReadCount,
 }
 
 #[derive(Debug,Serialize,Deserialize,Clone)]
@@ -106,6 +131,34 @@ struct OutRow {
     adjusted_psi: String,
 }
 
+/// The merged ranges of the annotated exons of the given types that
+/// overlap a range on one strand.
+fn annotated_exons(
+    annot: &IndexedAnnotation,
+    chr: &str,
+    range: Range<u64>,
+    strand: Strand,
+    exon_types: &[String])
+    -> Vec<Range<u64>>
+{
+    let exon_types = exon_types.iter().map(String::as_str).collect::<Vec<_>>();
+    let exons = annot.find_features(chr, range, Some(strand), &exon_types).
+        map(|t| &annot.rows[t]).
+        collect::<Vec<_>>();
+
+    let mut merged_exons = Vec::<Range<u64>>::new();
+    for exon in exons {
+        if let Some(ref mut last) = merged_exons.last_mut() {
+            if (exon.start-1) <= last.end && (last.start-1) <= exon.end {
+                last.end = exon.end;
+                continue;
+            }
+        }
+        merged_exons.push((exon.start-1)..exon.end);
+    }
+    merged_exons
+}
+
 fn write_intron_cov(
     options: &Options,
     bamfiles: &Vec<String>,
@@ -113,9 +166,7 @@ fn write_intron_cov(
     annot: &Arc<IndexedAnnotation>)
     -> Result<()> 
 {
-    let input: Box<dyn std::io::Read> = match options.input.as_ref() {
-        "-" => Box::new(std::io::stdin()),
-        _ => Box::new(std::fs::File::open(&options.input)? )};
+    let input = open_input(&options.input)?;
 
     let output: BufWriter<Box<dyn Write>> = BufWriter::new(
         if options.outfile == "-" { Box::new(std::io::stdout()) }
@@ -123,18 +174,7 @@ fn write_intron_cov(
 
     let mut tidmaps = HashMap::<String,HashMap<String,u32>>::new();
     for bamfile in bamfiles {
-        let bam = IndexedReader::from_path(bamfile)?;
-        // build the tid map for this bam file
-        let mut tidmap = HashMap::<String,u32>::new();
-        {   let header = bam.header();
-            for target_name in header.target_names() {
-                let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
-                let target_name = String::from(std::str::from_utf8(target_name)?);
-                let chr = annot.chrmap.get(&target_name).unwrap_or(&target_name);
-                tidmap.insert(chr.clone(), tid);
-            }
-        }
-        tidmaps.insert(bamfile.clone(), tidmap);
+        tidmaps.insert(bamfile.clone(), get_tidmap(bamfile, annot)?);
     }
     let tidmaps = Arc::new(tidmaps);
 
@@ -152,19 +192,23 @@ fn write_intron_cov(
         let bamfiles = bamfiles.clone();
         let bamstrand = bamstrand.clone();
         let tidmaps = tidmaps.clone();
+        let read_count = options.read_count;
+        let exon_types = options.exon_type.clone();
         let pair_future = pool.spawn_fn(move || -> Result<OutRow> {
             //get all the bam reads in parallel
             let mut exon_reads = HashSet::<String>::new();
             let mut coverage = vec![0u64; (row.intron_end-row.intron_start+1) as usize];
             let mut read_length = 0;
+            let chr = annot.map_seqname(&row.contig);
             for (i, bamfile) in bamfiles.iter().enumerate() {
                 let read1strand = bamstrand[i];
                 let tidmap = &tidmaps[bamfile];
-                if let Some(tid) = tidmap.get(&row.contig) {
+                if let Some(tid) = tidmap.get(&chr) {
                     let mut bam = IndexedReader::from_path(bamfile)?;
                     bam.fetch(*tid, (row.exon1_start - 1) as u32, row.exon2_end as u32)?;
                     for read in bam.records() {
                         let read = read?;
+                        if !read_count.includes(&read) { continue }
                         if read_length == 0 { read_length = read.seq().len(); }
                         // make sure the read's strand matches
                         if let Some(is_read1strand) = read1strand {
@@ -193,28 +237,8 @@ fn write_intron_cov(
                     }
                 }
             }
-            // get the annotated exons
-            let mut annotated_exons = Vec::new();
-            let chr = annot.chrmap.get(&row.contig).unwrap_or(&row.contig);
-            if let Some(tree) = annot.tree.get(chr) {
-                annotated_exons = tree.find(row.intron_start - 1..row.intron_end).
-                    map(|t| &annot.rows[*t.data()]).
-                    filter(|t| t.feature_type == "exon" && t.strand == row.strand).
-                    collect::<Vec<_>>();
-            }
-            annotated_exons.sort_by_key(|e| e.start);
-
-            // merge the annotated exons
-            let mut merged_exons = Vec::<Range<u64>>::new();
-            for exon in annotated_exons {
-                if let Some(ref mut last) = merged_exons.last_mut() {
-                    if (exon.start-1) <= last.end && (last.start-1) <= exon.end {
-                        last.end = exon.end;
-                        continue;
-                    }
-                }
-                merged_exons.push((exon.start-1)..exon.end);
-            }
+            let merged_exons = annotated_exons(
+                &annot, &chr, row.intron_start - 1..row.intron_end, Strand::from(row.strand.as_str()), &exon_types);
 
             let mut intron_bases = 0u64;
             let mut intron_coverage = 0u64;
@@ -330,32 +354,62 @@ fn run() -> Result<()> {
     let transcript_type = String::from("transcript");
     let mut annot = if let Some(annotfile_gff) = options.annotfile_gff.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gff);
-        IndexedAnnotation::from_gff(
+        IndexedAnnotation::from_file_with(
             &annotfile_gff, 
+            "gff",
+            "",
+            "",
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else if let Some(annotfile_gtf) = options.annotfile_gtf.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gtf);
-        IndexedAnnotation::from_gtf(&annotfile_gtf, 
+        IndexedAnnotation::from_file_with(&annotfile_gtf, 
+            "gtf",
+            options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
+            options.transcript_type.get(0).unwrap_or(&transcript_type),
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else if let Some(annotfile) = options.annotfile.clone() {
+        let format = annotation_format(&annotfile, &options.annot_format)?;
+        eprintln!("Reading {} annotation file {:?}", format, &annotfile);
+        IndexedAnnotation::from_file_with(&annotfile, 
+            &format,
             options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
             options.transcript_type.get(0).unwrap_or(&transcript_type),
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else {
         Options::clap().print_help()?;
         eprintln!("\n\nNo annotation file was given!");
         std::process::exit(1);
     };
+    if let Some(chromalias_file) = options.chromalias_file.clone() {
+        annot.set_chrom_aliases(ChromAliases::from_file(&chromalias_file)?);
+    }
+    if let Some(ref filter) = options.filter {
+        let removed = annot.retain_features(filter)?;
+        eprintln!("Removed {} annotation rows that did not pass the filter", removed.len());
+    }
     eprintln!("Getting refseq lengths from bam file {:?}", &bamfiles[0]);
     let refs = match options.sizes_file.clone() {
-        Some(sizes_file) => read_sizes_file(&sizes_file, &annot.chrmap)?,
-        None => get_bam_refs(&bamfiles[0], &annot.chrmap)?,
+        Some(sizes_file) => read_sizes_file(&sizes_file, &annot)?,
+        None => get_bam_refs(&bamfiles[0], &annot)?,
     };
     annot.refs = refs;
+    if options.autostrand {
+        eprintln!("Inferring strandedness of the unstranded bam files");
+        assign_bam_strands(&bamfiles, &mut bamstrand, &annot, &options.strand_report, options.strand_sample_reads, options.strand_threshold)?;
+    }
     
     // get the total bam reads
-    eprintln!("Running samtools idxstats to get total bam read counts");
-    let total_reads = get_bam_total_reads(&bamfiles)?;
+    eprintln!("Getting total bam read counts");
+    let total_reads = get_bam_total_reads(&bamfiles, options.read_count)?;
     eprintln!("Found {} total reads", total_reads);
     write_intron_cov(&options, &bamfiles, &bamstrand,  &Arc::new(annot))?;
     Ok(())
@@ -366,3 +420,29 @@ fn main() -> Result<()> {
     std::env::set_var("RUST_BACKTRACE", "full");
     run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exon_types() {
+        let gff = std::env::temp_dir().join(format!("adjusted_intron_psi_{}.gff", std::process::id())).to_str().unwrap().to_string();
+        std::fs::write(&gff, concat!(
+            "chr1\tt\tmRNA\t1\t1000\t.\t+\t.\tID=t1\n",
+            "chr1\tt\texon_part\t101\t200\t.\t+\t.\tParent=t1\n",
+            "chr1\tt\texon_part\t151\t300\t.\t+\t.\tParent=t1\n",
+            "chr1\tt\texon_part\t501\t600\t.\t+\t.\tParent=t1\n",
+            "chr1\tt\texon_part\t701\t800\t.\t-\t.\tParent=t1\n",
+            "chr1\tt\texon\t801\t900\t.\t+\t.\tParent=t1\n")).unwrap();
+        let annot = IndexedAnnotation::from_file(&gff, "gff", "gene", "mRNA", &None, &None).unwrap();
+        std::fs::remove_file(&gff).unwrap();
+        // only the configured exon types on the same strand count
+        let exon_types = vec!["exon_part".to_string()];
+        assert_eq!(annotated_exons(&annot, "chr1", 0..1000, Strand::Forward, &exon_types), vec![100..300, 500..600]);
+        assert_eq!(annotated_exons(&annot, "chr1", 0..1000, Strand::Reverse, &exon_types), vec![700..800]);
+        assert_eq!(annotated_exons(&annot, "chr1", 250..550, Strand::Forward, &exon_types), vec![150..300, 500..600]);
+        assert_eq!(annotated_exons(&annot, "chr1", 0..1000, Strand::Forward, &["exon".to_string()]), vec![800..900]);
+        assert_eq!(annotated_exons(&annot, "chr2", 0..1000, Strand::Forward, &exon_types), vec![]);
+    }
+}
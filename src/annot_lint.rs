@@ -0,0 +1,161 @@
+use anyhow::{Result, anyhow};
+
+use cassette_reannotation::lint::*;
+use cassette_reannotation::indexed_annotation::FeatureTypes;
+
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "annot_lint", about = "Validate a GFF/GTF annotation file")]
+struct Options {
+    // input files
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
+    annotfile_gff: Option<String>,
+    #[structopt(long="gtf", help = "A genome annotation file in gtf format, optionally gzipped, or - for stdin", name="ANNOT_GTF_FILE")]
+    annotfile_gtf: Option<String>,
+    // feature types
+    #[structopt(long="exon_type", help = "The exon type(s) to check CDS features against", name="EXON_TYPE")]
+    exon_type: Vec<String>,
+    #[structopt(long="transcript_type", help = "The GTF transcript type(s)", name="TRANSCRIPT_TYPE")]
+    transcript_type: Vec<String>,
+    #[structopt(long="gene_type", help = "The GTF gene type(s)", name="GENE_TYPE")]
+    gene_type: Vec<String>,
+    #[structopt(long="cds_type", help = "The CDS type(s)", name="CDS_TYPE")]
+    cds_type: Vec<String>,
+    // output file
+    #[structopt(long="out", short="o", help = "Report file, written as JSON if the name ends in .json, otherwise tab-delimited", name="OUT_FILE", default_value="-")]
+    outfile: String,
+    // flags
+    #[structopt(long="warnings_as_errors", help = "Fail if there are any warnings")]
+    warnings_as_errors: bool,
+}
+
+fn run(mut options: Options) -> Result<bool> {
+    // set defaults for feature types
+    options.gene_type =
+        (if options.gene_type.is_empty() { vec!["gene".to_string()] }
+         else { options.gene_type.clone() }).into_iter().collect();
+    options.transcript_type =
+        (if options.transcript_type.is_empty() { vec!["transcript".to_string()] }
+         else { options.transcript_type.clone() }).into_iter().collect();
+    options.exon_type =
+        (if options.exon_type.is_empty() { vec!["exon".to_string()] }
+         else { options.exon_type.clone() }).into_iter().collect();
+    options.cds_type =
+        (if options.cds_type.is_empty() { vec!["CDS".to_string()] }
+         else { options.cds_type.clone() }).into_iter().collect();
+    let types = FeatureTypes::new(&options.gene_type, &options.transcript_type, &options.exon_type, &options.cds_type);
+    let report = if let Some(ref annotfile_gff) = options.annotfile_gff {
+        eprintln!("Checking annotation file {:?}", &annotfile_gff);
+        lint_file(annotfile_gff, "gff", &types)?
+    } else if let Some(ref annotfile_gtf) = options.annotfile_gtf {
+        eprintln!("Checking annotation file {:?}", &annotfile_gtf);
+        lint_file(annotfile_gtf, "gtf", &types)?
+    } else {
+        Options::clap().print_help()?;
+        return Err(anyhow!("No annotation file was given!"));
+    };
+    report.write(&options.outfile)?;
+    eprintln!("Found {} errors and {} warnings in {} lines", report.errors, report.warnings, report.lines);
+    Ok(report.passed && !(options.warnings_as_errors && report.warnings > 0))
+}
+
+fn main() -> Result<()> {
+    // enable stack traces
+    std::env::set_var("RUST_BACKTRACE", "full");
+    match exit_status(run(Options::from_args())) {
+        0 => Ok(()),
+        status => std::process::exit(status),
+    }
+}
+
+/// The exit status: 1 if the annotation has problems, and 2 on other errors.
+fn exit_status(result: Result<bool>) -> i32 {
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // lint an annotation with the given arguments, returning the exit
+    // status and the names of the failed checks
+    fn lint(name: &str, format: &str, text: &str, args: &[&str]) -> (i32, Vec<String>) {
+        let file = |ext: &str| std::env::temp_dir().join(format!("annot_lint_{}_{}.{}", std::process::id(), name, ext)).to_str().unwrap().to_string();
+        let annotfile = file(format);
+        let report = file("json");
+        std::fs::write(&annotfile, text.replace("  ", "\t")).unwrap();
+        let format_arg = format!("--{}", format);
+        let mut all_args = vec!["annot_lint", &format_arg, &annotfile, "-o", &report];
+        all_args.extend(args);
+        let status = exit_status(run(Options::from_iter(all_args)));
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+        std::fs::remove_file(&annotfile).unwrap();
+        std::fs::remove_file(&report).unwrap();
+        let checks = value["issues"].as_array().unwrap().iter().
+            map(|i| i["check"].as_str().unwrap().to_string()).
+            collect::<Vec<_>>();
+        (status, checks)
+    }
+
+    const TRANSCRIPT: &str = concat!(
+        "chr1  t  gene  1  1000  .  +  .  ID=g1\n",
+        "chr1  t  mRNA  1  1000  .  +  .  ID=t1;Parent=g1\n",
+        "chr1  t  exon  1  100  .  +  .  ID=e1;Parent=t1\n",
+        "chr1  t  CDS  51  100  .  +  0  ID=c1;Parent=t1\n");
+
+    #[test]
+    fn checks() {
+        let cases = [
+            ("clean", "gff", "", None),
+            ("duplicate_id", "gff", "chr1  t  exon  200  300  .  +  .  ID=g1;Parent=t1\n", Some(1)),
+            ("dangling_parent", "gff", "chr1  t  exon  200  300  .  +  .  ID=e2;Parent=t2\n", Some(1)),
+            ("mixed_seqname", "gff", "chr2  t  exon  200  300  .  +  .  ID=e2;Parent=t1\n", Some(1)),
+            ("mixed_strand", "gff", "chr1  t  exon  200  300  .  -  .  ID=e2;Parent=t1\n", Some(1)),
+            ("outside_parent", "gff", "chr1  t  exon  900  1100  .  +  .  ID=e2;Parent=t1\n", Some(1)),
+            ("cds_outside_exon", "gff", "chr1  t  CDS  150  200  .  +  0  ID=c1;Parent=t1\n", Some(1)),
+            ("cds_phase", "gff", "chr1  t  CDS  60  90  .  +  .  ID=c2;Parent=t1\n", Some(0)),
+        ];
+        for (check, format, extra, status) in &cases {
+            let (actual_status, checks) = lint(check, format, &format!("{}{}", TRANSCRIPT, extra), &[]);
+            match status {
+                Some(status) => {
+                    assert_eq!(checks, vec![check.to_string()], "{}", check);
+                    assert_eq!(actual_status, *status, "{}", check);
+                }
+                None => assert_eq!((actual_status, checks), (0, vec![])),
+            }
+        }
+        // GTF records carry their gene and transcript IDs
+        let gtf = concat!(
+            "chr1  t  transcript  1  1000  .  +  .  gene_id \"g1\"; transcript_id \"t1\";\n",
+            "chr1  t  exon  1  100  .  +  .  gene_id \"g1\";\n");
+        assert_eq!(lint("missing_attribute", "gtf", gtf, &[]), (1, vec!["missing_attribute".to_string()]));
+        // warnings only fail with --warnings_as_errors
+        let missing_phase = format!("{}chr1  t  CDS  60  90  .  +  .  ID=c2;Parent=t1\n", TRANSCRIPT);
+        assert_eq!(lint("warnings", "gff", &missing_phase, &["--warnings_as_errors"]), (1, vec!["cds_phase".to_string()]));
+        // other errors exit with 2
+        let missing = std::env::temp_dir().join(format!("annot_lint_{}_missing.gff", std::process::id()));
+        assert_eq!(exit_status(run(Options::from_iter(&["annot_lint", "--gff", missing.to_str().unwrap()]))), 2);
+        assert_eq!(exit_status(run(Options::from_iter(&["annot_lint"]))), 2);
+    }
+
+    #[test]
+    fn feature_types() {
+        let text = concat!(
+            "chr1  t  mRNA  1  1000  .  +  .  ID=t1\n",
+            "chr1  t  exon_part  1  100  .  +  .  ID=e1;Parent=t1\n",
+            "chr1  t  coding_part  150  200  .  +  .  ID=c1;Parent=t1\n");
+        // the CDS and exon checks follow the configured types
+        assert_eq!(lint("default_types", "gff", text, &[]), (0, vec![]));
+        assert_eq!(lint("types", "gff", text, &["--exon_type", "exon_part", "--cds_type", "coding_part"]),
+            (1, vec!["cds_phase".to_string(), "cds_outside_exon".to_string()]));
+    }
+}
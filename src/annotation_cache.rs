@@ -0,0 +1,311 @@
+//! A binary on-disk cache of parsed annotation files, so repeated runs can
+//! skip parsing the GFF/GTF and resolving its feature hierarchy. The cache
+//! is written next to the annotation file and is used only while the
+//! annotation file, chr maps and parse options are unchanged.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::time::UNIX_EPOCH;
+use linked_hash_map::LinkedHashMap;
+use anyhow::{Result, anyhow};
+
+use crate::indexed_annotation::{IndexedAnnotation, Record, Attributes, Strand, Frame};
+use crate::symbol::Sym;
+use crate::chrom_alias::{ChromAliases, ChromMatcher};
+
+const MAGIC: &[u8] = b"ANNOTCACHE";
+// increment whenever the encoding or the parsed IndexedAnnotation changes
+const VERSION: u64 = 5;
+
+/// The cache file used for an annotation file.
+pub fn cache_file(annotfile: &str) -> String {
+    format!("{}.annotcache", annotfile)
+}
+
+// path, size and modification time of an input file
+fn file_stamp(file: &str) -> Result<String> {
+    let metadata = std::fs::metadata(file)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(format!("{}:{}:{}.{:09}", std::fs::canonicalize(file)?.display(), metadata.len(), mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+/// Describe the inputs and options that an annotation was parsed from.
+/// Returns None if any input is stdin, since stdin cannot be cached.
+pub fn cache_key(
+    annotfile: &str,
+    filetype: &str,
+    gene_type: &str,
+    transcript_type: &str,
+    chrmap_file: &Option<String>,
+    vizchrmap_file: &Option<String>,
+    strict: bool)
+    -> Option<String>
+{
+    let mut key = vec![
+        filetype.to_string(),
+        gene_type.to_string(),
+        transcript_type.to_string(),
+        strict.to_string(),
+    ];
+    for file in [Some(annotfile.to_string()), chrmap_file.clone(), vizchrmap_file.clone()].iter() {
+        match file {
+            Some(file) if file == "-" => return None,
+            Some(file) => key.push(file_stamp(file).ok()?),
+            None => key.push(String::new()),
+        }
+    }
+    Some(key.join("\t"))
+}
+
+struct Encoder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Encoder<W> {
+    fn u64(&mut self, value: u64) -> Result<()> {
+        self.writer.write_all(&value.to_le_bytes())?;
+        Ok(())
+    }
+    fn str(&mut self, value: &str) -> Result<()> {
+        self.u64(value.len() as u64)?;
+        self.writer.write_all(value.as_bytes())?;
+        Ok(())
+    }
+    fn rows(&mut self, map: &HashMap<usize,Vec<usize>>) -> Result<()> {
+        self.u64(map.len() as u64)?;
+        for (row, rows) in map {
+            self.u64(*row as u64)?;
+            self.u64(rows.len() as u64)?;
+            for r in rows {
+                self.u64(*r as u64)?;
+            }
+        }
+        Ok(())
+    }
+    fn attributes(&mut self, attributes: &Attributes) -> Result<()> {
+        self.u64(attributes.len() as u64)?;
+        for (k, vs) in attributes.iter() {
+            self.str(k)?;
+            self.u64(vs.len() as u64)?;
+            for v in vs {
+                self.str(v)?;
+            }
+        }
+        Ok(())
+    }
+    fn strs<'a>(&mut self, len: usize, pairs: impl Iterator<Item=(&'a String,&'a String)>) -> Result<()> {
+        self.u64(len as u64)?;
+        for (k, v) in pairs {
+            self.str(k)?;
+            self.str(v)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+    fn usize(&mut self) -> Result<usize> {
+        Ok(self.u64()? as usize)
+    }
+    fn str(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        let mut bytes = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(anyhow!("Truncated string in annotation cache"));
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+    fn sym(&mut self) -> Result<Sym> {
+        Ok(Sym::from(self.str()?))
+    }
+    fn rows(&mut self) -> Result<HashMap<usize,Vec<usize>>> {
+        let len = self.usize()?;
+        let mut map = HashMap::new();
+        for _ in 0..len {
+            let row = self.usize()?;
+            let count = self.usize()?;
+            let mut rows = Vec::new();
+            for _ in 0..count {
+                rows.push(self.usize()?);
+            }
+            map.insert(row, rows);
+        }
+        Ok(map)
+    }
+    fn attributes(&mut self) -> Result<Attributes> {
+        let len = self.usize()?;
+        let mut attributes = Attributes::new();
+        for _ in 0..len {
+            let key = self.sym()?;
+            let count = self.usize()?;
+            let mut values = Vec::new();
+            for _ in 0..count {
+                values.push(self.sym()?);
+            }
+            attributes.insert_all(key, values);
+        }
+        Ok(attributes)
+    }
+    fn strs<C: std::iter::FromIterator<(String,String)>>(&mut self) -> Result<C> {
+        let len = self.usize()?;
+        (0..len).map(|_| Ok((self.str()?, self.str()?))).collect()
+    }
+}
+
+/// Write a parsed annotation to a cache file. The file is written under a
+/// temporary name and renamed so readers never see a partial cache.
+pub fn write_cache(file: &str, key: &str, annot: &IndexedAnnotation) -> Result<()> {
+    let tmp_file = format!("{}.{}.tmp", file, std::process::id());
+    {   let mut e = Encoder { writer: BufWriter::new(File::create(&tmp_file)?) };
+        e.writer.write_all(MAGIC)?;
+        e.u64(VERSION)?;
+        e.str(key)?;
+        e.u64(annot.rows.len() as u64)?;
+        for record in &annot.rows {
+            e.u64(record.row as u64)?;
+            e.str(&record.seqname)?;
+            e.str(&record.source)?;
+            e.str(&record.feature_type)?;
+            e.u64(record.start)?;
+            e.u64(record.end)?;
+            e.str(&record.score)?;
+            e.str(record.strand.as_str())?;
+            e.str(record.frame.as_str())?;
+            e.attributes(&record.attributes)?;
+        }
+        e.u64(annot.id2row.len() as u64)?;
+        for (id, row) in &annot.id2row {
+            e.str(id)?;
+            e.u64(*row as u64)?;
+        }
+        e.rows(&annot.row2segments)?;
+        e.rows(&annot.row2parents)?;
+        e.rows(&annot.row2children)?;
+        e.strs(annot.chrmap.len(), annot.chrmap.iter())?;
+        e.strs(annot.vizchrmap.len(), annot.vizchrmap.iter())?;
+        e.u64(annot.refs.len() as u64)?;
+        for (chr, length) in &annot.refs {
+            e.str(chr)?;
+            e.u64(*length)?;
+        }
+        e.writer.flush()?;
+    }
+    std::fs::rename(&tmp_file, file)?;
+    Ok(())
+}
+
+/// Read a cached annotation. Returns None if the cache file is missing or
+/// was written by another version or from different inputs.
+pub fn read_cache(file: &str, key: &str) -> Result<Option<IndexedAnnotation>> {
+    let mut d = match File::open(file) {
+        Ok(f) => Decoder { reader: BufReader::new(f) },
+        Err(_) => return Ok(None),
+    };
+    let mut magic = vec![0u8; MAGIC.len()];
+    d.reader.read_exact(&mut magic)?;
+    if magic != MAGIC || d.u64()? != VERSION || d.str()? != key {
+        return Ok(None);
+    }
+    let len = d.usize()?;
+    let mut rows = Vec::new();
+    for _ in 0..len {
+        rows.push(Record {
+            row: d.usize()?,
+            seqname: d.sym()?,
+            source: d.sym()?,
+            feature_type: d.sym()?,
+            start: d.u64()?,
+            end: d.u64()?,
+            score: d.sym()?,
+            strand: Strand::from(d.str()?.as_str()),
+            frame: Frame::from(d.str()?.as_str()),
+            attributes: d.attributes()?,
+        });
+    }
+    let len = d.usize()?;
+    let mut id2row = HashMap::new();
+    for _ in 0..len {
+        id2row.insert(d.sym()?, d.usize()?);
+    }
+    let row2segments = d.rows()?;
+    let row2parents = d.rows()?;
+    let row2children = d.rows()?;
+    let chrmap = d.strs::<HashMap<_,_>>()?;
+    let vizchrmap = d.strs::<HashMap<_,_>>()?;
+    let len = d.usize()?;
+    let mut refs = LinkedHashMap::new();
+    for _ in 0..len {
+        refs.insert(d.str()?, d.u64()?);
+    }
+    let tree = IndexedAnnotation::build_tree(&rows)?;
+    let seqnames = ChromMatcher::new(ChromAliases::new(), refs.keys());
+    Ok(Some(IndexedAnnotation {
+        rows: rows,
+        id2row: id2row,
+        row2segments: row2segments,
+        row2parents: row2parents,
+        row2children: row2children,
+        tree: tree,
+        chrmap: chrmap,
+        vizchrmap: vizchrmap,
+        refs: refs,
+        seqnames: seqnames,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalidated_by_changed_inputs() {
+        let dir = std::env::temp_dir();
+        let annotfile = dir.join(format!("annotation_cache_{}.gff3", std::process::id()));
+        let annotfile = annotfile.to_str().unwrap();
+        let chrmap_file = dir.join(format!("annotation_cache_{}.chrmap", std::process::id()));
+        let chrmap_file = Some(chrmap_file.to_str().unwrap().to_string());
+        let gene = "1\ttest\tgene\t100\t200\t.\t+\t.\tID=g1\n";
+        let read = || IndexedAnnotation::from_file_with(annotfile, "gff", "", "", &chrmap_file, &None, false, true).unwrap();
+
+        std::fs::write(annotfile, gene).unwrap();
+        std::fs::write(chrmap_file.as_ref().unwrap(), "1\tchr1\n").unwrap();
+        let annot = read();
+        assert_eq!(annot.rows.len(), 1);
+        assert_eq!(annot.rows[0].seqname, "chr1");
+        assert!(std::path::Path::new(&cache_file(annotfile)).exists());
+        let key = cache_key(annotfile, "gff", "", "", &chrmap_file, &None, false).unwrap();
+        assert_eq!(read_cache(&cache_file(annotfile), &key).unwrap().unwrap().rows.len(), 1);
+
+        // a changed chr map is not read from the cache
+        std::fs::write(chrmap_file.as_ref().unwrap(), "1\tchrom1\n").unwrap();
+        let new_key = cache_key(annotfile, "gff", "", "", &chrmap_file, &None, false).unwrap();
+        assert_ne!(key, new_key);
+        assert!(read_cache(&cache_file(annotfile), &new_key).unwrap().is_none());
+        assert_eq!(read().rows[0].seqname, "chrom1");
+
+        // neither is a changed annotation
+        std::fs::write(annotfile, format!("{}{}", gene, gene.replace("g1", "g2"))).unwrap();
+        let annot = read();
+        assert_eq!(annot.rows.len(), 2);
+        assert_eq!(annot.rows[1].seqname, "chrom1");
+
+        // and the cache is neither read nor written when it is turned off
+        std::fs::remove_file(cache_file(annotfile)).unwrap();
+        let annot = IndexedAnnotation::from_file_with(annotfile, "gff", "", "", &chrmap_file, &None, false, false).unwrap();
+        assert_eq!(annot.rows.len(), 2);
+        assert!(!std::path::Path::new(&cache_file(annotfile)).exists());
+
+        std::fs::remove_file(annotfile).unwrap();
+        std::fs::remove_file(chrmap_file.unwrap()).unwrap();
+    }
+}
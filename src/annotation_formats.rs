@@ -0,0 +1,367 @@
+//! Transcript table formats that are read as annotations: BED12 (and
+//! bigBed), UCSC genePred and refFlat. Each line is one transcript, which is
+//! converted into GTF-style transcript, exon and CDS records carrying
+//! gene_id and transcript_id attributes, so the usual GTF hierarchy can be
+//! built from them.
+use std::collections::HashMap;
+use std::ops::Range;
+use anyhow::{Result, anyhow};
+
+use crate::indexed_annotation::{Record, Attributes, Strand, Frame};
+use crate::symbol::Sym;
+
+/// The annotation file formats that IndexedAnnotation can read.
+pub const ANNOTATION_FORMATS: &[&str] = &["gff", "gtf", "bed", "bigbed", "genepred", "refflat"];
+
+/// Get the format of an annotation file, either the given format or one
+/// guessed from the file extension. A .gz or .bgz suffix is ignored.
+pub fn annotation_format(annotfile: &str, format: &Option<String>) -> Result<String> {
+    if let Some(format) = format {
+        let format = format.to_lowercase();
+        if !ANNOTATION_FORMATS.contains(&format.as_str()) {
+            return Err(anyhow!("Unknown annotation format {}, expected one of {}", format, ANNOTATION_FORMATS.join(", ")));
+        }
+        return Ok(format);
+    }
+    let name = annotfile.to_lowercase();
+    let name = name.trim_end_matches(".gz").trim_end_matches(".bgz");
+    let format =
+        if name.ends_with(".gff") || name.ends_with(".gff3") { "gff" }
+        else if name.ends_with(".gtf") { "gtf" }
+        else if name.ends_with(".bed") || name.ends_with(".bed12") { "bed" }
+        else if name.ends_with(".bb") || name.ends_with(".bigbed") { "bigbed" }
+        else if name.ends_with(".genepred") || name.ends_with(".gp") { "genepred" }
+        else if name.ends_with(".refflat") || name.ends_with("refflat.txt") { "refflat" }
+        else {
+            return Err(anyhow!("Could not tell the format of annotation file {} from its extension, please give the format", annotfile));
+        };
+    Ok(format.to_string())
+}
+
+/// Returns true for lines of a transcript table that hold no transcript.
+fn is_table_comment(line: &str) -> bool {
+    line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") || line.trim().is_empty()
+}
+
+/// Converts the lines of a BED, genePred or refFlat file into records.
+pub struct TranscriptTable {
+    filetype: String,
+    transcript_type: String,
+    // how many times each transcript name has been seen
+    transcript_names: HashMap<String, usize>,
+    // the gene_id given to each (gene name, seqname, strand)
+    gene_ids: HashMap<(String, String, String), String>,
+    // how many loci each gene name has been seen at
+    gene_names: HashMap<String, usize>,
+}
+
+impl TranscriptTable {
+    /// Returns None for formats that are not transcript tables.
+    pub fn new(filetype: &str, transcript_type: &str) -> Option<TranscriptTable> {
+        match filetype {
+            "bed" | "bigbed" | "genepred" | "refflat" => Some(TranscriptTable {
+                filetype: filetype.to_string(),
+                transcript_type: transcript_type.to_string(),
+                transcript_names: HashMap::new(),
+                gene_ids: HashMap::new(),
+                gene_names: HashMap::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Convert one line into a transcript record followed by its exon and CDS
+    /// records, numbered from `row`. Comment and track lines give no records.
+    pub fn records(&mut self, row: usize, line: &str, chrmap: &HashMap<String,String>) -> Result<Vec<Record>> {
+        if is_table_comment(line) {
+            return Ok(Vec::new());
+        }
+        let cols = line.split('\t').collect::<Vec<_>>();
+        let transcript = match self.filetype.as_str() {
+            "bed" | "bigbed" => parse_bed(&cols)?,
+            "genepred" => {
+                // UCSC table dumps start with a bin column
+                let cols = if cols.len() > 3 && !["+", "-"].contains(&cols[2]) && ["+", "-"].contains(&cols[3]) { &cols[1..] } else { &cols[..] };
+                parse_genepred(cols)?
+            }
+            "refflat" => {
+                let mut transcript = parse_genepred(cols.get(1..).unwrap_or(&[]))?;
+                transcript.gene = Some(cols[0].to_string());
+                transcript
+            }
+            _ => return Err(anyhow!("Don't know how to read filetype {}", self.filetype)),
+        };
+        let seqname = chrmap.get(&transcript.seqname).unwrap_or(&transcript.seqname).clone();
+
+        // make the transcript IDs unique, and give each gene name a separate
+        // gene ID on each chromosome and strand
+        let count = self.transcript_names.entry(transcript.name.clone()).or_insert(0);
+        *count += 1;
+        let transcript_id = if *count == 1 { transcript.name.clone() } else { format!("{}.{}", transcript.name, count) };
+        let gene_id = match transcript.gene {
+            Some(ref gene) => {
+                let gene_names = &mut self.gene_names;
+                self.gene_ids.entry((gene.clone(), seqname.clone(), transcript.strand.clone())).
+                    or_insert_with(|| {
+                        let count = gene_names.entry(gene.clone()).or_insert(0);
+                        *count += 1;
+                        if *count == 1 { gene.clone() } else { format!("{}.{}", gene, count) }
+                    }).clone()
+            }
+            None => transcript_id.clone(),
+        };
+
+        let source = match self.filetype.as_str() {
+            "bed" | "bigbed" => "bed",
+            "genepred" => "genePred",
+            _ => "refFlat",
+        };
+        let mut attributes = Attributes::new();
+        attributes.insert("gene_id", gene_id);
+        attributes.insert("transcript_id", transcript_id);
+        if let Some(ref gene) = transcript.gene {
+            attributes.insert("gene_name", gene);
+        }
+        let record = |row: usize, feature_type: &str, range: &Range<u64>, frame: Frame, attributes: Attributes| Record {
+            row: row,
+            seqname: Sym::new(&seqname),
+            source: Sym::new(source),
+            feature_type: Sym::new(feature_type),
+            start: range.start+1,
+            end: range.end,
+            score: Sym::new(&transcript.score),
+            strand: Strand::from(transcript.strand.as_str()),
+            frame: frame,
+            attributes: attributes,
+        };
+
+        let mut records = vec![record(row, &self.transcript_type, &(transcript.start..transcript.end), Frame::None, attributes.clone())];
+        // number exons and compute CDS phases in the direction of transcription
+        let reverse = transcript.strand == "-";
+        let mut exons = transcript.exons.clone();
+        if reverse { exons.reverse() }
+        let mut cds_length = 0;
+        let mut cdss = Vec::<Record>::new();
+        for (i, exon) in exons.iter().enumerate() {
+            let mut exon_attributes = attributes.clone();
+            exon_attributes.insert("exon_number", (i+1).to_string());
+            records.push(record(row+records.len(), "exon", exon, Frame::None, exon_attributes.clone()));
+            let cds = std::cmp::max(exon.start, transcript.cds.start)..std::cmp::min(exon.end, transcript.cds.end);
+            if cds.start < cds.end {
+                let phase = (3 - cds_length % 3) % 3;
+                cds_length += cds.end - cds.start;
+                cdss.push(record(0, "CDS", &cds, Frame::from(phase), exon_attributes));
+            }
+        }
+        for mut cds in cdss {
+            cds.row = row+records.len();
+            records.push(cds);
+        }
+        Ok(records)
+    }
+}
+
+// the fields of one transcript table line, in 0-based half-open coordinates
+struct TableTranscript {
+    seqname: String,
+    name: String,
+    gene: Option<String>,
+    strand: String,
+    start: u64,
+    end: u64,
+    score: String,
+    cds: Range<u64>,
+    exons: Vec<Range<u64>>,
+}
+
+fn parse_u64(value: &str, name: &str) -> Result<u64> {
+    value.trim().parse::<u64>().map_err(|_| anyhow!("Could not parse {} \"{}\"", name, value))
+}
+
+fn parse_list(value: &str, name: &str) -> Result<Vec<u64>> {
+    value.split(',').filter(|v| !v.trim().is_empty()).map(|v| parse_u64(v, name)).collect()
+}
+
+fn check_exons(exons: &[Range<u64>], start: u64, end: u64) -> Result<()> {
+    for exon in exons {
+        if exon.start >= exon.end || exon.start < start || exon.end > end {
+            return Err(anyhow!("Block {}..{} is empty or outside of the transcript {}..{}", exon.start, exon.end, start, end));
+        }
+    }
+    Ok(())
+}
+
+// chrom, start, end, and optionally name, score, strand, thickStart,
+// thickEnd, itemRgb, blockCount, blockSizes, blockStarts
+fn parse_bed(cols: &[&str]) -> Result<TableTranscript> {
+    if cols.len() < 3 {
+        return Err(anyhow!("Expected at least 3 tab-delimited BED columns, found {}", cols.len()));
+    }
+    let start = parse_u64(cols[1], "chromStart")?;
+    let end = parse_u64(cols[2], "chromEnd")?;
+    if start >= end {
+        return Err(anyhow!("Invalid feature range {}..{}", start, end));
+    }
+    let cds = if cols.len() >= 8 { parse_u64(cols[6], "thickStart")?..parse_u64(cols[7], "thickEnd")? } else { start..start };
+    let exons = if cols.len() >= 12 {
+        let sizes = parse_list(cols[10], "blockSizes")?;
+        let starts = parse_list(cols[11], "blockStarts")?;
+        if sizes.len() != starts.len() || sizes.len() as u64 != parse_u64(cols[9], "blockCount")? {
+            return Err(anyhow!("The blockCount, blockSizes and blockStarts columns do not agree"));
+        }
+        let mut exons = starts.iter().zip(sizes.iter()).map(|(s, l)| (start+s)..(start+s+l)).collect::<Vec<_>>();
+        exons.sort_by_key(|e| e.start);
+        exons
+    } else { vec![Range{start: start, end: end}] };
+    check_exons(&exons, start, end)?;
+    Ok(TableTranscript {
+        seqname: cols[0].to_string(),
+        name: cols.get(3).filter(|n| !n.is_empty()).map(|n| n.to_string()).
+            unwrap_or_else(|| format!("{}:{}-{}", cols[0], start+1, end)),
+        gene: None,
+        strand: cols.get(5).map(|s| s.to_string()).unwrap_or_else(|| String::from(".")),
+        start: start,
+        end: end,
+        score: cols.get(4).map(|s| s.to_string()).unwrap_or_else(|| String::from(".")),
+        cds: cds,
+        exons: exons,
+    })
+}
+
+// name, chrom, strand, txStart, txEnd, cdsStart, cdsEnd, exonCount,
+// exonStarts, exonEnds, and for genePredExt score and name2
+fn parse_genepred(cols: &[&str]) -> Result<TableTranscript> {
+    if cols.len() < 10 {
+        return Err(anyhow!("Expected at least 10 tab-delimited genePred columns, found {}", cols.len()));
+    }
+    let start = parse_u64(cols[3], "txStart")?;
+    let end = parse_u64(cols[4], "txEnd")?;
+    if start >= end {
+        return Err(anyhow!("Invalid feature range {}..{}", start, end));
+    }
+    let starts = parse_list(cols[8], "exonStarts")?;
+    let ends = parse_list(cols[9], "exonEnds")?;
+    if starts.len() != ends.len() || starts.len() as u64 != parse_u64(cols[7], "exonCount")? {
+        return Err(anyhow!("The exonCount, exonStarts and exonEnds columns do not agree"));
+    }
+    let mut exons = starts.iter().zip(ends.iter()).map(|(s, e)| *s..*e).collect::<Vec<_>>();
+    exons.sort_by_key(|e| e.start);
+    check_exons(&exons, start, end)?;
+    Ok(TableTranscript {
+        seqname: cols[1].to_string(),
+        name: cols[0].to_string(),
+        gene: cols.get(11).filter(|n| !n.is_empty()).map(|n| n.to_string()),
+        strand: cols[2].to_string(),
+        start: start,
+        end: end,
+        score: cols.get(10).map(|s| s.to_string()).unwrap_or_else(|| String::from(".")),
+        cds: parse_u64(cols[5], "cdsStart")?..parse_u64(cols[6], "cdsEnd")?,
+        exons: exons,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (type, start, end, phase, exon_number) of the records of one line
+    fn records(filetype: &str, line: &str) -> Vec<(String, u64, u64, String, String)> {
+        let mut table = TranscriptTable::new(filetype, "transcript").unwrap();
+        table.records(0, line, &HashMap::new()).unwrap().iter().enumerate().map(|(i, r)| {
+            assert_eq!(r.row, i);
+            (r.feature_type.to_string(), r.start, r.end, r.frame.to_string(),
+                r.attributes.get("exon_number").map(|n| n.to_string()).unwrap_or_default())
+        }).collect()
+    }
+
+    fn record(feature_type: &str, start: u64, end: u64, phase: &str, exon_number: &str) -> (String, u64, u64, String, String) {
+        (feature_type.to_string(), start, end, phase.to_string(), exon_number.to_string())
+    }
+
+    const GENEPRED: &str = "tx1\tchr1\t+\t100\t1000\t150\t900\t3\t100,500,900,\t200,700,1000,";
+
+    #[test]
+    fn transcript_tables() {
+        // the CDS ends at the start of the last exon, so that exon has none
+        let expected = vec![
+            record("transcript", 101, 1000, ".", ""),
+            record("exon", 101, 200, ".", "1"),
+            record("exon", 501, 700, ".", "2"),
+            record("exon", 901, 1000, ".", "3"),
+            record("CDS", 151, 200, "0", "1"),
+            record("CDS", 501, 700, "1", "2"),
+        ];
+        assert_eq!(records("bed", "chr1\t100\t1000\ttx1\t0\t+\t150\t900\t0\t3\t100,200,100,\t0,400,800,"), expected);
+        assert_eq!(records("genepred", GENEPRED), expected);
+        assert_eq!(records("genepred", &format!("585\t{}", GENEPRED)), expected);
+        assert_eq!(records("refflat", &format!("GENE1\t{}", GENEPRED)), expected);
+
+        // BED lines without blocks are one exon, and without thick ends
+        // are noncoding
+        assert_eq!(records("bed", "chr1\t100\t200\ttx1"), vec![
+            record("transcript", 101, 200, ".", ""),
+            record("exon", 101, 200, ".", "1"),
+        ]);
+        assert_eq!(records("bed", "track name=genes"), vec![]);
+        assert_eq!(records("genepred", "# comment"), vec![]);
+    }
+
+    #[test]
+    fn minus_strand_phases() {
+        // exons are numbered and phased from the 3' end of the table: the CDS
+        // pieces are 50, 200 and 50 bases, so the phases are 0, (3-50%3)%3 = 1
+        // and (3-250%3)%3 = 2
+        let line = "tx2\tchr1\t-\t100\t1000\t150\t950\t3\t100,500,900,\t200,700,1000,";
+        assert_eq!(records("genepred", line), vec![
+            record("transcript", 101, 1000, ".", ""),
+            record("exon", 901, 1000, ".", "1"),
+            record("exon", 501, 700, ".", "2"),
+            record("exon", 101, 200, ".", "3"),
+            record("CDS", 901, 950, "0", "1"),
+            record("CDS", 501, 700, "1", "2"),
+            record("CDS", 151, 200, "2", "3"),
+        ]);
+    }
+
+    #[test]
+    fn ids() {
+        let mut table = TranscriptTable::new("refflat", "mRNA").unwrap();
+        let ids = |table: &mut TranscriptTable, line: &str| {
+            let records = table.records(0, line, &HashMap::new()).unwrap();
+            let attributes = &records[0].attributes;
+            (records[0].feature_type.to_string(), attributes["gene_id"].to_string(),
+                attributes["transcript_id"].to_string(), attributes["gene_name"].to_string())
+        };
+        let id = |gene_id: &str, transcript_id: &str| ("mRNA".to_string(), gene_id.to_string(), transcript_id.to_string(), "GENE1".to_string());
+        assert_eq!(ids(&mut table, &format!("GENE1\t{}", GENEPRED)), id("GENE1", "tx1"));
+        // a repeated transcript name gets a new transcript ID in the same gene
+        assert_eq!(ids(&mut table, &format!("GENE1\t{}", GENEPRED)), id("GENE1", "tx1.2"));
+        // the same gene name on another chromosome is another gene
+        assert_eq!(ids(&mut table, &format!("GENE1\t{}", GENEPRED.replace("chr1", "chr2"))), id("GENE1.2", "tx1.3"));
+
+        let mut table = TranscriptTable::new("genepred", "transcript").unwrap();
+        assert!(table.records(0, "tx1\tchr1\t+\t100\t1000", &HashMap::new()).is_err());
+        // exonCount must match the exon lists
+        assert!(table.records(0, &GENEPRED.replace("\t3\t", "\t2\t"), &HashMap::new()).is_err());
+        assert!(TranscriptTable::new("gff", "transcript").is_none());
+    }
+
+    #[test]
+    fn formats() {
+        let format = |file: &str| annotation_format(file, &None).ok();
+        assert_eq!(format("genes.gff").as_deref(), Some("gff"));
+        assert_eq!(format("genes.GFF3.gz").as_deref(), Some("gff"));
+        assert_eq!(format("genes.gtf.bgz").as_deref(), Some("gtf"));
+        assert_eq!(format("genes.bed12").as_deref(), Some("bed"));
+        assert_eq!(format("genes.bb").as_deref(), Some("bigbed"));
+        assert_eq!(format("genes.gp").as_deref(), Some("genepred"));
+        assert_eq!(format("genes.genePred.gz").as_deref(), Some("genepred"));
+        assert_eq!(format("refFlat.txt.gz").as_deref(), Some("refflat"));
+        assert_eq!(format("genes.txt"), None);
+        assert_eq!(format("-"), None);
+        // a given format wins over the extension
+        assert_eq!(annotation_format("genes.txt", &Some("RefFlat".to_string())).unwrap(), "refflat");
+        assert_eq!(annotation_format("genes.gff", &Some("bed".to_string())).unwrap(), "bed");
+        assert!(annotation_format("genes.gff", &Some("gff2".to_string())).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::vec::Vec;
+use std::io::Write;
+use anyhow::{Result, anyhow};
+
+use cassette_reannotation::*;
+use cassette_reannotation::indexed_annotation::*;
+use cassette_reannotation::annotation_formats::annotation_format;
+use cassette_reannotation::chrom_alias::ChromAliases;
+use cassette_reannotation::faidx::Faidx;
+
+use structopt::StructOpt;
+
+/// The output formats that annotconv can write.
+const OUTPUT_FORMATS: &[&str] = &["gff", "gtf", "bed", "genepred", "fasta", "cds_fasta"];
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "annotconv", about = "Convert a genome annotation to GFF3, GTF, BED12, genePred or FASTA format")]
+struct Options {
+    // input files
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
+    annotfile_gff: Option<String>,
+    #[structopt(long="gtf", help = "A genome annotation file in gtf format, optionally gzipped, or - for stdin", name="ANNOT_GTF_FILE")]
+    annotfile_gtf: Option<String>,
+    #[structopt(long="annot", help = "A genome annotation file in gff3, gtf, bed12, bigbed, genepred or refflat format, optionally gzipped, or - for stdin", name="ANNOT_FILE")]
+    annotfile: Option<String>,
+    #[structopt(long="annot_format", help = "The format of the --annot file: gff, gtf, bed, bigbed, genepred or refflat. Guessed from the file extension if not given.", name="ANNOT_FORMAT")]
+    annot_format: Option<String>,
+    #[structopt(long="chrmap", help = "Optional tab-delimited chr name mapping file", name="CHRMAP_FILE")]
+    chrmap_file: Option<String>,
+    #[structopt(long="vizchrmap", help = "Optional tab-delimited chr name mapping file, applied to the chr names of the output", name="VIZCHRMAP_FILE")]
+    vizchrmap_file: Option<String>,
+    #[structopt(long="chromalias", help = "Optional UCSC chromAlias.txt file or other tab-delimited table of equivalent chr names", name="CHROM_ALIAS_FILE")]
+    chromalias_file: Option<String>,
+    #[structopt(long="strict", help = "Fail on malformed annotation lines instead of skipping them")]
+    strict: bool,
+    #[structopt(long="no_annotation_cache", help = "Always parse the annotation file instead of reading or writing its cache")]
+    no_annotation_cache: bool,
+    #[structopt(long="genome", help = "The genome FASTA file, required for fasta and cds_fasta output", name="GENOME_FASTA_FILE")]
+    genome_file: Option<String>,
+    // output file
+    #[structopt(long="out", short="o", help = "Output file, or - for stdout", name="OUT_FILE", default_value="-")]
+    outfile: String,
+    #[structopt(long="format", help = "The output format: gff, gtf, bed, genepred, fasta or cds_fasta. Guessed from the output file extension if not given.", name="OUT_FORMAT")]
+    format: Option<String>,
+    #[structopt(long="bgzip", help = "Compress the output with bgzip. Output files ending in .gz are always compressed.")]
+    bgzip: bool,
+    #[structopt(long="tabix", help = "Compress the output with bgzip and index it with tabix")]
+    tabix: bool,
+    // feature types filter
+    #[structopt(long="exon_type", help = "The exon type(s) to search for", name="EXON_TYPE")]
+    exon_type: Vec<String>,
+    #[structopt(long="transcript_type", help = "The transcript type(s) to search for", name="TRANSCRIPT_TYPE")]
+    transcript_type: Vec<String>,
+    #[structopt(long="gene_type", help = "The gene type(s) to search for", name="GENE_TYPE")]
+    gene_type: Vec<String>,
+    #[structopt(long="cds_type", help = "The CDS type(s) to search for", name="CDS_TYPE")]
+    cds_type: Vec<String>,
+}
+
+// get the output format from the option or the output file extension
+fn output_format(outfile: &str, format: &Option<String>) -> Result<String> {
+    if let Some(format) = format {
+        let format = format.to_lowercase();
+        if !OUTPUT_FORMATS.contains(&format.as_str()) {
+            return Err(anyhow!("Unknown output format {}, expected one of {}", format, OUTPUT_FORMATS.join(", ")));
+        }
+        return Ok(format);
+    }
+    let name = outfile.to_lowercase();
+    let name = name.trim_end_matches(".gz").trim_end_matches(".bgz");
+    let format =
+        if name.ends_with(".gff") || name.ends_with(".gff3") { "gff" }
+        else if name.ends_with(".gtf") { "gtf" }
+        else if name.ends_with(".bed") || name.ends_with(".bed12") { "bed" }
+        else if name.ends_with(".genepred") || name.ends_with(".gp") { "genepred" }
+        else if name.ends_with(".fa") || name.ends_with(".fasta") || name.ends_with(".fna") { "fasta" }
+        else {
+            return Err(anyhow!("Could not tell the output format from the file name {}, please give --format", outfile));
+        };
+    Ok(format.to_string())
+}
+
+fn run(mut options: Options) -> Result<()> {
+    // set defaults for feature types
+    options.gene_type =
+        (if options.gene_type.is_empty() { vec!["gene".to_string()] }
+         else { options.gene_type.clone() }).into_iter().collect();
+    options.cds_type =
+        (if options.cds_type.is_empty() { vec!["CDS".to_string()] }
+         else { options.cds_type.clone() }).into_iter().collect();
+    options.exon_type =
+        (if options.exon_type.is_empty() { vec!["exon".to_string()] }
+         else { options.exon_type.clone() }).into_iter().collect();
+    let format = output_format(&options.outfile, &options.format)?;
+    let lower_outfile = options.outfile.to_lowercase();
+    let bgzip = options.bgzip || options.tabix || lower_outfile.ends_with(".gz") || lower_outfile.ends_with(".bgz");
+    if options.tabix && (format == "fasta" || format == "cds_fasta") {
+        return Err(anyhow!("FASTA output can't be indexed with tabix"));
+    }
+    if bgzip && options.outfile == "-" {
+        return Err(anyhow!("Compressed output must be written to a file, not stdout"));
+    }
+    let mut genome = if format == "fasta" || format == "cds_fasta" {
+        let genome_file = options.genome_file.clone().ok_or(anyhow!("FASTA output requires a --genome file"))?;
+        Some(Faidx::open(&genome_file)?)
+    } else { None };
+
+    let transcript_type = String::from("transcript");
+    let mut annot = if let Some(annotfile_gff) = options.annotfile_gff.clone() {
+        eprintln!("Reading annotation file {:?}", &annotfile_gff);
+        IndexedAnnotation::from_file_with(
+            &annotfile_gff,
+            "gff",
+            "",
+            "",
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else if let Some(annotfile_gtf) = options.annotfile_gtf.clone() {
+        eprintln!("Reading annotation file {:?}", &annotfile_gtf);
+        IndexedAnnotation::from_file_with(&annotfile_gtf,
+            "gtf",
+            options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
+            options.transcript_type.get(0).unwrap_or(&transcript_type),
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else if let Some(annotfile) = options.annotfile.clone() {
+        let format = annotation_format(&annotfile, &options.annot_format)?;
+        eprintln!("Reading {} annotation file {:?}", format, &annotfile);
+        IndexedAnnotation::from_file_with(&annotfile,
+            &format,
+            options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
+            options.transcript_type.get(0).unwrap_or(&transcript_type),
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else {
+        Options::clap().print_help()?;
+        eprintln!("\n\nNo annotation file was given!");
+        std::process::exit(1);
+    };
+    if let Some(chromalias_file) = options.chromalias_file.clone() {
+        annot.set_chrom_aliases(ChromAliases::from_file(&chromalias_file)?);
+    }
+    // FASTA headers have no chr names, and the genome is read with the
+    // annotation's own names
+    if let Some(genome) = genome.as_mut() {
+        genome.match_seqnames(&annot);
+    } else {
+        let vizchrmap = std::mem::take(&mut annot.vizchrmap);
+        annot.rename_seqnames(&vizchrmap)?;
+    }
+
+    eprintln!("Writing {} file {:?}", format, &options.outfile);
+    let types = FeatureTypes::new(&options.gene_type, &options.transcript_type, &options.exon_type, &options.cds_type);
+    let mut output = create_output(&options.outfile, bgzip)?;
+    match format.as_str() {
+        "gff" => annot.write_gff(&mut output, true, &[])?,
+        "gtf" => annot.write_gtf(&mut output, &[])?,
+        "bed" => {
+            for record in annot.to_bed_records(&options.exon_type, &options.cds_type, &options.transcript_type, &options.gene_type)? {
+                writeln!(output, "{}", record.to_line())?;
+            }
+        }
+        "genepred" => annot.write_genepred(&mut output, &types)?,
+        "fasta" | "cds_fasta" => {
+            let genome = genome.as_ref().ok_or(anyhow!("NoneError"))?;
+            annot.write_fasta(&mut output, genome, &types, format == "cds_fasta")?;
+        }
+        _ => return Err(anyhow!("Unknown output format {}", format)),
+    }
+    output.flush()?;
+    // close the file before indexing it
+    drop(output);
+    if options.tabix {
+        eprintln!("Indexing {:?} with tabix", &options.outfile);
+        tabix_index(&options.outfile, &format)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    // enable stack traces
+    std::env::set_var("RUST_BACKTRACE", "full");
+    run(Options::from_args())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gtf_round_trip() {
+        let file = |ext: &str| std::env::temp_dir().join(format!("annotconv_{}.{}", std::process::id(), ext)).to_str().unwrap().to_string();
+        let convert = |args: &[&str]| {
+            let args = ["annotconv", "--no_annotation_cache"].iter().chain(args.iter());
+            run(Options::from_iter(args)).unwrap();
+        };
+        // two transcripts with a gene line, and one without any
+        let gtf = file("gtf");
+        std::fs::write(&gtf, concat!(
+            "chr1\ttest\tgene\t100\t1000\t.\t+\t.\tgene_id \"G1\";\n",
+            "chr1\ttest\ttranscript\t100\t1000\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n",
+            "chr1\ttest\texon\t100\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n",
+            "chr1\ttest\texon\t500\t1000\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";\n",
+            "chr1\ttest\tCDS\t150\t200\t.\t+\t0\tgene_id \"G1\"; transcript_id \"T1\";\n",
+            "chr1\ttest\ttranscript\t100\t1000\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T2\";\n",
+            "chr1\ttest\texon\t100\t200\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T2\";\n",
+            "chr1\ttest\texon\t700\t1000\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T2\";\n",
+            "chr2\ttest\texon\t10\t20\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T3\";\n",
+            "chr2\ttest\texon\t40\t50\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T3\";\n")).unwrap();
+        let names = |file: &str, col: usize| std::fs::read_to_string(file).unwrap().lines().
+            map(|l| l.split('\t').nth(col).unwrap().to_string()).
+            collect::<Vec<_>>();
+
+        // one line per transcript, with all of its exons
+        let bed = file("bed");
+        convert(&["--gtf", &gtf, "-o", &bed]);
+        assert_eq!(names(&bed, 3), vec!["T1", "T2", "T3"]);
+        assert_eq!(names(&bed, 9), vec!["2", "2", "2"]);
+        let genepred = file("genepred");
+        convert(&["--gtf", &gtf, "-o", &genepred]);
+        assert_eq!(names(&genepred, 0), vec!["T1", "T2", "T3"]);
+        assert_eq!(names(&genepred, 7), vec!["2", "2", "2"]);
+
+        // and back to GTF and BED again
+        let gtf2 = file("2.gtf");
+        convert(&["--annot", &bed, "-o", &gtf2]);
+        let transcripts = names(&gtf2, 2).iter().filter(|t| *t == "transcript").count();
+        assert_eq!(transcripts, 3);
+        let bed2 = file("2.bed");
+        convert(&["--gtf", &gtf2, "-o", &bed2]);
+        assert_eq!(std::fs::read_to_string(&bed2).unwrap(), std::fs::read_to_string(&bed).unwrap());
+
+        for file in &[gtf, bed, genepred, gtf2, bed2] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
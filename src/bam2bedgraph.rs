@@ -7,19 +7,21 @@ use std::path::{PathBuf, Path};
 use std::vec::Vec;
 use std::ops::Range;
 use anyhow::{Result, anyhow};
-use duct::cmd;
 
 use regex::Regex;
 
 use rust_htslib::bam::Read;
 use rust_htslib::bam::Reader;
 
-use bio::data_structures::interval_tree::IntervalTree;
-use bio::utils::Interval;
-
 use structopt::StructOpt;
 
-use cassette_reannotation::cigar2exons;
+use linked_hash_map::LinkedHashMap;
+
+use cassette_reannotation::{cigar2exons, infer_library_strandedness_with};
+use cassette_reannotation::bigwig::BigWigWriter;
+use cassette_reannotation::indexed_annotation::IndexedAnnotation;
+use cassette_reannotation::annotation_formats::annotation_format;
+use cassette_reannotation::chrom_alias::ChromAliases;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "bam2bedgraph", about = "Convert bam files to bedgraph/bigWig format")]
@@ -38,12 +40,14 @@ struct Options {
     primary_only: bool,
     #[structopt(long = "notrackline", help = "Do not output a UCSC track line")]
     notrackline: bool,
-    #[structopt(long = "bigwig", help = "Output bigwig files (requires bedGraphToBigWig in $PATH)")]
+    #[structopt(long = "bigwig", help = "Output bigwig files instead of bedgraph files")]
     bigwig: bool,
     #[structopt(long = "uniq", help = "Keep only unique alignments (NH:i:1)")]
     uniq: bool,
     #[structopt(long = "fixchr", help = "Transform chromosome names to be UCSC-compatible")]
     fixchr: bool,
+    #[structopt(long = "chromalias", help = "Optional UCSC chromAlias.txt file or other tab-delimited table of equivalent chr names, used by --fixchr and --autostrand", name="CHROM_ALIAS_FILE")]
+    chromalias: Option<String>,
     #[structopt(help = "Convert a bam file into a bedgraph/bigwig file.", name="BAMFILE")]
     bamfile: String,
     #[structopt(long = "trackname", help = "Name of track for the track line", name="TRACKNAME", default_value="")]
@@ -52,8 +56,14 @@ struct Options {
     out: String,
     #[structopt(long = "autostrand", help = 
         "Attempt to determine the strandedness of the input data using an \
-        annotation file. Must be a .bam file.", name="AUTOSTRAND_FILE", default_value="")]
+        annotation file in GFF, GTF, BED12, bigBed, genePred or refFlat format, \
+        chosen by the file extension. The bam file \
+        must be indexed.", name="AUTOSTRAND_FILE", default_value="")]
     autostrand: String,
+    #[structopt(long = "strand_threshold", help = "The fraction of the --autostrand reads that must agree to call a library stranded, above 0.5 and at most 1", name="STRAND_THRESHOLD", default_value="0.8")]
+    strand_threshold: f64,
+    #[structopt(long = "strand_sample_reads", help = "The number of strand-informative reads that --autostrand samples", name="STRAND_SAMPLE_READS", default_value="200000")]
+    strand_sample_reads: u64,
     #[structopt(long = "split_strand", help =
         "Split output bedgraph by strand: Possible values: u s r uu us ur su ss \
         sr ru rs rr, first char is read1, second is read2, u=unstranded, \
@@ -61,11 +71,17 @@ struct Options {
     split_strand: String,
 }
 
+enum Output {
+    BedGraph(BufWriter<File>),
+    BigWig(BigWigWriter),
+}
+
 fn open_file(options: &Options,
              read_number: i32,
              strand: &str,
              split_strand: &str,
-             fhs: &mut BTreeMap<String, Option<BufWriter<File>>>)
+             refs: &LinkedHashMap<String, u64>,
+             fhs: &mut BTreeMap<String, Option<Output>>)
              -> Result<String> {
     let mut prefix = PathBuf::new();
     prefix.set_file_name(&options.bamfile);
@@ -104,19 +120,27 @@ fn open_file(options: &Options,
                         } else {
                             "".to_string()
                         },
-                        ".bedgraph".to_string()]
+                        if options.bigwig {
+                            ".bw".to_string()
+                        } else {
+                            ".bedgraph".to_string()
+                        }]
         .join("");
 
     // initialize the file if needed
     if !fhs.contains_key(&filename) {
-        let mut f = BufWriter::new(File::create(&filename)?);
-        if !options.notrackline && !options.bigwig {
-            writeln!(f,
-                     "track type=bedGraph name=\"{}\" description=\"{}\" visibility=full",
-                     track_name,
-                     track_name)?;
+        if options.bigwig {
+            fhs.insert(filename.clone(), Some(Output::BigWig(BigWigWriter::create(&filename, refs)?)));
+        } else {
+            let mut f = BufWriter::new(File::create(&filename)?);
+            if !options.notrackline {
+                writeln!(f,
+                         "track type=bedGraph name=\"{}\" description=\"{}\" visibility=full",
+                         track_name,
+                         track_name)?;
+            }
+            fhs.insert(filename.clone(), Some(Output::BedGraph(f)));
         }
-        fhs.insert(filename.clone(), Some(f));
     }
     Ok(filename)
 }
@@ -124,15 +148,24 @@ fn open_file(options: &Options,
 fn write_chr(options: &Options,
              chr: &(u32, String),
              histogram: &BTreeMap<(i32, String), Vec<i32>>,
-             fhs: &mut BTreeMap<String, Option<BufWriter<File>>>,
+             refs: &LinkedHashMap<String, u64>,
+             fhs: &mut BTreeMap<String, Option<Output>>,
              split_strand: &str)
              -> Result<()> {
     for (key, histo) in histogram {
         let read_number = key.0;
         let strand = &key.1;
-        let filename = open_file(options, read_number, strand, split_strand, fhs)?;
+        let filename = open_file(options, read_number, strand, split_strand, refs, fhs)?;
         let f = fhs.get_mut(&filename).ok_or(anyhow!("NoneError"))?;
-        let file = f.as_mut().ok_or(anyhow!("NoneError"))?;
+        let file = match f.as_mut().ok_or(anyhow!("NoneError"))? {
+            Output::BigWig(bw) => {
+                let sign = if strand == "-" { -1.0 } else { 1.0 };
+                let values = (0..chr.0 as usize).map(|i| sign * *histo.get(i).unwrap_or(&0) as f32);
+                bw.add_runs(&chr.1, values, options.zero)?;
+                continue;
+            }
+            Output::BedGraph(file) => file,
+        };
         let mut writer = BufWriter::new(file);
 
         // scan the histogram to produce the bedgraph data
@@ -165,8 +198,7 @@ fn write_chr(options: &Options,
 
 fn analyze_bam(options: &Options,
                split_strand: &str,
-               autostrand_pass: bool,
-               intervals: &Option<BTreeMap<String, IntervalTree<u64, u8>>>)
+               aliases: &ChromAliases)
                -> Result<()> {
     if !Path::new(&options.bamfile).exists() {
         return Err(anyhow!("Bam file {} could not be found!", &options.bamfile))
@@ -184,37 +216,25 @@ fn analyze_bam(options: &Options,
 
     if options.fixchr {
         for r in &mut refs {
-            let regex = Regex::new(r"^(chr|Zv9_)")?;
-            if regex.is_match(&r.1) {
-                let refname = r.1.to_string();
-                r.1.clear();
-                r.1.push_str(&format!("chr{}", refname));
-            }
+            r.1 = aliases.ucsc_name(&r.1);
         }
     }
-    if autostrand_pass {
-        eprintln!("Running strand detection phase on {}", options.bamfile);
-    } else {
-        eprintln!("Building histograms for {}", options.bamfile);
-    }
+    eprintln!("Building histograms for {}", options.bamfile);
 
     // build a lookup map for the refseqs
     let mut refmap: BTreeMap<String, usize> = BTreeMap::new();
     for (i, _) in refs.iter().enumerate() {
         refmap.insert(refs[i].1.to_string(), i);
     }
+    // the chromosome sizes for bigwig output, in bam header order
+    let chrom_sizes = refs.iter().
+        map(|r| (r.1.clone(), r.0 as u64)).
+        collect::<LinkedHashMap<String, u64>>();
 
     let mut lastchr: i32 = -1;
-    let mut fhs: BTreeMap<String, Option<BufWriter<File>>> = BTreeMap::new();
+    let mut fhs: BTreeMap<String, Option<Output>> = BTreeMap::new();
     let mut histogram: BTreeMap<(i32, String), Vec<i32>> = BTreeMap::new();
 
-    let mut autostrand_totals: BTreeMap<char, i64> = BTreeMap::new();
-    autostrand_totals.insert('s', 0);
-    autostrand_totals.insert('r', 0);
-    let mut autostrand_totals2: BTreeMap<char, i64> = BTreeMap::new();
-    autostrand_totals2.insert('s', 0);
-    autostrand_totals2.insert('r', 0);
-
     let mut read = rust_htslib::bam::record::Record::new();
     while bam.read(&mut read).is_ok() {
         // skip unaligned reads
@@ -222,10 +242,11 @@ fn analyze_bam(options: &Options,
 
         // if we've hit a new chr, write out the bedgraph data and clear the histogram
         if lastchr == -1 || read.tid() != lastchr {
-            if !autostrand_pass && !histogram.is_empty() && lastchr != -1 {
+            if !histogram.is_empty() && lastchr != -1 {
                 write_chr(options,
                           &refs[lastchr as usize],
                           &histogram,
+                          &chrom_sizes,
                           &mut fhs,
                           split_strand)?;
             }
@@ -298,63 +319,38 @@ fn analyze_bam(options: &Options,
 
         // add the read to the histogram
         for exon in exons {
-            // try to determine the strandedness of the data
-            if autostrand_pass {
-                if intervals.is_some() {
-                    let intervals = intervals.as_ref().ok_or(anyhow!("NoneError"))?;
-                    if intervals.contains_key(&refs[lastchr as usize].1) {
-                        for r in intervals[&refs[lastchr as usize].1].find(&exon) {
-                            let overlap_length = std::cmp::min(exon.end, r.interval().end) -
-                                                 std::cmp::max(exon.start, r.interval().start);
-
-                            let strandtype = if read.is_reverse() == (*r.data() == b'-') {
-                                's'
-                            } else {
-                                'r'
-                            };
-                            if read_number == 1 {
-                                let at = autostrand_totals.get_mut(&strandtype).ok_or(anyhow!("NoneError"))?;
-                                *at += overlap_length as i64
-                            } else if read_number == 2 {
-                                let at2 = autostrand_totals2.get_mut(&strandtype).ok_or(anyhow!("NoneError"))?;
-                                *at2 += overlap_length as i64
-                            }
-                        }
-                    }
-                }
-            } else {
-                let tuple = (read_num, strand.to_string());
-                if !histogram.contains_key(&tuple) {
-                    histogram.insert(tuple.clone(), Vec::new());
-                }
-                // keep track of chromosome sizes
-                if ref_length < exon.end as u32 {
-                    refs[read.tid() as usize].0 = exon.end as u32;
-                }
-                if histogram[&tuple].len() < ref_length as usize {
-                    let h = histogram.get_mut(&tuple).ok_or(anyhow!("NoneError"))?;
-                    h.resize(ref_length as usize, 0);
-                }
-
+            let tuple = (read_num, strand.to_string());
+            if !histogram.contains_key(&tuple) {
+                histogram.insert(tuple.clone(), Vec::new());
+            }
+            // keep track of chromosome sizes
+            if ref_length < exon.end as u32 {
+                refs[read.tid() as usize].0 = exon.end as u32;
+            }
+            if histogram[&tuple].len() < ref_length as usize {
                 let h = histogram.get_mut(&tuple).ok_or(anyhow!("NoneError"))?;
-                for pos in std::cmp::max(0u64, exon.start)..std::cmp::min(ref_length as u64, exon.end)
-                {
-                    (*h)[pos as usize] += 1;
-                }
+                h.resize(ref_length as usize, 0);
+            }
+
+            let h = histogram.get_mut(&tuple).ok_or(anyhow!("NoneError"))?;
+            for pos in std::cmp::max(0u64, exon.start)..std::cmp::min(ref_length as u64, exon.end)
+            {
+                (*h)[pos as usize] += 1;
             }
         }
     }
 
-    if !autostrand_pass && !histogram.is_empty() && lastchr != -1 {
+    if !histogram.is_empty() && lastchr != -1 {
         write_chr(options,
                   &refs[lastchr as usize],
                   &histogram,
+                  &chrom_sizes,
                   &mut fhs,
                   split_strand)?;
     }
 
     // make sure empty files were created
-    if histogram.is_empty() && !autostrand_pass {
+    if histogram.is_empty() {
         for read_number in if options.split_read {
             vec![1, 2]
         } else {
@@ -365,113 +361,18 @@ fn analyze_bam(options: &Options,
             } else {
                 vec![""]
             } {
-                open_file(options, read_number, s, split_strand, &mut fhs)?;
+                open_file(options, read_number, s, split_strand, &chrom_sizes, &mut fhs)?;
             }
         }
     }
 
     // close the filehandles
     for fh in &mut fhs {
-        *fh.1 = None;
-    }
-
-    if autostrand_pass {
-        // get the read 1 and read2 totals
-        let mut total1: i64 = 0;
-        let mut total2: i64 = 0;
-        for i in &autostrand_totals {
-            total1 += *i.1;
-        }
-        for i in &autostrand_totals2 {
-            total2 += *i.1;
-        }
-        // figure out the best and second-best strand types for reads 1 and 2
-        let mut best1: (char, i64) = ('\0', 0);
-        let mut second_best1: (char, i64) = ('\0', 0);
-        let mut best2: (char, i64) = ('\0', 0);
-        let mut second_best2: (char, i64) = ('\0', 0);
-        for i in autostrand_totals {
-            if i.1 > 0 {
-                eprintln!(
-                         "Total evidence for read 1 strand type {}: {}",
-                         i.0,
-                         i.1);
-            }
-            if best1.1 < i.1 {
-                second_best1 = best1;
-                best1 = i;
-            } else if second_best1.1 < i.1 {
-                second_best1 = i;
-            }
+        if let Some(Output::BigWig(bw)) = fh.1.take() {
+            bw.finish()?;
         }
-        for i in autostrand_totals2 {
-            if i.1 > 0 {
-                eprintln!("Total evidence for read 2 strand type {}: {}",
-                         i.0,
-                         i.1);
-            }
-            if best2.1 < i.1 {
-                second_best2 = best2;
-                best2 = i;
-            } else if second_best2.1 < i.1 {
-                second_best2 = i;
-            }
-        }
-        let threshold: f64 = 0.0; //threshold isn't working, set to zero
-        let strand1 = if total1 > 0 {
-            if threshold < (best1.1 - second_best1.1) as f64 / (total1) as f64 {
-                best1.0
-            } else {
-                'u'
-            }
-        } else {
-            'u'
-        };
-        let strand2 = if total2 > 0 {
-            if threshold < (best2.1 - second_best2.1) as f64 / (total2) as f64 {
-                best2.0
-            } else {
-                'u'
-            }
-        } else {
-            'u'
-        };
-
-        let best_strand = format!("{}{}", strand1, strand2);
-        eprintln!(
-                 "autostrand_pass found best strand type: {}",
-                 best_strand);
-
-        // re-run analyzeBam with the strand type indicated
-        analyze_bam(options, &best_strand, false, intervals)?;
     }
 
-    if !autostrand_pass && options.bigwig {
-        // Convert bedgraph file to bigwig file
-        for fh in &fhs {
-            // write the genome file for bigwigs
-            let genome_filename = format!("{}.genome", fh.0);
-            {
-                let mut genome_fh = BufWriter::new(File::create(&genome_filename)?);
-                for r in &refs {
-                    writeln!(genome_fh, "{}\t{}", r.1, r.0)?;
-                }
-            }
-
-            // run bedGraphToBigWig
-            let regex = Regex::new(r"\.bedgraph$")?;
-            let bigwig_file = String::from(regex.replace(fh.0, ".bw"));
-            let sorted_bedgraph = String::from(regex.replace(fh.0, ".sorted.bedgraph"));
-            cmd!("sort","-k1,1","-k2,2n","-o", &sorted_bedgraph, fh.0).env("LC_COLLATE","C").run()?;
-            cmd!("bedGraphToBigWig",&sorted_bedgraph,&genome_filename,&bigwig_file).run()?;
-            // remove the bedgraph file
-            std::fs::remove_file(fh.0)?;
-            // remove the sorted bedgraph file
-            std::fs::remove_file(&sorted_bedgraph)?;
-            // remove the genome file
-            std::fs::remove_file(&genome_filename)?;
-        }
-    };
     Ok(())
 }
 
@@ -483,80 +384,35 @@ fn run() -> Result<()> {
     }
     let regex = Regex::new(r"^[usr][usr]$")?;
     if !regex.is_match(&options.split_strand) {
-        anyhow!("Invalid value for split_strand: \"{}\": values must be \
+        return Err(anyhow!("Invalid value for split_strand: \"{}\": values must be \
                                        one of: u s r uu us ur su ss sr ru rs rr",
-                           options.split_strand);
+                           options.split_strand));
     }
 
-    // read in the annotation file
-    let mut intervals: Option<BTreeMap<String, IntervalTree<u64, u8>>> = None;
+    let aliases = match options.chromalias {
+        Some(ref chromalias) => ChromAliases::from_file(chromalias)?,
+        None => ChromAliases::new(),
+    };
     if !options.autostrand.is_empty() {
+        // read in the annotation file
         if !Path::new(&options.autostrand).exists() {
-            anyhow!("Autostrand Bam file {} could not be found!", &options.autostrand);
-        }
-        let mut bam = rust_htslib::bam::Reader::from_path(&options.autostrand)?;
-        let header = bam.header().clone();
-
-        let mut refs = vec![(0, "".to_string()); header.target_count() as usize];
-        let target_names = header.target_names();
-        for target_name in target_names {
-            let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
-            let target_len = header.target_len(tid).ok_or(anyhow!("NoneError"))?;
-            let target_name = std::str::from_utf8(target_name)?;
-            refs[tid as usize] = (target_len, target_name.to_string());
-        }
-        if options.fixchr {
-            for r in &mut refs {
-                let regex = Regex::new(r"^(chr|Zv9_)")?;
-                if regex.is_match(&r.1) {
-                    let refname = r.1.to_string();
-                    r.1.clear();
-                    r.1.push_str(&format!("chr{}", refname));
-                }
-            }
+            return Err(anyhow!("Autostrand annotation file {} could not be found!", &options.autostrand));
         }
-
-        let mut interval_lists: BTreeMap<String, Vec<(Interval<u64>, u8)>> = BTreeMap::new();
-        let mut read = rust_htslib::bam::record::Record::new();
-        while bam.read(&mut read).is_ok() {
-            let chr = refs[read.tid() as usize].1.clone();
-            if !interval_lists.contains_key(&chr) {
-                interval_lists.insert(chr.clone(), Vec::new());
-            }
-
-            let exons = cigar2exons(&read.cigar(), read.pos() as u64)?;
-            let interval_list = interval_lists.get_mut(&chr).ok_or(anyhow!("NoneError"))?;
-            for exon in exons {
-                interval_list.push((Interval::new(exon)?,
-                                    if read.is_reverse() { b'-' } else { b'+' }));
-            }
-        }
-        for (chr, list) in &interval_lists {
-            if intervals.is_none() {
-                intervals = Some(BTreeMap::new());
-            }
-            let interval = intervals.as_mut().ok_or(anyhow!("NoneError"))?;
-            let mut tree = IntervalTree::<u64, u8>::new();
-            for l in list {
-                tree.insert(l.0.clone(), l.1);
-            }
-            interval.insert(chr.clone(), tree);
-        }
-    }
-
-    // // analyze the bam file and produce histograms
-    if !options.autostrand.is_empty() {
+        // files without a known annotation extension are read as GFF
+        let format = annotation_format(&options.autostrand, &None).unwrap_or_else(|_| String::from("gff"));
+        let mut annot = IndexedAnnotation::from_file(&options.autostrand, &format, "gene", "transcript", &None, &None)?;
+        annot.set_chrom_aliases(aliases.clone());
+        eprintln!("Running strand detection phase on {}", options.bamfile);
+        let report = infer_library_strandedness_with(&options.bamfile, &annot, options.strand_sample_reads, options.strand_threshold)?;
+        eprintln!("Read 1 sense/antisense reads: {}/{}", report.read1_sense, report.read1_antisense);
+        eprintln!("Read 2 sense/antisense reads: {}/{}", report.read2_sense, report.read2_antisense);
+        let best_strand = format!("{}{}", report.read_strand(1), report.read_strand(2));
+        eprintln!("autostrand_pass found best strand type: {}", best_strand);
         // make both stranded and unstranded files
-        analyze_bam(&options,
-                    &options.split_strand,
-                    !options.autostrand.is_empty(),
-                    &intervals)?;
-        analyze_bam(&options, "uu", false, &intervals)?;
+        analyze_bam(&options, &best_strand, &aliases)?;
+        analyze_bam(&options, "uu", &aliases)?;
     } else {
-        analyze_bam(&options,
-                    &options.split_strand,
-                    !options.autostrand.is_empty(),
-                    &intervals)?;
+        analyze_bam(&options, &options.split_strand, &aliases)?;
     }
     Ok(())
 }
@@ -0,0 +1,847 @@
+//! Low-level writer for the UCSC "bbi" container shared by bigBed and bigWig
+//! files: header, chromosome B+ tree, zlib-compressed data blocks, R-tree
+//! block index, zoom level summaries and optional extra B+ tree indices.
+//! Also reads back the chromosome list and data blocks of existing files.
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use anyhow::{Result, anyhow};
+
+pub const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+pub const BIGBED_MAGIC: u32 = 0x8789_F2EB;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const CIR_TREE_MAGIC: u32 = 0x2468_ACE0;
+const BBI_VERSION: u16 = 4;
+const BLOCK_SIZE: usize = 256;
+const MAX_ZOOM_LEVELS: usize = 10;
+const ZOOM_INCREMENT: u32 = 4;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+const TOTAL_SUMMARY_SIZE: u64 = 40;
+const EXT_HEADER_SIZE: u64 = 64;
+const EXTRA_INDEX_SIZE: u64 = 20;
+
+trait PutLe {
+    fn put_u8(&mut self, v: u8);
+    fn put_u16(&mut self, v: u16);
+    fn put_u32(&mut self, v: u32);
+    fn put_u64(&mut self, v: u64);
+    fn put_f32(&mut self, v: f32);
+    fn put_f64(&mut self, v: f64);
+    fn put_zeros(&mut self, n: usize);
+}
+impl PutLe for Vec<u8> {
+    fn put_u8(&mut self, v: u8) { self.push(v) }
+    fn put_u16(&mut self, v: u16) { self.extend_from_slice(&v.to_le_bytes()) }
+    fn put_u32(&mut self, v: u32) { self.extend_from_slice(&v.to_le_bytes()) }
+    fn put_u64(&mut self, v: u64) { self.extend_from_slice(&v.to_le_bytes()) }
+    fn put_f32(&mut self, v: f32) { self.extend_from_slice(&v.to_le_bytes()) }
+    fn put_f64(&mut self, v: f64) { self.extend_from_slice(&v.to_le_bytes()) }
+    fn put_zeros(&mut self, n: usize) { self.resize(self.len()+n, 0) }
+}
+
+/// The kind of data stored in the full-resolution data blocks.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlockKind {
+    /// bigBed: chromId, start, end, then the remaining bed fields as a C string
+    Bed,
+    /// bigWig: a bedGraph section header followed by start, end, value items
+    BedGraph,
+}
+
+/// Bounds and location of one data block, used as an R-tree leaf.
+#[derive(Clone, Copy, Debug)]
+struct BlockExtent {
+    start_chrom: u32,
+    start_base: u32,
+    end_chrom: u32,
+    end_base: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// A keyed secondary index (e.g. bigBed `-extraIndex=name`).
+pub struct ExtraIndex {
+    pub field_id: u16,
+    // key -> (data block offset, data block size)
+    pub keys: Vec<(Vec<u8>, u64, u64)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Summary {
+    chrom: u32,
+    start: u32,
+    end: u32,
+    valid_count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+impl Summary {
+    fn new(chrom: u32, start: u32, end: u32, value: f64) -> Summary {
+        let n = (end-start) as f64;
+        Summary {
+            chrom,
+            start,
+            end,
+            valid_count: (end-start) as u64,
+            min: value,
+            max: value,
+            sum: value*n,
+            sum_squares: value*value*n,
+        }
+    }
+    fn add(&mut self, start: u32, end: u32, value: f64) {
+        let n = (end-start) as f64;
+        self.start = std::cmp::min(self.start, start);
+        self.end = std::cmp::max(self.end, end);
+        self.valid_count += (end-start) as u64;
+        if value < self.min { self.min = value }
+        if value > self.max { self.max = value }
+        self.sum += value*n;
+        self.sum_squares += value*value*n;
+    }
+}
+
+struct ZoomLevel {
+    reduction: u32,
+    chrom: Option<u32>,
+    open: BTreeMap<u32, Summary>,
+    records: Vec<Summary>,
+}
+impl ZoomLevel {
+    fn flush_before(&mut self, bucket: u32) {
+        let keep = self.open.split_off(&bucket);
+        let done = std::mem::replace(&mut self.open, keep);
+        self.records.extend(done.into_values());
+    }
+    fn add(&mut self, chrom: u32, chrom_size: u32, start: u32, end: u32, value: f64) {
+        if self.chrom != Some(chrom) {
+            self.flush_before(u32::MAX);
+            self.chrom = Some(chrom);
+        }
+        // input is sorted by start, so buckets left of this one are complete
+        self.flush_before(start / self.reduction);
+        for bucket in (start / self.reduction)..=((end-1) / self.reduction) {
+            let bucket_start = bucket.saturating_mul(self.reduction);
+            let bucket_end = std::cmp::min(bucket_start.saturating_add(self.reduction), chrom_size);
+            let s = std::cmp::max(start, bucket_start);
+            let e = std::cmp::min(end, bucket_end);
+            if s >= e { continue }
+            if let Some(summary) = self.open.get_mut(&bucket) {
+                summary.add(s, e, value);
+            }
+            else {
+                self.open.insert(bucket, Summary::new(chrom, s, e, value));
+            }
+        }
+    }
+}
+
+/// Streams (chrom, start, end, value) ranges into the zoom level summaries.
+/// The first reduction level is chosen from the average span of the first
+/// block of input, so the ranges seen before that are held back.
+struct ZoomBuilder {
+    items_per_slot: usize,
+    pending: Vec<(u32, u32, u32, u32, f64)>,
+    levels: Vec<ZoomLevel>,
+    total: Option<Summary>,
+    item_count: u64,
+}
+impl ZoomBuilder {
+    fn new(items_per_slot: usize) -> ZoomBuilder {
+        ZoomBuilder {
+            items_per_slot,
+            pending: Vec::new(),
+            levels: Vec::new(),
+            total: None,
+            item_count: 0,
+        }
+    }
+    fn add(&mut self, chrom: u32, chrom_size: u32, start: u32, end: u32, value: f64) {
+        if start >= end { return }
+        self.item_count += 1;
+        match self.total {
+            Some(ref mut total) => total.add(start, end, value),
+            None => self.total = Some(Summary::new(chrom, start, end, value)),
+        }
+        if self.levels.is_empty() {
+            self.pending.push((chrom, chrom_size, start, end, value));
+            if self.pending.len() >= self.items_per_slot {
+                self.init_levels();
+            }
+        }
+        else {
+            for level in &mut self.levels {
+                level.add(chrom, chrom_size, start, end, value);
+            }
+        }
+    }
+    fn init_levels(&mut self) {
+        if self.pending.is_empty() { return }
+        let span_sum = self.pending.iter().map(|p| (p.3-p.2) as u64).sum::<u64>();
+        let average_span = span_sum / self.pending.len() as u64;
+        let mut reduction = std::cmp::max(average_span*10, 10);
+        for _ in 0..MAX_ZOOM_LEVELS {
+            if reduction > u32::MAX as u64 { break }
+            self.levels.push(ZoomLevel {
+                reduction: reduction as u32,
+                chrom: None,
+                open: BTreeMap::new(),
+                records: Vec::new(),
+            });
+            reduction *= ZOOM_INCREMENT as u64;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        for (chrom, chrom_size, start, end, value) in pending {
+            for level in &mut self.levels {
+                level.add(chrom, chrom_size, start, end, value);
+            }
+        }
+    }
+    // only keep levels that actually shrink the data
+    fn finish(mut self) -> (Vec<ZoomLevel>, Option<Summary>) {
+        self.init_levels();
+        let mut levels = Vec::new();
+        let mut last_count = self.item_count as usize;
+        for mut level in self.levels {
+            level.flush_before(u32::MAX);
+            if level.records.is_empty() || level.records.len() >= last_count { break }
+            last_count = level.records.len();
+            levels.push(level);
+        }
+        (levels, self.total)
+    }
+}
+
+/// Streaming writer for a bbi file. Chromosomes are assigned IDs in the order
+/// they are given, and items must be added sorted by (chromosome ID, start).
+/// The chromosome B+ tree is always keyed in byte order of the names, so the
+/// ID order is free to follow e.g. a bam header.
+pub struct BbiWriter {
+    path: String,
+    out: BufWriter<File>,
+    pos: u64,
+    magic: u32,
+    kind: BlockKind,
+    field_count: u16,
+    defined_field_count: u16,
+    items_per_slot: usize,
+    chroms: HashMap<String, (u32, u32)>,
+    auto_sql_offset: u64,
+    total_summary_offset: u64,
+    ext_offset: u64,
+    extra_index_count: usize,
+    chrom_tree_offset: u64,
+    data_offset: u64,
+    data_count: u64,
+    block: Vec<u8>,
+    block_items: usize,
+    block_chrom: Option<u32>,
+    block_start: u32,
+    block_end: u32,
+    blocks: Vec<BlockExtent>,
+    max_block_size: usize,
+    last_item: Option<(u32, u32)>,
+    zoom: ZoomBuilder,
+}
+
+impl BbiWriter {
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        path: &str,
+        magic: u32,
+        kind: BlockKind,
+        chrom_sizes: &[(String, u64)],
+        auto_sql: Option<&str>,
+        field_count: u16,
+        defined_field_count: u16,
+        extra_index_count: usize,
+        items_per_slot: usize)
+        -> Result<BbiWriter>
+    {
+        // bedGraph sections store their item count in 16 bits
+        if items_per_slot == 0 || items_per_slot > u16::MAX as usize {
+            return Err(anyhow!("Invalid items per slot {} for file {}, expected 1 to {}", items_per_slot, path, u16::MAX));
+        }
+        let mut chroms = HashMap::<String, (u32, u32)>::new();
+        for (chr, size) in chrom_sizes {
+            if *size > u32::MAX as u64 {
+                return Err(anyhow!("Chromosome {} of size {} is too large for file {}", chr, size, path));
+            }
+            if !chroms.contains_key(chr) {
+                let id = chroms.len() as u32;
+                chroms.insert(chr.clone(), (id, *size as u32));
+            }
+        }
+
+        let mut writer = BbiWriter {
+            path: path.to_string(),
+            out: BufWriter::new(File::create(path)?),
+            pos: 0,
+            magic,
+            kind,
+            field_count,
+            defined_field_count,
+            items_per_slot,
+            chroms,
+            auto_sql_offset: 0,
+            total_summary_offset: 0,
+            ext_offset: 0,
+            extra_index_count,
+            chrom_tree_offset: 0,
+            data_offset: 0,
+            data_count: 0,
+            block: Vec::new(),
+            block_items: 0,
+            block_chrom: None,
+            block_start: 0,
+            block_end: 0,
+            blocks: Vec::new(),
+            max_block_size: 0,
+            last_item: None,
+            zoom: ZoomBuilder::new(items_per_slot),
+        };
+        // reserve space for the header and zoom headers, these get filled in by finish()
+        writer.write(&vec![0u8; (HEADER_SIZE + ZOOM_HEADER_SIZE*MAX_ZOOM_LEVELS as u64) as usize])?;
+        if let Some(auto_sql) = auto_sql {
+            writer.auto_sql_offset = writer.pos;
+            let mut buf = auto_sql.as_bytes().to_vec();
+            buf.push(0);
+            writer.write(&buf)?;
+        }
+        writer.total_summary_offset = writer.pos;
+        writer.write(&vec![0u8; TOTAL_SUMMARY_SIZE as usize])?;
+        if extra_index_count > 0 {
+            writer.ext_offset = writer.pos;
+            writer.write(&vec![0u8; (EXT_HEADER_SIZE + EXTRA_INDEX_SIZE*extra_index_count as u64) as usize])?;
+        }
+        // write the chromosome B+ tree
+        writer.chrom_tree_offset = writer.pos;
+        let key_size = writer.chroms.keys().map(|c| c.len()).max().unwrap_or(1);
+        let mut items = writer.chroms.iter().map(|(chr, (id, size))| {
+            let mut val = Vec::new();
+            val.put_u32(*id);
+            val.put_u32(*size);
+            (chr.as_bytes().to_vec(), val)
+        }).collect::<Vec<_>>();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        let tree = bptree(&items, key_size, 8, writer.pos, CHROM_TREE_MAGIC);
+        writer.write(&tree)?;
+        // the data section starts with the item/section count
+        writer.data_offset = writer.pos;
+        writer.write(&[0u8; 8])?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.out.write_all(buf)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Look up the (chromosome ID, chromosome size) for a chromosome name.
+    pub fn chrom(&self, chrom: &str) -> Result<(u32, u32)> {
+        self.chroms.get(chrom).cloned().ok_or_else(||
+            anyhow!("Chromosome {} was not found in the chromosome sizes for file {}", chrom, self.path))
+    }
+
+    /// Index of the data block the next item will be written to.
+    pub fn current_block(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// File offset and compressed size of a data block that has been flushed.
+    pub fn block_extent(&self, block: usize) -> Option<(u64, u64)> {
+        self.blocks.get(block).map(|b| (b.offset, b.size))
+    }
+
+    fn check_item(&mut self, chrom: &str, start: u64, end: u64) -> Result<(u32, u32, u32, u32)> {
+        let (chrom_id, chrom_size) = self.chrom(chrom)?;
+        if start > end || end > chrom_size as u64 {
+            return Err(anyhow!("Range {}:{}..{} is outside of chromosome size {} for file {}",
+                chrom, start, end, chrom_size, self.path));
+        }
+        let (start, end) = (start as u32, end as u32);
+        if let Some(last) = self.last_item {
+            if (chrom_id, start) < last {
+                return Err(anyhow!("Item {}:{}..{} is not sorted by chromosome and start for file {}",
+                    chrom, start, end, self.path));
+            }
+        }
+        self.last_item = Some((chrom_id, start));
+        if self.block_chrom.is_some() && (self.block_chrom != Some(chrom_id) || self.block_items >= self.items_per_slot) {
+            self.flush_block()?;
+        }
+        if self.block_chrom.is_none() {
+            self.block_chrom = Some(chrom_id);
+            self.block_start = start;
+            self.block_end = end;
+        }
+        self.block_end = std::cmp::max(self.block_end, end);
+        self.block_items += 1;
+        Ok((chrom_id, chrom_size, start, end))
+    }
+
+    /// Add a bigBed item. `rest` holds the bed fields after chromEnd, tab-separated.
+    pub fn add_bed(&mut self, chrom: &str, start: u64, end: u64, rest: &str) -> Result<()> {
+        if self.kind != BlockKind::Bed {
+            return Err(anyhow!("Cannot add bed items to bigWig file {}", self.path));
+        }
+        let (chrom_id, chrom_size, start, end) = self.check_item(chrom, start, end)?;
+        self.block.put_u32(chrom_id);
+        self.block.put_u32(start);
+        self.block.put_u32(end);
+        self.block.extend_from_slice(rest.as_bytes());
+        self.block.put_u8(0);
+        self.data_count += 1;
+        self.zoom.add(chrom_id, chrom_size, start, end, 1.0);
+        Ok(())
+    }
+
+    /// Add a bigWig bedGraph item.
+    pub fn add_bedgraph(&mut self, chrom: &str, start: u64, end: u64, value: f32) -> Result<()> {
+        if self.kind != BlockKind::BedGraph {
+            return Err(anyhow!("Cannot add bedGraph items to bigBed file {}", self.path));
+        }
+        let (chrom_id, chrom_size, start, end) = self.check_item(chrom, start, end)?;
+        self.block.put_u32(start);
+        self.block.put_u32(end);
+        self.block.put_f32(value);
+        self.zoom.add(chrom_id, chrom_size, start, end, value as f64);
+        Ok(())
+    }
+
+    /// Compress and write out the current data block.
+    pub fn flush_block(&mut self) -> Result<()> {
+        let chrom_id = match self.block_chrom { Some(c) => c, None => return Ok(()) };
+        let mut uncompressed = Vec::new();
+        if self.kind == BlockKind::BedGraph {
+            uncompressed.put_u32(chrom_id);
+            uncompressed.put_u32(self.block_start);
+            uncompressed.put_u32(self.block_end);
+            uncompressed.put_u32(0); // itemStep
+            uncompressed.put_u32(0); // itemSpan
+            uncompressed.put_u8(1); // type = bedGraph
+            uncompressed.put_u8(0); // reserved
+            uncompressed.put_u16(self.block_items as u16);
+            self.data_count += 1;
+        }
+        uncompressed.append(&mut self.block);
+        let compressed = compress(&uncompressed)?;
+        self.max_block_size = std::cmp::max(self.max_block_size, uncompressed.len());
+        self.blocks.push(BlockExtent {
+            start_chrom: chrom_id,
+            start_base: self.block_start,
+            end_chrom: chrom_id,
+            end_base: self.block_end,
+            offset: self.pos,
+            size: compressed.len() as u64,
+        });
+        self.write(&compressed)?;
+        self.block_chrom = None;
+        self.block_items = 0;
+        Ok(())
+    }
+
+    /// Write the indices and zoom levels, then fill in the header.
+    pub fn finish(mut self, extra_indices: &[ExtraIndex]) -> Result<()> {
+        if extra_indices.len() != self.extra_index_count {
+            return Err(anyhow!("Expected {} extra indices for file {}, got {}",
+                self.extra_index_count, self.path, extra_indices.len()));
+        }
+        self.flush_block()?;
+        let data_end = self.pos;
+        let full_index_offset = self.pos;
+        let blocks = std::mem::take(&mut self.blocks);
+        let index = rtree(&blocks, data_end, self.items_per_slot, self.pos);
+        self.write(&index)?;
+
+        // write the zoom levels
+        let zoom = std::mem::replace(&mut self.zoom, ZoomBuilder::new(self.items_per_slot));
+        let (levels, total) = zoom.finish();
+        let mut zoom_headers = Vec::<u8>::new();
+        for level in &levels {
+            let zoom_data_offset = self.pos;
+            let mut count = Vec::new();
+            count.put_u32(level.records.len() as u32);
+            self.write(&count)?;
+            let mut zoom_blocks = Vec::<BlockExtent>::new();
+            for chunk in level.records.chunks(self.items_per_slot) {
+                let mut uncompressed = Vec::<u8>::new();
+                for r in chunk {
+                    uncompressed.put_u32(r.chrom);
+                    uncompressed.put_u32(r.start);
+                    uncompressed.put_u32(r.end);
+                    uncompressed.put_u32(std::cmp::min(r.valid_count, u32::MAX as u64) as u32);
+                    uncompressed.put_f32(r.min as f32);
+                    uncompressed.put_f32(r.max as f32);
+                    uncompressed.put_f32(r.sum as f32);
+                    uncompressed.put_f32(r.sum_squares as f32);
+                }
+                let compressed = compress(&uncompressed)?;
+                self.max_block_size = std::cmp::max(self.max_block_size, uncompressed.len());
+                let first = &chunk[0];
+                let last = &chunk[chunk.len()-1];
+                zoom_blocks.push(BlockExtent {
+                    start_chrom: first.chrom,
+                    start_base: first.start,
+                    end_chrom: last.chrom,
+                    end_base: chunk.iter().filter(|r| r.chrom == last.chrom).map(|r| r.end).max().unwrap_or(last.end),
+                    offset: self.pos,
+                    size: compressed.len() as u64,
+                });
+                self.write(&compressed)?;
+            }
+            let zoom_index_offset = self.pos;
+            let index = rtree(&zoom_blocks, zoom_index_offset, self.items_per_slot, self.pos);
+            self.write(&index)?;
+            zoom_headers.put_u32(level.reduction);
+            zoom_headers.put_u32(0);
+            zoom_headers.put_u64(zoom_data_offset);
+            zoom_headers.put_u64(zoom_index_offset);
+        }
+
+        // write the extra indices
+        let mut extra_index_list = Vec::<u8>::new();
+        for extra in extra_indices {
+            let mut keys = extra.keys.iter().collect::<Vec<_>>();
+            keys.sort_by(|a, b| a.0.cmp(&b.0));
+            let key_size = keys.iter().map(|k| k.0.len()).max().unwrap_or(1);
+            let items = keys.iter().map(|(key, offset, size)| {
+                let mut val = Vec::new();
+                val.put_u64(*offset);
+                val.put_u64(*size);
+                (key.clone(), val)
+            }).collect::<Vec<_>>();
+            extra_index_list.put_u16(0); // type
+            extra_index_list.put_u16(1); // field count
+            extra_index_list.put_u64(self.pos);
+            extra_index_list.put_zeros(4);
+            extra_index_list.put_u16(extra.field_id);
+            extra_index_list.put_zeros(2);
+            let tree = bptree(&items, key_size, 16, self.pos, CHROM_TREE_MAGIC);
+            self.write(&tree)?;
+        }
+        // the file ends with the magic number
+        let mut magic = Vec::new();
+        magic.put_u32(self.magic);
+        self.write(&magic)?;
+
+        // now go back and fill in the header
+        let mut header = Vec::<u8>::new();
+        header.put_u32(self.magic);
+        header.put_u16(BBI_VERSION);
+        header.put_u16(levels.len() as u16);
+        header.put_u64(self.chrom_tree_offset);
+        header.put_u64(self.data_offset);
+        header.put_u64(full_index_offset);
+        header.put_u16(self.field_count);
+        header.put_u16(self.defined_field_count);
+        header.put_u64(self.auto_sql_offset);
+        header.put_u64(self.total_summary_offset);
+        header.put_u32(self.max_block_size as u32);
+        header.put_u64(self.ext_offset);
+        header.append(&mut zoom_headers);
+
+        let mut data_count = Vec::new();
+        data_count.put_u64(self.data_count);
+
+        let mut summary = Vec::<u8>::new();
+        match total {
+            Some(total) => {
+                summary.put_u64(total.valid_count);
+                summary.put_f64(total.min);
+                summary.put_f64(total.max);
+                summary.put_f64(total.sum);
+                summary.put_f64(total.sum_squares);
+            }
+            None => summary.put_zeros(TOTAL_SUMMARY_SIZE as usize),
+        }
+
+        let mut file = self.out.into_inner().map_err(|e| anyhow!("{}", e))?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.seek(SeekFrom::Start(self.data_offset))?;
+        file.write_all(&data_count)?;
+        file.seek(SeekFrom::Start(self.total_summary_offset))?;
+        file.write_all(&summary)?;
+        if self.extra_index_count > 0 {
+            let mut ext = Vec::<u8>::new();
+            ext.put_u16(EXT_HEADER_SIZE as u16);
+            ext.put_u16(self.extra_index_count as u16);
+            ext.put_u64(self.ext_offset + EXT_HEADER_SIZE);
+            ext.put_zeros((EXT_HEADER_SIZE - 12) as usize);
+            ext.append(&mut extra_index_list);
+            file.seek(SeekFrom::Start(self.ext_offset))?;
+            file.write_all(&ext)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+// Split items into tree levels, bottom level first. Each level is a list of
+// (first item index, item count) ranges into the level below it.
+fn tree_levels(item_count: usize, block_size: usize) -> Vec<Vec<(usize, usize)>> {
+    let mut levels = Vec::<Vec<(usize, usize)>>::new();
+    // an empty tree still gets an empty root node
+    if item_count == 0 {
+        levels.push(vec![(0, 0)]);
+        return levels;
+    }
+    let mut count = item_count;
+    loop {
+        let nodes = (0..count).step_by(block_size).
+            map(|i| (i, std::cmp::min(block_size, count-i))).
+            collect::<Vec<_>>();
+        let node_count = nodes.len();
+        levels.push(nodes);
+        if node_count <= 1 { break }
+        count = node_count;
+    }
+    levels
+}
+
+// Compute the file offset of the first node of each level, given that the
+// levels are written root first starting at `start`.
+fn level_offsets(levels: &[Vec<(usize, usize)>], start: u64, leaf_size: u64, node_size: u64) -> Vec<u64> {
+    let mut offsets = vec![0u64; levels.len()];
+    let mut offset = start;
+    for l in (0..levels.len()).rev() {
+        offsets[l] = offset;
+        offset += levels[l].len() as u64 * if l == 0 { leaf_size } else { node_size };
+    }
+    offsets
+}
+
+/// Serialize a B+ tree of sorted (key, value) pairs, as used for the
+/// chromosome list and the extra indices.
+fn bptree(items: &[(Vec<u8>, Vec<u8>)], key_size: usize, val_size: usize, offset: u64, magic: u32) -> Vec<u8> {
+    let block_size = items.len().clamp(1, BLOCK_SIZE);
+    let mut buf = Vec::<u8>::new();
+    buf.put_u32(magic);
+    buf.put_u32(block_size as u32);
+    buf.put_u32(key_size as u32);
+    buf.put_u32(val_size as u32);
+    buf.put_u64(items.len() as u64);
+    buf.put_u64(0);
+    let levels = tree_levels(items.len(), block_size);
+    let leaf_size = 4 + (block_size*(key_size+val_size)) as u64;
+    let node_size = 4 + (block_size*(key_size+8)) as u64;
+    let offsets = level_offsets(&levels, offset + buf.len() as u64, leaf_size, node_size);
+    // the first item index covered by each node, per level
+    let mut first_item = vec![Vec::<usize>::new(); levels.len()];
+    first_item[0] = levels[0].iter().map(|n| n.0).collect();
+    for l in 1..levels.len() {
+        first_item[l] = levels[l].iter().map(|n| first_item[l-1][n.0]).collect();
+    }
+    let put_key = |buf: &mut Vec<u8>, key: &[u8]| {
+        buf.extend_from_slice(&key[..std::cmp::min(key.len(), key_size)]);
+        buf.put_zeros(key_size.saturating_sub(key.len()));
+    };
+    for l in (0..levels.len()).rev() {
+        for &(first, count) in &levels[l] {
+            buf.put_u8(if l == 0 { 1 } else { 0 });
+            buf.put_u8(0);
+            buf.put_u16(count as u16);
+            if l == 0 {
+                for (key, val) in &items[first..first+count] {
+                    put_key(&mut buf, key);
+                    buf.extend_from_slice(val);
+                }
+                buf.put_zeros((block_size-count)*(key_size+val_size));
+            }
+            else {
+                let child_size = if l == 1 { leaf_size } else { node_size };
+                for child in first..first+count {
+                    put_key(&mut buf, &items[first_item[l-1][child]].0);
+                    buf.put_u64(offsets[l-1] + child as u64*child_size);
+                }
+                buf.put_zeros((block_size-count)*(key_size+8));
+            }
+        }
+    }
+    buf
+}
+
+/// Serialize the R-tree ("cirTree") index over a list of data blocks.
+fn rtree(blocks: &[BlockExtent], data_end: u64, items_per_slot: usize, offset: u64) -> Vec<u8> {
+    let block_size = BLOCK_SIZE;
+    let mut buf = Vec::<u8>::new();
+    buf.put_u32(CIR_TREE_MAGIC);
+    buf.put_u32(block_size as u32);
+    buf.put_u64(blocks.len() as u64);
+    let first = blocks.first();
+    let last = blocks.iter().max_by_key(|b| (b.end_chrom, b.end_base));
+    buf.put_u32(first.map(|b| b.start_chrom).unwrap_or(0));
+    buf.put_u32(first.map(|b| b.start_base).unwrap_or(0));
+    buf.put_u32(last.map(|b| b.end_chrom).unwrap_or(0));
+    buf.put_u32(last.map(|b| b.end_base).unwrap_or(0));
+    buf.put_u64(data_end);
+    buf.put_u32(items_per_slot as u32);
+    buf.put_u32(0);
+    let levels = tree_levels(blocks.len(), block_size);
+    let leaf_size = 4 + (block_size*32) as u64;
+    let node_size = 4 + (block_size*24) as u64;
+    let offsets = level_offsets(&levels, offset + buf.len() as u64, leaf_size, node_size);
+    // the bounds covered by each node, per level
+    let mut bounds = vec![Vec::<BlockExtent>::new(); levels.len()];
+    let merge = |extents: &[BlockExtent]| {
+        let mut b = extents[0];
+        for e in extents {
+            if (e.start_chrom, e.start_base) < (b.start_chrom, b.start_base) {
+                b.start_chrom = e.start_chrom;
+                b.start_base = e.start_base;
+            }
+            if (e.end_chrom, e.end_base) > (b.end_chrom, b.end_base) {
+                b.end_chrom = e.end_chrom;
+                b.end_base = e.end_base;
+            }
+        }
+        b
+    };
+    if !blocks.is_empty() {
+        bounds[0] = levels[0].iter().map(|&(f, c)| merge(&blocks[f..f+c])).collect();
+        for l in 1..levels.len() {
+            bounds[l] = levels[l].iter().map(|&(f, c)| merge(&bounds[l-1][f..f+c])).collect();
+        }
+    }
+    for l in (0..levels.len()).rev() {
+        for &(first, count) in &levels[l] {
+            buf.put_u8(if l == 0 { 1 } else { 0 });
+            buf.put_u8(0);
+            buf.put_u16(count as u16);
+            if l == 0 {
+                for b in &blocks[first..first+count] {
+                    buf.put_u32(b.start_chrom);
+                    buf.put_u32(b.start_base);
+                    buf.put_u32(b.end_chrom);
+                    buf.put_u32(b.end_base);
+                    buf.put_u64(b.offset);
+                    buf.put_u64(b.size);
+                }
+                buf.put_zeros((block_size-count)*32);
+            }
+            else {
+                let child_size = if l == 1 { leaf_size } else { node_size };
+                for (child, b) in bounds[l-1].iter().enumerate().skip(first).take(count) {
+                    buf.put_u32(b.start_chrom);
+                    buf.put_u32(b.start_base);
+                    buf.put_u32(b.end_chrom);
+                    buf.put_u32(b.end_base);
+                    buf.put_u64(offsets[l-1] + child as u64*child_size);
+                }
+                buf.put_zeros((block_size-count)*24);
+            }
+        }
+    }
+    buf
+}
+
+// little-endian reads from a whole file held in memory
+fn get_bytes<'a>(data: &'a [u8], offset: u64, len: u64, path: &str) -> Result<&'a [u8]> {
+    data.get(offset as usize..(offset+len) as usize).ok_or_else(||
+        anyhow!("Unexpected end of file {} at offset {}", path, offset))
+}
+fn get_u16(data: &[u8], offset: u64, path: &str) -> Result<u16> {
+    let mut bytes = [0u8; 2];
+    bytes.copy_from_slice(get_bytes(data, offset, 2, path)?);
+    Ok(u16::from_le_bytes(bytes))
+}
+fn get_u32(data: &[u8], offset: u64, path: &str) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(get_bytes(data, offset, 4, path)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+fn get_u64(data: &[u8], offset: u64, path: &str) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(get_bytes(data, offset, 8, path)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The contents of a bbi file: chromosome names by ID, and the
+/// uncompressed full-resolution data blocks in file order.
+pub struct BbiContents {
+    pub chroms: HashMap<u32, String>,
+    pub blocks: Vec<Vec<u8>>,
+}
+
+/// Read the chromosome list and every data block of a bbi file with the
+/// given magic number. Only little-endian files are supported.
+pub fn read_bbi(path: &str, magic: u32) -> Result<BbiContents> {
+    let data = std::fs::read(path)?;
+    let file_magic = get_u32(&data, 0, path)?;
+    if file_magic != magic {
+        if file_magic.swap_bytes() == magic {
+            return Err(anyhow!("File {} is big-endian, which is not supported", path));
+        }
+        return Err(anyhow!("File {} is not in the expected bbi format", path));
+    }
+    let chrom_tree_offset = get_u64(&data, 8, path)?;
+    let full_index_offset = get_u64(&data, 24, path)?;
+    let uncompress_buf_size = get_u32(&data, 52, path)?;
+
+    // walk the chromosome B+ tree
+    let mut chroms = HashMap::<u32, String>::new();
+    let key_size = get_u32(&data, chrom_tree_offset+8, path)? as u64;
+    let val_size = get_u32(&data, chrom_tree_offset+12, path)? as u64;
+    let mut nodes = vec![chrom_tree_offset+32];
+    while let Some(node) = nodes.pop() {
+        let is_leaf = get_bytes(&data, node, 1, path)?[0] != 0;
+        let count = get_u16(&data, node+2, path)? as u64;
+        for i in 0..count {
+            if is_leaf {
+                let item = node + 4 + i*(key_size+val_size);
+                let key = get_bytes(&data, item, key_size, path)?;
+                let name = String::from_utf8_lossy(key).trim_end_matches('\0').to_string();
+                chroms.insert(get_u32(&data, item+key_size, path)?, name);
+            }
+            else {
+                nodes.push(get_u64(&data, node + 4 + i*(key_size+8) + key_size, path)?);
+            }
+        }
+    }
+
+    // walk the R-tree to find the data blocks
+    let mut extents = Vec::<(u64, u64)>::new();
+    let mut nodes = vec![full_index_offset+48];
+    while let Some(node) = nodes.pop() {
+        let is_leaf = get_bytes(&data, node, 1, path)?[0] != 0;
+        let count = get_u16(&data, node+2, path)? as u64;
+        for i in 0..count {
+            if is_leaf {
+                let item = node + 4 + i*32;
+                extents.push((get_u64(&data, item+16, path)?, get_u64(&data, item+24, path)?));
+            }
+            else {
+                nodes.push(get_u64(&data, node + 4 + i*24 + 16, path)?);
+            }
+        }
+    }
+    extents.sort();
+    let mut blocks = Vec::<Vec<u8>>::new();
+    for (offset, size) in extents {
+        let block = get_bytes(&data, offset, size, path)?;
+        if uncompress_buf_size > 0 {
+            let mut uncompressed = Vec::new();
+            ZlibDecoder::new(block).read_to_end(&mut uncompressed)?;
+            blocks.push(uncompressed);
+        }
+        else {
+            blocks.push(block.to_vec());
+        }
+    }
+    Ok(BbiContents { chroms, blocks })
+}
@@ -0,0 +1,287 @@
+//! Native bigBed (bed12 + extraIndex=name) writer and reader.
+use linked_hash_map::LinkedHashMap;
+use itertools::Itertools;
+use anyhow::{Result, anyhow};
+
+use crate::bbi::{BbiWriter, BlockKind, ExtraIndex, BIGBED_MAGIC, read_bbi};
+
+const ITEMS_PER_SLOT: usize = 512;
+// the name field is the 4th bed column
+const NAME_FIELD_ID: u16 = 3;
+
+pub const BED12_AUTOSQL: &str = r#"table bed
+"Browser Extensible Data"
+    (
+    string chrom;       "Reference sequence chromosome or scaffold"
+    uint   chromStart;  "Start position in chromosome"
+    uint   chromEnd;    "End position in chromosome"
+    string name;        "Name of item."
+    uint score;          "Score (0-1000)"
+    char[1] strand;     "+ or - for strand"
+    uint thickStart;   "Start of where display should be thick (start codon)"
+    uint thickEnd;     "End of where display should be thick (stop codon)"
+    uint reserved;     "Used as itemRgb as of 2004-11-22"
+    int blockCount;    "Number of blocks"
+    int[blockCount] blockSizes; "Comma separated list of block sizes"
+    int[blockCount] chromStarts; "Start positions relative to chromStart"
+    )
+"#;
+
+#[derive(Clone, Debug, Default)]
+pub struct Bed12 {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64,
+    pub name: String,
+    pub score: u64,
+    pub strand: String,
+    pub thick_start: u64,
+    pub thick_end: u64,
+    pub item_rgb: String,
+    pub block_sizes: Vec<u64>,
+    pub block_starts: Vec<u64>,
+}
+
+impl Bed12 {
+    // the columns after chromEnd
+    fn rest(&self) -> String {
+        [
+            &self.name,
+            &self.score.to_string(),
+            &self.strand,
+            &self.thick_start.to_string(),
+            &self.thick_end.to_string(),
+            &self.item_rgb,
+            &self.block_sizes.len().to_string(),
+            &self.block_sizes.iter().map(|v| v.to_string()).join(","),
+            &self.block_starts.iter().map(|v| v.to_string()).join(","),
+        ].iter().join("\t")
+    }
+
+    pub fn to_line(&self) -> String {
+        format!("{}\t{}\t{}\t{}", self.chrom, self.start, self.end, self.rest())
+    }
+}
+
+/// Sort bed records by chromosome name bytes then start, the order required by bigBed.
+pub fn sort_bed(records: &mut [Bed12]) {
+    records.sort_by(|a, b|
+        a.chrom.as_bytes().cmp(b.chrom.as_bytes()).
+        then_with(|| a.start.cmp(&b.start)));
+}
+
+/// Write sorted bed12 records to a bigBed file with an extra index on the name field.
+pub fn write_bigbed(file: &str, refs: &LinkedHashMap<String,u64>, records: &[Bed12]) -> Result<()> {
+    // chromosome IDs must follow the same order as sort_bed()
+    let mut chrom_sizes = refs.iter().map(|(k,v)| (k.clone(), *v)).collect::<Vec<_>>();
+    chrom_sizes.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    let mut bbi = BbiWriter::create(
+        file,
+        BIGBED_MAGIC,
+        BlockKind::Bed,
+        &chrom_sizes,
+        Some(BED12_AUTOSQL),
+        12,
+        12,
+        1,
+        ITEMS_PER_SLOT)?;
+    let mut names = Vec::<(Vec<u8>,usize)>::new();
+    for record in records {
+        bbi.add_bed(&record.chrom, record.start, record.end, &record.rest())?;
+        names.push((record.name.as_bytes().to_vec(), bbi.current_block()));
+    }
+    bbi.flush_block()?;
+    let mut keys = Vec::<(Vec<u8>,u64,u64)>::new();
+    for (name, block) in names {
+        if let Some((offset, size)) = bbi.block_extent(block) {
+            keys.push((name, offset, size));
+        }
+    }
+    bbi.finish(&[ExtraIndex { field_id: NAME_FIELD_ID, keys }])
+}
+
+/// Read the records of a bigBed file as tab-delimited BED lines, in file order.
+pub fn read_bigbed(file: &str) -> Result<Vec<String>> {
+    let bbi = read_bbi(file, BIGBED_MAGIC)?;
+    let mut lines = Vec::<String>::new();
+    for block in &bbi.blocks {
+        let mut pos = 0;
+        while pos + 12 <= block.len() {
+            let field = |i: usize| u32::from_le_bytes([block[pos+i], block[pos+i+1], block[pos+i+2], block[pos+i+3]]);
+            let chrom = bbi.chroms.get(&field(0)).ok_or_else(||
+                anyhow!("Unknown chromosome ID {} in bigBed file {}", field(0), file))?;
+            let (start, end) = (field(4), field(8));
+            // the remaining fields are a null-terminated string
+            let rest_start = pos + 12;
+            let rest_end = block[rest_start..].iter().position(|b| *b == 0).
+                map(|p| rest_start + p).
+                ok_or_else(|| anyhow!("Unterminated record in bigBed file {}", file))?;
+            let rest = String::from_utf8_lossy(&block[rest_start..rest_end]);
+            lines.push(
+                if rest.is_empty() { format!("{}\t{}\t{}", chrom, start, end) }
+                else { format!("{}\t{}\t{}\t{}", chrom, start, end, rest) });
+            pos = rest_end + 1;
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use flate2::read::ZlibDecoder;
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: u64) -> u16 {
+        let o = offset as usize;
+        u16::from_le_bytes([data[o], data[o+1]])
+    }
+    fn u32_at(data: &[u8], offset: u64) -> u32 {
+        let o = offset as usize;
+        u32::from_le_bytes([data[o], data[o+1], data[o+2], data[o+3]])
+    }
+    fn u64_at(data: &[u8], offset: u64) -> u64 {
+        u32_at(data, offset) as u64 | (u32_at(data, offset+4) as u64) << 32
+    }
+    fn f64_at(data: &[u8], offset: u64) -> f64 {
+        f64::from_bits(u64_at(data, offset))
+    }
+
+    // the (offset, size) of every block in an R-tree, and the tree's depth
+    fn rtree_leaves(data: &[u8], index_offset: u64) -> (Vec<(u64, u64)>, usize) {
+        assert_eq!(u32_at(data, index_offset), 0x2468_ACE0);
+        let mut leaves = Vec::new();
+        let mut depth = 0;
+        let mut level = vec![index_offset+48];
+        while !level.is_empty() {
+            depth += 1;
+            let mut next = Vec::new();
+            for node in level {
+                let is_leaf = data[node as usize] != 0;
+                for i in 0..u16_at(data, node+2) as u64 {
+                    if is_leaf {
+                        let item = node + 4 + i*32;
+                        leaves.push((u64_at(data, item+16), u64_at(data, item+24)));
+                    }
+                    else {
+                        next.push(u64_at(data, node + 4 + i*24 + 16));
+                    }
+                }
+            }
+            level = next;
+        }
+        (leaves, depth)
+    }
+
+    fn uncompress(data: &[u8], offset: u64, size: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ZlibDecoder::new(&data[offset as usize..(offset+size) as usize]).read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn write_then_read() {
+        // not in byte order, with enough records for a two-level R-tree
+        let mut refs = LinkedHashMap::<String,u64>::new();
+        for chrom in &["chr2", "chr10", "chr1", "Zv9_NA1", "chrX"] {
+            refs.insert(chrom.to_string(), 10_000_000);
+        }
+        let mut records = Vec::<Bed12>::new();
+        for chrom in refs.keys() {
+            for i in 0..30_000u64 {
+                let start = i*30;
+                let size = 20 + (i%7)*10;
+                records.push(Bed12 {
+                    chrom: chrom.clone(),
+                    start,
+                    end: start+size,
+                    name: format!("{}.{}", chrom, i),
+                    score: 0,
+                    strand: "+".to_string(),
+                    thick_start: start,
+                    thick_end: start+size,
+                    item_rgb: "0".to_string(),
+                    block_sizes: vec![size],
+                    block_starts: vec![0],
+                });
+            }
+        }
+        sort_bed(&mut records);
+        let file = std::env::temp_dir().join(format!("bigbed_roundtrip_{}.bb", std::process::id()));
+        let file = file.to_str().unwrap();
+        write_bigbed(file, &refs, &records).unwrap();
+        let data = std::fs::read(file).unwrap();
+
+        // header
+        assert_eq!(u32_at(&data, 0), BIGBED_MAGIC);
+        assert_eq!(u32_at(&data, data.len() as u64 - 4), BIGBED_MAGIC);
+        assert_eq!(u16_at(&data, 4), 4);
+        let zoom_levels = u16_at(&data, 6) as u64;
+        let chrom_tree_offset = u64_at(&data, 8);
+        let full_data_offset = u64_at(&data, 16);
+        let full_index_offset = u64_at(&data, 24);
+        assert_eq!(u16_at(&data, 32), 12);
+        assert_eq!(u16_at(&data, 34), 12);
+        let auto_sql_offset = u64_at(&data, 36) as usize;
+        let auto_sql_end = auto_sql_offset + data[auto_sql_offset..].iter().position(|b| *b == 0).unwrap();
+        assert_eq!(&data[auto_sql_offset..auto_sql_end], BED12_AUTOSQL.as_bytes());
+        assert_eq!(u64_at(&data, full_data_offset), records.len() as u64);
+        let bases = records.iter().map(|r| r.end-r.start).sum::<u64>();
+        let total_summary_offset = u64_at(&data, 44);
+        assert_eq!(u64_at(&data, total_summary_offset), bases);
+        assert_eq!(f64_at(&data, total_summary_offset+8), 1.0);
+        assert_eq!(f64_at(&data, total_summary_offset+16), 1.0);
+        assert_eq!(f64_at(&data, total_summary_offset+24), bases as f64);
+
+        // chromosome tree: IDs follow the byte order of the names
+        assert_eq!(u32_at(&data, chrom_tree_offset), 0x78CA_8C91);
+        assert_eq!(u64_at(&data, chrom_tree_offset+16), refs.len() as u64);
+        let bbi = read_bbi(file, BIGBED_MAGIC).unwrap();
+        let mut names = refs.keys().cloned().collect::<Vec<_>>();
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for (id, name) in names.iter().enumerate() {
+            assert_eq!(bbi.chroms.get(&(id as u32)), Some(name));
+        }
+
+        // R-tree: every block is indexed, and every record comes back
+        let (blocks, depth) = rtree_leaves(&data, full_index_offset);
+        assert_eq!(u64_at(&data, full_index_offset+8), blocks.len() as u64);
+        assert_eq!(blocks.len(), bbi.blocks.len());
+        assert!(blocks.len() > 256);
+        assert_eq!(depth, 2);
+        let lines = read_bigbed(file).unwrap();
+        assert_eq!(lines, records.iter().map(|r| r.to_line()).collect::<Vec<_>>());
+
+        // zoom levels: each one is coarser and smaller than the last, and
+        // covers every base of the input
+        assert!(zoom_levels > 0);
+        let mut last_reduction = 0;
+        let mut last_count = records.len() as u32;
+        for z in 0..zoom_levels {
+            let header = 64 + z*24;
+            let reduction = u32_at(&data, header);
+            let data_offset = u64_at(&data, header+8);
+            let index_offset = u64_at(&data, header+16);
+            assert!(reduction > last_reduction);
+            let count = u32_at(&data, data_offset);
+            assert!(count < last_count);
+            let mut summaries = 0;
+            let mut valid_count = 0;
+            for (offset, size) in rtree_leaves(&data, index_offset).0 {
+                let block = uncompress(&data, offset, size);
+                assert_eq!(block.len() % 32, 0);
+                for summary in block.chunks(32) {
+                    let (start, end) = (u32_at(summary, 4), u32_at(summary, 8));
+                    assert!(start < end && end - start <= reduction);
+                    valid_count += u32_at(summary, 12) as u64;
+                    summaries += 1;
+                }
+            }
+            assert_eq!(summaries, count);
+            assert_eq!(valid_count, bases);
+            last_reduction = reduction;
+            last_count = count;
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! Native bigWig writer for run-length coverage data.
+use linked_hash_map::LinkedHashMap;
+use anyhow::Result;
+
+use crate::bbi::{BbiWriter, BlockKind, BIGWIG_MAGIC};
+
+const ITEMS_PER_SLOT: usize = 1024;
+
+/// Streams bedGraph-style intervals into a bigWig file. Chromosomes are
+/// written in the order of `refs`, so intervals must be added sorted by that
+/// chromosome order and then by start.
+pub struct BigWigWriter {
+    bbi: BbiWriter,
+}
+
+impl BigWigWriter {
+    pub fn create(file: &str, refs: &LinkedHashMap<String,u64>) -> Result<BigWigWriter> {
+        let chrom_sizes = refs.iter().map(|(k,v)| (k.clone(), *v)).collect::<Vec<_>>();
+        let bbi = BbiWriter::create(
+            file,
+            BIGWIG_MAGIC,
+            BlockKind::BedGraph,
+            &chrom_sizes,
+            None,
+            0,
+            0,
+            0,
+            ITEMS_PER_SLOT)?;
+        Ok(BigWigWriter { bbi })
+    }
+
+    /// Add a single interval with a constant value.
+    pub fn add(&mut self, chrom: &str, start: u64, end: u64, value: f32) -> Result<()> {
+        self.bbi.add_bedgraph(chrom, start, end, value)
+    }
+
+    /// Run-length encode a per-base array of values starting at position 0
+    /// and add the runs. Runs of zeroes are skipped unless `zero` is set.
+    /// Values past the end of the chromosome are ignored.
+    pub fn add_runs<I: IntoIterator<Item=f32>>(&mut self, chrom: &str, values: I, zero: bool) -> Result<()> {
+        let (_, chrom_size) = self.bbi.chrom(chrom)?;
+        let mut run: Option<(u64, f32)> = None;
+        let mut pos = 0u64;
+        for value in values.into_iter().take(chrom_size as usize) {
+            if let Some((start, run_value)) = run {
+                if run_value != value {
+                    if zero || run_value != 0.0 {
+                        self.bbi.add_bedgraph(chrom, start, pos, run_value)?;
+                    }
+                    run = Some((pos, value));
+                }
+            }
+            else {
+                run = Some((pos, value));
+            }
+            pos += 1;
+        }
+        if let Some((start, run_value)) = run {
+            if zero || run_value != 0.0 {
+                self.bbi.add_bedgraph(chrom, start, pos, run_value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the index and zoom levels and close the file.
+    pub fn finish(self) -> Result<()> {
+        self.bbi.finish(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbi::read_bbi;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset+4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    // the (chrom, start, end, value) items of every bedGraph section
+    fn read_bigwig(file: &str) -> Vec<(String, u32, u32, f32)> {
+        let bbi = read_bbi(file, BIGWIG_MAGIC).unwrap();
+        let mut items = Vec::new();
+        for block in &bbi.blocks {
+            assert_eq!(block[20], 1);
+            let chrom = &bbi.chroms[&u32_at(block, 0)];
+            let count = u16::from_le_bytes([block[22], block[23]]) as usize;
+            assert_eq!(block.len(), 24 + count*12);
+            for item in block[24..].chunks(12) {
+                let value = f32::from_le_bytes([item[8], item[9], item[10], item[11]]);
+                items.push((chrom.clone(), u32_at(item, 0), u32_at(item, 4), value));
+            }
+        }
+        items
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut refs = LinkedHashMap::<String,u64>::new();
+        refs.insert("chr1".to_string(), 10);
+        refs.insert("chr2".to_string(), 6);
+        let file = std::env::temp_dir().join(format!("bigwig_roundtrip_{}.bw", std::process::id()));
+        let file = file.to_str().unwrap();
+        let mut writer = BigWigWriter::create(file, &refs).unwrap();
+        // zero runs are skipped, and the last run is cut at the chromosome end
+        writer.add_runs("chr1", vec![0.0, 0.0, 1.0, 1.0, 2.5, 0.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0], false).unwrap();
+        // or kept
+        writer.add_runs("chr2", vec![0.0, 0.0, 1.0, 1.0, 0.0, 0.0], true).unwrap();
+        assert!(writer.add_runs("chr3", vec![1.0], false).is_err());
+        writer.finish().unwrap();
+        assert_eq!(read_bigwig(file), vec![
+            ("chr1".to_string(), 2, 4, 1.0),
+            ("chr1".to_string(), 4, 5, 2.5),
+            ("chr1".to_string(), 6, 10, 3.0),
+            ("chr2".to_string(), 0, 2, 0.0),
+            ("chr2".to_string(), 2, 4, 1.0),
+            ("chr2".to_string(), 4, 6, 0.0),
+        ]);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn items_per_slot_fits_the_item_count() {
+        let file = std::env::temp_dir().join(format!("bigwig_slots_{}.bw", std::process::id()));
+        let file = file.to_str().unwrap();
+        let sizes = vec![("chr1".to_string(), 10)];
+        assert!(BbiWriter::create(file, BIGWIG_MAGIC, BlockKind::BedGraph, &sizes, None, 0, 0, 0, 65536).is_err());
+        assert!(BbiWriter::create(file, BIGWIG_MAGIC, BlockKind::BedGraph, &sizes, None, 0, 0, 0, 0).is_err());
+        assert!(BbiWriter::create(file, BIGWIG_MAGIC, BlockKind::BedGraph, &sizes, None, 0, 0, 0, 65535).is_ok());
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use cassette_reannotation::indexed_annotation::IndexedAnnotation;
+use cassette_reannotation::feature_filter::Filter;
 use anyhow::{Result, anyhow};
 
 use structopt::StructOpt;
@@ -11,37 +12,46 @@ struct Options {
     #[structopt(long="overlapping_genes", help = "Only show cassettes which overlap other genes?")]
     overlapping_genes: bool,
     // input files
-    #[structopt(long="gff", help = "A genome annotation file in gff3 format", name="ANNOT_GFF_FILE")]
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
     annotfile_gff: Option<String>,
+    #[structopt(long="no_annotation_cache", help = "Always parse the annotation file instead of reading or writing its cache")]
+    no_annotation_cache: bool,
+    #[structopt(long="filter", help = "Only use the annotation features that pass a filter expression, such as \"gene_biotype=protein_coding and tag contains basic\"", name="FILTER")]
+    filter: Option<Filter>,
 }
 
 fn run() -> Result<()> {
     let options = Options::from_args();
-    let annot = IndexedAnnotation::from_gff(
+    let mut annot = IndexedAnnotation::from_file_with(
         &options.annotfile_gff.ok_or(anyhow!("NoneError"))?,
+        "gff",
+        "",
+        "",
         &None,
-        &None)?;
+        &None,
+        false,
+        !options.no_annotation_cache)?;
+    if let Some(ref filter) = options.filter {
+        let removed = annot.retain_features(filter)?;
+        eprintln!("Removed {} annotation rows that did not pass the filter", removed.len());
+    }
     println!("row\tgene_name\tother_gene_name\tcassette_location\tcassette_length");
     for (row, record) in annot.rows.iter().enumerate() {
         if let Some(exon_type) = record.attributes.get("exon_type") {
             if exon_type == "cassette" && record.feature_type == "exon" {
                 if let Some(record_gene_name) = record.attributes.get("gene_name") {
                     let mut other_genes = HashMap::<String,u64>::new();
-                    for overlap_row in annot.tree[&record.seqname].find(record.start-1..record.end) {
-                        let overlaps = &annot.rows[*overlap_row.data()];
-                        if overlaps.strand == record.strand && 
-                            (overlaps.feature_type == "gene" || overlaps.feature_type == "exon")
-                        {
-                            if let Some(overlaps_gene_name) = overlaps.attributes.get("gene_name") {
-                                if overlaps_gene_name != record_gene_name && 
-                                    &format!("{}.reannot", overlaps_gene_name) != record_gene_name 
-                                {
-                                    if overlaps.feature_type == "exon" {
-                                        *other_genes.entry(overlaps_gene_name.clone()).or_insert(0) += 1;
-                                    }
-                                    else {
-                                        other_genes.entry(overlaps_gene_name.clone()).or_insert(0);
-                                    }
+                    for overlap_row in annot.find_features(&record.seqname, record.start-1..record.end, Some(record.strand), &["gene","exon"]) {
+                        let overlaps = &annot.rows[overlap_row];
+                        if let Some(overlaps_gene_name) = overlaps.attributes.get("gene_name") {
+                            if overlaps_gene_name != record_gene_name && 
+                                &format!("{}.reannot", overlaps_gene_name) != record_gene_name 
+                            {
+                                if overlaps.feature_type == "exon" {
+                                    *other_genes.entry(overlaps_gene_name.to_string()).or_insert(0) += 1;
+                                }
+                                else {
+                                    other_genes.entry(overlaps_gene_name.to_string()).or_insert(0);
                                 }
                             }
                         }
@@ -3,31 +3,31 @@ use std::vec::Vec;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ops::Range;
-use std::fs::OpenOptions;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::io::{stdout, sink};
 use std::path::Path;
 use std::sync::Arc;
 use serde::{Serialize, Deserialize};
-use duct::cmd;
-use lazy_static::lazy_static;
 use anyhow::{Result, anyhow};
 
 use cassette_reannotation::*;
-use cassette_reannotation::power_set::*;
+use cassette_reannotation::interval_scheduling::*;
 use cassette_reannotation::indexed_annotation::*;
+use cassette_reannotation::annotation_formats::annotation_format;
+use cassette_reannotation::chrom_alias::ChromAliases;
+use cassette_reannotation::feature_filter::Filter;
+use cassette_reannotation::interval_index::SortedIntervals;
+use cassette_reannotation::faidx::Faidx;
+use cassette_reannotation::bigbed::*;
+use cassette_reannotation::bigwig::BigWigWriter;
 
 use percent_encoding::{utf8_percent_encode, CONTROLS};
 
-use regex::Regex;
-use regex::Captures;
-
 use rust_htslib::bam::Read;
 use rust_htslib::bam::IndexedReader;
 
-use bio::data_structures::interval_tree::IntervalTree;
-use bio::utils::Interval;
+use bio::alphabets::dna;
 
 use structopt::StructOpt;
 
@@ -45,27 +45,49 @@ use linked_hash_map::LinkedHashMap;
 #[structopt(name = "intronrpkm", about = "Analyze RPKM values in intronic space")]
 struct Options {
     // input files
-    #[structopt(long="gff", help = "A genome annotation file in gff3 format", name="ANNOT_GFF_FILE")]
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
     annotfile_gff: Option<String>,
-    #[structopt(long="gtf", help = "A genome annotation file in gtf format", name="ANNOT_GTF_FILE")]
+    #[structopt(long="gtf", help = "A genome annotation file in gtf format, optionally gzipped, or - for stdin", name="ANNOT_GTF_FILE")]
     annotfile_gtf: Option<String>,
+    #[structopt(long="annot", help = "A genome annotation file in gff3, gtf, bed12, bigbed, genepred or refflat format, optionally gzipped, or - for stdin", name="ANNOT_FILE")]
+    annotfile: Option<String>,
+    #[structopt(long="annot_format", help = "The format of the --annot file: gff, gtf, bed, bigbed, genepred or refflat. Guessed from the file extension if not given.", name="ANNOT_FORMAT")]
+    annot_format: Option<String>,
     #[structopt(long="bam1", short="1", help = "The set of stranded .bam files to analyze where read1 indicates strand", name="BAMFILE1")]
     bam1: Vec<String>,
     #[structopt(long="bam2", short="2", help = "The set of stranded .bam files to analyze where read2 indicates strand", name="BAMFILE2")]
     bam2: Vec<String>,
-    #[structopt(long="bam", short="u", help = "The set of unstranded .bam files to analyze", name="BAMFILE")]
+    #[structopt(long="bam", short="u", help = "The set of unstranded .bam files to analyze, or of unknown strandedness with --autostrand", name="BAMFILE")]
     bam: Vec<String>,
+    #[structopt(long="autostrand", help = "Infer the strandedness of the --bam files from the annotation")]
+    autostrand: bool,
+    #[structopt(long="strand_report", help = "Write the --autostrand report to a .json or tab-delimited file", name="STRAND_REPORT_FILE")]
+    strand_report: Option<String>,
+    #[structopt(long="strand_threshold", help = "The fraction of the --autostrand reads that must agree to call a library stranded, above 0.5 and at most 1", name="STRAND_THRESHOLD", default_value="0.8")]
+    strand_threshold: f64,
+    #[structopt(long="strand_sample_reads", help = "The number of strand-informative reads that --autostrand samples", name="STRAND_SAMPLE_READS", default_value="200000")]
+    strand_sample_reads: u64,
     #[structopt(long="chrmap", help = "Optional tab-delimited chr name mapping file", name="CHRMAP_FILE")]
     chrmap_file: Option<String>,
     #[structopt(long="vizchrmap", help = "Optional tab-delimited chr name mapping file for bigwig/bigbed exports", name="VIZCHRMAP_FILE")]
     vizchrmap_file: Option<String>,
-    #[structopt(long="sizes", help = "Optional chr sizes file", name="SIZES_FILE")]
+    #[structopt(long="chromalias", help = "Optional UCSC chromAlias.txt file or other tab-delimited table of equivalent chr names", name="CHROM_ALIAS_FILE")]
+    chromalias_file: Option<String>,
+    #[structopt(long="strict", help = "Fail on malformed annotation lines instead of skipping them")]
+    strict: bool,
+    #[structopt(long="no_annotation_cache", help = "Always parse the annotation file instead of reading or writing its cache")]
+    no_annotation_cache: bool,
+    #[structopt(long="sizes", help = "Optional chr sizes file, such as a FASTA .fai index", name="SIZES_FILE")]
     sizes_file: Option<String>,
     // output file
     #[structopt(long="out", short="o", help = "Output file", name="OUT_FILE", default_value="-")]
     outfile: String,
-    #[structopt(long="outannot", help = "Output Annotation file", name="OUT_ANNOT_FILE")]
+    #[structopt(long="outannot", help = "Output Annotation file, written as GTF if the file name ends in .gtf, otherwise as GFF3", name="OUT_ANNOT_FILE")]
     outannot: Option<String>,
+    #[structopt(long="cassette_stats", help = "Write per-cassette read evidence to a tab-delimited file", name="CASSETTE_STATS_FILE")]
+    cassette_stats: Option<String>,
+    #[structopt(long="orf_summary", help = "Write the reading frame effect of each coding cassette to a tab-delimited file (requires --outannot and --genome)", name="ORF_SUMMARY_FILE")]
+    orf_summary: Option<String>,
     // feature types filter
     #[structopt(long="exon_type", help = "The exon type(s) to search for", name="EXON_TYPE")]
     exon_type: Vec<String>,
@@ -75,14 +97,30 @@ struct Options {
     gene_type: Vec<String>,
     #[structopt(long="cds_type", help = "The CDS type(s) to search for", name="CDS_TYPE")]
     cds_type: Vec<String>,
-    #[structopt(long="genome", help = "The input genome FASTA file", name="GENOME_FASTA_FILE")]
+    #[structopt(long="filter", help = "Only search the annotation features that pass a filter expression, such as \"gene_biotype=protein_coding and tag contains basic\", for cassettes. All features are still written to --outannot.", name="FILTER")]
+    filter: Option<Filter>,
+    #[structopt(long="genome", help = "The input genome FASTA file, optionally compressed with bgzip, or a GFF3 file with a ##FASTA section. A .fai index is created next to it if missing.", name="GENOME_FASTA_FILE")]
     genome_file: Option<String>,
     
     // flags
-    #[structopt(long="max_iterations", help = "How many start/stop combinations before we skip this one?", name="MAX_ITERATIONS", default_value="1000000")]
+    #[structopt(long="max_iterations", help = "How many candidate cassette start/stop combinations before we skip this one?", name="MAX_ITERATIONS", default_value="1000000")]
     max_iterations: usize,
+    #[structopt(long="min_read_count_per_base", help = "Minimum per-base read depth of a candidate cassette region", name="MIN_READ_COUNT_PER_BASE", default_value="1")]
+    min_read_count_per_base: i32,
+    #[structopt(long="min_starts", help = "Minimum number of spliced reads supporting a cassette start", name="MIN_STARTS", default_value="2")]
+    min_starts: i32,
+    #[structopt(long="min_stops", help = "Minimum number of spliced reads supporting a cassette stop", name="MIN_STOPS", default_value="2")]
+    min_stops: i32,
+    #[structopt(long="retained_intron_coverage", help = "Fraction of non-cassette intronic bases covered by reads to call a retained intron", name="RETAINED_INTRON_COVERAGE", default_value="0.9")]
+    retained_intron_coverage: f64,
+    #[structopt(long="splice_motifs", help = "Classify the splice site motifs of each cassette using the --genome sequence")]
+    splice_motifs: bool,
+    #[structopt(long="drop_noncanonical", help = "Drop cassettes with non-canonical splice site motifs (implies --splice_motifs)")]
+    drop_noncanonical: bool,
     #[structopt(long="cpu_threads", short="t", help = "How many threads to use for processing", default_value="0")]
     cpu_threads: usize,
+    #[structopt(long="read_count", help = "Which alignments count as reads for RPKM: mapped, primary, unique or fragments", name="READ_COUNT", default_value="mapped")]
+    read_count: ReadCount,
     
     // debug output files
     #[structopt(long="debug", help = "Output all debug files?")]
@@ -117,15 +155,204 @@ struct Options {
     debug_retained_introns: Option<String>,
 }
 
+/// The thresholds a cassette call was made under.
+#[derive(Clone, Debug, Serialize)]
+struct CassetteParams {
+    min_read_count_per_base: i32,
+    min_starts: i32,
+    min_stops: i32,
+    max_iterations: usize,
+    retained_intron_coverage: f64,
+    drop_noncanonical: bool,
+}
+impl CassetteParams {
+    fn from_options(options: &Options) -> Result<CassetteParams> {
+        let params = CassetteParams {
+            min_read_count_per_base: options.min_read_count_per_base,
+            min_starts: options.min_starts,
+            min_stops: options.min_stops,
+            max_iterations: options.max_iterations,
+            retained_intron_coverage: options.retained_intron_coverage,
+            drop_noncanonical: options.drop_noncanonical,
+        };
+        if params.min_read_count_per_base < 1 {
+            return Err(anyhow!("--min_read_count_per_base must be at least 1, got {}", params.min_read_count_per_base));
+        }
+        if params.min_starts < 1 {
+            return Err(anyhow!("--min_starts must be at least 1, got {}", params.min_starts));
+        }
+        if params.min_stops < 1 {
+            return Err(anyhow!("--min_stops must be at least 1, got {}", params.min_stops));
+        }
+        if params.max_iterations < 1 {
+            return Err(anyhow!("--max_iterations must be at least 1, got {}", params.max_iterations));
+        }
+        if !(0.0..=1.0).contains(&params.retained_intron_coverage) {
+            return Err(anyhow!("--retained_intron_coverage must be between 0 and 1, got {}", params.retained_intron_coverage));
+        }
+        if (options.splice_motifs || options.drop_noncanonical) && options.genome_file.is_none() {
+            return Err(anyhow!("--splice_motifs and --drop_noncanonical require a --genome file"));
+        }
+        if options.orf_summary.is_some() && (options.genome_file.is_none() || options.outannot.is_none()) {
+            return Err(anyhow!("--orf_summary requires --outannot and a --genome file"));
+        }
+        Ok(params)
+    }
+}
+impl std::fmt::Display for CassetteParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "min_read_count_per_base={},min_starts={},min_stops={},max_iterations={},retained_intron_coverage={},drop_noncanonical={}",
+            self.min_read_count_per_base, self.min_starts, self.min_stops,
+            self.max_iterations, self.retained_intron_coverage, self.drop_noncanonical)
+    }
+}
+
+/// Splice site motif class of a cassette, named by the intron donor and
+/// acceptor dinucleotides.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+enum SpliceMotif {
+    #[serde(rename = "GT-AG")]
+    GtAg,
+    #[serde(rename = "GC-AG")]
+    GcAg,
+    #[serde(rename = "AT-AC")]
+    AtAc,
+    #[serde(rename = "non-canonical")]
+    NonCanonical,
+}
+impl SpliceMotif {
+    /// Classify a cassette from the dinucleotides flanking it in the genome.
+    /// The acceptor precedes the cassette and the donor follows it in
+    /// transcript orientation. Returns None if the flanks are off the
+    /// end of the sequence or the sequence is missing.
+    fn classify(genome: &Faidx, seqname: &str, strand: Strand, range: &Range<u64>) -> Option<SpliceMotif> {
+        let len = genome.len(seqname)?;
+        if range.start < 2 || range.end + 2 > len { return None }
+        let left = genome.fetch(seqname, range.start-2..range.start).ok()?.to_ascii_uppercase();
+        let right = genome.fetch(seqname, range.end..range.end+2).ok()?.to_ascii_uppercase();
+        let (acceptor, donor) = if strand == Strand::Reverse {
+            (dna::revcomp(&right), dna::revcomp(&left))
+        } else {
+            (left, right)
+        };
+        Some(match (&donor[..], &acceptor[..]) {
+            (b"GT", b"AG") => SpliceMotif::GtAg,
+            (b"GC", b"AG") => SpliceMotif::GcAg,
+            (b"AT", b"AC") => SpliceMotif::AtAc,
+            _ => SpliceMotif::NonCanonical,
+        })
+    }
+}
+impl std::fmt::Display for SpliceMotif {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            SpliceMotif::GtAg => "GT-AG",
+            SpliceMotif::GcAg => "GC-AG",
+            SpliceMotif::AtAc => "AT-AC",
+            SpliceMotif::NonCanonical => "non-canonical",
+        })
+    }
+}
+
+/// Read evidence supporting a reannotated cassette exon. Only fragments
+/// with a junction at one of the flanking constituitive exons are counted,
+/// the same fragments the cassette start/stop histograms are built from.
+#[derive(Clone, Debug, Default, Serialize)]
+struct CassetteStats {
+    // spliced reads starting at the cassette's acceptor site
+    start_junction_reads: i32,
+    // spliced reads ending at the cassette's donor site
+    end_junction_reads: i32,
+    // fragments with a junction at either cassette splice site
+    inclusion_reads: i32,
+    // fragments with a junction that skips over the whole cassette
+    skipping_reads: i32,
+    // None if no fragments were counted
+    psi: Option<f64>,
+    psi_ci_low: f64,
+    psi_ci_high: f64,
+    // lower bound of the PSI interval scaled to 0..1000
+    score: u32,
+}
+impl CassetteStats {
+    fn compute(
+        range: &Range<u64>,
+        read_pairs: &[&Vec<Vec<Range<u64>>>]) 
+        -> CassetteStats 
+    {
+        let mut stats = CassetteStats::default();
+        for &read_pair in read_pairs {
+            let mut includes = false;
+            let mut skips = false;
+            for exons in read_pair {
+                for (i, exon) in exons.iter().enumerate() {
+                    if i > 0 && exon.start == range.start {
+                        stats.start_junction_reads += 1;
+                        includes = true;
+                    }
+                    if i < exons.len()-1 && exon.end == range.end {
+                        stats.end_junction_reads += 1;
+                        includes = true;
+                    }
+                    if i < exons.len()-1 && exon.end <= range.start && range.end <= exons[i+1].start {
+                        skips = true;
+                    }
+                }
+            }
+            if includes { stats.inclusion_reads += 1 }
+            else if skips { stats.skipping_reads += 1 }
+        }
+        let n = stats.inclusion_reads + stats.skipping_reads;
+        if n > 0 {
+            stats.psi = Some(stats.inclusion_reads as f64 / n as f64);
+        }
+        let (low, high) = CassetteStats::wilson_interval(stats.inclusion_reads, n);
+        stats.psi_ci_low = low;
+        stats.psi_ci_high = high;
+        stats.score = (stats.psi_ci_low * 1000.0).round() as u32;
+        stats
+    }
+    /// The 95% Wilson score interval for k successes out of n, or the whole
+    /// range 0..1 if n is 0.
+    fn wilson_interval(k: i32, n: i32) -> (f64, f64) {
+        const Z: f64 = 1.96;
+        if n <= 0 { return (0.0, 1.0) }
+        let n = n as f64;
+        let p = k as f64 / n;
+        let denom = 1.0 + Z*Z/n;
+        let center = (p + Z*Z/(2.0*n)) / denom;
+        let half = Z * (p*(1.0-p)/n + Z*Z/(4.0*n*n)).sqrt() / denom;
+        ((center - half).max(0.0), (center + half).min(1.0))
+    }
+    /// The stats as GFF attribute key/value pairs. The PSI is left out if no
+    /// fragments were counted.
+    fn attributes(&self) -> Vec<(String,Vec<String>)> {
+        let mut attributes = vec![
+            ("cassette_start_junction_reads".to_string(), vec![self.start_junction_reads.to_string()]),
+            ("cassette_end_junction_reads".to_string(), vec![self.end_junction_reads.to_string()]),
+            ("cassette_junction_reads".to_string(), vec![self.inclusion_reads.to_string()]),
+            ("cassette_skipping_reads".to_string(), vec![self.skipping_reads.to_string()]),
+        ];
+        if let Some(psi) = self.psi {
+            attributes.push(("cassette_psi".to_string(), vec![psi.to_string()]));
+        }
+        attributes.push(("cassette_psi_ci".to_string(), vec![self.psi_ci_low.to_string(), self.psi_ci_high.to_string()]));
+        attributes.push(("cassette_score".to_string(), vec![self.score.to_string()]));
+        attributes
+    }
+}
+
 #[derive(Clone, Serialize)]
 struct Cassette {
     range: Range<u64>,
     cassette_row: Option<usize>,
+    stats: CassetteStats,
+    splice_motif: Option<SpliceMotif>,
 }
 impl std::fmt::Debug for Cassette {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{{range: {:?}, cassette_row: {:?}}}", 
-            self.range, self.cassette_row)
+        write!(f, "{{range: {:?}, cassette_row: {:?}, stats: {:?}, splice_motif: {:?}}}", 
+            self.range, self.cassette_row, self.stats, self.splice_motif)
     }    
 }
 #[derive(Clone, Serialize)]
@@ -135,167 +362,136 @@ struct ConstituitivePair {
     // start..end, optional cassette_row
     cassettes: Vec<Cassette>,
     is_retained_intron: bool,
+    // the parameters used to reannotate this pair
+    params: Option<CassetteParams>,
 }
 impl std::fmt::Debug for ConstituitivePair {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "exon1_row: {}, exon2_row: {}, cassettes: {:?}, is_retained_intron: {}", 
-            self.exon1_row, self.exon2_row, self.cassettes, self.is_retained_intron)
+        write!(f, "exon1_row: {}, exon2_row: {}, cassettes: {:?}, is_retained_intron: {}, params: {:?}", 
+            self.exon1_row, self.exon2_row, self.cassettes, self.is_retained_intron, self.params)
     }    
 }
 
+// `analysed` optionally limits the search to the rows that passed --filter
 fn find_constituitive_splice_pairs(annot: &IndexedAnnotation,
-                            options: &Options)
+                            options: &Options,
+                            analysed: Option<&HashSet<usize>>)
                             -> Result<Vec<ConstituitivePair>> {
-    let gene_types: HashSet<_> = options.gene_type.iter().map(|t| String::from(t)).collect();
-    let transcript_types: HashSet<_> = options.transcript_type.iter().map(|t| String::from(t)).collect();
-    let exon_types: HashSet<_> = options.exon_type.iter().map(|t| String::from(t)).collect();
-    
-    // set default feature types
+    let types = FeatureTypes::new(&options.gene_type, &options.transcript_type, &options.exon_type, &options.cds_type);
+    let is_analysed = |row: usize| analysed.map(|a| a.contains(&row)).unwrap_or(true);
     let mut exonpairs = Vec::<ConstituitivePair>::new();
-    for (gene_row, gene) in annot.rows.iter().enumerate() {
-        if gene_types.is_empty() || gene_types.contains(&gene.feature_type) {
-            if let Some(feature_rows) = annot.row2children.get(&gene_row) {
-                // get the transcript rows for this gene
-                let mut transcript_rows = HashSet::<usize>::new();
-                let mut transcript2exon = HashMap::<usize,HashSet<usize>>::new();
-                let mut start2transcript = HashMap::<u64,HashSet<usize>>::new();
-                let mut end2transcript = HashMap::<u64,HashSet<usize>>::new();
-                let mut transcript_tree = IntervalTree::<u64, usize>::new();
-                // splice start..splice end -> vec![(transcript_row, exon1_row, exon2_row)]
-                let mut splices = HashMap::<Range<u64>,Vec<(usize,usize,usize)>>::new();
-                'transcript_row:
-                for transcript_row in feature_rows {
-                    let transcript = &annot.rows[*transcript_row];
-                    // make sure this is a transcript type
-                    // make sure transcript coordinates are consistent with the gene coordinates
-                    if (transcript_types.is_empty() || 
-                            transcript_types.contains(&transcript.feature_type)) &&
-                        transcript.seqname == gene.seqname &&
-                        transcript.strand == gene.strand
-                    {
-                        if let Some(exon_rows) = annot.row2children.get(transcript_row) {
-                            // first get exon start/stop -> transcript associations
-                            // and splice start/stop -> transcript associations
-                            for exon_row in exon_rows {
-                                let exon = &annot.rows[*exon_row];
-                                if exon_types.is_empty() || exon_types.contains(&exon.feature_type) {
-                                    // skip any transcripts with weird exons
-                                    if exon.seqname != transcript.seqname || exon.strand != transcript.strand {
-                                        continue 'transcript_row;
-                                    }
-                                    // make sure the transcript has at least 1 exon
-                                    if !transcript_rows.contains(transcript_row) {
-                                        transcript_rows.insert(*transcript_row);
-                                        transcript_tree.insert(
-                                            Interval::new(transcript.start-1..transcript.end)?, 
-                                            *transcript_row);
-                                    }
-                                    transcript2exon.entry(*transcript_row).or_insert_with(HashSet::new).
-                                        insert(*exon_row);
-                                }
-                            }
+    for gene in annot.genes(&types) {
+        if !is_analysed(gene.row) { continue }
+        // get the transcript rows for this gene
+        let mut transcript_rows = HashSet::<usize>::new();
+        let mut transcript2exon = HashMap::<usize,Vec<usize>>::new();
+        let mut start2transcript = HashMap::<u64,HashSet<usize>>::new();
+        let mut end2transcript = HashMap::<u64,HashSet<usize>>::new();
+        let mut transcript_ranges = Vec::<(Range<u64>, usize)>::new();
+        // splice start..splice end -> vec![(transcript_row, exon1_row, exon2_row)]
+        let mut splices = HashMap::<Range<u64>,Vec<(usize,usize,usize)>>::new();
+        for transcript in annot.transcripts(&gene, &types) {
+            if !is_analysed(transcript.row) { continue }
+            // make sure the transcript has at least 1 exon
+            let exons = annot.exons(&transcript, &types).into_iter().
+                filter(|e| is_analysed(e.row)).
+                collect::<Vec<_>>();
+            if exons.is_empty() { continue }
+            transcript_rows.insert(transcript.row);
+            transcript_ranges.push((transcript.record.start-1..transcript.record.end, transcript.row));
+            transcript2exon.insert(transcript.row, exons.iter().map(|e| e.row).collect());
+        }
+        let transcript_tree = SortedIntervals::new(transcript_ranges);
+        // record each transcript's splice starts/ends
+        for transcript_row in &transcript_rows {
+            for exon_row in &transcript2exon[transcript_row] {
+                let exon = &annot.rows[*exon_row];
+                start2transcript.entry(exon.start-1).or_insert_with(HashSet::new).
+                    insert(*transcript_row);
+                end2transcript.entry(exon.end).or_insert_with(HashSet::new).
+                    insert(*transcript_row);
+            }
+        }
+        // find constituitive splice pairs
+        for transcript_row in &transcript_rows {
+            let mut exon1_row: Option<usize> = None;
+            for exon_row in &transcript2exon[transcript_row] {
+                let exon = &annot.rows[*exon_row];
+                if let Some(exon1_row_) = exon1_row {
+                    let exon1 = &annot.rows[exon1_row_];
+                    let containing_trs = transcript_tree.
+                        find((exon.start-1)..exon.start).
+                        map(|t| *t.data()).
+                        collect::<HashSet<_>>();
+                    if start2transcript[&(exon.start-1)].is_superset(&containing_trs) {
+                        splices.entry(exon1.end..(exon.start-1)).
+                            or_insert_with(Vec::new).
+                            push((*transcript_row, exon1_row_, *exon_row));
+                        exon1_row = None;
+                        let containing_trs = transcript_tree.
+                            find(exon.end..exon.end+1).
+                            map(|t| *t.data()).
+                            collect::<HashSet<_>>();
+                        if end2transcript[&(exon.end)].is_superset(&containing_trs) {
+                            exon1_row = Some(*exon_row);
                         }
                     }
                 }
-                // record each transcript's splice starts/ends
-                for transcript_row in &transcript_rows {
-                    if let Some(exon_rows) = transcript2exon.get(&transcript_row) {
-                        let mut exon_rows = exon_rows.iter().collect::<Vec<_>>();
-                        exon_rows.sort_by_key(|a| &annot.rows[**a].start);
-                        for exon_row in exon_rows {
-                            let exon = &annot.rows[*exon_row];
-                            start2transcript.entry(exon.start-1).or_insert_with(HashSet::new).
-                                insert(*transcript_row);
-                            end2transcript.entry(exon.end).or_insert_with(HashSet::new).
-                                insert(*transcript_row);
-                        }
+                else {
+                    let containing_trs = transcript_tree.
+                        find(exon.end..exon.end+1).
+                        map(|t| *t.data()).
+                        collect::<HashSet<_>>();
+                    if end2transcript[&(exon.end)].is_superset(&containing_trs) {
+                        exon1_row = Some(*exon_row);
                     }
                 }
-                // find constituitive splice pairs
-                for transcript_row in &transcript_rows {
-                    if let Some(exon_rows) = transcript2exon.get(&transcript_row) {
-                        let mut exon_rows = exon_rows.iter().collect::<Vec<_>>();
-                        exon_rows.sort_by_key(|a| &annot.rows[**a].start);
-                        let mut exon1_row: Option<usize> = None;
-                        for exon_row in exon_rows {
-                            let exon = &annot.rows[*exon_row];
-                            if let Some(exon1_row_) = exon1_row {
-                                let exon1 = &annot.rows[exon1_row_];
-                                let containing_trs = transcript_tree.
-                                    find((exon.start-1)..exon.start).
-                                    map(|t| *t.data()).
-                                    collect::<HashSet<_>>();
-                                if start2transcript[&(exon.start-1)].is_superset(&containing_trs) {
-                                    splices.entry(exon1.end..(exon.start-1)).
-                                        or_insert_with(Vec::new).
-                                        push((*transcript_row, exon1_row_, *exon_row));
-                                    exon1_row = None;
-                                    let containing_trs = transcript_tree.
-                                        find(exon.end..exon.end+1).
-                                        map(|t| *t.data()).
-                                        collect::<HashSet<_>>();
-                                    if end2transcript[&(exon.end)].is_superset(&containing_trs) {
-                                        exon1_row = Some(*exon_row);
-                                    }
-                                }
-                            }
-                            else {
-                                let containing_trs = transcript_tree.
-                                    find(exon.end..exon.end+1).
-                                    map(|t| *t.data()).
-                                    collect::<HashSet<_>>();
-                                if end2transcript[&(exon.end)].is_superset(&containing_trs) {
-                                    exon1_row = Some(*exon_row);
-                                }
-                            }
-                        }
-                    }
+            }
+        }
+        for (splice_range, splices) in splices {
+            let containing_trs = transcript_tree.
+                find(splice_range.clone()).
+                map(|t| *t.data()).
+                filter(|t| annot.rows[*t].start-1 < splice_range.start && 
+                    splice_range.end < annot.rows[*t].end).collect::<HashSet<_>>();
+            let splice_trs = splices.iter().map(|&(transcript_row,_,_)| transcript_row).collect::<HashSet<_>>();
+            // look at constituitive splices
+            if splice_trs.is_superset(&containing_trs) {
+                let mut exon_rows = HashSet::<(usize,usize)>::new();
+                for &(_, exon1_row, exon2_row) in &splices {
+                    exon_rows.insert((exon1_row, exon2_row));
                 }
-                for (splice_range, splices) in splices {
-                    let containing_trs = transcript_tree.
-                        find(&splice_range).
-                        map(|t| *t.data()).
-                        filter(|t| annot.rows[*t].start-1 < splice_range.start && 
-                            splice_range.end < annot.rows[*t].end).collect::<HashSet<_>>();
-                    let splice_trs = splices.iter().map(|&(transcript_row,_,_)| transcript_row).collect::<HashSet<_>>();
-                    // look at constituitive splices
-                    if splice_trs.is_superset(&containing_trs) {
-                        let mut exon_rows = HashSet::<(usize,usize)>::new();
-                        for &(_, exon1_row, exon2_row) in &splices {
-                            exon_rows.insert((exon1_row, exon2_row));
-                        }
-                        // sort by shortest sum of exon lengths
-                        let mut exon_rows = exon_rows.iter().collect::<Vec<_>>();
-                        exon_rows.sort_by(|a,b| 
-                            ((&annot.rows[a.0].end-&annot.rows[a.0].start+1) + (&annot.rows[a.1].end-&annot.rows[a.1].start+1)).
-                            cmp(&((&annot.rows[b.0].end-&annot.rows[b.0].start+1) + (&annot.rows[b.1].end-&annot.rows[b.1].start+1))).
-                            // then sort by lowest exon1_row 
-                            then_with(|| a.0.cmp(&b.0)).
-                            // then sort by lowest exon2_row
-                            then_with(|| a.1.cmp(&b.1)));
-                        if let Some(exon_row) = exon_rows.get(0) {
-                            let exon1 = &annot.rows[exon_row.0];
-                            let exon2 = &annot.rows[exon_row.1];
-                            let region = (exon2.start-1)-exon1.end;
-                            if region == 0 {
-                                eprintln!("Region between exon1 (row {}) and exon2 (row {}) is zero, skipping constituitive pair", exon_row.0, exon_row.1);
-                            }
-                            else {
-                                exonpairs.push(ConstituitivePair {
-                                    exon1_row: exon_row.0,
-                                    exon2_row: exon_row.1,
-                                    cassettes: Vec::new(),
-                                    is_retained_intron: false,
-                                });
-                            }
-                        }
+                // sort by shortest sum of exon lengths
+                let mut exon_rows = exon_rows.iter().collect::<Vec<_>>();
+                exon_rows.sort_by(|a,b| 
+                    ((&annot.rows[a.0].end-&annot.rows[a.0].start+1) + (&annot.rows[a.1].end-&annot.rows[a.1].start+1)).
+                    cmp(&((&annot.rows[b.0].end-&annot.rows[b.0].start+1) + (&annot.rows[b.1].end-&annot.rows[b.1].start+1))).
+                    // then sort by lowest exon1_row 
+                    then_with(|| a.0.cmp(&b.0)).
+                    // then sort by lowest exon2_row
+                    then_with(|| a.1.cmp(&b.1)));
+                if let Some(exon_row) = exon_rows.get(0) {
+                    let exon1 = &annot.rows[exon_row.0];
+                    let exon2 = &annot.rows[exon_row.1];
+                    let region = (exon2.start-1)-exon1.end;
+                    if region == 0 {
+                        eprintln!("Region between exon1 (row {}) and exon2 (row {}) is zero, skipping constituitive pair", exon_row.0, exon_row.1);
+                    }
+                    else {
+                        exonpairs.push(ConstituitivePair {
+                            exon1_row: exon_row.0,
+                            exon2_row: exon_row.1,
+                            cassettes: Vec::new(),
+                            is_retained_intron: false,
+                            params: None,
+                        });
                     }
                 }
             }
         }
     }
     // sort the pairs, by chr, start
-    // this is required by bedToBigBed
+    // this is required by the bigBed writer
     exonpairs.sort_by(|a,b| 
         annot.rows[a.exon1_row].seqname.as_bytes().cmp(annot.rows[b.exon1_row].seqname.as_bytes()).
         then_with(|| annot.rows[a.exon1_row].start.cmp(&annot.rows[b.exon1_row].start)));
@@ -303,38 +499,15 @@ fn find_constituitive_splice_pairs(annot: &IndexedAnnotation,
 }
 
 fn bed2bigbed(
-    bed_file: &str, 
+    records: &mut [Bed12], 
     bigbed_file: &str, 
     refs: &LinkedHashMap<String,u64>, 
-    sort_bed: bool,
-    remove_bed: bool,
     trackdb: &mut BufWriter<Box<dyn Write>>)
     -> Result<()> 
 {
-    // write the genome file
-    let genome_filename = format!("{}.genome", bigbed_file);
-    {   let mut genome_fh = BufWriter::new(File::create(&genome_filename)?);
-        for (chr, length) in refs {
-            writeln!(genome_fh, "{}\t{}", chr, length)?;
-        }
-    }
-    
-    // sort the bed file
-    let sorted_bed_file = format!("{}.sorted.bed", bed_file);
-    if sort_bed {
-        cmd!("sort","-k1,1","-k2,2n","-o",&sorted_bed_file,&bed_file).env("LC_COLLATE","C").run()?;
-    }
-    
-    // call bedToBigBed
-    cmd!("bedToBigBed","-type=bed12","-tab","-extraIndex=name",
-        if sort_bed {&sorted_bed_file} else {bed_file},
-        &genome_filename,
-        &bigbed_file).run()?;
-    // remove the bed file
-    if remove_bed { std::fs::remove_file(&bed_file)?; }
-    if remove_bed && sort_bed { std::fs::remove_file(&sorted_bed_file)?; }
-    // remove the genome file
-    std::fs::remove_file(&genome_filename)?;
+    // bigBed requires the records to be sorted by chr, start
+    sort_bed(records);
+    write_bigbed(bigbed_file, refs, records)?;
     
     // write to the trackDb file
     const PATH_ENCODE_SET: &percent_encoding::AsciiSet = &CONTROLS.add(b'+').add(b'?').add(b'&');
@@ -362,11 +535,12 @@ fn reannotate_pair(
     exon2: &Record,
     read_pairs: &HashMap<(String,String),Vec<Vec<Range<u64>>>>,
     debug_bigwig: &Option<String>,
-    max_iterations: usize,
+    params: &CassetteParams,
+    genome: &Option<Arc<Faidx>>,
     bw_histogram: Arc<ConcHashMap<usize,i32>>,
     start_bw_histogram: Arc<ConcHashMap<usize,i32>>,
     end_bw_histogram: Arc<ConcHashMap<usize,i32>>) 
-    -> Result<(ConstituitivePair, IntervalTree<u64,String>)> 
+    -> Result<(ConstituitivePair, SortedIntervals<String>)> 
 {
     let start = exon1.end as usize;
     let end = (exon2.start-1) as usize;
@@ -374,9 +548,11 @@ fn reannotate_pair(
     let mut start_histo = vec![0i32; region_size];
     let mut end_histo = vec![0i32; region_size];
     let mut histo = vec![0i32; region_size];
-    let mut mapped_reads = IntervalTree::<u64, String>::new();
+    let mut mapped_reads = Vec::<(Range<u64>, String)>::new();
     let mut cassettes = Vec::<Cassette>::new();
     let mut read_coverage = vec![0i32; region_size];
+    // the read pairs that matched a constituitive splice junction
+    let mut spliced_pairs = Vec::<&Vec<Vec<Range<u64>>>>::new();
     for (&(_,ref read_name),read_pair) in read_pairs {
         // fill in the read_coverage histogram
         for exons in read_pair {
@@ -397,11 +573,12 @@ fn reannotate_pair(
             if matches_splice { break }
         }
         if !matches_splice { continue }
+        spliced_pairs.push(read_pair);
         
         for exons in read_pair {
             // write the internal reads histogram and mapped_reads
             for exon in exons {
-                mapped_reads.insert(Interval::new(exon.clone())?, read_name.clone());
+                mapped_reads.push((exon.clone(), read_name.clone()));
                 for pos in std::cmp::max(start as u64, exon.start)..std::cmp::min(end as u64, exon.end) {
                     if debug_bigwig.is_some() {
                         bw_histogram.upsert(pos as usize, 1, &|v| *v += 1);
@@ -448,10 +625,9 @@ fn reannotate_pair(
     let mut exon_start = start;
     let mut exon_value = histo[exon_start-start];
     let mut exon_regions = Vec::<Range<usize>>::new();
-    const MIN_READ_COUNT_PER_BASE: i32 = 1;
     for (i, value) in histo.iter().enumerate() {
-        if (*value == 0) != (exon_value == 0) {
-            if exon_value >= MIN_READ_COUNT_PER_BASE {
+        if (*value >= params.min_read_count_per_base) != (exon_value >= params.min_read_count_per_base) {
+            if exon_value >= params.min_read_count_per_base {
                 let exon_end = i+start;
                 exon_regions.push(exon_start..exon_end);
             }
@@ -459,19 +635,17 @@ fn reannotate_pair(
             exon_value = *value;
         }
     }
-    const MIN_STARTS: i32 = 2;
-    const MIN_STOPS: i32 = 2;
     'EXON_REGION:
     for exon_region in exon_regions {
         let mut starts = Vec::<(usize,i32)>::new();
         for (i, value) in start_histo[(exon_region.start-start)..(exon_region.end-start)].iter().enumerate() {
-            if *value >= MIN_STARTS {
+            if *value >= params.min_starts {
                 starts.push((i+exon_region.start,*value));
             }
         }
         let mut ends = Vec::<(usize,i32)>::new();
         for (i, value) in end_histo[(exon_region.start-start)..(exon_region.end-start)].iter().enumerate() {
-            if *value >= MIN_STOPS {
+            if *value >= params.min_stops {
                 ends.push((i+exon_region.start,*value));
             }
         }
@@ -484,53 +658,36 @@ fn reannotate_pair(
                     pairs.insert(s..(e+1), (sscore, escore));
                 }
                 iterations += 1;
-                if iterations > max_iterations {
-                    eprintln!("More than {} iterations on pair {}", max_iterations, pair_name);
-                    continue 'EXON_REGION;
-                }
-            }
-        }
-        if pairs.len() >= 64 {
-            eprintln!("Too many possible cassette start/stops for pair {}", pair_name);
-            continue 'EXON_REGION;
-        }
-        // iterate over the powerset of the start/stop set
-        let mut best_set_score = 0;
-        let mut best_set = None;
-        let mut iterations = 0;
-        'SET:
-        for set in PowerSet::new(&pairs.keys().collect::<Vec<_>>()) {
-            let mut set_score = 0;
-            let mut overlaps = IntervalTree::<usize,()>::new();
-            let mut set = set.clone();
-            set.sort_by_key(|a| a.start);
-            for pair in &set {
-                // make sure there are no overlaps in this set
-                for _ in overlaps.find(pair.start..pair.end) { continue 'SET; }
-                overlaps.insert(Interval::new(pair.start..pair.end)?, ());
-                // add to the set score
-                let (sscore, escore) = pairs[pair];
-                set_score += sscore + escore;
-                // make sure we don't go over the max iterations
-                iterations += 1;
-                if iterations > max_iterations {
-                    eprintln!("More than {} iterations on pair {}", max_iterations, pair_name);
+                if iterations > params.max_iterations {
+                    eprintln!("More than {} iterations on pair {}", params.max_iterations, pair_name);
                     continue 'EXON_REGION;
                 }
             }
-            if best_set.is_none() || set_score > best_set_score {
-                best_set = Some(set);
-                best_set_score = set_score;
-            }
         }
+        // find the highest scoring set of non-overlapping cassettes
+        let candidates = pairs.iter().
+            map(|(range, &(sscore, escore))| (range.clone(), (sscore + escore) as i64)).
+            collect::<Vec<_>>();
+        let best_set = max_weight_intervals(&candidates);
         // store the reannotated cassette exon
-        if let Some(best_set) = best_set {
-            for pair in best_set {
-                cassettes.push(Cassette {
-                    range: pair.start as u64..pair.end as u64,
-                    cassette_row: None,
-                });
+        for pair in best_set {
+            let range = pair.start as u64..pair.end as u64;
+            let splice_motif = genome.as_ref().and_then(|g| 
+                SpliceMotif::classify(g, &exon1.seqname, exon1.strand, &range));
+            // cassettes whose flanks aren't in the genome are kept, since
+            // they can't be shown to be non-canonical
+            if genome.is_some() && splice_motif.is_none() {
+                eprintln!("Could not read the splice sites of cassette {}:{}-{} on pair {} from the genome, keeping it",
+                    exon1.seqname, range.start+1, range.end, pair_name);
             }
+            if params.drop_noncanonical && splice_motif == Some(SpliceMotif::NonCanonical) { continue }
+            let stats = CassetteStats::compute(&range, &spliced_pairs);
+            cassettes.push(Cassette {
+                range: range,
+                cassette_row: None,
+                stats: stats,
+                splice_motif: splice_motif,
+            });
         }
     }
     let mut cassette_coverage = vec![0i32; region_size];
@@ -548,15 +705,16 @@ fn reannotate_pair(
         }
     }
     let base_coverage = covered_bases as f64 / total_bases as f64;
-    let is_retained_intron = base_coverage >= 0.9;
+    let is_retained_intron = base_coverage >= params.retained_intron_coverage;
     let reannotpair = ConstituitivePair {
         exon1_row: exon1.row,
         exon2_row: exon2.row,
         cassettes: cassettes,
         is_retained_intron: is_retained_intron,
+        params: Some(params.clone()),
     };
     //eprintln!("Writing reannotated pair: {:?}", reannotpair);
-    Ok((reannotpair, mapped_reads))
+    Ok((reannotpair, SortedIntervals::new(mapped_reads)))
 }
 
 fn reannotate_regions(
@@ -566,6 +724,8 @@ fn reannotate_regions(
     bamstrand: &[Option<bool>], 
     total_reads: u64,
     options: &Options,
+    params: &CassetteParams,
+    genome: &Option<Arc<Faidx>>,
     trackdb: &mut BufWriter<Box<dyn Write>>)
     -> Result<(Vec<ConstituitivePair>,Vec<RpkmStats>)>
 {
@@ -583,7 +743,8 @@ fn reannotate_regions(
     {   let opts = || {
             concurrent_hashmap::Options {concurrency: pool_cpus as u16, ..Default::default() }
         };
-        for (chr, _) in &annot.refs {
+        // the annotation can have seqnames missing from the bam refs
+        for chr in annot.refs.keys().chain(annot.tree.keys()) {
             plus_bw_histo.insert(chr.clone(),Arc::new(ConcHashMap::<usize,i32>::with_options(opts())));
             minus_bw_histo.insert(chr.clone(),Arc::new(ConcHashMap::<usize,i32>::with_options(opts())));
             start_plus_bw_histo.insert(chr.clone(),Arc::new(ConcHashMap::<usize,i32>::with_options(opts())));
@@ -602,18 +763,7 @@ fn reannotate_regions(
     
     let mut tidmaps = HashMap::<String,HashMap<String,u32>>::new();
     for bamfile in bamfiles {
-        let bam = IndexedReader::from_path(bamfile)?;
-        // build the tid map for this bam file
-        let mut tidmap = HashMap::<String,u32>::new();
-        {   let header = bam.header();
-            for target_name in header.target_names() {
-                let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
-                let target_name = String::from(std::str::from_utf8(target_name)?);
-                let chr = annot.chrmap.get(&target_name).unwrap_or(&target_name);
-                tidmap.insert(chr.clone(), tid);
-            }
-        }
-        tidmaps.insert(bamfile.clone(), tidmap);
+        tidmaps.insert(bamfile.clone(), get_tidmap(bamfile, annot)?);
     }
     let tidmaps = Arc::new(tidmaps);
     
@@ -627,41 +777,43 @@ fn reannotate_regions(
         let exon2 = &annot.rows[pair.exon2_row];
         let start = exon1.end;
         let end = (exon2.start-1) as usize;
-        let strand = &exon1.strand;
-        let strand_is_plus = strand == "+";
+        let strand_is_plus = exon1.strand == Strand::Forward;
         
-        let chr = exon1.seqname.clone();
+        let chr = exon1.seqname;
         let bw_histogram = 
-            if strand_is_plus { plus_bw_histo[&chr].clone() } 
-            else { minus_bw_histo[&chr].clone() };
+            if strand_is_plus { plus_bw_histo[chr.as_str()].clone() } 
+            else { minus_bw_histo[chr.as_str()].clone() };
         let start_bw_histogram = 
-            if strand_is_plus { start_plus_bw_histo[&chr].clone() } 
-            else { start_minus_bw_histo[&chr].clone() };
+            if strand_is_plus { start_plus_bw_histo[chr.as_str()].clone() } 
+            else { start_minus_bw_histo[chr.as_str()].clone() };
         let end_bw_histogram = 
-            if strand_is_plus { end_plus_bw_histo[&chr].clone() }
-            else { end_minus_bw_histo[&chr].clone() };
+            if strand_is_plus { end_plus_bw_histo[chr.as_str()].clone() }
+            else { end_minus_bw_histo[chr.as_str()].clone() };
             
-        // process the constituitivepair in parallel
-        let exon1 = exon1.clone();
-        let exon2 = exon2.clone();
+        // process the constituitivepair in parallel. The worker reads the
+        // exons from the shared annotation instead of copying them.
+        let (exon1_row, exon2_row) = (pair.exon1_row, pair.exon2_row);
         let debug_bigwig = options.debug_bigwig.clone().map(String::from);
-        let max_iterations = options.max_iterations;
+        let params = params.clone();
+        let genome = genome.clone();
         let bamfiles = Arc::new(bamfiles.to_vec());
         let bamstrand = Arc::new(bamstrand.to_vec());
         let tidmaps = tidmaps.clone();
         let annot = annot.clone();
         let pair_name = get_pair_name(pair, &annot);
+        let read_count = options.read_count;
         let pair_future = pool.spawn_fn(move ||->Result<(ConstituitivePair,RpkmStats)> {
             //get all the bam reads in parallel
             let mut read_pairs = HashMap::<(String,String),Vec<Vec<Range<u64>>>>::new();
             for (i,bamfile) in bamfiles.iter().enumerate() {
                 let read1strand = bamstrand[i];
                 let tidmap = &tidmaps[bamfile];
-                if let Some(tid) = tidmap.get(&chr) {
+                if let Some(tid) = tidmap.get(chr.as_str()) {
                     let mut bam = IndexedReader::from_path(bamfile)?;
                     bam.fetch(*tid, start as u32, end as u32)?;
                     for read in bam.records() {
                         let read = read?;
+                        if !read_count.includes(&read) { continue }
                         // make sure the read's strand matches
                         if let Some(is_read1strand) = read1strand {
                             if !(((is_read1strand == read.is_first_in_template()) == !read.is_reverse()) == strand_is_plus) {
@@ -677,11 +829,12 @@ fn reannotate_regions(
             }
             let (pair,mapped_reads) = reannotate_pair(
                 &pair_name,
-                &exon1,
-                &exon2,
+                &annot.rows[exon1_row],
+                &annot.rows[exon2_row],
                 &read_pairs,
                 &debug_bigwig,
-                max_iterations,
+                &params,
+                &genome,
                 bw_histogram,
                 start_bw_histogram,
                 end_bw_histogram)?;
@@ -732,61 +885,24 @@ fn write_bigwig(
     write_parent: bool) 
     -> Result<()> 
 {
-    // write the bedgraph file
-    let bedgraph_file = format!("{}.{}.bedgraph", file, strand);
-    {   let mut bw = BufWriter::new(File::create(&bedgraph_file)?);
-        // sort the chrs by ascii values. This is required by bedGraphToBigWig
-        let mut chrs = histogram.keys().map(|c| (c, vizchrmap.get(c).unwrap_or(c))).collect::<Vec<_>>();
-        chrs.sort_by_key(|a| a.1.as_bytes());
-        for (chr, vizchr) in chrs {
-            let histo = &histogram[chr];
-            let reflength = refs[chr] as usize;
-            let mut start = 0usize;
-            let mut start_value = 0i32;
-            if let Some(start_val) = histo.find(&start) {
-                start_value = *start_val.get();
-            }
-            for i in 0usize..reflength {
-                let mut value = 0i32;
-                if let Some(v) = histo.find(&i) {
-                    value = *v.get();
-                }
-                if value != start_value {
-                    if start_value > 0i32 {
-                        if strand == "-" {
-                            writeln!(bw, "{}\t{}\t{}\t{}\n",
-                                     vizchr, start, i, -(start_value as i64))?;
-                        }
-                        else {
-                            writeln!(bw, "{}\t{}\t{}\t{}\n",
-                                     vizchr, start, i, start_value)?;
-                        }
-                    }
-                    start = i;
-                    start_value = value;
-                }
-            }
-        }
-    }
-    
     let vizrefs = refs.iter().
         map(|(k,v)| (vizchrmap.get(k).unwrap_or(k).clone(), *v)).
         collect::<LinkedHashMap<String,u64>>();
-    // write the genome file
-    let genome_filename = format!("{}.{}.bw.genome", file, strand);
-    {   let mut genome_fh = BufWriter::new(File::create(&genome_filename)?);
-        for (chr, length) in vizrefs {
-            writeln!(genome_fh, "{}\t{}", chr, length)?;
+    let bigwig_file = format!("{}.{}.bw", file, strand);
+    let mut bw = BigWigWriter::create(&bigwig_file, &vizrefs)?;
+    // write the chrs in the same order as the chromosome sizes
+    for chr in refs.keys() {
+        if let Some(histo) = histogram.get(chr) {
+            let vizchr = vizchrmap.get(chr).unwrap_or(chr);
+            let reflength = refs[chr] as usize;
+            let values = (0usize..reflength).map(|i| {
+                let value = histo.find(&i).map(|v| *v.get()).unwrap_or(0);
+                if strand == "-" { -(value as f32) } else { value as f32 }
+            });
+            bw.add_runs(vizchr, values, false)?;
         }
     }
-    
-    // call bedGraphToBigWig
-    let bigwig_file = format!("{}.{}.bw", file, strand);
-    cmd!("bedGraphToBigWig",&bedgraph_file,&genome_filename,&bigwig_file).run()?;
-    // remove the bedgraph file
-    std::fs::remove_file(&bedgraph_file)?;
-    // remove the genome file
-    std::fs::remove_file(&genome_filename)?;
+    bw.finish()?;
     
     // write to the trackDb file
     const PATH_ENCODE_SET: &percent_encoding::AsciiSet = &CONTROLS.add(b'+').add(b'?').add(b'&');
@@ -833,12 +949,13 @@ struct RpkmStats {
     exon2_rpkm: f64,
     total_constituitive_rpkm: f64,
     total_cassette_rpkm: f64,
+    params: Option<CassetteParams>,
 }
 
 fn compute_rpkm( 
     annot: &IndexedAnnotation, 
     pair: &ConstituitivePair,
-    mapped_reads: &IntervalTree<u64,String>,
+    mapped_reads: &SortedIntervals<String>,
     total_reads: u64) 
     -> Result<RpkmStats>
 {
@@ -878,7 +995,7 @@ fn compute_rpkm(
     let mut intron_features = Vec::<Range<u64>>::new();
     if pair.cassettes.is_empty() {
         let intron_range = annot.rows[pair.exon1_row].end..(annot.rows[pair.exon2_row].start-1);
-        for read in mapped_reads.find(&intron_range) {
+        for read in mapped_reads.find(intron_range.clone()) {
             let intron_read = intron_reads.entry(read.data().clone()).or_insert_with(Vec::new);
             intron_read.push(read.interval().start..read.interval().end);
         }
@@ -886,14 +1003,14 @@ fn compute_rpkm(
     }
     else {
         let intron_range = annot.rows[pair.exon1_row].end..pair.cassettes[0].range.start;
-        for read in mapped_reads.find(&intron_range) {
+        for read in mapped_reads.find(intron_range.clone()) {
             let intron_read = intron_reads.entry(read.data().clone()).or_insert_with(Vec::new);
             intron_read.push(read.interval().start..read.interval().end);
         }
         intron_features.push(intron_range);
         
         for (i, cassette) in pair.cassettes.iter().enumerate() {
-            let reads: Vec<_> = mapped_reads.find(&cassette.range).collect();
+            let reads: Vec<_> = mapped_reads.find(cassette.range.clone()).collect();
             for read in &reads {
                 let cassette_read = cassette_reads.entry(read.data().clone()).or_insert_with(Vec::new);
                 cassette_read.push(read.interval().start..read.interval().end);
@@ -907,7 +1024,7 @@ fn compute_rpkm(
 
             if i < pair.cassettes.len()-1 {
                 let intron_range = cassette.range.end..pair.cassettes[i+1].range.start;
-                for read in mapped_reads.find(&intron_range) {
+                for read in mapped_reads.find(intron_range.clone()) {
                     let intron_read = intron_reads.entry(read.data().clone()).or_insert_with(Vec::new);
                     intron_read.push(read.interval().start..read.interval().end);
                 }
@@ -916,7 +1033,7 @@ fn compute_rpkm(
         }
         
         let intron_range = pair.cassettes[pair.cassettes.len()-1].range.start..annot.rows[pair.exon2_row].start;
-        for read in mapped_reads.find(&intron_range) {
+        for read in mapped_reads.find(intron_range.clone()) {
                 let intron_read = intron_reads.entry(read.data().clone()).or_insert_with(Vec::new);
                 intron_read.push(read.interval().start..read.interval().end);
         }
@@ -937,6 +1054,7 @@ fn compute_rpkm(
         exon2_rpkm: exon2_rpkm,
         total_constituitive_rpkm: total_constituitive_rpkm,
         total_cassette_rpkm: total_cassette_rpkm,
+        params: pair.params.clone(),
     };
     return Ok(rpkmstats);
 }
@@ -959,7 +1077,7 @@ fn write_rpkm_stats(
         then_with(|| a.pair_name.cmp(&b.pair_name)));
         
     // write the header
-    output.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", 
+    output.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", 
         "constituitive_pair_name",
         "intron_rpkm/max_cassette_rpkm",
         "intron_rpkm",
@@ -969,12 +1087,13 @@ fn write_rpkm_stats(
         "exon2_rpkm",
         "total_constituitive_rpkm",
         "total_cassette_rpkm",
+        "cassette_params",
     ))?;
     for rpkm in rpkmstats {
         let ratio = rpkm.intron_rpkm / rpkm.max_cassette_rpkm;
         if !ratio.is_finite() { continue }
         
-        output.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", 
+        output.write_fmt(format_args!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", 
             rpkm.pair_name, 
             ratio,
             rpkm.intron_rpkm, 
@@ -983,7 +1102,64 @@ fn write_rpkm_stats(
             rpkm.exon1_rpkm, 
             rpkm.exon2_rpkm, 
             rpkm.total_constituitive_rpkm, 
-            rpkm.total_cassette_rpkm))?;
+            rpkm.total_cassette_rpkm,
+            rpkm.params.as_ref().map(|p| p.to_string()).unwrap_or_else(|| ".".to_string())))?;
+    }
+    Ok(())
+}
+
+fn write_cassette_stats(
+    outfile: &str,
+    pairs: &[ConstituitivePair],
+    annot: &IndexedAnnotation)
+    -> Result<()>
+{
+    let mut output: BufWriter<Box<dyn Write>> = BufWriter::new(
+        if outfile == "-" { Box::new(stdout()) } 
+        else { Box::new(File::create(outfile)?) });
+    
+    // write the header
+    writeln!(output, "{}", [
+        "constituitive_pair_name",
+        "seqname",
+        "start",
+        "end",
+        "strand",
+        "start_junction_reads",
+        "end_junction_reads",
+        "inclusion_reads",
+        "skipping_reads",
+        "psi",
+        "psi_ci_low",
+        "psi_ci_high",
+        "score",
+        "splice_motif",
+        "cassette_params",
+    ].iter().join("\t"))?;
+    for pair in pairs {
+        let pair_name = get_pair_name(pair, annot);
+        let exon1 = &annot.rows[pair.exon1_row];
+        let params = pair.params.as_ref().map(|p| p.to_string()).unwrap_or_else(|| ".".to_string());
+        for cassette in &pair.cassettes {
+            let stats = &cassette.stats;
+            writeln!(output, "{}", [
+                pair_name.clone(),
+                exon1.seqname.to_string(),
+                cassette.range.start.to_string(),
+                cassette.range.end.to_string(),
+                exon1.strand.to_string(),
+                stats.start_junction_reads.to_string(),
+                stats.end_junction_reads.to_string(),
+                stats.inclusion_reads.to_string(),
+                stats.skipping_reads.to_string(),
+                stats.psi.map(|p| p.to_string()).unwrap_or_else(|| "NA".to_string()),
+                stats.psi_ci_low.to_string(),
+                stats.psi_ci_high.to_string(),
+                stats.score.to_string(),
+                cassette.splice_motif.map(|m| m.to_string()).unwrap_or_else(|| ".".to_string()),
+                params.clone(),
+            ].iter().join("\t"))?;
+        }
     }
     Ok(())
 }
@@ -1045,53 +1221,45 @@ fn write_exon_bigbed(
     trackdb: &mut BufWriter<Box<dyn Write>>)
     -> Result<()> 
 {
-    // write the bed file
-    let bed_file = format!("{}.bed", file);
-    
-    {   let mut bw = BufWriter::new(File::create(&bed_file)?);
-        for pair in pairs {
-            // find the gene ID
-            let name = get_pair_name(pair, &annot);
-            // write the bed record
-            let score = 0;
-            let item_rgb = [0,0,0].iter().map(|v| v.to_string()).join(",");
-            let mut block_sizes = Vec::<u64>::new();
-            let mut block_starts = Vec::<u64>::new();
-            block_sizes.push(annot.rows[pair.exon1_row].end-annot.rows[pair.exon1_row].start+1);
-            block_starts.push(0u64);
-            for cassette in &pair.cassettes {
-                block_sizes.push(cassette.range.end-cassette.range.start);
-                block_starts.push(cassette.range.start-annot.rows[pair.exon1_row].start+1);
-            }
-            block_sizes.push(annot.rows[pair.exon2_row].end-annot.rows[pair.exon2_row].start+1);
-            block_starts.push(annot.rows[pair.exon2_row].start-annot.rows[pair.exon1_row].start);
-            let chr = &annot.rows[pair.exon1_row].seqname;
-            let chr = annot.vizchrmap.get(chr).unwrap_or(&chr);
-            let thick_start = if pair.cassettes.is_empty() { (annot.rows[pair.exon1_row].start-1).to_string() } 
-                else { pair.cassettes[0].range.start.to_string() };
-            let thick_end = if pair.cassettes.is_empty() { (annot.rows[pair.exon1_row].start-1).to_string() } 
-                else { pair.cassettes[pair.cassettes.len()-1].range.end.to_string() };
-            let line = &[
-                &chr,
-                &(annot.rows[pair.exon1_row].start-1).to_string(),
-                &annot.rows[pair.exon2_row].end.to_string(),
-                &name,
-                &score.to_string(),
-                &annot.rows[pair.exon2_row].strand,
-                &thick_start,
-                &thick_end,
-                &item_rgb,
-                &(pair.cassettes.len()+2).to_string(),
-                &block_sizes.iter().map(|v| v.to_string()).join(","),
-                &block_starts.iter().map(|v| v.to_string()).join(","),
-            ].iter().join("\t");
-            writeln!(bw, "{}", line)?;
+    let mut records = Vec::<Bed12>::new();
+    for pair in pairs {
+        // find the gene ID
+        let name = get_pair_name(pair, &annot);
+        // build the bed record
+        let mut block_sizes = Vec::<u64>::new();
+        let mut block_starts = Vec::<u64>::new();
+        block_sizes.push(annot.rows[pair.exon1_row].end-annot.rows[pair.exon1_row].start+1);
+        block_starts.push(0u64);
+        for cassette in &pair.cassettes {
+            block_sizes.push(cassette.range.end-cassette.range.start);
+            block_starts.push(cassette.range.start-annot.rows[pair.exon1_row].start+1);
         }
+        block_sizes.push(annot.rows[pair.exon2_row].end-annot.rows[pair.exon2_row].start+1);
+        block_starts.push(annot.rows[pair.exon2_row].start-annot.rows[pair.exon1_row].start);
+        let chr = annot.rows[pair.exon1_row].seqname.as_str();
+        let chr = annot.vizchrmap.get(chr).map(|c| c.as_str()).unwrap_or(chr);
+        let thick_start = if pair.cassettes.is_empty() { annot.rows[pair.exon1_row].start-1 } 
+            else { pair.cassettes[0].range.start };
+        let thick_end = if pair.cassettes.is_empty() { annot.rows[pair.exon1_row].start-1 } 
+            else { pair.cassettes[pair.cassettes.len()-1].range.end };
+        records.push(Bed12 {
+            chrom: chr.to_string(),
+            start: annot.rows[pair.exon1_row].start-1,
+            end: annot.rows[pair.exon2_row].end,
+            name: name,
+            score: 0,
+            strand: annot.rows[pair.exon2_row].strand.to_string(),
+            thick_start: thick_start,
+            thick_end: thick_end,
+            item_rgb: [0,0,0].iter().map(|v| v.to_string()).join(","),
+            block_sizes: block_sizes,
+            block_starts: block_starts,
+        });
     }
     let vizrefs = annot.refs.iter().
         map(|(k,v)| (annot.vizchrmap.get(k).unwrap_or(k).clone(), *v)).
         collect::<LinkedHashMap<String,u64>>();
-    bed2bigbed(&bed_file,file,&vizrefs,true,true,trackdb)?;
+    bed2bigbed(&mut records,file,&vizrefs,trackdb)?;
     Ok(())
 }
 
@@ -1107,10 +1275,11 @@ fn write_retained_introns(
             let pair_name = get_pair_name(pair, annot);
             let line = &[
                 &pair_name,
-                &annot.rows[pair.exon1_row].seqname,
+                &annot.rows[pair.exon1_row].seqname.to_string(),
                 &annot.rows[pair.exon1_row].end.to_string(),
                 &(annot.rows[pair.exon2_row].start-1).to_string(),
-                &annot.rows[pair.exon1_row].strand,
+                &annot.rows[pair.exon1_row].strand.to_string(),
+                &pair.params.as_ref().map(|p| p.to_string()).unwrap_or_else(|| ".".to_string()),
             ].iter().join("\t");
             writeln!(bw, "{}", line)?;
         }
@@ -1118,392 +1287,487 @@ fn write_retained_introns(
     Ok(())
 }
 
+/// Reading frame effect of a coding cassette on its reannotated transcript.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OrfEffect {
+    FramePreserving,
+    Frameshift,
+    // a premature stop codon more than NMD_DISTANCE nt upstream of the last
+    // exon-exon junction
+    NmdCandidate,
+}
+impl std::fmt::Display for OrfEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            OrfEffect::FramePreserving => "frame_preserving",
+            OrfEffect::Frameshift => "frameshift",
+            OrfEffect::NmdCandidate => "nmd_candidate",
+        })
+    }
+}
+
+struct OrfSummary {
+    transcript_id: String,
+    seqname: String,
+    start: u64,
+    end: u64,
+    strand: String,
+    effect: OrfEffect,
+    // transcript coordinate of the first premature stop codon
+    stop_position: Option<u64>,
+    // transcript coordinate of the last exon-exon junction
+    last_junction: Option<u64>,
+}
+
+const NMD_DISTANCE: u64 = 50;
+
+/// Translate the CDS of a reannotated transcript and label each cassette
+/// CDS feature with its effect on the reading frame. `records` must be in
+/// transcript order, and translation starts at the phase of the first CDS
+/// feature. Returns nothing if the sequence is not in the genome.
+fn annotate_orf_effects(
+    records: &mut [Record],
+    transcript_id: &str,
+    genome: &Faidx,
+    types: &FeatureTypes)
+    -> Result<Vec<OrfSummary>>
+{
+    let mut summaries = Vec::new();
+    // get the spliced transcript coordinate of a genomic position
+    let exons = records.iter().
+        filter(|r| types.is_exon(&r.feature_type)).
+        map(|r| (r.start, r.end, r.strand == "-")).
+        collect::<Vec<_>>();
+    let transcript_pos = |pos: u64| -> Option<u64> {
+        let mut offset = 0;
+        for &(start, end, minus) in &exons {
+            if start <= pos && pos <= end {
+                return Some(offset + if minus { end-pos } else { pos-start });
+            }
+            offset += end-start+1;
+        }
+        None
+    };
+    let last_junction = if exons.len() > 1 {
+        Some(exons[..exons.len()-1].iter().map(|&(start, end, _)| end-start+1).sum::<u64>())
+    } else { None };
+    
+    // build the CDS sequence
+    let mut cds_seq = Vec::<u8>::new();
+    let mut cds_start = None;
+    let mut phase = 0;
+    for record in records.iter().filter(|r| types.is_cds(&r.feature_type)) {
+        match genome.len(&record.seqname) {
+            Some(len) if record.end <= len => (),
+            _ => return Ok(summaries),
+        }
+        let mut segment = genome.fetch(&record.seqname, record.start-1..record.end)?.to_ascii_uppercase();
+        if record.strand == "-" {
+            segment = dna::revcomp(&segment);
+        }
+        if cds_start.is_none() {
+            // skip the bases of a codon that started before the first CDS
+            phase = std::cmp::min(record.frame.phase(), segment.len() as u64);
+            cds_start = transcript_pos(if record.strand == "-" { record.end } else { record.start }).
+                map(|s| s + phase);
+        }
+        cds_seq.extend(segment);
+    }
+    let cds_seq = &cds_seq[phase as usize..];
+    // find the first premature stop codon, ignoring the final codon
+    let stop_position = cds_seq.chunks(3).
+        take(std::cmp::max(cds_seq.len()/3, 1)-1).
+        position(|codon| codon == b"TAA" || codon == b"TAG" || codon == b"TGA").
+        and_then(|i| cds_start.map(|s| s + (i*3) as u64));
+    
+    let mut effects = Vec::<(u64,u64,OrfEffect)>::new();
+    for record in records.iter() {
+        if types.is_cds(&record.feature_type) && 
+            record.attributes.get("exon_type").map(|t| t == "cassette").unwrap_or(false) 
+        {
+            let cassette_start = transcript_pos(if record.strand == "-" { record.end } else { record.start });
+            // only count stops at or downstream of this cassette
+            let nmd = match (stop_position, last_junction, cassette_start) {
+                (Some(stop), Some(junction), Some(cassette_start)) => 
+                    cassette_start <= stop && stop + 3 + NMD_DISTANCE < junction,
+                _ => false,
+            };
+            let effect = 
+                if nmd { OrfEffect::NmdCandidate }
+                else if (record.end-record.start+1) % 3 == 0 { OrfEffect::FramePreserving }
+                else { OrfEffect::Frameshift };
+            effects.push((record.start, record.end, effect));
+            summaries.push(OrfSummary {
+                transcript_id: transcript_id.to_string(),
+                seqname: record.seqname.to_string(),
+                start: record.start-1,
+                end: record.end,
+                strand: record.strand.to_string(),
+                effect: effect,
+                stop_position: stop_position,
+                last_junction: last_junction,
+            });
+        }
+    }
+    // label both the cassette exon and CDS features
+    for record in records.iter_mut() {
+        if record.attributes.get("exon_type").map(|t| t == "cassette").unwrap_or(false) {
+            if let Some(&(_, _, effect)) = effects.iter().find(|e| e.0 == record.start && e.1 == record.end) {
+                record.attributes.insert("cassette_orf_effect".to_string(), effect.to_string());
+            }
+        }
+    }
+    Ok(summaries)
+}
+
+fn write_orf_summary(outfile: &str, summaries: &[OrfSummary]) -> Result<()> {
+    let mut output: BufWriter<Box<dyn Write>> = BufWriter::new(
+        if outfile == "-" { Box::new(stdout()) } 
+        else { Box::new(File::create(outfile)?) });
+    writeln!(output, "{}", [
+        "transcript_id",
+        "seqname",
+        "start",
+        "end",
+        "strand",
+        "cassette_length",
+        "orf_effect",
+        "premature_stop_position",
+        "last_junction_position",
+    ].iter().join("\t"))?;
+    for summary in summaries {
+        writeln!(output, "{}", [
+            summary.transcript_id.clone(),
+            summary.seqname.clone(),
+            summary.start.to_string(),
+            summary.end.to_string(),
+            summary.strand.clone(),
+            (summary.end-summary.start).to_string(),
+            summary.effect.to_string(),
+            summary.stop_position.map(|p| p.to_string()).unwrap_or_else(|| ".".to_string()),
+            summary.last_junction.map(|p| p.to_string()).unwrap_or_else(|| ".".to_string()),
+        ].iter().join("\t"))?;
+    }
+    Ok(())
+}
+
+/// The UTR types a cassette copies from the features across its pair's
+/// junction, with the prefix for the IDs of the copies.
+const UTR_TYPES: [(&str, &str); 3] = [("five_prime_UTR", "UTR5"), ("three_prime_UTR", "UTR3"), ("UTR", "UTR")];
+
 fn write_enriched_annotation(
-    annot: &Arc<IndexedAnnotation>, 
+    annot: Arc<IndexedAnnotation>, 
     reannotated_pairs: &Vec<ConstituitivePair>,
     outannot: &str,
     options: &Options,
+    params: &CassetteParams,
+    genome: &Option<Arc<Faidx>>,
     trackdb: &mut BufWriter<Box<dyn Write>>)
     -> Result<()> 
 {
-    annot.to_gff(&outannot)?; 
-    {
-        let mut output = OpenOptions::new().append(true).open(outannot)?;
-        // get the set of transcript -> pair associations
-        let mut transcript2pair = HashMap::<usize,HashSet<usize>>::new();
-        for (i, pair) in reannotated_pairs.iter().enumerate() {
-            if !pair.cassettes.is_empty() {
-                if let Some(ref exon1parents) = annot.row2parents.get(&pair.exon1_row) {
-                    if let Some(ref exon2parents) = annot.row2parents.get(&pair.exon2_row) {
-                        let exon2parents = exon2parents.iter().collect::<HashSet<_>>();
-                        for transcript_row in exon1parents.iter() {
-                            if exon2parents.contains(transcript_row) {
-                                transcript2pair.entry(*transcript_row).or_insert_with(HashSet::new).insert(i);
-                            }
+    // the new transcripts are added to the annotation in place
+    let mut annot = Arc::try_unwrap(annot).unwrap_or_else(|annot| (*annot).clone());
+    let types = FeatureTypes::new(&options.gene_type, &options.transcript_type, &options.exon_type, &options.cds_type);
+    // get the set of transcript -> pair associations
+    let mut transcript2pair = HashMap::<usize,HashSet<usize>>::new();
+    for (i, pair) in reannotated_pairs.iter().enumerate() {
+        if !pair.cassettes.is_empty() {
+            if let Some(ref exon1parents) = annot.row2parents.get(&pair.exon1_row) {
+                if let Some(ref exon2parents) = annot.row2parents.get(&pair.exon2_row) {
+                    let exon2parents = exon2parents.iter().collect::<HashSet<_>>();
+                    for transcript_row in exon1parents.iter() {
+                        if exon2parents.contains(transcript_row) {
+                            transcript2pair.entry(*transcript_row).or_insert_with(HashSet::new).insert(i);
                         }
                     }
                 }
             }
         }
+    }
+    
+    // keep track of unique transcript names
+    let mut names = HashSet::<String>::new();
+    // sort transcripts by largest number of associated constituitive pairs
+    let mut transcript_order = transcript2pair.keys().collect::<Vec<_>>();
+    transcript_order.sort_by(|a,b| transcript2pair[b].len().cmp(&transcript2pair[a].len()));
+    let mut seen_pair = HashSet::<usize>::new();
+    let mut orf_summaries = Vec::<OrfSummary>::new();
+    for transcript_row in transcript_order {
+        let transcript = annot.rows[*transcript_row].clone();
         
-        // keep track of unique transcript identifiers
-        lazy_static! {
-            static ref TRANSCRIPT_RENAME: Regex = Regex::new(r"(?:\.([0-9]+))?$").unwrap();
-            static ref EXON_RENAME: Regex = Regex::new(r"(?:[:]([0-9]+))?$").unwrap();
+        // make sure there is at least one never-before-seen pair that this transcript owns
+        let mut any_new_pairs = false;
+        for pair_row in &transcript2pair[transcript_row] {
+            if !seen_pair.contains(pair_row) {
+                any_new_pairs = true;
+            }
         }
-        let mut ids = HashSet::<String>::new();
-        let mut names = HashSet::<String>::new();
-        // sort transcripts by largest number of associated constituitive pairs
-        let mut transcript_order = transcript2pair.keys().collect::<Vec<_>>();
-        transcript_order.sort_by(|a,b| transcript2pair[b].len().cmp(&transcript2pair[a].len()));
-        let mut seen_pair = HashSet::<usize>::new();
-        for transcript_row in transcript_order {
-            let transcript = &annot.rows[*transcript_row];
-            let mut records = Vec::<Record>::new();
+        if !any_new_pairs { continue }
             
-            // make sure there is at least one never-before-seen pair that this transcript owns
-            let mut any_new_pairs = false;
-            for pair_row in &transcript2pair[transcript_row] {
-                if !seen_pair.contains(pair_row) {
-                    any_new_pairs = true;
-                }
+        // find unique transcript IDs and names
+        let transcript_id = transcript.attributes.get("transcript_id").
+            or_else(|| transcript.attributes.get("ID")).
+            map(|s| annot.unique_id(&format!("{}.reannot", s)));
+        let transcript_name = if let Some(transcript_name) = transcript.attributes.get("transcript_name") {
+            let mut transcript_name = format!("{}.reannot", transcript_name);
+            while names.contains(&transcript_name) {
+                transcript_name = next_id(&transcript_name);
             }
-            if !any_new_pairs { continue }
-                
-            // find unique transcript IDs and names
-            let mut transcript_id = transcript.attributes.get("transcript_id").map(|s| format!("{}.reannot", s));
-            let mut transcript_name = transcript.attributes.get("transcript_name").map(|s| format!("{}.reannot", s));
-            transcript_id = if let Some(mut transcript_id) = transcript_id {
-                while ids.contains(&transcript_id) {
-                    transcript_id = String::from(TRANSCRIPT_RENAME.replace(&transcript_id.as_ref(), |caps: &Captures| {
-                        format!(".{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
+            names.insert(transcript_name.clone());
+            Some(transcript_name)
+        } else {transcript_id.clone()};
+        // create a new transcript record
+        let mut new_transcript = transcript.clone();
+        if let Some(ref transcript_id) = transcript_id {
+            new_transcript.attributes.insert("ID".to_string(), transcript_id.clone());
+            if new_transcript.attributes.contains_key("transcript_id") {
+                new_transcript.attributes.insert("transcript_id".to_string(), transcript_id.clone());
+            }
+        }
+        if let Some(ref transcript_name) = transcript_name {
+            new_transcript.attributes.insert("Name".to_string(), transcript_name.clone());
+            new_transcript.attributes.insert("transcript_name".to_string(), transcript_name.clone());
+        }
+        // transcripts read from GTF have no Parent attribute
+        if !new_transcript.attributes.contains_key("Parent") {
+            let gene_ids = annot.row2parents.get(transcript_row).iter().
+                flat_map(|ps| ps.iter()).
+                filter_map(|p| annot.rows[*p].attributes.get("ID").cloned()).
+                collect::<Vec<_>>();
+            if !gene_ids.is_empty() {
+                new_transcript.attributes.insert_all("Parent".to_string(), gene_ids);
+            }
+        }
+        // add the new transcript record
+        let mut new_rows = vec![annot.add_record(new_transcript)?];
+        
+        // get a list of feature starts/stops, and a tree of CDS features
+        let mut featurestarts = HashSet::<(Sym,u64)>::new();
+        let mut featurestops = HashSet::<(Sym,u64)>::new();
+        // original -> new IDs of the copied features
+        let mut renamed = HashMap::<Sym,String>::new();
+        if let (Some(id), Some(transcript_id)) = (transcript.attributes.get("ID"), transcript_id.as_ref()) {
+            renamed.insert(*id, transcript_id.clone());
+        }
+        // copy the descendants of the original transcript, parents first
+        let mut seen = HashSet::<usize>::new();
+        let mut children = annot.row2children.get(transcript_row).cloned().unwrap_or_default();
+        while !children.is_empty() {
+            let mut newchildren = Vec::<usize>::new();
+            'child:
+            for child_row in children {
+                if !seen.insert(child_row) { continue }
+                // make a copy
+                let mut child = annot.rows[child_row].clone();
+                // if this is a cassette that overlaps a reannotated pair,
+                // do not include it
+                for pair_row in &transcript2pair[transcript_row] {
+                    let pair = &reannotated_pairs[*pair_row];
+                    if child.start-1 < annot.rows[pair.exon2_row].start-1 &&
+                        annot.rows[pair.exon1_row].end < child.end
+                    { continue 'child; }
                 }
-                ids.insert(transcript_id.clone());
-                Some(transcript_id)
-            } else {transcript_id};
-            transcript_name = if let Some(mut transcript_name) = transcript_name {
-                while ids.contains(&transcript_name) {
-                    transcript_name = String::from(TRANSCRIPT_RENAME.replace(&transcript_name.as_ref(), |caps: &Captures| {
-                        format!(".{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
+                // populate featurestarts/stops
+                featurestarts.insert((child.feature_type, child.start-1));
+                featurestops.insert((child.feature_type, child.end));
+                // the copy's parents are the copies of its parents. Features
+                // read from GTF have no Parent attribute, so use the index.
+                let newparents = annot.row2parents.get(&child_row).iter().
+                    flat_map(|ps| ps.iter()).
+                    filter_map(|p| annot.rows[*p].attributes.get("ID")).
+                    filter_map(|id| renamed.get(id).cloned()).
+                    collect::<Vec<_>>();
+                if newparents.is_empty() {
+                    child.attributes.remove("Parent");
+                } else {
+                    child.attributes.insert_all("Parent".to_string(), newparents);
                 }
-                names.insert(transcript_name.clone());
-                Some(transcript_name)
-            } else {transcript_id.clone()};
-            // create a new transcript record
-            let mut new_transcript = transcript.clone();
-            if let Some(ref transcript_id) = transcript_id {
-                {   let entry = new_transcript.attributes.entry("ID".to_string()).or_insert_with(|| "".to_string());
-                    entry.clear();
-                    entry.push_str(&mut transcript_id.clone());
+                // update transcript_id and transcript_name attributes
+                if child.attributes.contains_key("transcript_id") {
+                    if let Some(ref transcript_id) = transcript_id {
+                        child.attributes.insert("transcript_id".to_string(), transcript_id.clone());
+                    }
                 }
-                {   let entry = new_transcript.attributes.entry("transcript_id".to_string()).or_insert_with(|| "".to_string());
-                    entry.clear();
-                    entry.push_str(&mut transcript_id.clone());
+                if child.attributes.contains_key("transcript_name") {
+                    if let Some(ref transcript_name) = transcript_name {
+                        child.attributes.insert("transcript_name".to_string(), transcript_name.clone());
+                    }
                 }
-            }
-            if let Some(ref transcript_name) = transcript_name {
-                {   let entry = new_transcript.attributes.entry("Name".to_string()).or_insert_with(|| "".to_string());
-                    entry.clear();
-                    entry.push_str(&mut transcript_name.clone());
+                if let Some(id) = child.attributes.get("ID").cloned() {
+                    // segments of a multi-row feature keep sharing one ID
+                    let feature_id = match renamed.get(&id) {
+                        Some(feature_id) => feature_id.clone(),
+                        None => {
+                            let feature_id = annot.unique_id(&format!("{}.reannot", id));
+                            renamed.insert(id, feature_id.clone());
+                            feature_id
+                        }
+                    };
+                    child.attributes.insert("ID".to_string(), feature_id);
                 }
-                {   let entry = new_transcript.attributes.entry("transcript_name".to_string()).or_insert_with(|| "".to_string());
-                    entry.clear();
-                    entry.push_str(&mut transcript_name.clone());
+                // add the child record
+                new_rows.push(annot.add_record(child)?);
+                // add more children
+                if let Some(cs) = annot.row2children.get(&child_row) {
+                    newchildren.extend(cs.iter().cloned());
                 }
             }
-            // write new transcript record to file
-            records.push(new_transcript);
-            
-            // get a list of feature starts/stops, and a tree of CDS features
-            let mut featurestarts = HashSet::<(String,u64)>::new();
-            let mut featurestops = HashSet::<(String,u64)>::new();
-            // for each child of the original transcript
-            if let Some(ref transcript_row2children) = annot.row2children.get(transcript_row) {
-                'child:
-                for child_row in transcript_row2children.iter() {
-                    // make a copy
-                    let mut child = annot.rows[*child_row].clone();
-                    // if this is a cassette that overlaps a reannotated pair,
-                    // do not include it
-                    for pair_row in &transcript2pair[transcript_row] {
-                        let pair = &reannotated_pairs[*pair_row];
-                        if child.start-1 < annot.rows[pair.exon2_row].start-1 &&
-                            annot.rows[pair.exon1_row].end < child.end
-                        { continue 'child; }
-                    }
-                    // populate featurestarts/stops
-                    featurestarts.insert((child.feature_type.clone(), child.start-1));
-                    featurestops.insert((child.feature_type.clone(), child.end));
-                    // update the parents list with the new transcript ID
-                    let mut newparents = Vec::<String>::new();
-                    if let Some(parent) = child.attributes.get("Parent") {
-                        if let Some(oldid) = transcript.attributes.get("ID") {
-                            if let Some(ref id) = transcript_id {
-                                for p in parent.split(",") {
-                                    newparents.push(if p == oldid {id.clone()} else {p.to_string()});
-                                }
-                            }
-                        }
-                    }
+            children = newchildren;
+        }
+        
+        for pair_row in &transcript2pair[transcript_row] {
+            if !seen_pair.contains(pair_row) {
+                seen_pair.insert(*pair_row);
+                let pair = &reannotated_pairs[*pair_row];
+                let exon1 = annot.rows[pair.exon1_row].clone();
+                let exon2 = annot.rows[pair.exon2_row].clone();
                     
-                    {   let entry = child.attributes.entry("Parent".to_string()).or_insert_with(|| "".to_string());
-                        entry.clear();
-                        entry.push_str(&mut newparents.join(","));
+                // the CDS or UTR type running across the pair's junction, and
+                // the prefix for the IDs of the cassette's copy of it
+                let spans_junction = |feature_type: &Sym|
+                    featurestops.contains(&(*feature_type, exon1.end)) &&
+                    featurestarts.contains(&(*feature_type, exon2.start-1));
+                let cdstype = featurestops.iter().
+                    map(|(feature_type, _)| *feature_type).
+                    filter(|feature_type| types.is_cds(feature_type) && spans_junction(feature_type)).
+                    min_by_key(|feature_type| feature_type.as_str()).
+                    map(|feature_type| (feature_type, feature_type.as_str())).
+                    or_else(|| UTR_TYPES.iter().
+                        map(|&(utr_type, prefix)| (Sym::new(utr_type), prefix)).
+                        find(|(utr_type, _)| spans_junction(utr_type)));
+                
+                // finally, add the reannotated cassettes
+                for cassette in &pair.cassettes {
+                    // get a unique ID for the cassette
+                    let cassette_id = transcript.attributes.get("ID").map(|s| annot.unique_id(&format!("exon:{}:1", s)));
+                    
+                    let mut attributes = exon1.attributes.clone();
+                    if let Some(cassette_id) = cassette_id {
+                        attributes.insert("ID".to_string(), cassette_id.clone());
+                        attributes.insert("exon_id".to_string(), cassette_id.clone());
                     }
-                    // update transcript_id and transcript_name attributes
-                    if child.attributes.contains_key("transcript_id") {
-                        if let Some(ref transcript_id) = transcript_id {
-                            let entry = child.attributes.entry("transcript_id".to_string()).or_insert_with(|| "".to_string());
-                            entry.clear();
-                            entry.push_str(&mut transcript_id.clone());
-                        }
+                    if let Some(ref transcript_id) = transcript_id {
+                        attributes.insert("Parent".to_string(), transcript_id.clone());
                     }
-                    if child.attributes.contains_key("transcript_name") {
-                        if let Some(ref transcript_name) = transcript_name {
-                            let entry = child.attributes.entry("transcript_name".to_string()).or_insert_with(|| "".to_string());
-                            entry.clear();
-                            entry.push_str(&mut transcript_name.clone());
-                        }
+                    if let Some(ref transcript_id) = transcript_id {
+                        attributes.insert("transcript_id".to_string(), transcript_id.clone());
                     }
-                    if let Some(id) = child.attributes.get_mut("ID") {
-                        let mut feature_id = format!("{}.reannot", id);
-                        while ids.contains(&feature_id) {
-                            feature_id = String::from(EXON_RENAME.replace(&feature_id.as_ref(), |caps: &Captures| {
-                                format!(".{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
-                        }
-                        id.clear();
-                        id.push_str(&mut feature_id);
-                        ids.insert(id.clone());
+                    if let Some(ref transcript_name) = transcript_name {
+                        attributes.insert("transcript_name".to_string(), transcript_name.clone());
                     }
-                    // store child record
-                    records.push(child);
                     
-                    // update any grandchildren with correct transcript_ids and transcript_names
-                    let mut seen = HashSet::<usize>::new();
-                    let mut children = Vec::<usize>::new();
-                    if let Some(ref mut cs) = annot.row2children.get(child_row) {
-                        children.append(&mut cs.clone());
+                    let mut attributes = attributes.clone();
+                    attributes.insert("exon_type".to_string(),"cassette".to_string());
+                    for (k, v) in cassette.stats.attributes() {
+                        attributes.insert_all(k, v);
                     }
-                    while !children.is_empty() {
-                        let mut newchildren = Vec::<usize>::new();
-                        'cc:
-                        for c in children {
-                            if !seen.contains(&c)  {
-                                seen.insert(c);
-                                let mut cc = annot.rows[c].clone();
-                                // if this is a cassette that overlaps a reannotated pair,
-                                // do not include it
-                                for pair_row in &transcript2pair[transcript_row] {
-                                    let pair = &reannotated_pairs[*pair_row];
-                                    if cc.start-1 < annot.rows[pair.exon2_row].start-1 &&
-                                        annot.rows[pair.exon1_row].end < cc.end
-                                    { continue 'cc; }
-                                }
-                                featurestarts.insert((cc.feature_type.clone(), cc.start-1));
-                                featurestops.insert((cc.feature_type.clone(), cc.end));
-                                // update transcript_id and transcript_name attributes
-                                if cc.attributes.contains_key("transcript_id") {
-                                    if let Some(ref transcript_id) = transcript_id {
-                                        let entry = cc.attributes.entry("transcript_id".to_string()).or_insert_with(|| "".to_string());
-                                        entry.clear();
-                                        entry.push_str(&mut transcript_id.clone());
-                                    }
-                                }
-                                if cc.attributes.contains_key("transcript_name") {
-                                    if let Some(ref transcript_name) = transcript_name {
-                                        let entry = cc.attributes.entry("transcript_name".to_string()).or_insert_with(|| "".to_string());
-                                        entry.clear();
-                                        entry.push_str(&mut transcript_name.clone());
-                                    }
-                                }
-                                if let Some(id) = cc.attributes.get_mut("ID") {
-                                    let mut feature_id = format!("{}.reannot", id);
-                                    while ids.contains(&feature_id) {
-                                        feature_id = String::from(EXON_RENAME.replace(&feature_id.as_ref(), |caps: &Captures| {
-                                            format!(".{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
-                                    }
-                                    id.clear();
-                                    id.push_str(&mut feature_id);
-                                    ids.insert(id.clone());
-                                }
-                                // write child record
-                                records.push(cc);
-                                // add more children
-                                if let Some(ref mut cs) = annot.row2children.get(&c) {
-                                    newchildren.append(&mut cs.clone());
-                                }
-                            }
-                        }
-                        children = newchildren;
+                    if let Some(splice_motif) = cassette.splice_motif {
+                        attributes.insert("cassette_splice_motif".to_string(), splice_motif.to_string());
                     }
-                }
-            }
-            
-            for pair_row in &transcript2pair[transcript_row] {
-                if !seen_pair.contains(pair_row) {
-                    seen_pair.insert(*pair_row);
-                    let pair = &reannotated_pairs[*pair_row];
-                    let exon1 = &annot.rows[pair.exon1_row];
-                    let exon2 = &annot.rows[pair.exon2_row];
-                        
-                    let cdstype = 
-                        if featurestops.contains(&("CDS".to_string(), exon1.end)) && 
-                            featurestarts.contains(&("CDS".to_string(), exon2.start-1))
-                        { Some(("CDS","CDS")) }
-                        else if featurestops.contains(&("five_prime_UTR".to_string(), exon1.end)) && 
-                            featurestarts.contains(&("five_prime_UTR".to_string(), exon2.start-1))
-                        { Some(("five_prime_UTR","UTR5")) }
-                        else if featurestops.contains(&("three_prime_UTR".to_string(), exon1.end)) && 
-                            featurestarts.contains(&("three_prime_UTR".to_string(), exon2.start-1))
-                        { Some(("three_prime_UTR","UTR3")) }
-                        else if featurestops.contains(&("UTR".to_string(), exon1.end)) && 
-                            featurestarts.contains(&("UTR".to_string(), exon2.start-1))
-                        { Some(("UTR","UTR")) }
-                        else { None };
-                    
-                    // finally, add the reannotated cassettes
-                    for cassette in &pair.cassettes {
-                        // get a unique ID for the cassette
-                        let mut cassette_id = transcript.attributes.get("ID").map(|s| format!("exon:{}:1", s));
-                        cassette_id = if let Some(mut cassette_id) = cassette_id {
-                            while ids.contains(&cassette_id) {
-                                cassette_id = String::from(EXON_RENAME.replace(&cassette_id.as_ref(), |caps: &Captures| {
-                                    format!(":{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
-                            }
-                            ids.insert(cassette_id.clone());
-                            Some(cassette_id)
-                        } else {cassette_id};
-                        
-                        let mut attributes = exon1.attributes.clone();
-                        if let Some(cassette_id) = cassette_id {
-                            {   let entry = attributes.entry("ID".to_string()).or_insert_with(|| "".to_string());
-                                entry.clear();
-                                entry.push_str(&mut cassette_id.clone());
-                            }
-                            {   let entry = attributes.entry("exon_id".to_string()).or_insert_with(|| "".to_string());
-                                entry.clear();
-                                entry.push_str(&mut cassette_id.clone());
-                            }
-                        }
-                        if let Some(ref transcript_id) = transcript_id {
-                            let entry = attributes.entry("Parent".to_string()).or_insert_with(|| "".to_string());
-                            entry.clear();
-                            entry.push_str(&mut transcript_id.clone());
-                        }
-                        if let Some(ref transcript_id) = transcript_id {
-                            let entry = attributes.entry("transcript_id".to_string()).or_insert_with(|| "".to_string());
-                            entry.clear();
-                            entry.push_str(&mut transcript_id.clone());
-                        }
-                        if let Some(ref transcript_name) = transcript_name {
-                            let entry = attributes.entry("transcript_name".to_string()).or_insert_with(|| "".to_string());
-                            entry.clear();
-                            entry.push_str(&mut transcript_name.clone());
-                        }
-                        
+                    let record = Record {
+                        row: 0,
+                        seqname: exon1.seqname,
+                        source: exon1.source,
+                        feature_type: exon1.feature_type,
+                        start: cassette.range.start+1,
+                        end: cassette.range.end,
+                        score: Sym::new("."),
+                        strand: exon1.strand,
+                        frame: Frame::None,
+                        attributes: attributes.clone(),
+                    };
+                    new_rows.push(annot.add_record(record)?);
+                    if let Some(cdstype) = cdstype {
                         let mut attributes = attributes.clone();
-                        attributes.insert("exon_type".to_string(),"cassette".to_string());
+                        // get a unique ID for the cassette CDS feature
+                        let cds_id = transcript.attributes.get("ID").map(|s| annot.unique_id(&format!("{}:{}:1", cdstype.1, s)));
+                        if let Some(cds_id) = cds_id {
+                            attributes.insert("ID".to_string(), cds_id.clone());
+                        }
                         let record = Record {
                             row: 0,
-                            seqname: exon1.seqname.clone(),
-                            source: exon1.source.clone(),
-                            feature_type: "exon".to_string(),
+                            seqname: exon1.seqname,
+                            source: exon1.source,
+                            feature_type: cdstype.0,
                             start: cassette.range.start+1,
                             end: cassette.range.end,
-                            score: ".".to_string(),
-                            strand: exon1.strand.clone(),
-                            frame: ".".to_string(),
-                            attributes: attributes.clone(),
+                            score: Sym::new("."),
+                            strand: exon1.strand,
+                            frame: Frame::None,
+                            attributes: attributes,
                         };
-                        records.push(record);
-                        if let Some(cdstype) = cdstype {
-                            let mut attributes = attributes.clone();
-                            // get a unique ID for the cassette CDS feature
-                            let mut cds_id = transcript.attributes.get("ID").map(|s| format!("{}:{}:1", cdstype.1, s));
-                            cds_id = if let Some(mut cds_id) = cds_id {
-                                while ids.contains(&cds_id) {
-                                    cds_id = String::from(EXON_RENAME.replace(&cds_id.as_ref(), |caps: &Captures| {
-                                        format!(":{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
-                                }
-                                ids.insert(cds_id.clone());
-                                Some(cds_id)
-                            } else {cds_id};
-                            if let Some(cds_id) = cds_id {
-                                let entry = attributes.entry("ID".to_string()).or_insert_with(|| "".to_string());
-                                entry.clear();
-                                entry.push_str(&mut cds_id.clone());
-                            }
-                            let record = Record {
-                                row: 0,
-                                seqname: exon1.seqname.clone(),
-                                source: exon1.source.clone(),
-                                feature_type: cdstype.0.to_string(),
-                                start: cassette.range.start+1,
-                                end: cassette.range.end,
-                                score: ".".to_string(),
-                                strand: exon1.strand.clone(),
-                                frame: ".".to_string(),
-                                attributes: attributes,
-                            };
-                            records.push(record);
-                        }
+                        new_rows.push(annot.add_record(record)?);
                     }
                 }
             }
-            // compute frame field for all the CDS features
-            // write features to output file
-            if transcript.strand=="-" {
-                records.sort_by(|a,b| b.start.cmp(&a.start));
-            } else {
-                records.sort_by(|a,b| a.start.cmp(&b.start));
+        }
+        // compute frame field for all the CDS features
+        let mut records = new_rows.iter().map(|row| annot.rows[*row].clone()).collect::<Vec<_>>();
+        if transcript.strand == Strand::Reverse {
+            records.sort_by(|a,b| b.start.cmp(&a.start));
+        } else {
+            records.sort_by(|a,b| a.start.cmp(&b.start));
+        }
+        let mut exon_numbers = Vec::<(Range<u64>, Sym)>::new();
+        let mut next_phase = None;
+        let mut exon_number=1;
+        for record in &mut records {
+            let record = record;
+            // compute the frame for CDS features, continuing the reading
+            // frame from the phase of the first CDS
+            if types.is_cds(&record.feature_type) {
+                let phase = next_phase.unwrap_or_else(|| record.frame.phase());
+                record.frame = Frame::from(phase);
+                let len = record.end-record.start+1;
+                next_phase = Some((3 - (len + 3 - phase) % 3) % 3);
             }
-            let mut exontree = IntervalTree::<u64,Record>::new();
-            let mut prevcdslen=0;
-            let mut exon_number=1;
-            for record in &mut records {
-                let record = record;
-                // compute the frame for CDS features
-                if record.feature_type == "CDS" {
-                    record.frame = (prevcdslen % 3).to_string();
-                    prevcdslen += record.end-record.start+1;
-                }
-                // store exons in an interval tree to compute exon_number rank
-                else if record.feature_type == "exon" {
-                    {   let en = record.attributes.entry("exon_number".to_string()).or_insert_with(|| "".to_string());
-                        en.clear();
-                        en.push_str(&mut exon_number.to_string());
-                    }
-                    exon_number += 1;
-                    exontree.insert(Interval::new((record.start - 1)..(record.end))?, record.clone());
-                }
+            // store exon numbers in an interval index to compute exon_number rank
+            else if types.is_exon(&record.feature_type) {
+                let number = Sym::from(exon_number.to_string());
+                record.attributes.insert("exon_number", number);
+                exon_number += 1;
+                exon_numbers.push((record.range()?, number));
             }
-            // compute exon_number rank
-            for record in &mut records {
-                if record.feature_type != "exon" {
-                    for exon in exontree.find(record.start-1..record.end) {
-                        if let Some(exon_number) = exon.data().attributes.get("exon_number") {
-                            if let Some(en) = record.attributes.get_mut("exon_number") {
-                                en.clear();
-                                en.push_str(&mut exon_number.clone());
-                            }
-                        }
+        }
+        let exontree = SortedIntervals::new(exon_numbers);
+        // compute exon_number rank
+        for record in &mut records {
+            if !types.is_exon(&record.feature_type) {
+                for exon in exontree.find(record.range()?) {
+                    if let Some(en) = record.attributes.get_mut("exon_number") {
+                        *en = *exon.data();
                     }
                 }
             }
-            if transcript.strand=="-" {
-                records.sort_by(|a,b| a.start.cmp(&b.start));
-            }
-            for record in &mut records {
-                writeln!(output, "{}", record.to_gff()?)?;
-            }
+        }
+        // check the reading frame of coding cassettes
+        if let Some(ref genome) = genome {
+            let id = transcript_id.clone().unwrap_or_else(|| ".".to_string());
+            orf_summaries.append(&mut annotate_orf_effects(&mut records, &id, genome, &types)?);
+        }
+        // only the attributes and frames were changed, so the row indices
+        // stay valid
+        for record in records {
+            let row = record.row;
+            annot.rows[row] = record;
         }
     }
+    if let Some(ref orf_summary) = options.orf_summary {
+        eprintln!("Writing ORF summary to {:?}", &orf_summary);
+        write_orf_summary(orf_summary, &orf_summaries)?;
+    }
     
+    // the parameters are the same for every cassette, so they go in the header
+    let comments = vec![format!("##cassette_params: {}", params)];
+    let mut output = create_output(outannot, false)?;
+    if outannot.to_lowercase().ends_with(".gtf") {
+        annot.write_gtf(&mut output, &comments)?;
+    } else {
+        annot.write_gff(&mut output, true, &comments)?;
+    }
+    output.flush()?;
     if let Some(ref debug_outannot_bigbed) = options.debug_outannot_bigbed {
-        let newannot = IndexedAnnotation::from_gff(&outannot, 
-            &options.chrmap_file,
-            &options.vizchrmap_file)?;
-        newannot.to_bigbed(
+        annot.to_bigbed(
             &debug_outannot_bigbed, 
             &options.exon_type, 
             &options.cds_type, 
@@ -1512,19 +1776,13 @@ fn write_enriched_annotation(
             trackdb)?; 
     }
     if let Some(ref debug_outannot_gtf) = options.debug_outannot_gtf {
-        let newannot = IndexedAnnotation::from_gff(&outannot, 
-            &options.chrmap_file,
-            &options.vizchrmap_file)?;
-        newannot.to_gtf(&debug_outannot_gtf)?; 
+        annot.to_gtf(&debug_outannot_gtf)?; 
     }
     if let Some(ref debug_outannot_fasta) = options.debug_outannot_fasta {
-        if let Some(ref genome_file) = options.genome_file {
-            let newannot = IndexedAnnotation::from_gff(&outannot, 
-                &options.chrmap_file,
-                &options.vizchrmap_file)?;
-            newannot.to_fasta(
+        if let Some(ref genome) = genome {
+            annot.to_fasta(
                 &debug_outannot_fasta, 
-                &genome_file,
+                genome,
                 &options.exon_type, 
                 &options.transcript_type, 
                 &options.gene_type)?; 
@@ -1564,6 +1822,7 @@ fn run() -> Result<()> {
         if options.debug_outannot_fasta.is_none() { options.debug_outannot_fasta = Some(format!("{}.outannot.fa", options.debug_prefix)) }
         if options.debug_retained_introns.is_none() { options.debug_retained_introns = Some(format!("{}.retained_introns.txt", options.debug_prefix)) }
     }
+    let params = CassetteParams::from_options(&options)?;
     
     // set up the trackdb writer
     let mut trackdb: BufWriter<Box<dyn Write>> = BufWriter::new(
@@ -1596,28 +1855,60 @@ fn run() -> Result<()> {
     let transcript_type = String::from("transcript");
     let mut annot = if let Some(annotfile_gff) = options.annotfile_gff.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gff);
-        IndexedAnnotation::from_gff(
+        IndexedAnnotation::from_file_with(
             &annotfile_gff, 
+            "gff",
+            "",
+            "",
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else if let Some(annotfile_gtf) = options.annotfile_gtf.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gtf);
-        IndexedAnnotation::from_gtf(&annotfile_gtf, 
+        IndexedAnnotation::from_file_with(&annotfile_gtf, 
+            "gtf",
             options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
             options.transcript_type.get(0).unwrap_or(&transcript_type),
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else if let Some(annotfile) = options.annotfile.clone() {
+        let format = annotation_format(&annotfile, &options.annot_format)?;
+        eprintln!("Reading {} annotation file {:?}", format, &annotfile);
+        IndexedAnnotation::from_file_with(&annotfile, 
+            &format,
+            options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
+            options.transcript_type.get(0).unwrap_or(&transcript_type),
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else {
         Options::clap().print_help()?;
         eprintln!("\n\nNo annotation file was given!");
         std::process::exit(1);
     };
+    if let Some(chromalias_file) = options.chromalias_file.clone() {
+        annot.set_chrom_aliases(ChromAliases::from_file(&chromalias_file)?);
+    }
+    // the filter only selects the features to search, since the whole
+    // annotation is written to the output annotation
+    let analysed = options.filter.as_ref().map(|filter| annot.filter_rows(filter));
+    if let Some(ref analysed) = analysed {
+        eprintln!("Searching {} of {} annotation rows that passed the filter", analysed.len(), annot.rows.len());
+    }
     eprintln!("Getting refseq lengths from bam file {:?}", &bamfiles[0]);
     let refs = match options.sizes_file.clone() {
-        Some(sizes_file) => read_sizes_file(&sizes_file, &annot.chrmap)?,
-        None => get_bam_refs(&bamfiles[0], &annot.chrmap)?,
+        Some(sizes_file) => read_sizes_file(&sizes_file, &annot)?,
+        None => get_bam_refs(&bamfiles[0], &annot)?,
     };
     annot.refs = refs;
+    if options.autostrand {
+        eprintln!("Inferring strandedness of the unstranded bam files");
+        assign_bam_strands(&bamfiles, &mut bamstrand, &annot, &options.strand_report, options.strand_sample_reads, options.strand_threshold)?;
+    }
     
     if let Some(ref debug_annot_gff) = options.debug_annot_gff {
         eprintln!("Writing annotation file to {:?}", &debug_annot_gff);
@@ -1639,13 +1930,24 @@ fn run() -> Result<()> {
     }
     
     // get the total bam reads
-    eprintln!("Running samtools idxstats to get total bam read counts");
-    let total_reads = get_bam_total_reads(&bamfiles)?;
+    eprintln!("Getting total bam read counts");
+    let total_reads = get_bam_total_reads(&bamfiles, options.read_count)?;
     eprintln!("Found {} total reads", total_reads);
     
     // find the constituitive exons
     eprintln!("Searching for constituitive exons in the annotation");
-    let exonpairs = find_constituitive_splice_pairs(&annot, &options)?;
+    let exonpairs = find_constituitive_splice_pairs(&annot, &options, analysed.as_ref())?;
+    
+    let splice_motifs = options.splice_motifs || options.drop_noncanonical;
+    let genome = match options.genome_file {
+        Some(ref genome_file) if splice_motifs || options.outannot.is_some() => {
+            eprintln!("Reading genome sequence from {:?}", genome_file);
+            let mut genome = Faidx::open(genome_file)?;
+            genome.match_seqnames(&annot);
+            Some(Arc::new(genome))
+        }
+        _ => None,
+    };
     
     let annot = Arc::new(annot);
     if let Some(ref debug_exon_bigbed) = options.debug_exon_bigbed {
@@ -1661,6 +1963,8 @@ fn run() -> Result<()> {
         &bamstrand,
         total_reads,
         &options,
+        &params,
+        &if splice_motifs { genome.clone() } else { None },
         &mut trackdb)?;
     if let Some(ref debug_reannot_bigbed) = options.debug_reannot_bigbed {
         eprintln!("Writing reannotation to bigbed");
@@ -1671,6 +1975,11 @@ fn run() -> Result<()> {
         write_retained_introns(&reannotated_pairs, &annot, &debug_retained_introns)?;
     }
     
+    if let Some(ref cassette_stats) = options.cassette_stats {
+        eprintln!("Writing cassette stats to {:?}", &cassette_stats);
+        write_cassette_stats(&cassette_stats, &reannotated_pairs, &annot)?;
+    }
+    
     eprintln!("Computing RPKM stats");
     if let Some(ref debug_rpkmstats_json) = options.debug_rpkmstats_json {
         eprintln!("Writing RPKM stats to {:?}", &debug_rpkmstats_json);
@@ -1684,7 +1993,7 @@ fn run() -> Result<()> {
     
     if let Some(ref outannot) = options.outannot {
         eprintln!("Writing output annotation file");
-        write_enriched_annotation(&annot, &reannotated_pairs, &outannot, &options, &mut trackdb)?;
+        write_enriched_annotation(annot, &reannotated_pairs, &outannot, &options, &params, &genome, &mut trackdb)?;
     }
     Ok(())
 }
@@ -1694,3 +2003,220 @@ fn main() -> Result<()> {
     std::env::set_var("RUST_BACKTRACE", "full");
     run()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!((actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{:?} != {:?}", actual, expected);
+    }
+
+    #[test]
+    fn wilson_interval() {
+        assert_eq!(CassetteStats::wilson_interval(0, 0), (0.0, 1.0));
+        assert_close(CassetteStats::wilson_interval(0, 10), (0.0, 0.2775));
+        assert_close(CassetteStats::wilson_interval(10, 10), (0.7225, 1.0));
+        assert_close(CassetteStats::wilson_interval(5, 10), (0.2366, 0.7634));
+        assert_close(CassetteStats::wilson_interval(1, 1), (0.2065, 1.0));
+    }
+
+    #[test]
+    fn splice_motifs() {
+        use SpliceMotif::*;
+        // each sequence is TT, the acceptor-side flank, a cassette at 4..8,
+        // the donor-side flank and TT, with the flanks given in genome order
+        let cases = [
+            ("AG", "GT", Strand::Forward, GtAg),
+            ("ag", "gt", Strand::Forward, GtAg),
+            ("AG", "GC", Strand::Forward, GcAg),
+            ("AC", "AT", Strand::Forward, AtAc),
+            ("AG", "GG", Strand::Forward, NonCanonical),
+            ("AC", "CT", Strand::Reverse, GtAg),
+            ("GC", "CT", Strand::Reverse, GcAg),
+            ("AT", "GT", Strand::Reverse, AtAc),
+            ("AG", "GT", Strand::Reverse, NonCanonical),
+            ("AC", "CT", Strand::Forward, NonCanonical),
+        ];
+        let mut fasta = String::new();
+        for (i, (left, right, _, _)) in cases.iter().enumerate() {
+            fasta += &format!(">s{}\nTT{}CCCC{}TT\n", i, left, right);
+        }
+        let path = std::env::temp_dir().join(format!("splice_motifs_{}.fa", std::process::id())).to_str().unwrap().to_string();
+        std::fs::write(&path, fasta).unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        for (i, (left, right, strand, motif)) in cases.iter().enumerate() {
+            assert_eq!(SpliceMotif::classify(&genome, &format!("s{}", i), *strand, &(4..8)), Some(*motif),
+                "{} {} {:?}", left, right, strand);
+        }
+        // flanks off either end of the sequence, or a missing sequence
+        assert_eq!(SpliceMotif::classify(&genome, "s0", Strand::Forward, &(1..8)), None);
+        assert_eq!(SpliceMotif::classify(&genome, "s0", Strand::Forward, &(4..11)), None);
+        assert_eq!(SpliceMotif::classify(&genome, "s0", Strand::Forward, &(2..10)), Some(NonCanonical));
+        assert_eq!(SpliceMotif::classify(&genome, "chrUn", Strand::Forward, &(4..8)), None);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.fai", path)).unwrap();
+    }
+
+    #[test]
+    fn enriched_annotation_types() {
+        let dir = std::env::temp_dir();
+        let gff = dir.join(format!("enriched_types_{}.gff", std::process::id())).to_str().unwrap().to_string();
+        let outannot = dir.join(format!("enriched_types_{}.out.gff", std::process::id())).to_str().unwrap().to_string();
+        std::fs::write(&gff, [
+            "chr1\tt\tgene\t1\t1000\t.\t+\t.\tID=g1",
+            "chr1\tt\tmRNA\t1\t1000\t.\t+\t.\tID=t1;Parent=g1",
+            "chr1\tt\texon_part\t1\t100\t.\t+\t.\tID=e1;Parent=t1",
+            "chr1\tt\texon_part\t901\t1000\t.\t+\t.\tID=e2;Parent=t1",
+            "chr1\tt\tcoding_part\t51\t100\t.\t+\t0\tID=c1;Parent=t1",
+            "chr1\tt\tcoding_part\t901\t950\t.\t+\t0\tID=c2;Parent=t1",
+        ].iter().map(|l| format!("{}\n", l)).join("")).unwrap();
+        let annot = IndexedAnnotation::from_file(&gff, "gff", "gene", "mRNA", &None, &None).unwrap();
+        let options = Options::from_iter(&["cassette_reannotation",
+            "--gene_type", "gene", "--transcript_type", "mRNA",
+            "--exon_type", "exon_part", "--cds_type", "coding_part"]);
+        let params = CassetteParams::from_options(&options).unwrap();
+        let pair = ConstituitivePair {
+            exon1_row: annot.id2row[&Sym::new("e1")],
+            exon2_row: annot.id2row[&Sym::new("e2")],
+            cassettes: vec![Cassette {
+                range: 400..460,
+                cassette_row: None,
+                stats: CassetteStats::default(),
+                splice_motif: None,
+            }],
+            is_retained_intron: false,
+            params: None,
+        };
+        let mut trackdb: BufWriter<Box<dyn Write>> = BufWriter::new(Box::new(sink()));
+        write_enriched_annotation(Arc::new(annot), &vec![pair], &outannot, &options, &params, &None, &mut trackdb).unwrap();
+        let annot = IndexedAnnotation::from_file(&outannot, "gff", "gene", "mRNA", &None, &None).unwrap();
+        std::fs::remove_file(&gff).unwrap();
+        std::fs::remove_file(&outannot).unwrap();
+
+        let transcript = annot.id2row[&Sym::new("t1.reannot")];
+        let children = annot.row2children[&transcript].iter().map(|r| &annot.rows[*r]).
+            sorted_by_key(|r| (r.start, r.feature_type.as_str())).
+            map(|r| (r.feature_type.as_str(), r.start, r.end, r.frame.phase(),
+                r.attributes.get("exon_number").map(|n| n.to_string()))).
+            collect::<Vec<_>>();
+        let number = |n: &str| Some(n.to_string());
+        // the cassette copies the configured exon and CDS types of its
+        // flanking exons, and continues their reading frame
+        assert_eq!(children, vec![
+            ("exon_part", 1, 100, 0, number("1")),
+            ("coding_part", 51, 100, 0, None),
+            ("coding_part", 401, 460, 1, None),
+            ("exon_part", 401, 460, 0, number("2")),
+            ("coding_part", 901, 950, 1, None),
+            ("exon_part", 901, 1000, 0, number("3")),
+        ]);
+        let cassette_cds = annot.row2children[&transcript].iter().map(|r| &annot.rows[*r]).
+            find(|r| r.feature_type == "coding_part" && r.start == 401).unwrap();
+        assert!(cassette_cds.attributes["ID"].starts_with("coding_part:t1:"));
+        assert_eq!(cassette_cds.attributes["exon_type"], "cassette");
+    }
+
+    // exon and CDS records of a fully coding transcript, in transcript
+    // order, with the exon at index `cassette` marked as a cassette
+    fn coding_records(seqname: &str, strand: Strand, exons: &[(u64,u64)], cassette: usize) -> Vec<Record> {
+        let mut records = Vec::new();
+        for (i, &(start, end)) in exons.iter().enumerate() {
+            for (feature_type, frame) in &[("exon", Frame::None), ("CDS", if i == 0 { Frame::Zero } else { Frame::None })] {
+                let mut record = Record::new();
+                record.seqname = Sym::new(seqname);
+                record.feature_type = Sym::new(feature_type);
+                record.start = start;
+                record.end = end;
+                record.strand = strand;
+                record.frame = *frame;
+                if i == cassette {
+                    record.attributes.insert("exon_type", "cassette");
+                }
+                records.push(record);
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn orf_effects() {
+        // every sequence is 500 C's, which code for Pro, or Gly on the minus
+        // strand, apart from the given stop codons
+        let sequences: Vec<(&str, Vec<(usize, &str)>)> = vec![
+            ("plain", vec![]),
+            ("stop", vec![(101, "TAA")]),
+            ("minus_stop", vec![(398, "TTA")]),
+        ];
+        let mut fasta = String::new();
+        for (name, stops) in &sequences {
+            let mut seq = vec![b'C'; 500];
+            for (pos, codon) in stops {
+                seq[pos-1..pos+2].copy_from_slice(codon.as_bytes());
+            }
+            fasta += &format!(">{}\n{}\n", name, String::from_utf8(seq).unwrap());
+        }
+        let path = std::env::temp_dir().join(format!("orf_effects_{}.fa", std::process::id())).to_str().unwrap().to_string();
+        std::fs::write(&path, fasta).unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        let types = FeatureTypes::new(&["gene".to_string()], &["mRNA".to_string()], &["exon".to_string()], &["CDS".to_string()]);
+        let effect = |seqname: &str, strand: Strand, exons: &[(u64,u64)]| {
+            let mut records = coding_records(seqname, strand, exons, 1);
+            let summaries = annotate_orf_effects(&mut records, "t1", &genome, &types).unwrap();
+            assert_eq!(summaries.len(), 1);
+            // the cassette exon and CDS are both labeled
+            let labels = records.iter().filter_map(|r| r.attributes.get("cassette_orf_effect")).collect::<Vec<_>>();
+            assert_eq!(labels.len(), 2);
+            assert!(labels.iter().all(|l| **l == summaries[0].effect.to_string()));
+            (summaries[0].effect, summaries[0].stop_position, summaries[0].last_junction)
+        };
+
+        // a 30 nt cassette keeps the frame, a 31 nt one shifts it
+        assert_eq!(effect("plain", Strand::Forward, &[(1,30), (101,130), (201,290), (401,500)]),
+            (OrfEffect::FramePreserving, None, Some(150)));
+        assert_eq!(effect("plain", Strand::Forward, &[(1,30), (101,131), (201,290), (401,500)]),
+            (OrfEffect::Frameshift, None, Some(151)));
+        // a stop at the start of the cassette, 30 + 3 + 50 nt before the
+        // last junction at 150, triggers NMD
+        assert_eq!(effect("stop", Strand::Forward, &[(1,30), (101,130), (201,290), (401,500)]),
+            (OrfEffect::NmdCandidate, Some(30), Some(150)));
+        // but not within 50 nt of the last junction at 70
+        assert_eq!(effect("stop", Strand::Forward, &[(1,30), (101,130), (201,210), (401,500)]),
+            (OrfEffect::FramePreserving, Some(30), Some(70)));
+        // nor when the cassette is the last exon
+        assert_eq!(effect("stop", Strand::Forward, &[(1,30), (101,130)]),
+            (OrfEffect::FramePreserving, Some(30), Some(30)));
+        // a stop upstream of the cassette is not the cassette's doing
+        assert_eq!(effect("stop", Strand::Forward, &[(92,121), (201,230), (301,390), (401,500)]),
+            (OrfEffect::FramePreserving, Some(9), Some(150)));
+        // the minus strand reads the exons from the highest coordinate down
+        assert_eq!(effect("minus_stop", Strand::Reverse, &[(471,500), (371,400), (211,300), (1,100)]),
+            (OrfEffect::NmdCandidate, Some(30), Some(150)));
+        assert_eq!(effect("plain", Strand::Reverse, &[(471,500), (370,400), (211,300), (1,100)]),
+            (OrfEffect::Frameshift, None, Some(151)));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{}.fai", path)).unwrap();
+    }
+
+    #[test]
+    fn cassette_stats() {
+        let range = 100..200;
+        // one fragment includes the cassette, one skips it, one does neither
+        let include = vec![vec![0..50, 100..150]];
+        let skip = vec![vec![0..50, 300..350]];
+        let unspliced = vec![vec![0..50]];
+        let stats = CassetteStats::compute(&range, &[&include, &skip, &unspliced]);
+        assert_eq!((stats.inclusion_reads, stats.skipping_reads), (1, 1));
+        assert_eq!(stats.psi, Some(0.5));
+        assert_eq!(stats.score, (CassetteStats::wilson_interval(1, 2).0 * 1000.0).round() as u32);
+
+        // without fragments there is no PSI to report
+        let stats = CassetteStats::compute(&range, &[&unspliced]);
+        assert_eq!(stats.psi, None);
+        assert_eq!((stats.psi_ci_low, stats.psi_ci_high, stats.score), (0.0, 1.0, 0));
+        let attributes = stats.attributes();
+        assert!(attributes.iter().all(|(k, _)| k != "cassette_psi"));
+        assert!(attributes.iter().any(|(k, v)| k == "cassette_psi_ci" && *v == vec!["0", "1"]));
+    }
+}
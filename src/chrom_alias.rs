@@ -0,0 +1,309 @@
+//! Chromosome name aliases, for matching the sequence names of bam files,
+//! annotations and genomes that name the same sequences differently.
+//! Aliases can be read from UCSC chromAlias.txt files or any tab-delimited
+//! table of equivalent names, and common naming schemes are matched
+//! automatically: chr1 and 1, chrM, M and MT, and the RefSeq accessions of
+//! the human chromosomes such as NC_000001.11.
+use std::collections::{HashMap, HashSet};
+use regex::Regex;
+use lazy_static::lazy_static;
+use anyhow::{Result, anyhow};
+
+use crate::open_input;
+
+/// Groups of names that refer to the same sequence.
+#[derive(Clone, Debug, Default)]
+pub struct ChromAliases {
+    // each group's names, with the UCSC name first if it is known
+    groups: Vec<Vec<String>>,
+    name2group: HashMap<String, usize>,
+}
+
+impl ChromAliases {
+    pub fn new() -> ChromAliases {
+        ChromAliases::default()
+    }
+
+    /// Read an alias table. A UCSC chromAlias.txt file with a
+    /// "# ucsc ..." header names the columns; an old-style UCSC table has
+    /// alias, chrom and source columns. In any other table every column of
+    /// a line names the same sequence, and the first is the preferred name.
+    pub fn from_file(file: &str) -> Result<ChromAliases> {
+        lazy_static! {
+            static ref SOURCES: Regex = Regex::new(r"^[a-z_]+(,[a-z_]+)*$").unwrap();
+        }
+        let mut aliases = ChromAliases::new();
+        let mut input = open_input(file)?;
+        let mut buf = String::new();
+        // the column of the UCSC names
+        let mut ucsc_col = 0;
+        let mut lineno = 0;
+        while input.read_line(&mut buf)? > 0 {
+            lineno += 1;
+            {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
+                if line.starts_with('#') {
+                    let header = line.trim_start_matches('#').split('\t').map(|c| c.trim()).collect::<Vec<_>>();
+                    if let Some(col) = header.iter().position(|c| *c == "ucsc") {
+                        ucsc_col = col;
+                    }
+                }
+                else if !line.trim().is_empty() {
+                    let cols = line.split('\t').map(|c| c.trim()).collect::<Vec<_>>();
+                    let names = if cols.len() == 3 && SOURCES.is_match(cols[2]) {
+                        // old-style alias, chrom, source
+                        vec![cols[1], cols[0]]
+                    } else {
+                        let mut names = cols.clone();
+                        if ucsc_col < names.len() {
+                            let ucsc = names.remove(ucsc_col);
+                            names.insert(0, ucsc);
+                        }
+                        names
+                    };
+                    let names = names.into_iter().filter(|n| !n.is_empty()).map(String::from).collect::<Vec<_>>();
+                    if names.is_empty() {
+                        return Err(anyhow!("No chromosome names on line {} of alias file {}", lineno, file));
+                    }
+                    aliases.add(names);
+                }
+            }
+            buf.clear();
+        }
+        Ok(aliases)
+    }
+
+    /// Add a group of names for one sequence, merging it with any group that
+    /// already has one of the names. The first name is the preferred name.
+    pub fn add(&mut self, names: Vec<String>) {
+        let group = names.iter().find_map(|n| self.name2group.get(n).cloned());
+        let group = match group {
+            Some(group) => group,
+            None => {
+                self.groups.push(Vec::new());
+                self.groups.len()-1
+            }
+        };
+        for name in names {
+            match self.name2group.get(&name).cloned() {
+                Some(other) if other != group => {
+                    // the names join two existing groups
+                    let others = std::mem::take(&mut self.groups[other]);
+                    for n in others {
+                        self.name2group.insert(n.clone(), group);
+                        self.groups[group].push(n);
+                    }
+                }
+                Some(_) => (),
+                None => {
+                    self.name2group.insert(name.clone(), group);
+                    self.groups[group].push(name);
+                }
+            }
+        }
+    }
+
+    /// The key that a name matches other names by: equivalent names have
+    /// the same key.
+    pub fn key(&self, name: &str) -> String {
+        let name = match self.name2group.get(name) {
+            Some(group) => &self.groups[*group][0],
+            None => name,
+        };
+        builtin_key(name)
+    }
+
+    /// The UCSC style name of a sequence: the name from the alias table if
+    /// it has one, otherwise "chr" followed by the chromosome for names of
+    /// numbered and sex chromosomes, the mitochondrial genome and the
+    /// zebrafish Zv9 scaffolds such as Zv9_NA1. Other names are unchanged.
+    pub fn ucsc_name(&self, name: &str) -> String {
+        if let Some(group) = self.name2group.get(name) {
+            return self.groups[*group][0].clone();
+        }
+        lazy_static! {
+            static ref CHROMOSOME: Regex = Regex::new(r"^([0-9]+|[XYZW]|M|Zv9_.+)$").unwrap();
+        }
+        let key = builtin_key(name);
+        if CHROMOSOME.is_match(&key) { format!("chr{}", key) } else { name.to_string() }
+    }
+}
+
+// the key of a name without an alias table: the name without any chr
+// prefix, with M for the mitochondrial genome and the chromosome for the
+// RefSeq accessions of the human chromosomes
+fn builtin_key(name: &str) -> String {
+    lazy_static! {
+        static ref CHR_PREFIX: Regex = Regex::new(r"^(?i:chr)").unwrap();
+        static ref REFSEQ: Regex = Regex::new(r"^NC_0000([0-9]{2})\.[0-9]+$").unwrap();
+    }
+    if let Some(caps) = REFSEQ.captures(name) {
+        match caps[1].parse::<u32>() {
+            Ok(n) if (1..=22).contains(&n) => return n.to_string(),
+            Ok(23) => return String::from("X"),
+            Ok(24) => return String::from("Y"),
+            _ => (),
+        }
+    }
+    if name.starts_with("NC_012920.") {
+        return String::from("M");
+    }
+    let name = CHR_PREFIX.replace(name, "");
+    if name.eq_ignore_ascii_case("M") || name.eq_ignore_ascii_case("MT") {
+        return String::from("M");
+    }
+    name.into_owned()
+}
+
+/// Matches names against a set of sequence names, either exactly or as
+/// aliases.
+#[derive(Clone, Debug, Default)]
+pub struct ChromMatcher {
+    aliases: ChromAliases,
+    names: HashSet<String>,
+    // the names with each alias key
+    keys: HashMap<String, Vec<String>>,
+}
+
+impl ChromMatcher {
+    pub fn new<'a>(aliases: ChromAliases, names: impl IntoIterator<Item=&'a String>) -> ChromMatcher {
+        let names = names.into_iter().cloned().collect::<HashSet<_>>();
+        let mut keys = HashMap::<String, Vec<String>>::new();
+        for name in &names {
+            keys.entry(aliases.key(name)).or_insert_with(Vec::new).push(name.clone());
+        }
+        ChromMatcher {
+            aliases: aliases,
+            names: names,
+            keys: keys,
+        }
+    }
+
+    pub fn aliases(&self) -> &ChromAliases {
+        &self.aliases
+    }
+
+    /// The sequence names that are matched against.
+    pub fn names(&self) -> impl Iterator<Item=&String> {
+        self.names.iter()
+    }
+
+    /// The name equal to `name`, or else the one name that is an alias of
+    /// it. Returns None if no name or more than one alias matches.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        if let Some(name) = self.names.get(name) {
+            return Some(name);
+        }
+        match self.keys.get(&self.aliases.key(name)) {
+            Some(names) if names.len() == 1 => Some(&names[0]),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_text(name: &str, text: &str) -> Result<ChromAliases> {
+        let file = std::env::temp_dir().join(format!("chrom_alias_{}_{}.txt", std::process::id(), name));
+        let file = file.to_str().unwrap();
+        std::fs::write(file, text).unwrap();
+        let aliases = ChromAliases::from_file(file);
+        std::fs::remove_file(file).unwrap();
+        aliases
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn file_formats() {
+        // a chromAlias.txt header names the UCSC column, wherever it is
+        let aliases = from_text("ucsc", concat!(
+            "# genbank\tucsc\trefseq\n",
+            "CM000663.2\tchr1\tNC_000001.11\n",
+            "KI270302.1\tchrUn_KI270302v1\t\n")).unwrap();
+        assert_eq!(aliases.ucsc_name("CM000663.2"), "chr1");
+        assert_eq!(aliases.ucsc_name("KI270302.1"), "chrUn_KI270302v1");
+        assert_eq!(aliases.key("CM000663.2"), aliases.key("1"));
+        assert_ne!(aliases.key("KI270302.1"), aliases.key("1"));
+
+        // old-style alias, chrom and source columns
+        let aliases = from_text("old", "1\tchr1\tensembl\nCM000663.2\tchr1\tgenbank,refseq\n").unwrap();
+        assert_eq!(aliases.ucsc_name("1"), "chr1");
+        assert_eq!(aliases.ucsc_name("CM000663.2"), "chr1");
+
+        // any other table: every column names the same sequence, and the
+        // first is preferred
+        let aliases = from_text("table", "\n1\tchr1\r\nscaffold_7\tSC7\tchrSC7\n").unwrap();
+        assert_eq!(aliases.ucsc_name("chr1"), "1");
+        assert_eq!(aliases.ucsc_name("chrSC7"), "scaffold_7");
+        assert_eq!(aliases.key("SC7"), aliases.key("scaffold_7"));
+
+        // blank lines are skipped, but a line naming no sequence is an error
+        assert!(from_text("blank_line", "chr1\t1\n\t\t\n").is_ok());
+        assert!(from_text("no_names", "chr1\t1\n\t\tensembl\n").is_err());
+    }
+
+    #[test]
+    fn add_merges_groups() {
+        let mut aliases = ChromAliases::new();
+        aliases.add(names(&["a", "b"]));
+        aliases.add(names(&["c", "d"]));
+        assert_ne!(aliases.key("a"), aliases.key("d"));
+        // a group with names of both groups joins them, keeping the first
+        // group's preferred name
+        aliases.add(names(&["b", "c", "e"]));
+        for name in &["b", "c", "d", "e"] {
+            assert_eq!(aliases.key(name), aliases.key("a"));
+            assert_eq!(aliases.ucsc_name(name), "a");
+        }
+        aliases.add(names(&["f"]));
+        assert_eq!(aliases.ucsc_name("f"), "f");
+    }
+
+    #[test]
+    fn builtin_keys() {
+        for (name, key) in &[
+            ("chr1", "1"), ("1", "1"), ("Chr2", "2"), ("CHRX", "X"), ("chrUn_KI270302v1", "Un_KI270302v1"),
+            ("NC_000001.11", "1"), ("NC_000022.10", "22"), ("NC_000023.11", "X"), ("NC_000024.10", "Y"),
+            ("NC_000025.1", "NC_000025.1"), ("NC_000100.1", "NC_000100.1"), ("NC_012920.1", "M"),
+            ("chrM", "M"), ("M", "M"), ("MT", "M"), ("chrMT", "M"), ("mt", "M"),
+        ] {
+            assert_eq!(builtin_key(name), *key, "{}", name);
+        }
+    }
+
+    #[test]
+    fn ucsc_names() {
+        let aliases = ChromAliases::new();
+        for (name, ucsc) in &[
+            ("1", "chr1"), ("chr1", "chr1"), ("X", "chrX"), ("MT", "chrM"), ("NC_000001.11", "chr1"),
+            ("NC_012920.1", "chrM"), ("scaffold_1", "scaffold_1"), ("KI270302.1", "KI270302.1"),
+            // zebrafish Zv9 scaffolds get a chr prefix, once
+            ("Zv9_NA1", "chrZv9_NA1"), ("chrZv9_NA1", "chrZv9_NA1"), ("Zv9_scaffold3453", "chrZv9_scaffold3453"),
+        ] {
+            assert_eq!(aliases.ucsc_name(name), *ucsc, "{}", name);
+        }
+    }
+
+    #[test]
+    fn matcher() {
+        let sequences = names(&["chr1", "chr2", "2", "scaffold_7"]);
+        let matcher = ChromMatcher::new(ChromAliases::new(), &sequences);
+        assert_eq!(matcher.get("chr1").map(|n| n.as_str()), Some("chr1"));
+        assert_eq!(matcher.get("1").map(|n| n.as_str()), Some("chr1"));
+        assert_eq!(matcher.get("NC_000001.11").map(|n| n.as_str()), Some("chr1"));
+        // exact names win, but an alias of two names matches neither
+        assert_eq!(matcher.get("2").map(|n| n.as_str()), Some("2"));
+        assert_eq!(matcher.get("NC_000002.12"), None);
+        assert_eq!(matcher.get("chr3"), None);
+
+        let mut aliases = ChromAliases::new();
+        aliases.add(names(&["chrSC7", "scaffold_7"]));
+        let matcher = ChromMatcher::new(aliases, &sequences);
+        assert_eq!(matcher.get("chrSC7").map(|n| n.as_str()), Some("scaffold_7"));
+        assert_eq!(matcher.names().count(), 4);
+    }
+}
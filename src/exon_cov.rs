@@ -11,6 +11,9 @@ use anyhow::{Result, anyhow};
 
 use cassette_reannotation::*;
 use cassette_reannotation::indexed_annotation::*;
+use cassette_reannotation::annotation_formats::annotation_format;
+use cassette_reannotation::chrom_alias::ChromAliases;
+use cassette_reannotation::feature_filter::Filter;
 
 use rust_htslib::bam::Read;
 use rust_htslib::bam::IndexedReader;
@@ -26,21 +29,39 @@ use ordered_float::OrderedFloat;
 #[structopt(name = "intronrpkm", about = "Analyze RPKM values in intronic space")]
 struct Options {
     // input files
-    #[structopt(long="gff", help = "A genome annotation file in gff3 format", name="ANNOT_GFF_FILE")]
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
     annotfile_gff: Option<String>,
-    #[structopt(long="gtf", help = "A genome annotation file in gtf format", name="ANNOT_GTF_FILE")]
+    #[structopt(long="gtf", help = "A genome annotation file in gtf format, optionally gzipped, or - for stdin", name="ANNOT_GTF_FILE")]
     annotfile_gtf: Option<String>,
+    #[structopt(long="annot", help = "A genome annotation file in gff3, gtf, bed12, bigbed, genepred or refflat format, optionally gzipped, or - for stdin", name="ANNOT_FILE")]
+    annotfile: Option<String>,
+    #[structopt(long="annot_format", help = "The format of the --annot file: gff, gtf, bed, bigbed, genepred or refflat. Guessed from the file extension if not given.", name="ANNOT_FORMAT")]
+    annot_format: Option<String>,
     #[structopt(long="bam1", short="1", help = "The set of stranded .bam files to analyze where read1 indicates strand", name="BAMFILE1")]
     bam1: Vec<String>,
     #[structopt(long="bam2", short="2", help = "The set of stranded .bam files to analyze where read2 indicates strand", name="BAMFILE2")]
     bam2: Vec<String>,
-    #[structopt(long="bam", short="u", help = "The set of unstranded .bam files to analyze", name="BAMFILE")]
+    #[structopt(long="bam", short="u", help = "The set of unstranded .bam files to analyze, or of unknown strandedness with --autostrand", name="BAMFILE")]
     bam: Vec<String>,
+    #[structopt(long="autostrand", help = "Infer the strandedness of the --bam files from the annotation")]
+    autostrand: bool,
+    #[structopt(long="strand_report", help = "Write the --autostrand report to a .json or tab-delimited file", name="STRAND_REPORT_FILE")]
+    strand_report: Option<String>,
+    #[structopt(long="strand_threshold", help = "The fraction of the --autostrand reads that must agree to call a library stranded, above 0.5 and at most 1", name="STRAND_THRESHOLD", default_value="0.8")]
+    strand_threshold: f64,
+    #[structopt(long="strand_sample_reads", help = "The number of strand-informative reads that --autostrand samples", name="STRAND_SAMPLE_READS", default_value="200000")]
+    strand_sample_reads: u64,
     #[structopt(long="chrmap", help = "Optional tab-delimited chr name mapping file", name="CHRMAP_FILE")]
     chrmap_file: Option<String>,
     #[structopt(long="vizchrmap", help = "Optional tab-delimited chr name mapping file for bigwig/bigbed exports", name="VIZCHRMAP_FILE")]
     vizchrmap_file: Option<String>,
-    #[structopt(long="sizes", help = "Optional chr sizes file", name="SIZES_FILE")]
+    #[structopt(long="chromalias", help = "Optional UCSC chromAlias.txt file or other tab-delimited table of equivalent chr names", name="CHROM_ALIAS_FILE")]
+    chromalias_file: Option<String>,
+    #[structopt(long="strict", help = "Fail on malformed annotation lines instead of skipping them")]
+    strict: bool,
+    #[structopt(long="no_annotation_cache", help = "Always parse the annotation file instead of reading or writing its cache")]
+    no_annotation_cache: bool,
+    #[structopt(long="sizes", help = "Optional chr sizes file, such as a FASTA .fai index", name="SIZES_FILE")]
     sizes_file: Option<String>,
     // output file
     #[structopt(long="out", short="o", help = "Output file", name="OUT_FILE", default_value="-")]
@@ -54,9 +75,13 @@ struct Options {
     transcript_type: Vec<String>,
     #[structopt(long="gene_type", help = "The gene type(s) to search for", name="GENE_TYPE")]
     gene_type: Vec<String>,
+    #[structopt(long="filter", help = "Only use the annotation features that pass a filter expression, such as \"gene_biotype=protein_coding and tag contains basic\"", name="FILTER")]
+    filter: Option<Filter>,
     // flags
     #[structopt(long="cpu_threads", short="t", help = "How many threads to use for processing", default_value="0")]
     cpu_threads: usize,
+    #[structopt(long="read_count", help = "Which alignments count as reads for RPKM: mapped, primary, unique or fragments", name="READ_COUNT", default_value="mapped")]
+    read_count: ReadCount,
 }
 
 #[derive(Ord, Eq, PartialOrd, PartialEq)]
@@ -81,50 +106,17 @@ fn write_exon_cov(
 {
     let mut tidmaps = HashMap::<String,HashMap<String,u32>>::new();
     for bamfile in bamfiles {
-        let bam = IndexedReader::from_path(bamfile)?;
-        // build the tid map for this bam file
-        let mut tidmap = HashMap::<String,u32>::new();
-        {   let header = bam.header();
-            for target_name in header.target_names() {
-                let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
-                let target_name = String::from(std::str::from_utf8(target_name)?);
-                let chr = annot.chrmap.get(&target_name).unwrap_or(&target_name);
-                tidmap.insert(chr.clone(), tid);
-            }
-        }
-        tidmaps.insert(bamfile.clone(), tidmap);
+        tidmaps.insert(bamfile.clone(), get_tidmap(bamfile, annot)?);
     }
     let tidmaps = Arc::new(tidmaps);
 
     let mut unmerged_exons = HashMap::<(String,String),Vec<(Range<u64>,Option<usize>)>>::new();
-    for (gene_row, gene) in annot.rows.iter().enumerate() {
-        if options.gene_type.is_empty() || options.gene_type.contains(&gene.feature_type) {
-            if let Some(feature_rows) = annot.row2children.get(&gene_row) {
-                // get the transcript rows for this gene
-                'transcript_row:
-                for transcript_row in feature_rows {
-                    let transcript = &annot.rows[*transcript_row];
-                    // make sure this is a transcript type
-                    // make sure transcript coordinates are consistent with the gene coordinates
-                    if (options.transcript_type.is_empty() ||
-                            options.transcript_type.contains(&transcript.feature_type)) &&
-                        transcript.seqname == gene.seqname &&
-                        transcript.strand == gene.strand
-                    {
-                        if let Some(exon_rows) = annot.row2children.get(transcript_row) {
-                            // first get exon start/stop -> transcript associations
-                            // and splice start/stop -> transcript associations
-                            for exon_row in exon_rows {
-                                let exon = &annot.rows[*exon_row];
-                                if options.exon_type.is_empty() || options.exon_type.contains(&exon.feature_type) {
-                                    let exon = &annot.rows[*exon_row];
-                                    unmerged_exons.entry((exon.seqname.clone(),exon.strand.clone())).
-                                        or_insert_with(Vec::new).push((exon.start-1..exon.end, Some(*exon_row)));
-                                }
-                            }
-                        }
-                    }
-                }
+    let types = FeatureTypes::new(&options.gene_type, &options.transcript_type, &options.exon_type, &[]);
+    for gene in annot.genes(&types) {
+        for transcript in annot.transcripts(&gene, &types) {
+            for exon in annot.exons(&transcript, &types) {
+                unmerged_exons.entry((exon.record.seqname.to_string(),exon.record.strand.to_string())).
+                    or_insert_with(Vec::new).push((exon.range(), Some(exon.row)));
             }
         }
     }
@@ -201,6 +193,7 @@ fn write_exon_cov_to_file(
             let bamstrand = bamstrand.clone();
             let tidmaps = tidmaps.clone();
             let annot = annot.clone();
+            let read_count = options.read_count;
 
             let pair_future = pool.spawn_fn(move ||->Result<Row> {
                 let mut exon_cov = 0f64;
@@ -213,6 +206,7 @@ fn write_exon_cov_to_file(
                         bam.fetch(*tid, exon.0.start as u32, exon.0.end as u32)?;
                         for read in bam.records() {
                             let read = read?;
+                            if !read_count.includes(&read) { continue }
                             // make sure the read's strand matches
                             if let Some(is_read1strand) = read1strand {
                                 if !(((is_read1strand == read.is_first_in_template()) == !read.is_reverse()) == strand_is_plus) {
@@ -334,32 +328,62 @@ fn run() -> Result<()> {
     let transcript_type = String::from("transcript");
     let mut annot = if let Some(annotfile_gff) = options.annotfile_gff.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gff);
-        IndexedAnnotation::from_gff(
+        IndexedAnnotation::from_file_with(
             &annotfile_gff, 
+            "gff",
+            "",
+            "",
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else if let Some(annotfile_gtf) = options.annotfile_gtf.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gtf);
-        IndexedAnnotation::from_gtf(&annotfile_gtf, 
+        IndexedAnnotation::from_file_with(&annotfile_gtf, 
+            "gtf",
             options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
             options.transcript_type.get(0).unwrap_or(&transcript_type),
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else if let Some(annotfile) = options.annotfile.clone() {
+        let format = annotation_format(&annotfile, &options.annot_format)?;
+        eprintln!("Reading {} annotation file {:?}", format, &annotfile);
+        IndexedAnnotation::from_file_with(&annotfile, 
+            &format,
+            options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
+            options.transcript_type.get(0).unwrap_or(&transcript_type),
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else {
         Options::clap().print_help()?;
         eprintln!("\n\nNo annotation file was given!");
         std::process::exit(1);
     };
+    if let Some(chromalias_file) = options.chromalias_file.clone() {
+        annot.set_chrom_aliases(ChromAliases::from_file(&chromalias_file)?);
+    }
+    if let Some(ref filter) = options.filter {
+        let removed = annot.retain_features(filter)?;
+        eprintln!("Removed {} annotation rows that did not pass the filter", removed.len());
+    }
     eprintln!("Getting refseq lengths from bam file {:?}", &bamfiles[0]);
     let refs = match options.sizes_file.clone() {
-        Some(sizes_file) => read_sizes_file(&sizes_file, &annot.chrmap)?,
-        None => get_bam_refs(&bamfiles[0], &annot.chrmap)?,
+        Some(sizes_file) => read_sizes_file(&sizes_file, &annot)?,
+        None => get_bam_refs(&bamfiles[0], &annot)?,
     };
     annot.refs = refs;
+    if options.autostrand {
+        eprintln!("Inferring strandedness of the unstranded bam files");
+        assign_bam_strands(&bamfiles, &mut bamstrand, &annot, &options.strand_report, options.strand_sample_reads, options.strand_threshold)?;
+    }
     
     // get the total bam reads
-    eprintln!("Running samtools idxstats to get total bam read counts");
-    let total_reads = get_bam_total_reads(&bamfiles)?;
+    eprintln!("Getting total bam read counts");
+    let total_reads = get_bam_total_reads(&bamfiles, options.read_count)?;
     eprintln!("Found {} total reads", total_reads);
     write_exon_cov(&options, &Arc::new(annot), total_reads, &bamfiles, &bamstrand)?;
     Ok(())
@@ -0,0 +1,549 @@
+//! Random access to genome FASTA files through a samtools-compatible .fai
+//! index. Plain and BGZF compressed FASTA files are supported, and other
+//! gzip compressed files are decompressed into memory.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use linked_hash_map::LinkedHashMap;
+use anyhow::{Result, anyhow};
+
+use crate::indexed_annotation::IndexedAnnotation;
+
+/// One line of a .fai index.
+#[derive(Clone, Debug)]
+pub struct FaidxEntry {
+    pub name: String,
+    pub len: u64,
+    /// uncompressed byte offset of the first base
+    pub offset: u64,
+    pub line_bases: u64,
+    pub line_width: u64,
+}
+
+impl FaidxEntry {
+    // uncompressed byte offset of a 0-based position
+    fn position(&self, pos: u64) -> u64 {
+        self.offset + (pos / self.line_bases) * self.line_width + pos % self.line_bases
+    }
+}
+
+trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// A FASTA file indexed for random access by region. Sequences are
+/// returned as stored, so soft-masked bases stay lowercase.
+pub struct Faidx {
+    path: String,
+    entries: LinkedHashMap<String,FaidxEntry>,
+    // maps annotation seqnames to differently named sequences of the file
+    aliases: HashMap<String,String>,
+    reader: Mutex<Box<dyn ReadSeek>>,
+}
+
+impl Faidx {
+    /// Open a FASTA file, reading its .fai index or building and writing one
+    /// if it does not exist. BGZF compressed files also use a .gzi block
+    /// index, which is likewise built if missing. A GFF3 file with a
+    /// ##FASTA section can be opened as well, and its sequences are used.
+    pub fn open(path: &str) -> Result<Faidx> {
+        if path == "-" {
+            return Err(anyhow!("The genome FASTA file must be a file, not stdin"));
+        }
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 4];
+        let n = file.read(&mut magic)?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader: Box<dyn ReadSeek> = if n >= 2 && magic[..2] == [0x1f, 0x8b] {
+            // BGZF blocks set the FEXTRA flag to hold the block size
+            if n >= 4 && magic[3] & 4 != 0 {
+                Box::new(BgzfReader::open(path, file)?)
+            }
+            else {
+                eprintln!("Genome file {:?} is not compressed with bgzip, reading it into memory", path);
+                let mut data = Vec::new();
+                MultiGzDecoder::new(BufReader::new(file)).read_to_end(&mut data)?;
+                Box::new(Cursor::new(data))
+            }
+        } else {
+            Box::new(file)
+        };
+        let fai_file = format!("{}.fai", path);
+        let entries = if Path::new(&fai_file).exists() {
+            read_fai(&fai_file)?
+        } else {
+            eprintln!("Indexing genome file {:?}", path);
+            let entries = build_fai(&mut reader, path)?;
+            if let Err(e) = write_fai(&fai_file, &entries) {
+                eprintln!("Could not write index file {:?}: {}", fai_file, e);
+            }
+            reader.seek(SeekFrom::Start(0))?;
+            entries
+        };
+        Ok(Faidx {
+            path: path.to_string(),
+            entries: entries,
+            aliases: HashMap::new(),
+            reader: Mutex::new(reader),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The index entries in file order.
+    pub fn sequences(&self) -> impl Iterator<Item=&FaidxEntry> {
+        self.entries.values()
+    }
+
+    /// Match the sequence names to an annotation's seqnames, so that
+    /// sequences can be fetched by the annotation's names.
+    pub fn match_seqnames(&mut self, annot: &IndexedAnnotation) {
+        let names = self.entries.keys().cloned().collect::<Vec<_>>();
+        self.aliases = annot.match_seqnames(&names, &self.path).into_iter().
+            filter(|(name, seqname)| name != seqname).
+            map(|(name, seqname)| (seqname, name)).
+            collect();
+    }
+
+    fn entry(&self, seqname: &str) -> Option<&FaidxEntry> {
+        self.entries.get(seqname).or_else(||
+            self.aliases.get(seqname).and_then(|name| self.entries.get(name)))
+    }
+
+    pub fn len(&self, seqname: &str) -> Option<u64> {
+        self.entry(seqname).map(|e| e.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Fetch the 0-based half-open range of a sequence.
+    pub fn fetch(&self, seqname: &str, range: Range<u64>) -> Result<Vec<u8>> {
+        let entry = self.entry(seqname).ok_or_else(||
+            anyhow!("Could not find fasta sequence for {} in file {}!", seqname, self.path))?;
+        if range.start > range.end || range.end > entry.len {
+            return Err(anyhow!("Range {}..{} exceeds sequence for {} of length {} in file {}!",
+                range.start, range.end, seqname, entry.len, self.path));
+        }
+        if range.start == range.end {
+            return Ok(Vec::new());
+        }
+        let start = entry.position(range.start);
+        let end = entry.position(range.end-1)+1;
+        let mut buf = vec![0u8; (end-start) as usize];
+        {   let mut reader = self.reader.lock().map_err(|_| anyhow!("Genome reader lock was poisoned"))?;
+            reader.seek(SeekFrom::Start(start))?;
+            reader.read_exact(&mut buf)?;
+        }
+        buf.retain(|b| *b != b'\n' && *b != b'\r');
+        Ok(buf)
+    }
+}
+
+fn read_fai(fai_file: &str) -> Result<LinkedHashMap<String,FaidxEntry>> {
+    let mut entries = LinkedHashMap::new();
+    let file = BufReader::new(File::open(fai_file)?);
+    for line in file.lines() {
+        let line = line?;
+        if line.is_empty() { continue }
+        let cols = line.split('\t').collect::<Vec<_>>();
+        if cols.len() < 5 {
+            return Err(anyhow!("Could not parse line \"{}\" of index file {}", line, fai_file));
+        }
+        let parse = |col: &str| col.parse::<u64>().map_err(|e|
+            anyhow!("Could not parse \"{}\" on line \"{}\" of index file {}: {}", col, line, fai_file, e));
+        let entry = FaidxEntry {
+            name: cols[0].to_string(),
+            len: parse(cols[1])?,
+            offset: parse(cols[2])?,
+            line_bases: parse(cols[3])?,
+            line_width: parse(cols[4])?,
+        };
+        if entry.line_bases == 0 && entry.len > 0 {
+            return Err(anyhow!("Invalid line length for {} in index file {}", entry.name, fai_file));
+        }
+        entries.insert(entry.name.clone(), entry);
+    }
+    Ok(entries)
+}
+
+fn write_fai(fai_file: &str, entries: &LinkedHashMap<String,FaidxEntry>) -> Result<()> {
+    let mut output = BufWriter::new(File::create(fai_file)?);
+    for e in entries.values() {
+        writeln!(output, "{}\t{}\t{}\t{}\t{}", e.name, e.len, e.offset, e.line_bases, e.line_width)?;
+    }
+    Ok(())
+}
+
+// Scan a FASTA file and index it. As with samtools faidx, every sequence
+// line but the last of each record must have the same length.
+fn build_fai(reader: &mut Box<dyn ReadSeek>, path: &str) -> Result<LinkedHashMap<String,FaidxEntry>> {
+    let mut entries = LinkedHashMap::<String,FaidxEntry>::new();
+    let mut input = BufReader::new(reader);
+    let mut buf = Vec::<u8>::new();
+    let mut pos = 0u64;
+    let mut lineno = 0;
+    let mut entry: Option<FaidxEntry> = None;
+    // set once a record has a line shorter than its first line
+    let mut short_line = false;
+    // set while skipping the features of a GFF3 file before its ##FASTA section
+    let mut in_gff = false;
+    loop {
+        buf.clear();
+        let width = input.read_until(b'\n', &mut buf)? as u64;
+        if width == 0 { break }
+        lineno += 1;
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if lineno == 1 && line.starts_with(b"##gff-version") {
+            in_gff = true;
+        }
+        if in_gff {
+            in_gff = !line.starts_with(b"##FASTA");
+        }
+        else if line.starts_with(b">") {
+            if let Some(entry) = entry.take() {
+                entries.insert(entry.name.clone(), entry);
+            }
+            let name = String::from_utf8_lossy(&line[1..]).split_whitespace().next().unwrap_or("").to_string();
+            if entries.contains_key(&name) {
+                return Err(anyhow!("Duplicate sequence name {} on line {} of {}", name, lineno, path));
+            }
+            entry = Some(FaidxEntry { name: name, len: 0, offset: pos+width, line_bases: 0, line_width: 0 });
+            short_line = false;
+        }
+        else if let Some(ref mut entry) = entry {
+            let bases = line.len() as u64;
+            if bases > 0 {
+                if entry.line_bases == 0 {
+                    entry.line_bases = bases;
+                    entry.line_width = width;
+                }
+                else if short_line || bases > entry.line_bases ||
+                    (bases == entry.line_bases && width != entry.line_width && buf.ends_with(b"\n"))
+                {
+                    return Err(anyhow!("Different line length in sequence {} on line {} of {}", entry.name, lineno, path));
+                }
+                short_line = bases < entry.line_bases;
+                entry.len += bases;
+            }
+            else { short_line = true }
+        }
+        else if !line.is_empty() {
+            return Err(anyhow!("Sequence data before the first header on line {} of {}", lineno, path));
+        }
+        pos += width;
+    }
+    if let Some(entry) = entry.take() {
+        entries.insert(entry.name.clone(), entry);
+    }
+    Ok(entries)
+}
+
+// A seekable reader over the uncompressed contents of a BGZF file. Each
+// block is a complete gzip member, so a seek only decompresses the block
+// that holds the target offset.
+struct BgzfReader {
+    file: File,
+    file_len: u64,
+    // (compressed offset, uncompressed offset) of each block
+    blocks: Vec<(u64,u64)>,
+    uncompressed_len: u64,
+    pos: u64,
+    cache: Option<(usize,Vec<u8>)>,
+}
+
+impl BgzfReader {
+    fn open(path: &str, mut file: File) -> Result<BgzfReader> {
+        let file_len = file.metadata()?.len();
+        let gzi_file = format!("{}.gzi", path);
+        let mut blocks = if Path::new(&gzi_file).exists() {
+            read_gzi(&gzi_file)?
+        } else {
+            let blocks = scan_blocks(&mut file, file_len, path)?;
+            if let Err(e) = write_gzi(&gzi_file, &blocks) {
+                eprintln!("Could not write index file {:?}: {}", gzi_file, e);
+            }
+            blocks
+        };
+        // the .gzi leaves out the first block, and the final block tells
+        // us the uncompressed length
+        blocks.insert(0, (0, 0));
+        let &(last_coffset, last_uoffset) = blocks.last().ok_or(anyhow!("NoneError"))?;
+        let mut last_block = Vec::new();
+        file.seek(SeekFrom::Start(last_coffset))?;
+        GzDecoder::new((&mut file).take(file_len-last_coffset)).read_to_end(&mut last_block)?;
+        Ok(BgzfReader {
+            file: file,
+            file_len: file_len,
+            blocks: blocks,
+            uncompressed_len: last_uoffset + last_block.len() as u64,
+            pos: 0,
+            cache: None,
+        })
+    }
+
+    fn load_block(&mut self, block: usize) -> std::io::Result<()> {
+        if self.cache.as_ref().map(|c| c.0 == block).unwrap_or(false) { return Ok(()) }
+        let coffset = self.blocks[block].0;
+        let cend = self.blocks.get(block+1).map(|b| b.0).unwrap_or(self.file_len);
+        self.file.seek(SeekFrom::Start(coffset))?;
+        let mut data = Vec::new();
+        GzDecoder::new((&mut self.file).take(cend-coffset)).read_to_end(&mut data)?;
+        self.cache = Some((block, data));
+        Ok(())
+    }
+}
+
+impl Read for BgzfReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.uncompressed_len || buf.is_empty() { return Ok(0) }
+        // empty blocks share their offset with the next block, so take the
+        // last block starting at or before pos
+        let block = self.blocks.partition_point(|b| b.1 <= self.pos) - 1;
+        self.load_block(block)?;
+        let data = match self.cache {
+            Some((_, ref data)) => data,
+            None => return Ok(0),
+        };
+        let offset = (self.pos - self.blocks[block].1) as usize;
+        if offset >= data.len() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupt BGZF block index"));
+        }
+        let n = std::cmp::min(buf.len(), data.len()-offset);
+        buf[..n].copy_from_slice(&data[offset..offset+n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BgzfReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.uncompressed_len as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if pos < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before the start of the file"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+// Find the offsets of every block after the first by reading the block
+// sizes from the BGZF headers and the uncompressed sizes from the footers.
+fn scan_blocks(file: &mut File, file_len: u64, path: &str) -> Result<Vec<(u64,u64)>> {
+    let mut blocks = Vec::new();
+    let mut coffset = 0u64;
+    let mut uoffset = 0u64;
+    while coffset < file_len {
+        let mut header = [0u8; 12];
+        file.seek(SeekFrom::Start(coffset))?;
+        file.read_exact(&mut header)?;
+        if header[..2] != [0x1f, 0x8b] || header[3] & 4 == 0 {
+            return Err(anyhow!("Invalid BGZF block at offset {} of {}", coffset, path));
+        }
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0u8; xlen];
+        file.read_exact(&mut extra)?;
+        // find the BC subfield holding the block size
+        let mut bsize = None;
+        let mut i = 0;
+        while i + 4 <= xlen {
+            let slen = u16::from_le_bytes([extra[i+2], extra[i+3]]) as usize;
+            if extra[i] == b'B' && extra[i+1] == b'C' && slen == 2 && i + 6 <= xlen {
+                bsize = Some(u16::from_le_bytes([extra[i+4], extra[i+5]]) as u64 + 1);
+            }
+            i += 4 + slen;
+        }
+        let bsize = bsize.ok_or_else(|| anyhow!("Missing BGZF block size at offset {} of {}", coffset, path))?;
+        let mut isize = [0u8; 4];
+        file.seek(SeekFrom::Start(coffset + bsize - 4))?;
+        file.read_exact(&mut isize)?;
+        coffset += bsize;
+        uoffset += u32::from_le_bytes(isize) as u64;
+        if coffset < file_len {
+            blocks.push((coffset, uoffset));
+        }
+    }
+    Ok(blocks)
+}
+
+fn read_gzi(gzi_file: &str) -> Result<Vec<(u64,u64)>> {
+    let mut data = Vec::new();
+    File::open(gzi_file)?.read_to_end(&mut data)?;
+    let word = |i: usize| -> Result<u64> {
+        let bytes = data.get(i*8..i*8+8).ok_or_else(|| anyhow!("Truncated index file {}", gzi_file))?;
+        let mut word = [0u8; 8];
+        word.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(word))
+    };
+    let count = word(0)? as usize;
+    let mut blocks = Vec::with_capacity(count);
+    for i in 0..count {
+        blocks.push((word(1+i*2)?, word(2+i*2)?));
+    }
+    Ok(blocks)
+}
+
+fn write_gzi(gzi_file: &str, blocks: &[(u64,u64)]) -> Result<()> {
+    let mut output = BufWriter::new(File::create(gzi_file)?);
+    output.write_all(&(blocks.len() as u64).to_le_bytes())?;
+    for (coffset, uoffset) in blocks {
+        output.write_all(&coffset.to_le_bytes())?;
+        output.write_all(&uoffset.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    const FASTA: &str = ">chr1 description\nACGTACGTAC\nGTACGTACGT\nacg\n>chr2\nTTTTGGGG\nCC\n";
+    // the index samtools faidx writes for FASTA
+    const FAI: &str = "chr1\t23\t18\t10\t11\nchr2\t10\t50\t8\t9\n";
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir().join(format!("faidx_{}_{}", std::process::id(), name)).to_str().unwrap().to_string()
+    }
+
+    // remove a test file and the index files made next to it
+    fn remove(path: &str) {
+        for file in &[path.to_string(), format!("{}.fai", path), format!("{}.gzi", path)] {
+            if Path::new(file).exists() { std::fs::remove_file(file).unwrap() }
+        }
+    }
+
+    // one BGZF block: a gzip member with the BC extra subfield holding the
+    // block size minus one
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new().
+            extra(vec![b'B', b'C', 2, 0, 0, 0]).
+            write(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        let mut block = encoder.finish().unwrap();
+        let bsize = (block.len() - 1) as u16;
+        block[16..18].copy_from_slice(&bsize.to_le_bytes());
+        block
+    }
+
+    // every fetchable range of every sequence matches the sequence
+    fn assert_fetches(genome: &Faidx, sequences: &[(&str, &str)]) {
+        for (name, seq) in sequences {
+            assert_eq!(genome.len(name), Some(seq.len() as u64));
+            for start in 0..=seq.len() {
+                for end in start..=seq.len() {
+                    assert_eq!(genome.fetch(name, start as u64..end as u64).unwrap(), &seq.as_bytes()[start..end],
+                        "{} {}..{}", name, start, end);
+                }
+            }
+            assert!(genome.fetch(name, 0..seq.len() as u64 + 1).is_err());
+        }
+        assert!(genome.fetch("chrUn", 0..1).is_err());
+        assert_eq!(genome.len("chrUn"), None);
+    }
+
+    const SEQUENCES: [(&str, &str); 2] = [("chr1", "ACGTACGTACGTACGTACGTacg"), ("chr2", "TTTTGGGGCC")];
+
+    #[test]
+    fn plain_fasta() {
+        let path = temp_file("plain.fa");
+        std::fs::write(&path, FASTA).unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(format!("{}.fai", path)).unwrap(), FAI);
+        assert_eq!(genome.sequences().map(|e| e.name.as_str()).collect::<Vec<_>>(), vec!["chr1", "chr2"]);
+        assert_fetches(&genome, &SEQUENCES);
+        // the second open reads the written index
+        let genome = Faidx::open(&path).unwrap();
+        assert_fetches(&genome, &SEQUENCES);
+        remove(&path);
+
+        // Windows line endings count toward the line width
+        std::fs::write(&path, ">chr1\r\nACGT\r\nAC\r\n").unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(format!("{}.fai", path)).unwrap(), "chr1\t6\t7\t4\t6\n");
+        assert_fetches(&genome, &[("chr1", "ACGTAC")]);
+        remove(&path);
+
+        // lines of a sequence other than the last must be the same length
+        std::fs::write(&path, ">chr1\nACGT\nAC\nACGT\n").unwrap();
+        assert!(Faidx::open(&path).is_err());
+        remove(&path);
+    }
+
+    #[test]
+    fn bgzf_fasta() {
+        // small blocks, so that most fetches cross a block boundary
+        let chunks = FASTA.as_bytes().chunks(7).chain(std::iter::once(&b""[..])).collect::<Vec<_>>();
+        let blocks = chunks.iter().map(|c| bgzf_block(c)).collect::<Vec<_>>();
+        let path = temp_file("bgzf.fa.gz");
+        std::fs::write(&path, blocks.concat()).unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(format!("{}.fai", path)).unwrap(), FAI);
+        assert_fetches(&genome, &SEQUENCES);
+        // the .gzi lists every block after the first, including the empty
+        // end of file block
+        let mut expected = Vec::new();
+        let (mut coffset, mut uoffset) = (0, 0);
+        for (chunk, block) in chunks.iter().zip(&blocks).take(blocks.len()-1) {
+            coffset += block.len() as u64;
+            uoffset += chunk.len() as u64;
+            expected.push((coffset, uoffset));
+        }
+        assert_eq!(read_gzi(&format!("{}.gzi", path)).unwrap(), expected);
+        // and is used when the file is opened again
+        let genome = Faidx::open(&path).unwrap();
+        assert_fetches(&genome, &SEQUENCES);
+        remove(&path);
+    }
+
+    #[test]
+    fn gzip_fasta() {
+        let path = temp_file("gzip.fa.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(FASTA.as_bytes()).unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(format!("{}.fai", path)).unwrap(), FAI);
+        assert!(!Path::new(&format!("{}.gzi", path)).exists());
+        assert_fetches(&genome, &SEQUENCES);
+        remove(&path);
+    }
+
+    #[test]
+    fn gff_fasta() {
+        let path = temp_file("genome.gff");
+        let features = "##gff-version 3\nchr1\tt\tgene\t1\t5\t.\t+\t.\tID=g1\n##FASTA\n";
+        std::fs::write(&path, format!("{}{}", features, FASTA)).unwrap();
+        let genome = Faidx::open(&path).unwrap();
+        // the offsets are those of FASTA after the features
+        let offset = features.len() as u64;
+        assert_eq!(genome.sequences().map(|e| e.offset).collect::<Vec<_>>(), vec![offset+18, offset+50]);
+        assert_fetches(&genome, &SEQUENCES);
+        remove(&path);
+    }
+
+    #[test]
+    fn gzi_round_trip() {
+        let path = temp_file("round_trip.gzi");
+        for blocks in &[vec![], vec![(10, 20)], vec![(100, 65280), (250, 130560), (u64::MAX, 1)]] {
+            write_gzi(&path, blocks).unwrap();
+            assert_eq!(read_gzi(&path).unwrap(), *blocks);
+        }
+        // a truncated index is an error
+        std::fs::write(&path, 2u64.to_le_bytes()).unwrap();
+        assert!(read_gzi(&path).is_err());
+        remove(&path);
+    }
+}
@@ -0,0 +1,480 @@
+//! Filter expressions for selecting annotation features by their columns
+//! and attributes, such as
+//! `gene_biotype=protein_coding and tag contains basic and transcript_support_level<=2`.
+//!
+//! A test names a column or attribute, and is true if any of its values
+//! passes:
+//!
+//! - `name` is true if the attribute is set
+//! - `name=value`, `name!=value`: exact comparison. `!=` is the negation
+//!   of `=`, so it passes features without the attribute, as does `!~`
+//! - `name<number`, `<=`, `>`, `>=`: numeric comparison of the leading
+//!   number of each value, so `1 (assigned to previous version 5)` counts
+//!   as 1; values that don't start with a number never pass
+//! - `name contains text`: substring match
+//! - `name~regex`, `name!~regex`: regular expression match
+//! - `name in (a, b, c)`, or `name in @file` with one value per line of
+//!   the file: list membership
+//!
+//! Tests can be combined with `and`, `or` and `not` (or `&&`, `||` and `!`)
+//! and grouped with parentheses. Values can be quoted with `"` or `'`.
+//! `seqname`, `source`, `type`, `start`, `end`, `score`, `strand` and
+//! `frame` name the columns, and any other name an attribute.
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::str::FromStr;
+use regex::Regex;
+use anyhow::{Result, anyhow};
+
+use crate::open_input;
+use crate::indexed_annotation::Record;
+
+/// A parsed filter expression.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Test(String, Test),
+}
+
+#[derive(Clone, Debug)]
+enum Test {
+    Exists,
+    Equals(String),
+    Contains(String),
+    Less(f64),
+    LessEq(f64),
+    Greater(f64),
+    GreaterEq(f64),
+    Matches(Regex),
+    In(HashSet<String>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Comma,
+    Op(&'static str),
+    Word(String),
+    Quoted(String),
+}
+
+const OPS: &[&str] = &["==", "!=", "<=", ">=", "!~", "&&", "||", "=", "<", ">", "~", "!"];
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Filter> {
+        let tokens = tokenize(text).map_err(|e| anyhow!("Invalid filter {:?}: {}", text, e))?;
+        let mut parser = Parser { tokens: tokens, pos: 0 };
+        let expr = parser.parse().map_err(|e| anyhow!("Invalid filter {:?}: {}", text, e))?;
+        Ok(Filter { expr: expr })
+    }
+
+    /// Does a feature pass the filter? `records` are the feature followed
+    /// by its ancestors, nearest first. Attributes that the feature doesn't
+    /// have are looked up in its ancestors, and columns are the feature's
+    /// own.
+    pub fn matches(&self, records: &[&Record]) -> bool {
+        self.expr.eval(records)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Filter> {
+        Filter::parse(s)
+    }
+}
+
+impl Expr {
+    fn eval(&self, records: &[&Record]) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(records) && b.eval(records),
+            Expr::Or(a, b) => a.eval(records) || b.eval(records),
+            Expr::Not(a) => !a.eval(records),
+            Expr::Test(name, test) => {
+                let values = values(name, records);
+                match test {
+                    Test::Exists => !values.is_empty(),
+                    Test::Equals(v) => values.iter().any(|value| value == v),
+                    Test::Contains(v) => values.iter().any(|value| value.contains(v.as_str())),
+                    Test::Less(n) => values.iter().filter_map(|value| number(value)).any(|value| value < *n),
+                    Test::LessEq(n) => values.iter().filter_map(|value| number(value)).any(|value| value <= *n),
+                    Test::Greater(n) => values.iter().filter_map(|value| number(value)).any(|value| value > *n),
+                    Test::GreaterEq(n) => values.iter().filter_map(|value| number(value)).any(|value| value >= *n),
+                    Test::Matches(re) => values.iter().any(|value| re.is_match(value)),
+                    Test::In(set) => values.iter().any(|value| set.contains(value.as_ref())),
+                }
+            }
+        }
+    }
+}
+
+// the values of a column of the first record, or of an attribute of the
+// first record that has it
+fn values<'a>(name: &str, records: &[&'a Record]) -> Vec<Cow<'a, str>> {
+    let record = match records.get(0) {
+        Some(record) => record,
+        None => return Vec::new(),
+    };
+    let column = match name {
+        "seqname" => Some(Cow::Borrowed(record.seqname.as_str())),
+        "source" => Some(Cow::Borrowed(record.source.as_str())),
+        "type" => Some(Cow::Borrowed(record.feature_type.as_str())),
+        "start" => Some(Cow::Owned(record.start.to_string())),
+        "end" => Some(Cow::Owned(record.end.to_string())),
+        "score" => Some(Cow::Borrowed(record.score.as_str())),
+        "strand" => Some(Cow::Borrowed(record.strand.as_str())),
+        "frame" => Some(Cow::Borrowed(record.frame.as_str())),
+        _ => None,
+    };
+    if let Some(column) = column {
+        return vec![column];
+    }
+    records.iter().
+        map(|r| r.attributes.get_all(name)).
+        find(|values| !values.is_empty()).
+        map(|values| values.iter().map(|v| Cow::Borrowed(v.as_str())).collect()).
+        unwrap_or_default()
+}
+
+// the leading number of a value
+fn number(value: &str) -> Option<f64> {
+    value.split_whitespace().next()?.parse::<f64>().ok()
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c == '(' {
+            tokens.push(Token::Open);
+            rest = &rest[1..];
+        }
+        else if c == ')' {
+            tokens.push(Token::Close);
+            rest = &rest[1..];
+        }
+        else if c == ',' {
+            tokens.push(Token::Comma);
+            rest = &rest[1..];
+        }
+        else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let mut end = None;
+            while let Some((i, ch)) = chars.next() {
+                if ch == c {
+                    end = Some(i + 2);
+                    break;
+                }
+                else if ch == '\\' {
+                    if let Some((_, escaped)) = chars.next() {
+                        value.push(escaped);
+                    }
+                }
+                else {
+                    value.push(ch);
+                }
+            }
+            let end = end.ok_or(anyhow!("Unterminated quoted value"))?;
+            tokens.push(Token::Quoted(value));
+            rest = &rest[end..];
+        }
+        else if let Some(op) = OPS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        else {
+            let end = rest.find(|ch: char| ch.is_whitespace() || "(),\"'=!<>~&|".contains(ch)).unwrap_or(rest.len());
+            if end == 0 {
+                return Err(anyhow!("Unexpected character {:?}", c));
+            }
+            tokens.push(Token::Word(rest[..end].to_string()));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn parse(&mut self) -> Result<Expr> {
+        if self.tokens.is_empty() {
+            return Err(anyhow!("Empty expression"));
+        }
+        let expr = self.parse_or()?;
+        if let Some(token) = self.peek() {
+            return Err(anyhow!("Unexpected {}", describe(token)));
+        }
+        Ok(expr)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // is the next token the operator or unquoted keyword `word`? If so,
+    // consume it.
+    fn accept(&mut self, word: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Op(op)) => *op == word,
+            Some(Token::Word(w)) => w == word,
+            _ => false,
+        };
+        if found { self.pos += 1 }
+        found
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.accept("or") || self.accept("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        while self.accept("and") || self.accept("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.accept("not") || self.accept("!") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let expr = self.parse_or()?;
+            if self.next() != Some(Token::Close) {
+                return Err(anyhow!("Missing )"));
+            }
+            return Ok(expr);
+        }
+        self.parse_test()
+    }
+
+    fn parse_test(&mut self) -> Result<Expr> {
+        let name = match self.next() {
+            Some(Token::Word(name)) if !["and", "or", "not", "in", "contains"].contains(&name.as_str()) => name,
+            Some(Token::Quoted(name)) => name,
+            Some(token) => return Err(anyhow!("Expected a column or attribute name, found {}", describe(&token))),
+            None => return Err(anyhow!("Expected a column or attribute name at the end")),
+        };
+        let test = |test| Expr::Test(name.clone(), test);
+        let expr = if self.accept("=") || self.accept("==") {
+            test(Test::Equals(self.value()?))
+        }
+        else if self.accept("!=") {
+            Expr::Not(Box::new(test(Test::Equals(self.value()?))))
+        }
+        else if self.accept("<") {
+            test(Test::Less(self.number()?))
+        }
+        else if self.accept("<=") {
+            test(Test::LessEq(self.number()?))
+        }
+        else if self.accept(">") {
+            test(Test::Greater(self.number()?))
+        }
+        else if self.accept(">=") {
+            test(Test::GreaterEq(self.number()?))
+        }
+        else if self.accept("~") {
+            test(Test::Matches(self.regex()?))
+        }
+        else if self.accept("!~") {
+            Expr::Not(Box::new(test(Test::Matches(self.regex()?))))
+        }
+        else if self.accept("contains") {
+            test(Test::Contains(self.value()?))
+        }
+        else if self.accept("in") {
+            test(Test::In(self.list()?))
+        }
+        else {
+            test(Test::Exists)
+        };
+        Ok(expr)
+    }
+
+    fn value(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => Ok(value),
+            Some(token) => Err(anyhow!("Expected a value, found {}", describe(&token))),
+            None => Err(anyhow!("Expected a value at the end")),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        let value = self.value()?;
+        value.parse::<f64>().map_err(|_| anyhow!("Expected a number, found {:?}", value))
+    }
+
+    fn regex(&mut self) -> Result<Regex> {
+        Ok(Regex::new(&self.value()?)?)
+    }
+
+    // a parenthesized list of values, or @file
+    fn list(&mut self) -> Result<HashSet<String>> {
+        match self.next() {
+            Some(Token::Word(ref word)) if word.starts_with('@') => read_list(&word[1..]),
+            Some(Token::Open) => {
+                let mut values = HashSet::new();
+                loop {
+                    values.insert(self.value()?);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::Close) => break,
+                        _ => return Err(anyhow!("Expected , or ) in list")),
+                    }
+                }
+                Ok(values)
+            }
+            _ => Err(anyhow!("Expected a list like (a, b) or @file after in")),
+        }
+    }
+}
+
+// read a list of values, one per line. Only the first whitespace-delimited
+// column is used, and empty lines and lines starting with # are skipped.
+fn read_list(file: &str) -> Result<HashSet<String>> {
+    let mut values = HashSet::new();
+    let mut input = open_input(file).map_err(|e| anyhow!("Could not read list file {}: {}", file, e))?;
+    let mut buf = String::new();
+    while input.read_line(&mut buf)? > 0 {
+        if !buf.starts_with('#') {
+            if let Some(value) = buf.split_whitespace().next() {
+                values.insert(value.to_string());
+            }
+        }
+        buf.clear();
+    }
+    Ok(values)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Open => "(".to_string(),
+        Token::Close => ")".to_string(),
+        Token::Comma => ",".to_string(),
+        Token::Op(op) => op.to_string(),
+        Token::Word(word) => format!("{:?}", word),
+        Token::Quoted(word) => format!("{:?}", word),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::Sym;
+
+    fn record(attributes: &[(&str, &str)]) -> Record {
+        let mut record = Record::new();
+        record.feature_type = Sym::new("exon");
+        for (key, value) in attributes {
+            record.attributes.push(*key, *value);
+        }
+        record
+    }
+
+    fn matches(filter: &str, records: &[&Record]) -> bool {
+        Filter::parse(filter).unwrap().matches(records)
+    }
+
+    #[test]
+    fn precedence() {
+        let a = record(&[("a", "1")]);
+        let none = record(&[]);
+        // and binds tighter than or, and not tighter than and
+        assert!(matches("a or b and c", &[&a]));
+        assert!(matches("a || b && c", &[&a]));
+        assert!(!matches("(a or b) and c", &[&a]));
+        assert!(!matches("not a and b", &[&none]));
+        assert!(!matches("! a && b", &[&none]));
+        assert!(matches("not (a and b)", &[&none]));
+        assert!(matches("not not a", &[&a]));
+        assert!(matches("b or not b and a", &[&a]));
+        assert!(matches("type=exon and a=1", &[&a]));
+        assert!(matches("type = exon and a == 1", &[&a]));
+    }
+
+    #[test]
+    fn not_equal_on_missing_attributes() {
+        let pseudogene = record(&[("gene_biotype", "pseudogene")]);
+        let coding = record(&[("gene_biotype", "protein_coding")]);
+        let none = record(&[]);
+        assert!(!matches("gene_biotype!=pseudogene", &[&pseudogene]));
+        assert!(matches("gene_biotype!=pseudogene", &[&coding]));
+        assert!(matches("gene_biotype!=pseudogene", &[&none]));
+        assert!(!matches("gene_biotype=pseudogene", &[&none]));
+        assert!(matches("gene_biotype!~pseudo", &[&none]));
+        // an attribute the feature doesn't have is looked up in its ancestors
+        assert!(!matches("gene_biotype!=pseudogene", &[&none, &pseudogene]));
+        assert!(matches("gene_biotype!=pseudogene", &[&coding, &pseudogene]));
+        // with several values, != fails if any value is equal
+        let tags = record(&[("tag", "basic"), ("tag", "CCDS")]);
+        assert!(matches("tag=CCDS", &[&tags]));
+        assert!(!matches("tag!=CCDS", &[&tags]));
+        // numeric tests never pass without a number
+        assert!(!matches("level<3", &[&none]));
+        assert!(matches("not level<3", &[&none]));
+    }
+
+    #[test]
+    fn in_file() {
+        let file = std::env::temp_dir().join(format!("feature_filter_{}.txt", std::process::id()));
+        let file = file.to_str().unwrap();
+        std::fs::write(file, "# gene ids\nENSG1\n\nENSG2\tsecond gene\n").unwrap();
+        let filter = Filter::parse(&format!("gene_id in @{}", file)).unwrap();
+        std::fs::remove_file(file).unwrap();
+        for (gene_id, pass) in &[("ENSG1", true), ("ENSG2", true), ("ENSG3", false), ("#", false), ("second", false)] {
+            assert_eq!(filter.matches(&[&record(&[("gene_id", gene_id)])]), *pass, "{}", gene_id);
+        }
+        assert!(!filter.matches(&[&record(&[])]));
+        assert!(Filter::parse(&format!("gene_id in @{}", file)).is_err());
+        assert!(matches("gene_id in (ENSG1, 'ENSG 2')", &[&record(&[("gene_id", "ENSG 2")])]));
+        assert!(Filter::parse("gene_id in (ENSG1").is_err());
+    }
+
+    #[test]
+    fn quote_escapes() {
+        let note = record(&[("note", "say \"hi\"; it's ok"), ("odd name", "a\\b")]);
+        assert!(matches(r#"note="say \"hi\"; it's ok""#, &[&note]));
+        assert!(matches(r#"note='say "hi"; it\'s ok'"#, &[&note]));
+        assert!(matches(r#"note contains "\"hi\"""#, &[&note]));
+        assert!(matches(r#""odd name"="a\\b""#, &[&note]));
+        assert!(!matches(r#""odd name"="a\b""#, &[&note]));
+        assert!(matches(r#"note~"^say \"""#, &[&note]));
+        assert!(Filter::parse(r#"note="say"#).is_err());
+        assert!(Filter::parse(r#"note="say\""#).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        for filter in &["", "a and", "(a", "a)", "a = ", "and", "a < b", "a ~ (", "a = b c"] {
+            assert!(Filter::parse(filter).is_err(), "{:?}", filter);
+        }
+    }
+}
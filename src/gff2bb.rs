@@ -6,6 +6,9 @@ use anyhow::{Result, anyhow};
 
 use cassette_reannotation::*;
 use cassette_reannotation::indexed_annotation::*;
+use cassette_reannotation::annotation_formats::annotation_format;
+use cassette_reannotation::chrom_alias::ChromAliases;
+use cassette_reannotation::feature_filter::Filter;
 
 use structopt::StructOpt;
 
@@ -13,15 +16,25 @@ use structopt::StructOpt;
 #[structopt(name = "gff2bb", about = "Convert GFF/GTF to bigBed format")]
 struct Options {
     // input files
-    #[structopt(long="gff", help = "A genome annotation file in gff3 format", name="ANNOT_GFF_FILE")]
+    #[structopt(long="gff", help = "A genome annotation file in gff3 format, optionally gzipped, or - for stdin", name="ANNOT_GFF_FILE")]
     annotfile_gff: Option<String>,
-    #[structopt(long="gtf", help = "A genome annotation file in gtf format", name="ANNOT_GTF_FILE")]
+    #[structopt(long="gtf", help = "A genome annotation file in gtf format, optionally gzipped, or - for stdin", name="ANNOT_GTF_FILE")]
     annotfile_gtf: Option<String>,
+    #[structopt(long="annot", help = "A genome annotation file in gff3, gtf, bed12, bigbed, genepred or refflat format, optionally gzipped, or - for stdin", name="ANNOT_FILE")]
+    annotfile: Option<String>,
+    #[structopt(long="annot_format", help = "The format of the --annot file: gff, gtf, bed, bigbed, genepred or refflat. Guessed from the file extension if not given.", name="ANNOT_FORMAT")]
+    annot_format: Option<String>,
     #[structopt(long="chrmap", help = "Optional tab-delimited chr name mapping file", name="CHRMAP_FILE")]
     chrmap_file: Option<String>,
     #[structopt(long="vizchrmap", help = "Optional tab-delimited chr name mapping file for bigwig/bigbed exports", name="VIZCHRMAP_FILE")]
     vizchrmap_file: Option<String>,
-    #[structopt(long="sizes", help = "Optional chr sizes file", name="SIZES_FILE")]
+    #[structopt(long="chromalias", help = "Optional UCSC chromAlias.txt file or other tab-delimited table of equivalent chr names", name="CHROM_ALIAS_FILE")]
+    chromalias_file: Option<String>,
+    #[structopt(long="strict", help = "Fail on malformed annotation lines instead of skipping them")]
+    strict: bool,
+    #[structopt(long="no_annotation_cache", help = "Always parse the annotation file instead of reading or writing its cache")]
+    no_annotation_cache: bool,
+    #[structopt(long="sizes", help = "Optional chr sizes file, such as a FASTA .fai index", name="SIZES_FILE")]
     sizes_file: Option<String>,
     // output file
     #[structopt(long="out", short="o", help = "Output file", name="OUT_FILE", default_value="-")]
@@ -35,6 +48,8 @@ struct Options {
     gene_type: Vec<String>,
     #[structopt(long="cds_type", help = "The CDS type(s) to search for", name="CDS_TYPE")]
     cds_type: Vec<String>,
+    #[structopt(long="filter", help = "Only use the annotation features that pass a filter expression, such as \"gene_biotype=protein_coding and tag contains basic\"", name="FILTER")]
+    filter: Option<Filter>,
     #[structopt(long="trackdb", help = "Write a UCSC trackDb.txt file with all the bigwigs/bigbeds", name="TRACKDB_FILE")]
     trackdb: Option<String>,
 }
@@ -59,24 +74,50 @@ fn run() -> Result<()> {
     let transcript_type = String::from("transcript");
     let mut annot = if let Some(annotfile_gff) = options.annotfile_gff.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gff);
-        IndexedAnnotation::from_gff(
+        IndexedAnnotation::from_file_with(
             &annotfile_gff, 
+            "gff",
+            "",
+            "",
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else if let Some(annotfile_gtf) = options.annotfile_gtf.clone() {
         eprintln!("Reading annotation file {:?}", &annotfile_gtf);
-        IndexedAnnotation::from_gtf(&annotfile_gtf, 
+        IndexedAnnotation::from_file_with(&annotfile_gtf, 
+            "gtf",
             options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
             options.transcript_type.get(0).unwrap_or(&transcript_type),
             &options.chrmap_file,
-            &options.vizchrmap_file)?
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
+    } else if let Some(annotfile) = options.annotfile.clone() {
+        let format = annotation_format(&annotfile, &options.annot_format)?;
+        eprintln!("Reading {} annotation file {:?}", format, &annotfile);
+        IndexedAnnotation::from_file_with(&annotfile, 
+            &format,
+            options.gene_type.get(0).ok_or(anyhow!("NoneError"))?,
+            options.transcript_type.get(0).unwrap_or(&transcript_type),
+            &options.chrmap_file,
+            &options.vizchrmap_file,
+            options.strict,
+            !options.no_annotation_cache)?
     } else {
         Options::clap().print_help()?;
         eprintln!("\n\nNo annotation file was given!");
         std::process::exit(1);
     };
+    if let Some(chromalias_file) = options.chromalias_file.clone() {
+        annot.set_chrom_aliases(ChromAliases::from_file(&chromalias_file)?);
+    }
+    if let Some(ref filter) = options.filter {
+        let removed = annot.retain_features(filter)?;
+        eprintln!("Removed {} annotation rows that did not pass the filter", removed.len());
+    }
     if let Some(sizes_file) = options.sizes_file {
-        annot.refs = read_sizes_file(&sizes_file, &annot.chrmap)?
+        annot.refs = read_sizes_file(&sizes_file, &annot)?
     }
 
     // set up the trackdb writer
@@ -2,15 +2,15 @@ use linked_hash_map::LinkedHashMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::BTreeSet;
+use std::collections::hash_map::Entry;
 use std::hash::{Hash, Hasher};
+use std::cmp::Ordering;
 use regex::Regex;
 use regex::Captures;
 use percent_encoding::{percent_decode, utf8_percent_encode, CONTROLS, AsciiSet};
-use bio::data_structures::interval_tree::IntervalTree;
-use bio::utils::Interval;
 use bio::alphabets::dna;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, BufRead, Write};
+use std::io::{BufWriter, BufRead, Cursor, Write};
 use std::io::stdout;
 use std::ops::Range;
 use std::path::Path;
@@ -18,20 +18,226 @@ use itertools::Itertools;
 use unindent::unindent;
 use lazy_static::lazy_static;
 use anyhow::{Result, anyhow};
-use duct::cmd;
+
+use crate::open_input;
+use crate::faidx::Faidx;
+use crate::annotation_cache;
+use crate::bigbed::{Bed12, sort_bed, write_bigbed, read_bigbed};
+use crate::annotation_formats::{ANNOTATION_FORMATS, TranscriptTable};
+use crate::chrom_alias::{ChromAliases, ChromMatcher};
+use crate::interval_index::SortedIntervals;
+use crate::feature_filter::Filter;
+pub use crate::symbol::Sym;
+
+/// The attributes of a feature, in file order. Every key keeps all of its
+/// values, from repeated GTF keys (`tag "basic"; tag "CCDS";`) or
+/// comma-separated GFF3 values (`Parent=a,b`).
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Attributes {
+    // features have few attributes, so a list is smaller and about as fast
+    // as a map
+    entries: Vec<(Sym, Vec<Sym>)>,
+}
+
+impl Attributes {
+    pub fn new() -> Attributes {
+        Attributes { entries: Vec::new() }
+    }
+    fn position(&self, key: &str) -> Option<usize> {
+        self.entries.iter().position(|(k, _)| *k == key)
+    }
+    /// The first value of a key.
+    pub fn get(&self, key: &str) -> Option<&Sym> {
+        self.position(key).and_then(|i| self.entries[i].1.first())
+    }
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Sym> {
+        self.position(key).and_then(move |i| self.entries[i].1.first_mut())
+    }
+    /// All values of a key, which is empty if the key is missing.
+    pub fn get_all(&self, key: &str) -> &[Sym] {
+        self.position(key).map(|i| self.entries[i].1.as_slice()).unwrap_or(&[])
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.position(key).is_some()
+    }
+    /// True if any value of the key is `value`, e.g. `has_value("tag", "basic")`.
+    pub fn has_value(&self, key: &str, value: &str) -> bool {
+        self.get_all(key).iter().any(|v| *v == value)
+    }
+    /// Set a key to a single value, replacing its values in place or
+    /// adding it at the end.
+    pub fn insert(&mut self, key: impl Into<Sym>, value: impl Into<Sym>) {
+        self.insert_all(key, vec![value]);
+    }
+    /// Set all the values of a key.
+    pub fn insert_all<V: Into<Sym>>(&mut self, key: impl Into<Sym>, values: Vec<V>) {
+        let key = key.into();
+        let values = values.into_iter().map(|v| v.into()).collect();
+        match self.position(&key) {
+            Some(i) => self.entries[i].1 = values,
+            None => self.entries.push((key, values)),
+        }
+    }
+    /// Add another value to a key.
+    pub fn push(&mut self, key: impl Into<Sym>, value: impl Into<Sym>) {
+        let key = key.into();
+        match self.position(&key) {
+            Some(i) => self.entries[i].1.push(value.into()),
+            None => self.entries.push((key, vec![value.into()])),
+        }
+    }
+    pub fn remove(&mut self, key: &str) -> Option<Vec<Sym>> {
+        self.position(key).map(|i| self.entries.remove(i).1)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn keys(&self) -> impl Iterator<Item=&Sym> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+    pub fn iter(&self) -> impl Iterator<Item=(&Sym, &Vec<Sym>)> {
+        self.entries.iter().map(|(k, vs)| (k, vs))
+    }
+}
+
+impl std::ops::Index<&str> for Attributes {
+    type Output = Sym;
+    fn index(&self, key: &str) -> &Sym {
+        self.get(key).expect("Attribute not found")
+    }
+}
+
+/// The strand column of a feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Strand {
+    Forward,
+    Reverse,
+    /// "." for features without a strand
+    #[default]
+    Unstranded,
+    /// "?" for stranded features whose strand is unknown
+    Unknown,
+}
+
+impl Strand {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+            Strand::Unstranded => ".",
+            Strand::Unknown => "?",
+        }
+    }
+}
+
+impl From<&str> for Strand {
+    /// Anything but "+", "-" and "?" reads as unstranded.
+    fn from(s: &str) -> Strand {
+        match s {
+            "+" => Strand::Forward,
+            "-" => Strand::Reverse,
+            "?" => Strand::Unknown,
+            _ => Strand::Unstranded,
+        }
+    }
+}
+
+/// The frame (phase) column of a feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Frame {
+    Zero,
+    One,
+    Two,
+    /// "." for features other than CDS
+    #[default]
+    None,
+}
+
+impl Frame {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Frame::Zero => "0",
+            Frame::One => "1",
+            Frame::Two => "2",
+            Frame::None => ".",
+        }
+    }
+    /// The number of bases before the first whole codon, 0 if there is no
+    /// frame.
+    pub fn phase(&self) -> u64 {
+        match self {
+            Frame::One => 1,
+            Frame::Two => 2,
+            _ => 0,
+        }
+    }
+}
+
+impl From<u64> for Frame {
+    /// The frame `n` modulo 3.
+    fn from(n: u64) -> Frame {
+        match n % 3 {
+            0 => Frame::Zero,
+            1 => Frame::One,
+            _ => Frame::Two,
+        }
+    }
+}
+
+impl From<&str> for Frame {
+    /// Anything but "0", "1" and "2" reads as no frame.
+    fn from(s: &str) -> Frame {
+        match s {
+            "0" => Frame::Zero,
+            "1" => Frame::One,
+            "2" => Frame::Two,
+            _ => Frame::None,
+        }
+    }
+}
+
+macro_rules! column_enum_impls {
+    ($t:ty) => {
+        impl std::fmt::Display for $t {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+        impl PartialEq<&str> for $t {
+            fn eq(&self, other: &&str) -> bool {
+                self.as_str() == *other
+            }
+        }
+        impl PartialEq<str> for $t {
+            fn eq(&self, other: &str) -> bool {
+                self.as_str() == other
+            }
+        }
+        impl PartialEq<String> for $t {
+            fn eq(&self, other: &String) -> bool {
+                self.as_str() == other
+            }
+        }
+    };
+}
+column_enum_impls!(Strand);
+column_enum_impls!(Frame);
 
 #[derive(Default, Clone, Debug)]
 pub struct Record {
     pub row: usize,
-    pub seqname: String,
-    pub source: String,
-    pub feature_type: String,
+    pub seqname: Sym,
+    pub source: Sym,
+    pub feature_type: Sym,
     pub start: u64,
     pub end: u64,
-    pub score: String,
-    pub strand: String,
-    pub frame: String,
-    pub attributes: LinkedHashMap<String, String>,
+    pub score: Sym,
+    pub strand: Strand,
+    pub frame: Frame,
+    pub attributes: Attributes,
 }
 
 impl std::fmt::Display for Record {
@@ -47,7 +253,7 @@ impl std::fmt::Display for Record {
             self.score,
             self.strand,
             self.frame,
-            self.attributes.iter().map(|(k,v)| format!(", {}=\"{}\"", k, v)).join(""))
+            self.attributes.iter().map(|(k,vs)| format!(", {}=\"{}\"", k, vs.iter().join(","))).join(""))
     }
 }
 
@@ -78,45 +284,135 @@ impl Record {
     pub fn new() -> Record {
         Record { ..Default::default() }
     }
+    /// The 0-based half-open range of the feature.
+    pub fn range(&self) -> Result<Range<u64>> {
+        if self.start == 0 || self.start > self.end+1 {
+            return Err(anyhow!("Invalid feature range {}..{}", self.start, self.end));
+        }
+        Ok((self.start-1)..self.end)
+    }
     pub fn from_row(row: usize, line: &str, filetype: &str, chrmap: &HashMap<String,String>) -> Result<Record> {
         lazy_static! {
-            static ref COMMENT: Regex = Regex::new(r"^#").unwrap();
-            static ref GTF_ATTR: Regex = Regex::new(r#"^(?P<key>\S+)\s+(?:"(?P<qval>[^"]*)"|(?P<val>\S+));\s*"#).unwrap();
+            static ref GTF_ATTR: Regex = Regex::new(r#"(?P<key>[^\s;]+)\s+(?:"(?P<qval>(?:[^"\\]|\\.)*)"|(?P<val>[^\s";]+));?\s*"#).unwrap();
+            static ref GTF_UNESCAPE: Regex = Regex::new(r#"\\(.)"#).unwrap();
         }
-        if COMMENT.is_match(line) {
-            anyhow!("Comment");
+        if is_comment(line) {
+            return Err(anyhow!("Comment"));
         }
         let fields: Vec<_> = line.split('\t').collect();
-        let seqname = String::from(*fields.get(0).unwrap_or(&""));
-        let seqname = chrmap.get(&seqname).unwrap_or(&seqname);
+        let seqname = *fields.get(0).unwrap_or(&"");
+        let seqname = chrmap.get(seqname).map(|s| s.as_str()).unwrap_or(seqname);
+        let start = fields.get(3).unwrap_or(&"0").parse::<u64>()?;
+        let end = fields.get(4).unwrap_or(&"0").parse::<u64>()?;
+        if start == 0 || start > end {
+            return Err(anyhow!("Invalid feature range {}..{}", start, end));
+        }
         Ok(Record {
             row: row,
-            seqname: seqname.clone(),
-            source: String::from(*fields.get(1).unwrap_or(&"")),
-            feature_type: String::from(*fields.get(2).unwrap_or(&"")),
-            start: fields.get(3).unwrap_or(&"0").parse::<u64>()?,
-            end: fields.get(4).unwrap_or(&"0").parse::<u64>()?,
-            score: String::from(*fields.get(5).unwrap_or(&"")),
-            strand: String::from(*fields.get(6).unwrap_or(&"")),
-            frame: String::from(*fields.get(7).unwrap_or(&"")),
+            seqname: Sym::new(seqname),
+            source: Sym::new(fields.get(1).unwrap_or(&"")),
+            feature_type: Sym::new(fields.get(2).unwrap_or(&"")),
+            start: start,
+            end: end,
+            score: Sym::new(fields.get(5).unwrap_or(&"")),
+            strand: Strand::from(*fields.get(6).unwrap_or(&"")),
+            frame: Frame::from(*fields.get(7).unwrap_or(&"")),
             attributes: if filetype == "gff" {
-                fields.get(8).unwrap_or(&"").split(';').map(|a| {
+                let mut attributes = Attributes::new();
+                let column = *fields.get(8).unwrap_or(&"");
+                for a in column.split(';').filter(|a| !a.is_empty() && column != ".") {
                     let kv: Vec<_> = a.splitn(2, '=').collect();
-                    let key = String::from(percent_decode(kv[0].as_bytes()).decode_utf8().unwrap());
-                    let value = String::from(percent_decode(kv.get(1).unwrap_or(&"").as_bytes()).decode_utf8().unwrap());
-                    (key, value)
-                }).collect()
+                    let key = percent_decode(kv[0].as_bytes()).decode_utf8_lossy().to_string();
+                    // commas separate values, escaped commas are part of a value
+                    let values = kv.get(1).unwrap_or(&"").split(',').map(|v|
+                        percent_decode(v.as_bytes()).decode_utf8_lossy().to_string());
+                    attributes.insert_all(key, values.collect());
+                }
+                attributes
             } else if filetype == "gtf" {
-                GTF_ATTR.captures_iter(fields.get(8).unwrap_or(&"")).map(|caps| {
-                    (String::from(caps["key"].to_string()), 
-                     String::from(caps.name("qval").unwrap_or_else(|| caps.name("val").unwrap()).as_str().to_string()))
-                }).collect()
+                // repeated keys like tag "basic"; tag "CCDS"; keep every value
+                let mut attributes = Attributes::new();
+                for caps in GTF_ATTR.captures_iter(fields.get(8).unwrap_or(&"")) {
+                    let value = match caps.name("qval") {
+                        Some(qval) => GTF_UNESCAPE.replace_all(qval.as_str(), "$1").to_string(),
+                        None => caps["val"].to_string(),
+                    };
+                    attributes.push(caps["key"].to_string(), value);
+                }
+                attributes
             } else {
                 return Err(anyhow!("Don't know how to read filetype {}", filetype))
             },
         })
     }
     
+    /// Check a GFF or GTF line for malformed columns. Returns a list of
+    /// (check name, message) problems, which is empty for a well-formed line.
+    pub fn check_row(line: &str, filetype: &str) -> Vec<(&'static str, String)> {
+        lazy_static! {
+            static ref GTF_ATTRS: Regex = Regex::new(r#"^\s*(?:\S+\s+(?:"(?:[^"\\]|\\.)*"|[^\s";]+);\s*)*(?:\S+\s+(?:"(?:[^"\\]|\\.)*"|[^\s";]+)\s*)?$"#).unwrap();
+        }
+        let mut problems = Vec::new();
+        let fields: Vec<_> = line.split('\t').collect();
+        if fields.len() != 9 {
+            problems.push(("column_count", format!("Expected 9 tab-delimited columns, found {}", fields.len())));
+            return problems;
+        }
+        for (i, name) in [(0, "seqname"), (1, "source"), (2, "type")].iter() {
+            if fields[*i].is_empty() {
+                problems.push(("empty_column", format!("The {} column is empty", name)));
+            }
+        }
+        let start = fields[3].parse::<u64>();
+        let end = fields[4].parse::<u64>();
+        match start {
+            Ok(0) => problems.push(("start", "Start coordinate must be at least 1".to_string())),
+            Err(_) => problems.push(("start", format!("Could not parse start coordinate \"{}\"", fields[3]))),
+            _ => (),
+        }
+        if end.is_err() {
+            problems.push(("end", format!("Could not parse end coordinate \"{}\"", fields[4])));
+        }
+        if let (Ok(start), Ok(end)) = (start, end) {
+            if start > end {
+                problems.push(("range", format!("Start coordinate {} is greater than end coordinate {}", start, end)));
+            }
+        }
+        if fields[5] != "." && fields[5].parse::<f64>().is_err() {
+            problems.push(("score", format!("Could not parse score \"{}\"", fields[5])));
+        }
+        if !["+", "-", ".", "?"].contains(&fields[6]) {
+            problems.push(("strand", format!("Invalid strand \"{}\"", fields[6])));
+        }
+        if ![".", "0", "1", "2"].contains(&fields[7]) {
+            problems.push(("frame", format!("Invalid frame \"{}\"", fields[7])));
+        }
+        match filetype {
+            "gff" => {
+                if fields[8] != "." {
+                    for attr in fields[8].split(';').filter(|a| !a.trim().is_empty()) {
+                        let kv: Vec<_> = attr.splitn(2, '=').collect();
+                        if kv.len() != 2 || kv[0].is_empty() {
+                            problems.push(("attributes", format!("Attribute \"{}\" is not a key=value pair", attr)));
+                        }
+                        else if percent_decode(kv[0].as_bytes()).decode_utf8().is_err() || 
+                            percent_decode(kv[1].as_bytes()).decode_utf8().is_err() 
+                        {
+                            problems.push(("attributes", format!("Attribute \"{}\" is not valid percent-encoded UTF-8", attr)));
+                        }
+                    }
+                }
+            }
+            "gtf" => {
+                if !GTF_ATTRS.is_match(fields[8]) {
+                    problems.push(("attributes", format!("Could not parse GTF attributes \"{}\"", fields[8])));
+                }
+            }
+            _ => problems.push(("filetype", format!("Don't know how to read filetype {}", filetype))),
+        }
+        problems
+    }
+    
     pub fn to_gtf(&self) -> Result<String> {
         Ok(format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", 
             self.seqname, 
@@ -127,12 +423,14 @@ impl Record {
             self.score,
             self.strand,
             self.frame,
-            self.attributes.iter().map(|(k,v)| format!("{} \"{}\";", k, v)).join(" ")))
+            // each value of a key is written as a repeated key
+            self.attributes.iter().flat_map(|(k,vs)| vs.iter().map(move |v| 
+                format!("{} \"{}\";", k, v.replace('\\', "\\\\").replace('"', "\\\""))
+            )).join(" ")))
         
     }
     
     pub fn to_gff(&self) -> Result<String> {
-        const GFF_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'\t').add(b'\r').add(b'\n').add(b';').add(b'%').add(b'=');
         Ok(format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
             self.seqname, 
             self.source, 
@@ -142,22 +440,153 @@ impl Record {
             self.score,
             self.strand,
             self.frame,
-            self.attributes.iter().map(|(k,v)| 
-                format!("{}={}", 
-                    utf8_percent_encode(k, GFF_ENCODE_SET), 
-                    utf8_percent_encode(v, GFF_ENCODE_SET))).join(";")))
+            gff_attributes(&self.attributes, ";")))
     }
 }
 
+// write attributes as GFF3 key=value pairs, with multiple values separated by commas
+fn gff_attributes(attributes: &Attributes, separator: &str) -> String {
+    const GFF_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'\t').add(b'\r').add(b'\n').add(b';').add(b'%').add(b'=').add(b',');
+    attributes.iter().map(|(k,vs)| 
+        format!("{}={}", 
+            utf8_percent_encode(k, GFF_ENCODE_SET), 
+            vs.iter().map(|v| utf8_percent_encode(v, GFF_ENCODE_SET)).join(","))).join(separator)
+}
+
+/// Returns true for comment, directive and blank lines in GFF and GTF files.
+pub fn is_comment(line: &str) -> bool {
+    line.starts_with('#') || line.trim().is_empty()
+}
+
+/// The feature types that make up gene models. An empty list of gene,
+/// transcript or exon types accepts any feature type, except that CDS
+/// features are not taken as exons. An empty list of CDS types means "CDS".
+#[derive(Clone, Debug, Default)]
+pub struct FeatureTypes {
+    pub gene: HashSet<String>,
+    pub transcript: HashSet<String>,
+    pub exon: HashSet<String>,
+    pub cds: HashSet<String>,
+}
+
+impl FeatureTypes {
+    pub fn new(
+        gene_types: &[String], 
+        transcript_types: &[String], 
+        exon_types: &[String], 
+        cds_types: &[String]) 
+        -> FeatureTypes 
+    {
+        let mut cds = cds_types.iter().cloned().collect::<HashSet<String>>();
+        if cds.is_empty() { cds.insert(String::from("CDS")); }
+        FeatureTypes {
+            gene: gene_types.iter().cloned().collect(),
+            transcript: transcript_types.iter().cloned().collect(),
+            exon: exon_types.iter().cloned().collect(),
+            cds: cds,
+        }
+    }
+    pub fn is_gene(&self, feature_type: &str) -> bool {
+        self.gene.is_empty() || self.gene.contains(feature_type)
+    }
+    pub fn is_transcript(&self, feature_type: &str) -> bool {
+        self.transcript.is_empty() || self.transcript.contains(feature_type)
+    }
+    pub fn is_exon(&self, feature_type: &str) -> bool {
+        if self.exon.is_empty() { !self.cds.contains(feature_type) }
+        else { self.exon.contains(feature_type) }
+    }
+    pub fn is_cds(&self, feature_type: &str) -> bool {
+        self.cds.contains(feature_type)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Gene<'a> {
+    pub row: usize,
+    pub record: &'a Record,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transcript<'a> {
+    pub row: usize,
+    pub record: &'a Record,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Exon<'a> {
+    pub row: usize,
+    pub record: &'a Record,
+    /// exon number counting from the 5' end of the transcript, starting at 1
+    pub number: usize,
+}
+
+impl<'a> Exon<'a> {
+    /// The 0-based half-open range of the exon.
+    pub fn range(&self) -> Range<u64> {
+        (self.record.start-1)..self.record.end
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cds<'a> {
+    pub row: usize,
+    pub record: &'a Record,
+}
+
+impl<'a> Cds<'a> {
+    /// The 0-based half-open range of the CDS feature.
+    pub fn range(&self) -> Range<u64> {
+        (self.record.start-1)..self.record.end
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UtrType {
+    FivePrime,
+    ThreePrime,
+}
+
+/// An untranslated region of a transcript as a 0-based half-open range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utr {
+    pub utr_type: UtrType,
+    pub range: Range<u64>,
+}
+
+/// Merge overlapping and adjacent 0-based half-open ranges.
+pub fn merge_ranges(ranges: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|r| r.start);
+    let mut merged = Vec::<Range<u64>>::new();
+    for r in ranges {
+        if let Some(last) = merged.last_mut() {
+            if r.start <= last.end {
+                last.end = std::cmp::max(last.end, r.end);
+                continue;
+            }
+        }
+        merged.push(r);
+    }
+    merged
+}
+
+#[derive(Clone)]
 pub struct IndexedAnnotation {
     pub rows: Vec<Record>,
-    pub id2row: HashMap<String, usize>,
+    // the first row of each feature ID
+    pub id2row: HashMap<Sym, usize>,
+    // for features split across several rows sharing one ID, maps each row
+    // to all of the feature's rows in file order
+    pub row2segments: HashMap<usize, Vec<usize>>,
     pub row2parents: HashMap<usize, Vec<usize>>,
     pub row2children: HashMap<usize, Vec<usize>>,
-    pub tree: HashMap<String, IntervalTree<u64, usize>>,
+    pub tree: HashMap<String, SortedIntervals<usize>>,
     pub chrmap: HashMap<String, String>,
     pub vizchrmap: HashMap<String, String>,
     pub refs: LinkedHashMap<String, u64>,
+    // matches the seqnames of bam files and genomes to the refs
+    pub seqnames: ChromMatcher,
 }
 
 impl IndexedAnnotation {
@@ -179,6 +608,8 @@ impl IndexedAnnotation {
     {
         IndexedAnnotation::from_file(annotfile, "gff", "", "", chrmap_file, vizchrmap_file)
     }
+    /// Read an annotation file without the annotation cache, for files
+    /// such as our own outputs that are read only once.
     pub fn from_file(
         annotfile: &str, 
         filetype: &str, 
@@ -187,12 +618,58 @@ impl IndexedAnnotation {
         chrmap_file: &Option<String>,
         vizchrmap_file: &Option<String>) 
         -> Result<IndexedAnnotation> 
+    {
+        IndexedAnnotation::parse_file(annotfile, filetype, gene_type, transcript_type, chrmap_file, vizchrmap_file, false)
+    }
+    /// Read an annotation file. If `strict` is set, any malformed line is
+    /// an error instead of being skipped with a warning. If `cache` is set, the parsed
+    /// annotation is cached next to the file and reused until the file or options change.
+    pub fn from_file_with(
+        annotfile: &str, 
+        filetype: &str, 
+        gene_type: &str, 
+        transcript_type: &str, 
+        chrmap_file: &Option<String>,
+        vizchrmap_file: &Option<String>,
+        strict: bool,
+        cache: bool) 
+        -> Result<IndexedAnnotation> 
+    {
+        let key = if cache {
+            annotation_cache::cache_key(annotfile, filetype, gene_type, transcript_type, chrmap_file, vizchrmap_file, strict)
+        } else {
+            None
+        };
+        let cache_file = annotation_cache::cache_file(annotfile);
+        if let Some(ref key) = key {
+            match annotation_cache::read_cache(&cache_file, key) {
+                Ok(Some(annot)) => return Ok(annot),
+                Ok(None) => (),
+                Err(e) => eprintln!("Could not read annotation cache {:?}: {}", cache_file, e),
+            }
+        }
+        let annot = IndexedAnnotation::parse_file(annotfile, filetype, gene_type, transcript_type, chrmap_file, vizchrmap_file, strict)?;
+        if let Some(ref key) = key {
+            if let Err(e) = annotation_cache::write_cache(&cache_file, key, &annot) {
+                eprintln!("Could not write annotation cache {:?}: {}", cache_file, e);
+            }
+        }
+        Ok(annot)
+    }
+    fn parse_file(
+        annotfile: &str, 
+        filetype: &str, 
+        gene_type: &str, 
+        transcript_type: &str, 
+        chrmap_file: &Option<String>,
+        vizchrmap_file: &Option<String>,
+        strict: bool) 
+        -> Result<IndexedAnnotation> 
     {
         // read in the optional chr map
         let mut chrmap = HashMap::<String,String>::new();
         if let Some(charmap_file) = chrmap_file.clone() {
-            let f = File::open(&charmap_file)?;
-            let mut file = BufReader::new(&f);
+            let mut file = open_input(&charmap_file)?;
             let mut buf = String::new();
             while file.read_line(&mut buf)? > 0 {
                 {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
@@ -209,8 +686,7 @@ impl IndexedAnnotation {
         // read in the optional visualization chr map
         let mut vizchrmap = HashMap::<String,String>::new();
         if let Some(vizcharmap_file) = vizchrmap_file.clone() {
-            let f = File::open(&vizcharmap_file)?;
-            let mut file = BufReader::new(&f);
+            let mut file = open_input(&vizcharmap_file)?;
             let mut buf = String::new();
             while file.read_line(&mut buf)? > 0 {
                 {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
@@ -225,21 +701,63 @@ impl IndexedAnnotation {
             }
         }
         
-        let mut id2row = HashMap::<String, usize>::new();
+        if !ANNOTATION_FORMATS.contains(&filetype) {
+            return Err(anyhow!("Don't know how to read filetype {}", filetype));
+        }
+        let gene_type = if gene_type.is_empty() { "gene" } else { gene_type };
+        let transcript_type = if transcript_type.is_empty() { "transcript" } else { transcript_type };
+        // BED, bigBed, genePred and refFlat lines are each a whole transcript
+        let mut table = TranscriptTable::new(filetype, transcript_type);
+
+        let mut id2row = HashMap::<Sym, usize>::new();
+        let mut id2rows = HashMap::<Sym, Vec<usize>>::new();
         let mut rows = Vec::<Record>::new();
-        let f = File::open(&annotfile)?;
-        let mut file = BufReader::new(&f);
+        let mut file: Box<dyn BufRead> = if filetype == "bigbed" {
+            Box::new(Cursor::new(read_bigbed(annotfile)?.join("\n")))
+        } else { open_input(annotfile)? };
         let mut refs = HashMap::<String,u64>::new();
         let mut buf = String::new();
+        let mut lineno = 0;
         while file.read_line(&mut buf)? > 0 {
+            lineno += 1;
             {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
+                // the rest of a GFF3 file is sequence
+                if filetype == "gff" && line.starts_with("##FASTA") { break }
                 let row = rows.len();
-                if let Ok(record) = Record::from_row(row, &line, filetype, &chrmap) {
+                let records = if let Some(ref mut table) = table {
+                    match table.records(row, line, &chrmap) {
+                        Ok(records) => records,
+                        Err(e) if strict => return Err(anyhow!("{}:{}: {}", annotfile, lineno, e)),
+                        Err(e) => {
+                            eprintln!("Skipping line {}:{}: {}", annotfile, lineno, e);
+                            Vec::new()
+                        }
+                    }
+                } else {
+                    if strict && !is_comment(line) {
+                        let problems = Record::check_row(line, filetype);
+                        if !problems.is_empty() {
+                            return Err(anyhow!("{}:{}: {}", annotfile, lineno, 
+                                problems.iter().map(|p| &p.1).join("; ")));
+                        }
+                    }
+                    match Record::from_row(row, line, filetype, &chrmap) {
+                        Ok(record) => vec![record],
+                        Err(_) if is_comment(line) => Vec::new(),
+                        Err(e) => {
+                            eprintln!("Skipping line {}:{}: {}", annotfile, lineno, e);
+                            Vec::new()
+                        }
+                    }
+                };
+                for record in records {
+                    let row = record.row;
                     if let Some(id) = record.attributes.get("ID") {
-                        id2row.insert(id.clone(), row);
+                        id2row.entry(*id).or_insert(row);
+                        id2rows.entry(*id).or_insert_with(Vec::new).push(row);
                     }
                     // get the max ref lengths
-                    let reflength = refs.entry(record.seqname.clone()).or_insert(record.end);
+                    let reflength = refs.entry(record.seqname.to_string()).or_insert(record.end);
                     if *reflength < record.end { *reflength = record.end }
                     rows.push(record);
                 }
@@ -254,6 +772,16 @@ impl IndexedAnnotation {
             sorted_refs.insert(chr.clone(), length);
         }
         
+        // rows sharing an ID are segments of a single discontinuous feature
+        let mut row2segments = HashMap::<usize, Vec<usize>>::new();
+        for segments in id2rows.values() {
+            if segments.len() > 1 {
+                for segment in segments {
+                    row2segments.insert(*segment, segments.clone());
+                }
+            }
+        }
+        
         // populate row2parents and row2children indices
         let mut row2parents = HashMap::<usize, BTreeSet<usize>>::new();
         let mut row2children = HashMap::<usize, BTreeSet<usize>>::new();
@@ -261,62 +789,99 @@ impl IndexedAnnotation {
         let mut fake_rows = Vec::<Record>::new();
         
         match filetype {
-            "gtf" => {
+            "gtf" | "bed" | "bigbed" | "genepred" | "refflat" => {
+                // use the file's own gene and transcript lines where it has
+                // them, and make one fake row for each other gene_id and
+                // transcript_id
+                let mut gene_rows = HashMap::<Sym, usize>::new();
+                let mut transcript_rows = HashMap::<Sym, usize>::new();
+                for (row, record) in rows.iter_mut().enumerate() {
+                    let (key, feature_rows) = 
+                        if record.feature_type == transcript_type { ("transcript_id", &mut transcript_rows) }
+                        else if record.feature_type == gene_type && !record.attributes.contains_key("transcript_id") { ("gene_id", &mut gene_rows) }
+                        else { continue };
+                    if let Some(id) = record.attributes.get(key).cloned() {
+                        if let Entry::Vacant(entry) = feature_rows.entry(id) {
+                            entry.insert(row);
+                            id2row.insert(id, row);
+                            if !record.attributes.contains_key("ID") {
+                                record.attributes.insert("ID", id);
+                            }
+                        }
+                    }
+                }
                 let mut firstrow = HashMap::<&Record, usize>::new();
                 for (row, record) in rows.iter().enumerate() {
                     // in GTF, if two rows both have identical fields (except attributes), they
                     // are the same feature.
-                    let row = *firstrow.entry(record).or_insert(row);
-                    if let Some(transcript_id) = record.attributes.get("transcript_id") {
-                        let mut transcript_record = Record::new();
-                        transcript_record.feature_type = String::from(transcript_type);
-                        transcript_record.attributes
-                            .insert(String::from("ID"), transcript_id.clone());
-                        if let Some(transcript_name) = record.attributes.get("transcript_name") {
-                            transcript_record.attributes
-                                .insert(String::from("Name"), transcript_name.clone());
-                        }
-                        let transcriptrow = rows.len() + fake_rows.len();
-                        id2row.insert(transcript_id.clone(), transcriptrow);
-                        row2parents.entry(row).or_insert_with(BTreeSet::new).insert(transcriptrow);
-                        row2children.entry(transcriptrow)
-                            .or_insert_with(BTreeSet::new)
-                            .insert(row);
-                        fake_rows.push(transcript_record);
-
-                        if let Some(gene_id) = record.attributes.get("gene_id") {
+                    // gene and transcript lines are told apart by their IDs instead.
+                    let is_id_row = 
+                        record.attributes.get("gene_id").and_then(|id| gene_rows.get(id)) == Some(&row) ||
+                        record.attributes.get("transcript_id").and_then(|id| transcript_rows.get(id)) == Some(&row);
+                    let row = if is_id_row { row } else { *firstrow.entry(record).or_insert(row) };
+                    let generow = record.attributes.get("gene_id").map(|gene_id| 
+                        *gene_rows.entry(*gene_id).or_insert_with(|| {
+                            let generow = rows.len() + fake_rows.len();
                             let mut gene_record = Record::new();
-                            gene_record.feature_type = String::from(gene_type);
-                            gene_record.attributes.insert(String::from("ID"), gene_id.clone());
+                            gene_record.row = generow;
+                            gene_record.feature_type = Sym::new(gene_type);
+                            gene_record.score = Sym::new(".");
+                            gene_record.frame = Frame::None;
+                            gene_record.attributes.insert("ID", gene_id);
                             if let Some(gene_name) = record.attributes.get("gene_name") {
-                                gene_record.attributes
-                                    .insert(String::from("Name"), gene_name.clone());
+                                gene_record.attributes.insert("gene_name", gene_name);
                             }
-                            let generow = rows.len() + fake_rows.len();
-                            id2row.insert(gene_id.clone(), generow);
-                            row2parents.entry(transcriptrow)
-                                .or_insert_with(BTreeSet::new)
-                                .insert(generow);
-                            row2children.entry(generow)
-                                .or_insert_with(BTreeSet::new)
-                                .insert(transcriptrow);
+                            id2row.insert(*gene_id, generow);
                             fake_rows.push(gene_record);
-                        }
+                            generow
+                        }));
+                    if generow == Some(row) { continue }
+                    let transcriptrow = record.attributes.get("transcript_id").map(|transcript_id| 
+                        *transcript_rows.entry(*transcript_id).or_insert_with(|| {
+                            let transcriptrow = rows.len() + fake_rows.len();
+                            let mut transcript_record = Record::new();
+                            transcript_record.row = transcriptrow;
+                            transcript_record.feature_type = Sym::new(transcript_type);
+                            transcript_record.score = Sym::new(".");
+                            transcript_record.frame = Frame::None;
+                            transcript_record.attributes.insert("ID", transcript_id);
+                            if let Some(transcript_name) = record.attributes.get("transcript_name") {
+                                transcript_record.attributes.insert("transcript_name", transcript_name);
+                            }
+                            id2row.insert(*transcript_id, transcriptrow);
+                            fake_rows.push(transcript_record);
+                            transcriptrow
+                        }));
+                    if let (Some(transcriptrow), Some(generow)) = (transcriptrow, generow) {
+                        row2parents.entry(transcriptrow)
+                            .or_insert_with(BTreeSet::new)
+                            .insert(generow);
+                        row2children.entry(generow)
+                            .or_insert_with(BTreeSet::new)
+                            .insert(transcriptrow);
+                    }
+                    // features without a transcript_id belong to the gene
+                    let parentrow = if transcriptrow == Some(row) { None } else { transcriptrow.or(generow) };
+                    if let Some(parentrow) = parentrow {
+                        row2parents.entry(row).or_insert_with(BTreeSet::new).insert(parentrow);
+                        row2children.entry(parentrow)
+                            .or_insert_with(BTreeSet::new)
+                            .insert(row);
                     }
                 }
             }
             "gff" => {
                 for (row, record) in rows.iter().enumerate() {
-                    if let Some(parent) = record.attributes.get("Parent") {
-                        for p in parent.split(',') {
-                            if let Some(parentrow) = id2row.get(p) {
-                                row2parents.entry(row)
-                                    .or_insert_with(BTreeSet::new)
-                                    .insert(*parentrow);
-                                row2children.entry(*parentrow)
-                                    .or_insert_with(BTreeSet::new)
-                                    .insert(row);
-                            }
+                    for p in record.attributes.get_all("Parent") {
+                        // a parent split across several rows is the parent
+                        // of its children in every one of them
+                        for parentrow in id2rows.get(p).into_iter().flatten() {
+                            row2parents.entry(row)
+                                .or_insert_with(BTreeSet::new)
+                                .insert(*parentrow);
+                            row2children.entry(*parentrow)
+                                .or_insert_with(BTreeSet::new)
+                                .insert(row);
                         }
                     }
                 }
@@ -333,16 +898,16 @@ impl IndexedAnnotation {
                 // recursively get all child nodes
                 let mut children = HashSet::<usize>::new();
                 let mut addchildren = BTreeSet::<usize>::new();
-                if let Some(cs) = row2children.get_mut(&row) {
-                    addchildren.append(cs);
+                if let Some(cs) = row2children.get(&row) {
+                    addchildren.extend(cs.iter().cloned());
                 }
                 while !addchildren.is_empty() {
                     let mut morechildren = BTreeSet::<usize>::new();
                     for ac in &mut addchildren.iter() {
                         if !children.contains(ac) {
                             children.insert(*ac);
-                            if let Some(cs) = row2children.get_mut(ac) {
-                                morechildren.append(cs);
+                            if let Some(cs) = row2children.get(ac) {
+                                morechildren.extend(cs.iter().cloned());
                             }
                         }
                     }
@@ -350,30 +915,32 @@ impl IndexedAnnotation {
                     addchildren.append(&mut morechildren);
                 }
                 // aggregate the child nodes to get the missing information
-                let mut seqname = HashMap::<String, u64>::new();
-                let mut source = HashMap::<String, u64>::new();
+                let mut seqname = HashMap::<Sym, u64>::new();
+                let mut source = HashMap::<Sym, u64>::new();
                 let mut start: Option<u64> = Option::None;
                 let mut end: Option<u64> = Option::None;
-                let mut strand = HashMap::<String, u64>::new();
+                let mut strand = HashMap::<Strand, u64>::new();
                 for c in &children {
                     let child = &rows[*c];
+                    // skip other rows that are still missing information
+                    if child.seqname.is_empty() { continue }
                     if start.is_none() || child.start < start.ok_or(anyhow!("NoneError"))? {
                         start = Some(child.start);
                     }
                     if end.is_none() || child.end > end.ok_or(anyhow!("NoneError"))? {
                         end = Some(child.end);
                     }
-                    *seqname.entry(child.seqname.clone()).or_insert(0u64) += 1;
-                    *source.entry(child.source.clone()).or_insert(0u64) += 1;
-                    *strand.entry(child.strand.clone()).or_insert(0u64) += 1;
+                    *seqname.entry(child.seqname).or_insert(0u64) += 1;
+                    *source.entry(child.source).or_insert(0u64) += 1;
+                    *strand.entry(child.strand).or_insert(0u64) += 1;
                 }
                 // enqueue the missing information
                 let mut update = Record::new();
                 if let Some((seqname, _)) = seqname.iter().max_by_key(|a| a.1) {
-                    update.seqname = seqname.clone();
+                    update.seqname = *seqname;
                 }
                 if let Some((source, _)) = source.iter().max_by_key(|a| a.1) {
-                    update.source = source.clone();
+                    update.source = *source;
                 }
                 if let Some(start) = start {
                     update.start = start;
@@ -382,7 +949,7 @@ impl IndexedAnnotation {
                     update.end = end;
                 }
                 if let Some((strand, _)) = strand.iter().max_by_key(|a| a.1) {
-                    update.strand = strand.clone();
+                    update.strand = *strand;
                 }
                 updates.push((row, update));
             }
@@ -391,11 +958,11 @@ impl IndexedAnnotation {
         for u in &mut updates {
             let update = &mut u.1;
             let record = &mut rows[u.0];
-            record.seqname = update.seqname.clone();
-            record.source = update.source.clone();
+            record.seqname = update.seqname;
+            record.source = update.source;
             record.start = update.start;
             record.end = update.end;
-            record.strand = update.strand.clone();
+            record.strand = update.strand;
         }
         // sort row2parents by coordinates
         let mut row2parents_update = HashMap::<usize, Vec<usize>>::new();
@@ -423,273 +990,542 @@ impl IndexedAnnotation {
             });
             *up = vec;
         }
-        // build the interval tree
-        let mut tree = HashMap::<String, IntervalTree<u64, usize>>::new();
-        for (row, record) in rows.iter().enumerate() {
-            let t = tree.entry(record.seqname.clone()).or_insert_with(IntervalTree::new);
-            t.insert(Interval::new((record.start - 1)..(record.end))?, row);
-        }
+        let tree = IndexedAnnotation::build_tree(&rows)?;
+        let seqnames = ChromMatcher::new(ChromAliases::new(), sorted_refs.keys());
         
         Ok(IndexedAnnotation {
             rows: rows,
             id2row: id2row,
+            row2segments: row2segments,
             row2children: row2children_update,
             row2parents: row2parents_update,
             tree: tree,
             chrmap: chrmap,
             vizchrmap: vizchrmap,
             refs: sorted_refs,
+            seqnames: seqnames,
+        })
+    }
+    
+    /// Build the per-sequence interval indexes of row indices.
+    pub fn build_tree(rows: &[Record]) -> Result<HashMap<String, SortedIntervals<usize>>> {
+        let mut ranges = HashMap::<Sym, Vec<(Range<u64>, usize)>>::new();
+        for (row, record) in rows.iter().enumerate() {
+            ranges.entry(record.seqname).or_insert_with(Vec::new).push((record.range()?, row));
+        }
+        Ok(ranges.into_iter().map(|(seqname, ranges)| (seqname.to_string(), SortedIntervals::new(ranges))).collect())
+    }
+    
+    /// The rows on `seqname` that overlap the 0-based half-open `range`, in
+    /// order of their start.
+    pub fn find(&self, seqname: &str, range: Range<u64>) -> impl Iterator<Item=usize> + '_ {
+        self.tree.get(seqname).into_iter().flat_map(move |index| index.find(range.clone())).map(|e| *e.data())
+    }
+    
+    /// The rows on `seqname` that overlap `range` and have one of the
+    /// feature `types`, or any type if `types` is empty, and are on `strand`
+    /// if it is given.
+    pub fn find_features<'a>(&'a self, seqname: &str, range: Range<u64>, strand: Option<Strand>, types: &'a [&str]) 
+        -> impl Iterator<Item=usize> + 'a 
+    {
+        self.find(seqname, range).filter(move |row| {
+            let record = &self.rows[*row];
+            strand.map(|s| record.strand == s).unwrap_or(true) &&
+                (types.is_empty() || types.iter().any(|t| record.feature_type == *t))
         })
     }
     
+    /// All rows of the feature at `row`. Features split across several rows
+    /// with the same ID have one row per segment.
+    pub fn segments(&self, row: usize) -> Vec<usize> {
+        self.row2segments.get(&row).cloned().unwrap_or_else(|| vec![row])
+    }
+    
+    /// Is `row` the first row of its feature? Every segment of a feature
+    /// has the same parents and children, so the feature views list a
+    /// feature split across several rows once, by its first row.
+    pub fn is_first_segment(&self, row: usize) -> bool {
+        self.row2segments.get(&row).map(|segments| segments.first() == Some(&row)).unwrap_or(true)
+    }
+    
+    /// Gene features with at least one child, in file order.
+    pub fn genes(&self, types: &FeatureTypes) -> Vec<Gene<'_>> {
+        self.rows.iter().enumerate().
+            filter(|(row, gene)| types.is_gene(&gene.feature_type) && self.row2children.contains_key(row)).
+            filter(|(row, _)| self.is_first_segment(*row)).
+            map(|(row, gene)| Gene { row: row, record: gene }).
+            collect()
+    }
+    
+    /// Transcripts of a gene that are on the gene's sequence and strand.
+    /// Transcripts with an exon on another sequence or strand are left
+    /// out, since their exons would imply introns that don't exist.
+    pub fn transcripts(&self, gene: &Gene, types: &FeatureTypes) -> Vec<Transcript<'_>> {
+        self.children_on_strand(gene.row, |t| types.is_transcript(t)).into_iter().
+            filter(|(row, _)| self.is_first_segment(*row)).
+            filter(|(row, transcript)| self.row2children.get(row).into_iter().flatten().
+                map(|child_row| &self.rows[*child_row]).
+                filter(|child| types.is_exon(&child.feature_type)).
+                all(|exon| exon.seqname == transcript.seqname && exon.strand == transcript.strand)).
+            map(|(row, transcript)| Transcript { row: row, record: transcript }).
+            collect()
+    }
+    
+    /// Exons of a transcript that are on the transcript's sequence and
+    /// strand, in genomic order.
+    pub fn exons(&self, transcript: &Transcript, types: &FeatureTypes) -> Vec<Exon<'_>> {
+        let exons = self.children_on_strand(transcript.row, |t| types.is_exon(t));
+        let count = exons.len();
+        let minus = transcript.record.strand == "-";
+        exons.into_iter().enumerate().
+            map(|(i, (row, exon))| Exon { row: row, record: exon, number: if minus { count-i } else { i+1 } }).
+            collect()
+    }
+    
+    /// CDS features of a transcript that are on the transcript's sequence
+    /// and strand, in genomic order.
+    pub fn cds(&self, transcript: &Transcript, types: &FeatureTypes) -> Vec<Cds<'_>> {
+        self.children_on_strand(transcript.row, |t| types.is_cds(t)).into_iter().
+            map(|(row, cds)| Cds { row: row, record: cds }).
+            collect()
+    }
+    
+    /// The introns between the merged exons of a transcript, in genomic order.
+    pub fn introns(&self, transcript: &Transcript, types: &FeatureTypes) -> Vec<Range<u64>> {
+        let exons = merge_ranges(&self.exons(transcript, types).iter().map(|e| e.range()).collect::<Vec<_>>());
+        exons.windows(2).map(|w| w[0].end..w[1].start).collect()
+    }
+    
+    /// The parts of the merged exons of a transcript outside of its CDS, in
+    /// genomic order. Transcripts without a CDS have no UTRs.
+    pub fn utrs(&self, transcript: &Transcript, types: &FeatureTypes) -> Vec<Utr> {
+        let cds = self.cds(transcript, types);
+        let cds_start = match cds.iter().map(|c| c.record.start-1).min() {
+            Some(start) => start,
+            None => return Vec::new(),
+        };
+        let cds_end = cds.iter().map(|c| c.record.end).max().unwrap_or(cds_start);
+        let (left, right) = if transcript.record.strand == "-" {
+            (UtrType::ThreePrime, UtrType::FivePrime)
+        } else {
+            (UtrType::FivePrime, UtrType::ThreePrime)
+        };
+        let exons = merge_ranges(&self.exons(transcript, types).iter().map(|e| e.range()).collect::<Vec<_>>());
+        let mut utrs = Vec::new();
+        for exon in &exons {
+            if exon.start < cds_start {
+                utrs.push(Utr { utr_type: left, range: exon.start..std::cmp::min(exon.end, cds_start) });
+            }
+        }
+        for exon in &exons {
+            if cds_end < exon.end {
+                utrs.push(Utr { utr_type: right, range: std::cmp::max(exon.start, cds_end)..exon.end });
+            }
+        }
+        utrs
+    }
+    
+    // genes ordered by sequence name and position
+    fn sorted_genes(&self, types: &FeatureTypes) -> Vec<Gene<'_>> {
+        let mut seqnames = self.tree.keys().collect::<Vec<_>>();
+        seqnames.sort();
+        seqnames.into_iter().
+            flat_map(|seqname| self.tree[seqname].iter()).
+            map(|e| *e.data()).
+            filter(|row| types.is_gene(&self.rows[*row].feature_type) && self.row2children.contains_key(row)).
+            filter(|row| self.is_first_segment(*row)).
+            map(|row| Gene { row: row, record: &self.rows[row] }).
+            collect()
+    }
+    
+    // children of a row with a matching feature type on the same sequence
+    // and strand, sorted by position
+    fn children_on_strand<F: Fn(&str) -> bool>(&self, row: usize, is_type: F) -> Vec<(usize, &Record)> {
+        let parent = &self.rows[row];
+        let mut children = self.row2children.get(&row).into_iter().flatten().
+            map(|child_row| (*child_row, &self.rows[*child_row])).
+            filter(|(_, child)| is_type(&child.feature_type) && 
+                child.seqname == parent.seqname && 
+                child.strand == parent.strand).
+            collect::<Vec<_>>();
+        children.sort_by_key(|(_, child)| (child.start, child.end));
+        children
+    }
+    
+    /// Find the strand-unambiguous regions of the annotation. Features are merged
+    /// per strand, then any region covered by a feature on the opposite strand
+    /// is removed. Returns sorted 0-based ranges with their strand, per seqname.
+    pub fn strand_regions(&self) -> HashMap<String, Vec<(Range<u64>, u8)>> {
+        let mut ranges = HashMap::<(String, u8), Vec<Range<u64>>>::new();
+        for record in &self.rows {
+            let strand = match record.strand {
+                Strand::Forward => b'+',
+                Strand::Reverse => b'-',
+                _ => continue,
+            };
+            if record.seqname.is_empty() || record.end < record.start { continue }
+            ranges.entry((record.seqname.to_string(), strand)).
+                or_insert_with(Vec::new).
+                push((record.start-1)..record.end);
+        }
+        // merge overlapping and adjacent ranges
+        let merged = ranges.into_iter().
+            map(|(key, list)| (key, merge_ranges(&list))).
+            collect::<HashMap<_,_>>();
+        // subtract the opposite strand
+        let empty = Vec::<Range<u64>>::new();
+        let mut regions = HashMap::<String, Vec<(Range<u64>, u8)>>::new();
+        for ((seqname, strand), list) in &merged {
+            let opposite = if *strand == b'+' { b'-' } else { b'+' };
+            let others = merged.get(&(seqname.clone(), opposite)).unwrap_or(&empty);
+            let region = regions.entry(seqname.clone()).or_insert_with(Vec::new);
+            let mut o = 0usize;
+            for r in list {
+                let mut start = r.start;
+                while o < others.len() && others[o].end <= start { o += 1 }
+                let mut i = o;
+                while i < others.len() && others[i].start < r.end {
+                    if start < others[i].start {
+                        region.push((start..others[i].start, *strand));
+                    }
+                    start = std::cmp::max(start, others[i].end);
+                    i += 1;
+                }
+                if start < r.end {
+                    region.push((start..r.end, *strand));
+                }
+            }
+        }
+        for region in regions.values_mut() {
+            region.sort_by_key(|r| r.0.start);
+        }
+        regions
+    }
+
+    /// Write the annotation as GTF. Each gene is written as its gene line,
+    /// then each transcript line followed by the transcript's exons, CDS,
+    /// UTRs and codons, and the lines are then sorted by position, so
+    /// overlapping genes are interleaved. gene_id and transcript_id are
+    /// written first, taken from the attribute of the same name or the ID
+    /// of the gene and transcript.
     pub fn to_gtf(&self, filename: &str) -> Result<()> {
         let mut output: BufWriter<Box<dyn Write>> = BufWriter::new(
             if filename == "-" { Box::new(stdout()) } 
             else { Box::new(File::create(filename)?) });
-            
+        self.write_gtf(&mut output, &[])?;
+        output.flush()?;
+        Ok(())
+    }
+    
+    /// Write the annotation as GTF, starting with the given comment lines.
+    pub fn write_gtf(&self, output: &mut dyn Write, comments: &[String]) -> Result<()> {
+        for comment in comments {
+            writeln!(output, "{}", comment)?;
+        }
+        // GTF files repeat a feature shared by several transcripts once for
+        // each transcript. The repeats are loaded as parentless copies of
+        // the first row, which hold the attributes for their transcript.
+        let mut firstrow = HashMap::<&Record, usize>::new();
+        let mut copies = HashMap::<(usize, &str), usize>::new();
+        let mut is_copy = HashSet::<usize>::new();
         for (row, record) in self.rows.iter().enumerate() {
-            if let Some(transcript_rows) = self.row2parents.get(&row) {
-                for transcript_row in transcript_rows {
-                    let transcript = &self.rows[*transcript_row];
-                    if let Some(transcript_id) = transcript.attributes.get("ID") {
-                        if let Some(gene_rows) = self.row2parents.get(transcript_row) {
-                            for gene_row in gene_rows {
-                                let gene = &self.rows[*gene_row];
-                                if let Some(gene_id) = gene.attributes.get("ID") {
-                                    let mut rec = Record {
-                                        row: row,
-                                        seqname: record.seqname.clone(),
-                                        source: record.source.clone(),
-                                        feature_type: record.feature_type.clone(),
-                                        start: record.start,
-                                        end: record.end,
-                                        score: record.score.clone(),
-                                        strand: record.strand.clone(),
-                                        frame: record.frame.clone(),
-                                        attributes: LinkedHashMap::new(),
-                                    };
-                                    rec.attributes.insert(String::from("gene_id"), gene_id.clone());
-                                    rec.attributes.insert(String::from("transcript_id"), transcript_id.clone());
-                                    for (k,v) in &record.attributes {
-                                        if k != "gene_id" && k != "transcript_id" {
-                                            rec.attributes.insert(k.clone(), v.clone());
-                                        }
-                                    }
-                                    output.write_fmt(format_args!("{}\n", rec.to_gtf()?))?;
-                                }
-                            }
-                        }
-                    }
+            let first = *firstrow.entry(record).or_insert(row);
+            if first != row && !self.row2parents.contains_key(&row) && !self.row2children.contains_key(&row) {
+                is_copy.insert(row);
+                if let Some(transcript_id) = record.attributes.get("transcript_id") {
+                    copies.insert((first, transcript_id.as_str()), row);
                 }
             }
         }
+        let mut roots = (0..self.rows.len()).
+            filter(|row| !self.row2parents.contains_key(row) && !is_copy.contains(row)).
+            collect::<Vec<_>>();
+        roots.sort_by(|a, b| {
+            self.rows[*a].seqname.as_bytes().cmp(self.rows[*b].seqname.as_bytes()).
+                then_with(|| self.rows[*a].start.cmp(&self.rows[*b].start)).
+                then_with(|| a.cmp(b))
+        });
+        // each gene is followed by its transcripts in order of position, and
+        // each transcript by its own features
+        let mut records = Vec::<Record>::new();
+        for root in roots {
+            let has_grandchildren = self.row2children.get(&root).
+                map(|cs| cs.iter().any(|c| self.row2children.contains_key(c))).
+                unwrap_or(false);
+            let gene_id = self.gtf_id(root, "gene_id");
+            // a root with only leaf children is a transcript without a gene
+            let transcript_id = 
+                if self.row2children.contains_key(&root) && !has_grandchildren { Some(self.gtf_id(root, "transcript_id")) }
+                else { self.rows[root].attributes.get("transcript_id").map(|id| id.to_string()) };
+            self.gtf_records(&mut records, &mut vec![root], &gene_id, transcript_id.as_deref(), &copies)?;
+        }
+        for record in &records {
+            writeln!(output, "{}", record.to_gtf()?)?;
+        }
+        Ok(())
+    }
+    
+    // the gene_id or transcript_id of a row
+    fn gtf_id(&self, row: usize, key: &str) -> String {
+        let record = &self.rows[row];
+        match record.attributes.get(key).or_else(|| record.attributes.get("ID")) {
+            Some(id) => id.to_string(),
+            None => format!("{}:{}-{}", record.seqname, record.start, record.end),
+        }
+    }
+    
+    // get the GTF records of the last row of `path` and its descendants
+    fn gtf_records(&self, 
+        records: &mut Vec<Record>, 
+        path: &mut Vec<usize>, 
+        gene_id: &str, 
+        transcript_id: Option<&str>,
+        copies: &HashMap<(usize, &str), usize>)
+        -> Result<()>
+    {
+        let row = *path.last().ok_or(anyhow!("NoneError"))?;
+        let mut record = self.rows[row].clone();
+        let attributes = transcript_id.
+            and_then(|t| copies.get(&(row, t))).
+            map(|copy| &self.rows[*copy].attributes).
+            unwrap_or(&self.rows[row].attributes);
+        record.attributes = Attributes::new();
+        record.attributes.insert(String::from("gene_id"), gene_id.to_string());
+        if let Some(transcript_id) = transcript_id {
+            record.attributes.insert(String::from("transcript_id"), transcript_id.to_string());
+        }
+        for (k,vs) in attributes.iter() {
+            if k == "gene_id" || k == "transcript_id" || k == "Parent" { continue }
+            // the ID of a gene or transcript is its gene_id or transcript_id
+            if k == "ID" && vs.iter().all(|v| v == gene_id || Some(v.as_str()) == transcript_id) { continue }
+            record.attributes.insert_all(*k, vs.clone());
+        }
+        records.push(record);
+        
+        // the children of a multi-row feature are written under its first row
+        if !self.is_first_segment(row) { return Ok(()) }
+        if let Some(children) = self.row2children.get(&row) {
+            let mut children = children.iter().collect::<Vec<_>>();
+            children.sort_by_key(|c| (self.rows[**c].start, **c));
+            for child in children {
+                if path.contains(child) { continue }
+                // the gene's children with children of their own are its transcripts
+                let transcript_id = 
+                    if path.len() == 1 && self.row2children.contains_key(child) { Some(self.gtf_id(*child, "transcript_id")) }
+                    else { transcript_id.map(String::from) };
+                path.push(*child);
+                self.gtf_records(records, path, gene_id, transcript_id.as_deref(), copies)?;
+                path.pop();
+            }
+        }
         Ok(())
     }
     
+    /// Write the annotation as GFF3, sorted by position with every parent
+    /// before its children.
     pub fn to_gff(&self, filename: &str) -> Result<()> {
         let mut output: BufWriter<Box<dyn Write>> = BufWriter::new(
             if filename == "-" { Box::new(stdout()) } 
             else { Box::new(File::create(filename)?) });
-            
-        for (row, record) in self.rows.iter().enumerate() {
-            let mut rec = Record {
-                row: row,
-                seqname: record.seqname.clone(),
-                source: record.source.clone(),
-                feature_type: record.feature_type.clone(),
-                start: record.start,
-                end: record.end,
-                score: record.score.clone(),
-                strand: record.strand.clone(),
-                frame: record.frame.clone(),
-                attributes: LinkedHashMap::new(),
-            };
-            let mut parents = Vec::<String>::new();
-            if let Some(parent_rows) = self.row2parents.get(&row) {
-                for parent_row in parent_rows {
-                    let parent = &self.rows[*parent_row];
-                    if let Some(parent_id) = parent.attributes.get("ID") {
-                        parents.push(parent_id.clone());
-                    }
+        self.write_gff(&mut output, true, &[])?;
+        output.flush()?;
+        Ok(())
+    }
+    
+    /// Write the annotation as GFF3, sorted by position with every parent
+    /// before its children. If `separators` is set, a ### line follows each
+    /// group of related features once all of them have been written. The
+    /// comment lines are written after the ##gff-version directive.
+    pub fn write_gff(&self, output: &mut dyn Write, separators: bool, comments: &[String]) -> Result<()> {
+        writeln!(output, "##gff-version 3")?;
+        for comment in comments {
+            writeln!(output, "{}", comment)?;
+        }
+        // rows whose parents, children or other segments are still to come
+        let mut pending = HashSet::<usize>::new();
+        let mut written = HashSet::<usize>::new();
+        let mut grouped = false;
+        for row in self.sorted_rows() {
+            let record = &self.rows[row];
+            written.insert(row);
+            pending.remove(&row);
+            let relatives = self.row2parents.get(&row).into_iter().flatten().
+                chain(self.row2children.get(&row).into_iter().flatten()).
+                chain(self.row2segments.get(&row).into_iter().flatten());
+            for relative in relatives {
+                if !written.contains(relative) {
+                    pending.insert(*relative);
                 }
+                grouped = true;
             }
-            for (k,v) in &record.attributes {
-                if k == "Parent" {
-                    rec.attributes.insert(k.clone(), String::from(parents.join(",")));
+            // rows read from GTF have no Parent attribute, so take their
+            // parents from the feature hierarchy
+            if record.attributes.contains_key("Parent") || !self.row2parents.contains_key(&row) {
+                writeln!(output, "{}", record.to_gff()?)?;
+            }
+            else {
+                let mut rec = record.clone();
+                let mut parents = Vec::<Sym>::new();
+                for parent_row in &self.row2parents[&row] {
+                    if let Some(parent_id) = self.rows[*parent_row].attributes.get("ID") {
+                        if !parents.contains(parent_id) {
+                            parents.push(*parent_id);
+                        }
+                    }
                 }
-                else {
-                    rec.attributes.insert(k.clone(), v.clone());
+                if !parents.is_empty() {
+                    rec.attributes.insert_all(String::from("Parent"), parents);
                 }
+                writeln!(output, "{}", rec.to_gff()?)?;
+            }
+            if separators && grouped && pending.is_empty() {
+                writeln!(output, "###")?;
+                grouped = false;
             }
-            output.write_fmt(format_args!("{}\n", rec.to_gff()?))?;
         }
         Ok(())
     }
     
-    pub fn to_bed(&self, 
-        bed_file: &str, 
+    // The rows sorted by position, with every parent before its children.
+    // The feature hierarchies are walked from their roots, visiting a row
+    // once all of its parents have been visited, and the walk is then
+    // stably sorted, so a parent stays ahead of the children it contains.
+    fn sorted_rows(&self) -> Vec<usize> {
+        let mut roots = (0..self.rows.len()).
+            filter(|row| !self.row2parents.contains_key(row)).
+            collect::<Vec<_>>();
+        roots.sort_by(|a, b| self.rows[*a].seqname.as_bytes().cmp(self.rows[*b].seqname.as_bytes()).
+            then_with(|| self.rows[*a].start.cmp(&self.rows[*b].start)));
+        let mut visited = HashSet::<usize>::new();
+        let mut order = Vec::<usize>::new();
+        let mut stack = roots.into_iter().rev().collect::<Vec<_>>();
+        while let Some(row) = stack.pop() {
+            if visited.contains(&row) { continue }
+            // wait to be reached from the last of the parents
+            if self.row2parents.get(&row).into_iter().flatten().any(|p| !visited.contains(p)) { continue }
+            visited.insert(row);
+            order.push(row);
+            if let Some(children) = self.row2children.get(&row) {
+                stack.extend(children.iter().rev());
+            }
+        }
+        // rows in parent cycles are never reached from a root
+        order.extend((0..self.rows.len()).filter(|row| !visited.contains(row)));
+        order.sort_by(|a, b| self.rows[*a].seqname.as_bytes().cmp(self.rows[*b].seqname.as_bytes()).
+            then_with(|| self.rows[*a].start.cmp(&self.rows[*b].start)));
+        order
+    }
+    
+    pub fn to_bed_records(&self, 
         exon_types: &[String],
         cds_types: &[String],
         transcript_types: &[String],
         gene_types: &[String])
-        -> Result<()>
+        -> Result<Vec<Bed12>>
     {
-        let exon_types = exon_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
-        let mut cds_types = cds_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
-        if cds_types.is_empty() { cds_types.insert(String::from("CDS")); }
-        let transcript_types = transcript_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
-        let gene_types = gene_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
+        let types = FeatureTypes::new(gene_types, transcript_types, exon_types, cds_types);
         let mut seen_transcript = HashSet::<usize>::new();
         let mut transcript_names = HashSet::<String>::new();
         
-        let unsorted_bed_file = format!("{}.unsorted.bed", bed_file);
-        {   let mut bw = BufWriter::new(File::create(&unsorted_bed_file)?);
-            let mut chrs = self.tree.keys().collect::<Vec<_>>();
-            chrs.sort_by_key(|a| a.as_bytes());
-            for chr in chrs {
-                for node in self.tree[chr].find(0..std::u64::MAX) {
-                    let gene_row = node.data();
-                    let gene = &self.rows[*gene_row];
-                    if gene_types.is_empty() || gene_types.contains(&gene.feature_type) {
-                        if let Some(ref gene_children) = self.row2children.get(gene_row) {
-                            for transcript_row in gene_children.iter() {
-                                if !seen_transcript.contains(transcript_row) {
-                                    let transcript = &self.rows[*transcript_row];
-                                    if transcript_types.is_empty() || transcript_types.contains(&transcript.feature_type) {
-                                        seen_transcript.insert(*transcript_row);
-                                        
-                                        // get the exon and CDS features
-                                        let mut exons = Vec::<usize>::new();
-                                        let mut cds_features = Vec::<usize>::new();
-                                        let mut exon_starts = HashSet::<u64>::new();
-                                        let mut exon_ends = HashSet::<u64>::new();
-                                        if let Some(child_rows) = self.row2children.get(transcript_row) {
-                                            for child_row in child_rows {
-                                                let child = &self.rows[*child_row];
-                                                if child.seqname == transcript.seqname {
-                                                    if cds_types.contains(&child.feature_type) {
-                                                        cds_features.push(*child_row);
-                                                    }
-                                                    else if exon_types.is_empty() || exon_types.contains(&child.feature_type) {
-                                                        exons.push(*child_row);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        if exons.is_empty() { exons.push(*transcript_row); }
-                                        exons.sort_by_key(|a| self.rows[*a].start);
-                                        cds_features.sort_by_key(|a| self.rows[*a].start);
-
-                                        // merge the exon records
-                                        let mut merged_exons = Vec::<Range<u64>>::new();
-                                        for exon_row in &exons {
-                                            let exon = &self.rows[*exon_row];
-                                            if merged_exons.is_empty() || merged_exons[merged_exons.len()-1].end < exon.start-1 {
-                                                merged_exons.push((exon.start-1)..exon.end);
-                                            }
-                                            else {
-                                                if let Some(last) = merged_exons.last_mut() {
-                                                    (*last).end = exon.end;
-                                                }
-                                            }
-                                        }
-                                        // merge the cds records
-                                        let mut merged_cds = Vec::<Range<u64>>::new();
-                                        for cds_row in &cds_features {
-                                            let cds = &self.rows[*cds_row];
-                                            if merged_cds.is_empty() || merged_cds[merged_cds.len()-1].end < cds.start-1 {
-                                                merged_cds.push((cds.start-1)..cds.end);
-                                            }
-                                            else {
-                                                if let Some(last) = merged_cds.last_mut() {
-                                                    (*last).end = cds.end;
-                                                }
-                                            }
-                                        }
-                                        // fill in exon starts/ends
-                                        for exon in &merged_exons {
-                                            exon_starts.insert(exon.start);
-                                            exon_ends.insert(exon.end);
-                                        }
-                                        // get the cds ranges by unsplicing the CDS features
-                                        let mut cdss = Vec::<Range<u64>>::new();
-                                        for cds in merged_cds {
-                                            if !cdss.is_empty() &&
-                                                exon_ends.contains(&cdss[cdss.len()-1].end) &&
-                                                exon_starts.contains(&cds.start)
-                                            {
-                                                // extend current CDS if this is a splice
-                                                if let Some(last) = cdss.last_mut() {
-                                                    (*last).end = cds.end;
-                                                }
-                                            }
-                                            else {
-                                                // otherwise add new CDS
-                                                cdss.push(cds.start..cds.end);
-                                            }
-                                        }
-                                        cdss.sort_by_key(|a| a.start);
-                                        
-                                        // write the bed record
-                                        let score = 0;
-                                        let item_rgb = &[0,0,0].iter().map(|v| v.to_string()).join(",");
-                                        let start = self.rows[exons[0]].start-1;
-                                        let end = self.rows[exons[exons.len()-1]].end;
-                                        // make a separate bed record for each cds
-                                        if cdss.is_empty() { cdss.push(start..start) }
-                                        for cds in cdss {
-                                            // choose a unique transcript name
-                                            let transcript_name = 
-                                                transcript.attributes.get("transcript_name").or_else(||
-                                                transcript.attributes.get("Name").or_else(||
-                                                transcript.attributes.get("transcript_id").or_else(||
-                                                transcript.attributes.get("ID"))));
-                                            let mut transcript_name = match transcript_name {
-                                                Some(t) => t.clone(),
-                                                None => String::from(format!("{}:{}..{}:{}", 
-                                                    transcript.seqname, transcript.start-1, transcript.end, transcript.strand)),
-                                            };
-                                            while transcript_names.contains(&transcript_name) {
-                                                lazy_static! {
-                                                    static ref TRANSCRIPT_RENAME: Regex = Regex::new(r"(?:\.([0-9]+))?$").unwrap();
-                                                }
-                                                transcript_name = String::from(TRANSCRIPT_RENAME.replace(&transcript_name.as_ref(), |caps: &Captures| {
-                                                    format!(".{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
-                                            }
-                                            transcript_names.insert(transcript_name.clone());
-                                            
-                                            let chr = self.vizchrmap.get(&transcript.seqname).unwrap_or(&transcript.seqname);
-                                            // write the bed line
-                                            let line = &[
-                                                &chr,
-                                                &start.to_string(),
-                                                &end.to_string(),
-                                                &transcript_name,
-                                                &score.to_string(),
-                                                &transcript.strand,
-                                                &cds.start.to_string(),
-                                                &cds.end.to_string(),
-                                                item_rgb,
-                                                &merged_exons.len().to_string(),
-                                                &merged_exons.iter().map(|e| (e.end-e.start).to_string()).join(","),
-                                                &merged_exons.iter().map(|e| (e.start-start).to_string()).join(","),
-                                            ].iter().join("\t");
-                                            writeln!(bw, "{}", line)?;
-                                        }
-                                    }
-                                }
+        let mut records = Vec::<Bed12>::new();
+        for gene in self.sorted_genes(&types) {
+            for transcript in self.transcripts(&gene, &types) {
+                if seen_transcript.insert(transcript.row) {
+                    // get the merged exon and CDS ranges, using the whole
+                    // transcript if it has no exons
+                    let mut exons = self.exons(&transcript, &types).iter().map(|e| e.range()).collect::<Vec<_>>();
+                    if exons.is_empty() { exons.push((transcript.record.start-1)..transcript.record.end); }
+                    let merged_exons = merge_ranges(&exons);
+                    let merged_cds = merge_ranges(&self.cds(&transcript, &types).iter().map(|c| c.range()).collect::<Vec<_>>());
+                    let transcript = transcript.record;
+                    let mut exon_starts = HashSet::<u64>::new();
+                    let mut exon_ends = HashSet::<u64>::new();
+                    // fill in exon starts/ends
+                    for exon in &merged_exons {
+                        exon_starts.insert(exon.start);
+                        exon_ends.insert(exon.end);
+                    }
+                    // get the cds ranges by unsplicing the CDS features
+                    let mut cdss = Vec::<Range<u64>>::new();
+                    for cds in merged_cds {
+                        if !cdss.is_empty() &&
+                            exon_ends.contains(&cdss[cdss.len()-1].end) &&
+                            exon_starts.contains(&cds.start)
+                        {
+                            // extend current CDS if this is a splice
+                            if let Some(last) = cdss.last_mut() {
+                                (*last).end = cds.end;
+                            }
+                        }
+                        else {
+                            // otherwise add new CDS
+                            cdss.push(cds.start..cds.end);
+                        }
+                    }
+                    cdss.sort_by_key(|a| a.start);
+                    
+                    // build the bed record
+                    let start = merged_exons[0].start;
+                    let end = merged_exons[merged_exons.len()-1].end;
+                    // make a separate bed record for each cds
+                    if cdss.is_empty() { cdss.push(start..start) }
+                    for cds in cdss {
+                        // choose a unique transcript name
+                        let transcript_name = 
+                            transcript.attributes.get("transcript_name").or_else(||
+                            transcript.attributes.get("Name").or_else(||
+                            transcript.attributes.get("transcript_id").or_else(||
+                            transcript.attributes.get("ID"))));
+                        let mut transcript_name = match transcript_name {
+                            Some(t) => t.to_string(),
+                            None => String::from(format!("{}:{}..{}:{}", 
+                                transcript.seqname, transcript.start-1, transcript.end, transcript.strand)),
+                        };
+                        while transcript_names.contains(&transcript_name) {
+                            lazy_static! {
+                                static ref TRANSCRIPT_RENAME: Regex = Regex::new(r"(?:\.([0-9]+))?$").unwrap();
                             }
+                            transcript_name = String::from(TRANSCRIPT_RENAME.replace(&transcript_name.as_ref(), |caps: &Captures| {
+                                format!(".{}", caps.get(1).map(|m| m.as_str().parse::<u64>().unwrap()).unwrap_or(0)+1)}));
                         }
+                        transcript_names.insert(transcript_name.clone());
+                        
+                        let chr = self.vizchrmap.get(transcript.seqname.as_str()).map(|c| c.as_str()).unwrap_or(&transcript.seqname);
+                        records.push(Bed12 {
+                            chrom: chr.to_string(),
+                            start: start,
+                            end: end,
+                            name: transcript_name,
+                            score: 0,
+                            strand: transcript.strand.to_string(),
+                            thick_start: cds.start,
+                            thick_end: cds.end,
+                            item_rgb: [0,0,0].iter().map(|v| v.to_string()).join(","),
+                            block_sizes: merged_exons.iter().map(|e| e.end-e.start).collect(),
+                            block_starts: merged_exons.iter().map(|e| e.start-start).collect(),
+                        });
                     }
                 }
             }
         }
-        
-        // sort the bed file
-        cmd!("sort","-k1,1","-k2,2n","-o",&bed_file,&unsorted_bed_file).env("LC_COLLATE","C").run()?;
-        // remove the unsorted bed file
-        std::fs::remove_file(&unsorted_bed_file)?;
-        
+        // sort by the visualization chr names
+        sort_bed(&mut records);
+        Ok(records)
+    }
+    
+    pub fn to_bed(&self, 
+        bed_file: &str, 
+        exon_types: &[String],
+        cds_types: &[String],
+        transcript_types: &[String],
+        gene_types: &[String])
+        -> Result<()>
+    {
+        let records = self.to_bed_records(exon_types, cds_types, transcript_types, gene_types)?;
+        let mut bw = BufWriter::new(File::create(&bed_file)?);
+        for record in &records {
+            writeln!(bw, "{}", record.to_line())?;
+        }
         Ok(())
     }
     
@@ -702,25 +1538,11 @@ impl IndexedAnnotation {
         trackdb: &mut BufWriter<Box<dyn Write>>)
         -> Result<()> 
     {
-        // write the bed file
-        let bed_file = format!("{}.bed", file);
-        self.to_bed(&bed_file, exon_types, cds_types, transcript_types, gene_types)?;
-        
-        // write the genome file
-        let genome_filename = format!("{}.genome", file);
-        {   let mut genome_fh = BufWriter::new(File::create(&genome_filename)?);
-            for (chr, length) in &self.refs {
-                let chr = self.vizchrmap.get(chr).unwrap_or(chr);
-                writeln!(genome_fh, "{}\t{}", chr, length)?;
-            }
-        }
-        
-        // call bedToBigBed
-        cmd!("bedToBigBed","-type=bed12","-tab","-extraIndex=name", &bed_file, &genome_filename, &file).run()?;
-        // remove the bed file
-        //std::fs::remove_file(&bed_file)?;
-        // remove the genome file
-        std::fs::remove_file(&genome_filename)?;
+        let records = self.to_bed_records(exon_types, cds_types, transcript_types, gene_types)?;
+        let vizrefs = self.refs.iter().
+            map(|(k,v)| (self.vizchrmap.get(k).unwrap_or(k).clone(), *v)).
+            collect::<LinkedHashMap<String,u64>>();
+        write_bigbed(file, &vizrefs, &records)?;
 
         const PATH_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'+').add(b'?').add(b'&');
         let url = utf8_percent_encode(file, PATH_ENCODE_SET);
@@ -741,131 +1563,699 @@ impl IndexedAnnotation {
         Ok(())
     }
     
-    pub fn read_fasta(fasta_file: &str) -> Result<LinkedHashMap<String,(String,String)>> {
-        let mut fasta = LinkedHashMap::<String,(String,String)>::new();
-        lazy_static! {
-            static ref HEADER: Regex = Regex::new(r"^>(\S*)([^\n]*)").unwrap();
-        }
-        let f = File::open(&fasta_file)?;
-        let mut file = BufReader::new(&f);
-        let mut header: Option<String> = None;
-        let mut attrs: Option<String> = None;
-        let mut sequence: Option<String> = None;
-        let mut buf = String::new();
-        while file.read_line(&mut buf)? > 0 {
-            {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
-                if let Some(cap) = HEADER.captures(&line) {
-                    if let Some(header) = header {
-                        if let Some(attrs) = attrs {
-                            if let Some(sequence) = sequence {
-                                fasta.insert(header.clone(), (attrs.clone(), sequence.clone()));
-                            }
-                        }
-                    }
-                    header = Some(cap[1].to_string());
-                    attrs = Some(cap[2].to_string());
-                    sequence = Some(String::new());
-                }
-                else if let Some(ref mut sequence) = sequence {
-                    sequence.push_str(line.trim());
-                }
-            }
-            buf.clear();
-        }
-        if let Some(header) = header {
-            if let Some(attrs) = attrs {
-                if let Some(sequence) = sequence {
-                    fasta.insert(header, (attrs, sequence));
-                }
-            }
-        }
-        Ok(fasta)
-    }
-    
     pub fn to_fasta(&self, 
         fasta_file: &str, 
-        genome_file: &str,
+        genome: &Faidx,
         exon_types: &[String],
         transcript_types: &[String],
         gene_types: &[String])
         -> Result<()> 
     {
-        let genome = IndexedAnnotation::read_fasta(genome_file)?;
-        let exon_types = exon_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
-        let transcript_types = transcript_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
-        let gene_types = gene_types.iter().map(|t| String::from(t)).collect::<HashSet<String>>();
-        
+        let types = FeatureTypes::new(gene_types, transcript_types, exon_types, &[]);
         let mut bw = BufWriter::new(File::create(&fasta_file)?);
-        let mut chrs = self.tree.keys().collect::<Vec<_>>();
-        chrs.sort_by_key(|a| a.as_bytes());
-        for chr in chrs {
-            for node in self.tree[chr].find(0..std::u64::MAX) {
-                let gene_row = node.data();
-                let gene = &self.rows[*gene_row];
-                if gene_types.is_empty() || gene_types.contains(&gene.feature_type) {
-                    if let Some(ref gene_children) = self.row2children.get(gene_row) {
-                        for transcript_row in gene_children.iter() {
-                            let transcript = &self.rows[*transcript_row];
-                            if transcript_types.is_empty() || transcript_types.contains(&transcript.feature_type) {
-                                let mut exons = Vec::<usize>::new();
-                                if let Some(child_rows) = self.row2children.get(transcript_row) {
-                                    for child_row in child_rows {
-                                        let child = &self.rows[*child_row];
-                                        if child.seqname == transcript.seqname {
-                                            if exon_types.is_empty() || exon_types.contains(&child.feature_type) {
-                                                exons.push(*child_row);
-                                            }
-                                        }
-                                    }
-                                }
-                                if exons.is_empty() { exons.push(*transcript_row); }
-                                exons.sort_by_key(|a| self.rows[*a].start);
-                                let mut transcript_seq = String::new();
-                                for exon_row in &exons {
-                                    let exon = &self.rows[*exon_row];
-                                    if let Some(seq) = genome.get(&exon.seqname) {
-                                        if exon.end as usize > seq.1.len() {
-                                            anyhow!("to_fasta: Range {}..{} of exon at row {} exceeds sequence for {} in file {}!",
-                                                exon.start-1, exon.end, exon_row, exon.seqname, genome_file);
-                                        }
-                                        let exon_seq = &seq.1[(exon.start-1) as usize..exon.end as usize];
-                                        transcript_seq.push_str(&exon_seq);
-                                    }
-                                    else {
-                                        anyhow!("to_fasta: Could not find fasta sequence for {} in file {}!",
-                                            exon.seqname, genome_file);
-                                    }
-                                }
-                                if transcript.strand == "-" {
-                                    let seq = transcript_seq.into_bytes();
-                                    transcript_seq = String::from_utf8(dna::revcomp(&seq))?;
-                                }
-                                
-                                let transcript_name: Option<String> = 
-                                    transcript.attributes.get("transcript_id").or_else(||
-                                    transcript.attributes.get("ID")).
-                                    map(|a| a.to_owned());
-                                if transcript_name.is_none() {
-                                    anyhow!("Could not get ID for transcript at row {}", transcript_row);
-                                }
-                                let transcript_name = transcript_name.ok_or(anyhow!("NoneError"))?;
-                                lazy_static! {
-                                    static ref FASTA_FORMAT: Regex = Regex::new(r".{1,72}").unwrap();
-                                }
-                                transcript_seq = FASTA_FORMAT.replace_all(&transcript_seq,"$0\n").into_owned();
-
-                                const GFF_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'\t').add(b'\r').add(b'\n').add(b';').add(b'%').add(b'=');
-                                let attrs = transcript.attributes.iter().map(|(k,v)|
-                                    format!("{}={}", 
-                                        utf8_percent_encode(k, GFF_ENCODE_SET), 
-                                        utf8_percent_encode(v, GFF_ENCODE_SET))).join("; ");
-                                write!(bw, ">{} {}\n{}", transcript_name, attrs, transcript_seq)?;
-                            }
-                        }
+        self.write_fasta(&mut bw, genome, &types, false)?;
+        bw.flush()?;
+        Ok(())
+    }
+    
+    /// Write the spliced sequence of each transcript as FASTA, or if `cds`
+    /// is set the spliced CDS of each coding transcript.
+    pub fn write_fasta(&self, 
+        output: &mut dyn Write, 
+        genome: &Faidx,
+        types: &FeatureTypes,
+        cds: bool)
+        -> Result<()> 
+    {
+        for gene in self.sorted_genes(types) {
+            for transcript in self.transcripts(&gene, types) {
+                let mut exons = if cds {
+                    let cdss = self.cds(&transcript, types).iter().map(|c| (c.row, c.range())).collect::<Vec<_>>();
+                    if cdss.is_empty() { continue }
+                    cdss
+                } else {
+                    self.exons(&transcript, types).iter().map(|e| (e.row, e.range())).collect::<Vec<_>>()
+                };
+                // use the whole transcript if it has no exons
+                if exons.is_empty() { exons.push((transcript.row, (transcript.record.start-1)..transcript.record.end)); }
+                let transcript_row = transcript.row;
+                let transcript = transcript.record;
+                let mut transcript_seq = String::new();
+                for (exon_row, range) in exons {
+                    let exon_seq = genome.fetch(&transcript.seqname, range).map_err(|e|
+                        anyhow!("to_fasta: Could not get sequence of exon at row {}: {}", exon_row, e))?;
+                    transcript_seq.push_str(&String::from_utf8(exon_seq)?);
+                }
+                if transcript.strand == "-" {
+                    let seq = transcript_seq.into_bytes();
+                    transcript_seq = String::from_utf8(dna::revcomp(&seq))?;
+                }
+                
+                let transcript_name: Option<String> = 
+                    transcript.attributes.get("transcript_id").or_else(||
+                    transcript.attributes.get("ID")).
+                    map(|a| a.to_string());
+                let transcript_name = transcript_name.ok_or_else(|| 
+                    anyhow!("Could not get ID for transcript at row {}", transcript_row))?;
+                lazy_static! {
+                    static ref FASTA_FORMAT: Regex = Regex::new(r".{1,72}").unwrap();
+                }
+                transcript_seq = FASTA_FORMAT.replace_all(&transcript_seq,"$0\n").into_owned();
+
+                let attrs = gff_attributes(&transcript.attributes, "; ");
+                write!(output, ">{} {}\n{}", transcript_name, attrs, transcript_seq)?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Write each transcript as a genePredExt line, with the gene's ID as
+    /// name2, sorted by position. Exons are merged, and a transcript
+    /// without exons is written as a single exon.
+    pub fn write_genepred(&self, output: &mut dyn Write, types: &FeatureTypes) -> Result<()> {
+        let mut seen_transcript = HashSet::<usize>::new();
+        let mut lines = Vec::<(&str, u64, String)>::new();
+        for gene in self.sorted_genes(types) {
+            for transcript in self.transcripts(&gene, types) {
+                if !seen_transcript.insert(transcript.row) { continue }
+                let mut exons = self.exons(&transcript, types).iter().map(|e| e.range()).collect::<Vec<_>>();
+                if exons.is_empty() { exons.push((transcript.record.start-1)..transcript.record.end); }
+                let exons = merge_ranges(&exons);
+                let cds = merge_ranges(&self.cds(&transcript, types).iter().map(|c| c.range()).collect::<Vec<_>>());
+                let record = transcript.record;
+                let start = exons[0].start;
+                let end = exons[exons.len()-1].end;
+                // non-coding transcripts have an empty CDS at txEnd
+                let (cds_start, cds_end) = match (cds.first(), cds.last()) {
+                    (Some(first), Some(last)) => (first.start, last.end),
+                    _ => (end, end),
+                };
+                let cds_stat = if cds.is_empty() { "none" } else { "cmpl" };
+                // the frame of each exon's first coding base, counting
+                // coding bases from the 5' end of the transcript
+                let mut frames = vec![-1i64; exons.len()];
+                let mut coding = 0u64;
+                let mut order = (0..exons.len()).collect::<Vec<_>>();
+                if record.strand == "-" { order.reverse() }
+                for i in order {
+                    let exon = &exons[i];
+                    let length = cds.iter().
+                        map(|c| std::cmp::min(c.end, exon.end).saturating_sub(std::cmp::max(c.start, exon.start))).
+                        sum::<u64>();
+                    if length > 0 {
+                        frames[i] = (coding % 3) as i64;
+                        coding += length;
                     }
                 }
+                lines.push((&record.seqname, start, [
+                    self.gtf_id(transcript.row, "transcript_id"),
+                    record.seqname.to_string(),
+                    record.strand.to_string(),
+                    start.to_string(),
+                    end.to_string(),
+                    cds_start.to_string(),
+                    cds_end.to_string(),
+                    exons.len().to_string(),
+                    exons.iter().map(|e| format!("{},", e.start)).join(""),
+                    exons.iter().map(|e| format!("{},", e.end)).join(""),
+                    String::from("0"),
+                    self.gtf_id(gene.row, "gene_id"),
+                    cds_stat.to_string(),
+                    cds_stat.to_string(),
+                    frames.iter().map(|f| format!("{},", f)).join(""),
+                ].join("\t")));
+            }
+        }
+        lines.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()).then_with(|| a.1.cmp(&b.1)));
+        for (_, _, line) in lines {
+            writeln!(output, "{}", line)?;
+        }
+        Ok(())
+    }
+    
+    /// Rename the sequences of all rows and refs through a chr name map,
+    /// such as the vizchrmap, and rebuild the interval trees.
+    pub fn rename_seqnames(&mut self, chrmap: &HashMap<String,String>) -> Result<()> {
+        for record in &mut self.rows {
+            if let Some(seqname) = chrmap.get(record.seqname.as_str()) {
+                record.seqname = Sym::new(seqname);
             }
         }
+        self.refs = self.refs.iter().
+            map(|(k,v)| (chrmap.get(k).unwrap_or(k).clone(), *v)).
+            collect();
+        self.tree = IndexedAnnotation::build_tree(&self.rows)?;
+        self.seqnames = ChromMatcher::new(self.seqnames.aliases().clone(), self.tree.keys());
         Ok(())
     }
+
+    /// Add a feature row and return its row index. The row is wired to the
+    /// parents named by its Parent attribute, in all of their segments, so
+    /// parents must be added before their children. A row with the ID of an
+    /// existing feature is added as another segment of that feature, with
+    /// the same children.
+    pub fn add_record(&mut self, mut record: Record) -> Result<usize> {
+        let row = self.rows.len();
+        record.row = row;
+        // a new segment of a feature shares the children of the others
+        let mut children = Vec::<usize>::new();
+        if let Some(id) = record.attributes.get("ID") {
+            match self.id2row.get(id).cloned() {
+                Some(first) => {
+                    let mut segments = self.segments(first);
+                    segments.push(row);
+                    for segment in &segments {
+                        self.row2segments.insert(*segment, segments.clone());
+                    }
+                    children = self.row2children.get(&first).cloned().unwrap_or_default();
+                }
+                None => { self.id2row.insert(*id, row); }
+            }
+        }
+        let parents = record.attributes.get_all("Parent").iter().
+            filter_map(|p| self.id2row.get(p).cloned()).
+            flat_map(|p| self.segments(p)).
+            collect::<BTreeSet<_>>();
+        // update the interval index and refs in place
+        let range = record.range()?;
+        let new_seqname = !self.tree.contains_key(record.seqname.as_str());
+        self.tree.entry(record.seqname.to_string()).or_insert_with(SortedIntervals::default).insert(range, row);
+        match self.refs.get_mut(record.seqname.as_str()) {
+            Some(length) => if *length < record.end { *length = record.end },
+            None => { self.refs.insert(record.seqname.to_string(), record.end); }
+        }
+        self.rows.push(record);
+        if new_seqname {
+            self.seqnames = ChromMatcher::new(self.seqnames.aliases().clone(), self.tree.keys());
+        }
+        // keep the children and parents sorted by position
+        let rows = &self.rows;
+        for parent in &parents {
+            let children = self.row2children.entry(*parent).or_insert_with(Vec::new);
+            let i = children.iter().position(|c| by_position(rows, *c, row) == Ordering::Greater).unwrap_or(children.len());
+            children.insert(i, row);
+        }
+        if !parents.is_empty() {
+            let mut parents = parents.into_iter().collect::<Vec<_>>();
+            parents.sort_by(|a, b| by_position(rows, *a, *b));
+            self.row2parents.insert(row, parents);
+        }
+        for child in &children {
+            let parents = self.row2parents.entry(*child).or_insert_with(Vec::new);
+            let i = parents.iter().position(|p| by_position(rows, *p, row) == Ordering::Greater).unwrap_or(parents.len());
+            parents.insert(i, row);
+        }
+        if !children.is_empty() {
+            self.row2children.insert(row, children);
+        }
+        Ok(row)
+    }
+
+    /// Remove features with all their segments, and all their descendants
+    /// that have no parent outside of them. Returns the removed records.
+    ///
+    /// Like `remove_rows`, this renumbers the rows, so all the features to
+    /// remove are passed in one call.
+    pub fn remove_features(&mut self, rows: &[usize]) -> Result<Vec<Record>> {
+        let segments = rows.iter().flat_map(|row| self.segments(*row)).collect::<HashSet<_>>();
+        let mut removed = segments.iter().cloned().collect::<BTreeSet<_>>();
+        let mut queue = segments.iter().cloned().collect::<Vec<_>>();
+        while let Some(r) = queue.pop() {
+            for child in self.row2children.get(&r).into_iter().flatten() {
+                if removed.insert(*child) { queue.push(*child) }
+            }
+        }
+        // keep the descendants that also belong to other features
+        loop {
+            let keep = removed.iter().
+                filter(|r| !segments.contains(r)).
+                filter(|r| self.row2parents.get(r).into_iter().flatten().any(|p| !removed.contains(p))).
+                cloned().
+                collect::<Vec<_>>();
+            if keep.is_empty() { break }
+            for r in keep {
+                removed.remove(&r);
+            }
+        }
+        self.remove_rows(&removed)
+    }
+
+    /// Remove rows and renumber the remaining rows. Parent, child and
+    /// segment links to the removed rows are dropped. Returns the removed
+    /// records.
+    ///
+    /// The rows keep their order, but every row after the first removed row
+    /// gets a new index, so row indices held from before the removal, such
+    /// as those returned by `add_record`, no longer refer to the same
+    /// features. Remove rows in one batch, and look features up again
+    /// afterwards, e.g. by ID through `id2row`.
+    pub fn remove_rows(&mut self, rows: &BTreeSet<usize>) -> Result<Vec<Record>> {
+        let mut newrows = HashMap::<usize,usize>::new();
+        let mut kept = Vec::<Record>::new();
+        let mut removed = Vec::<Record>::new();
+        for (row, mut record) in std::mem::take(&mut self.rows).into_iter().enumerate() {
+            if rows.contains(&row) {
+                removed.push(record);
+            } else {
+                newrows.insert(row, kept.len());
+                record.row = kept.len();
+                kept.push(record);
+            }
+        }
+        self.rows = kept;
+        let remap = |index: &HashMap<usize, Vec<usize>>| -> HashMap<usize, Vec<usize>> {
+            index.iter().filter_map(|(row, rs)| {
+                let rs = rs.iter().filter_map(|r| newrows.get(r).cloned()).collect::<Vec<_>>();
+                if rs.is_empty() { None } else { newrows.get(row).map(|row| (*row, rs)) }
+            }).collect()
+        };
+        // an ID whose first row was removed moves to its next segment
+        let id2row = self.id2row.iter().filter_map(|(id, row)| {
+            newrows.get(row).cloned().
+                or_else(|| self.row2segments.get(row).and_then(|ss| ss.iter().find_map(|s| newrows.get(s).cloned()))).
+                map(|row| (*id, row))
+        }).collect();
+        self.id2row = id2row;
+        self.row2parents = remap(&self.row2parents);
+        self.row2children = remap(&self.row2children);
+        self.row2segments = remap(&self.row2segments).into_iter().filter(|(_, ss)| ss.len() > 1).collect();
+        self.tree = IndexedAnnotation::build_tree(&self.rows)?;
+        self.seqnames = ChromMatcher::new(self.seqnames.aliases().clone(), self.tree.keys());
+        Ok(removed)
+    }
+
+    /// The rows of the features that pass a filter. The filter is
+    /// evaluated on the features without children, such as exons, once for
+    /// each path from a top-level feature, with the attributes a feature
+    /// doesn't have looked up in its ancestors on the path. Features with
+    /// children pass if any of their children pass along a path through
+    /// them, so a gene passes with only its transcripts that pass.
+    pub fn filter_rows(&self, filter: &Filter) -> HashSet<usize> {
+        let mut kept = HashSet::<usize>::new();
+        let mut path = Vec::<usize>::new();
+        for row in 0..self.rows.len() {
+            if !self.row2parents.contains_key(&row) {
+                self.retain_path(row, filter, &mut path, &mut kept);
+            }
+        }
+        // keep all segments of a kept feature
+        for row in kept.iter().cloned().collect::<Vec<_>>() {
+            kept.extend(self.segments(row));
+        }
+        kept
+    }
+
+    /// Remove the features that don't pass a filter, as selected by
+    /// `filter_rows`. Returns the removed records. The rows are renumbered
+    /// as by `remove_rows`.
+    pub fn retain_features(&mut self, filter: &Filter) -> Result<Vec<Record>> {
+        let kept = self.filter_rows(filter);
+        let removed = (0..self.rows.len()).filter(|row| !kept.contains(row)).collect::<BTreeSet<_>>();
+        self.remove_rows(&removed)
+    }
+
+    // does the feature at `row` pass the filter below `path`? Adds the rows
+    // that pass to `kept`.
+    fn retain_path(&self, row: usize, filter: &Filter, path: &mut Vec<usize>, kept: &mut HashSet<usize>) -> bool {
+        // parent cycles are invalid, and their features are removed
+        if path.contains(&row) { return false }
+        path.push(row);
+        let pass = match self.row2children.get(&row) {
+            Some(children) => {
+                let mut pass = false;
+                for child in children {
+                    pass |= self.retain_path(*child, filter, path, kept);
+                }
+                pass
+            }
+            None => {
+                let records = path.iter().rev().map(|r| &self.rows[*r]).collect::<Vec<_>>();
+                filter.matches(&records)
+            }
+        };
+        path.pop();
+        if pass { kept.insert(row); }
+        pass
+    }
+
+    /// An ID that no feature has: `id` itself if it is unused, or else
+    /// `id` with the first unused numeric suffix.
+    pub fn unique_id(&self, id: &str) -> String {
+        let mut id = id.to_string();
+        while Sym::get(&id).map(|sym| self.id2row.contains_key(&sym)).unwrap_or(false) {
+            id = next_id(&id);
+        }
+        id
+    }
+
+    /// Match other files' seqnames to the annotation's through a chr alias
+    /// table, in addition to the built-in matching of chr1, 1 and
+    /// NC_000001.11 style names.
+    pub fn set_chrom_aliases(&mut self, aliases: ChromAliases) {
+        self.seqnames = ChromMatcher::new(aliases, self.tree.keys());
+    }
+
+    /// The annotation's seqname for a seqname of another file, after
+    /// applying the chrmap and then matching chr name aliases.
+    pub fn seqname(&self, name: &str) -> Option<&String> {
+        let name = self.chrmap.get(name).map(|n| n.as_str()).unwrap_or(name);
+        self.seqnames.get(name)
+    }
+
+    /// Like `seqname`, but returns the name itself after applying the
+    /// chrmap if it matches no annotation seqname.
+    pub fn map_seqname(&self, name: &str) -> String {
+        match self.seqname(name) {
+            Some(seqname) => seqname.clone(),
+            None => self.chrmap.get(name).map(|n| n.as_str()).unwrap_or(name).to_string(),
+        }
+    }
+
+    /// Map the seqnames of another file, such as a bam file or genome, to
+    /// the annotation's seqnames. Names that match no annotation seqname
+    /// map to themselves after applying the chrmap. Warns about
+    /// annotation seqnames that `source` has no match for.
+    pub fn match_seqnames<'a>(&self, names: impl IntoIterator<Item=&'a String>, source: &str) -> HashMap<String,String> {
+        let mut matched = HashMap::<String,String>::new();
+        let mut aliased = Vec::<String>::new();
+        for name in names {
+            let seqname = self.map_seqname(name);
+            if &seqname != self.chrmap.get(name).unwrap_or(name) {
+                aliased.push(format!("{}={}", name, seqname));
+            }
+            matched.insert(name.clone(), seqname);
+        }
+        if !aliased.is_empty() {
+            eprintln!("Matched {} seqnames of {} to the annotation by chr name aliases: {}",
+                aliased.len(), source, abbreviate(&aliased));
+        }
+        let found = matched.values().collect::<HashSet<_>>();
+        let mut missing = self.seqnames.names().filter(|n| !found.contains(n)).cloned().collect::<Vec<_>>();
+        if !missing.is_empty() {
+            missing.sort();
+            eprintln!("Warning: {} of {} annotation seqnames have no matching seqname in {}: {}",
+                missing.len(), self.tree.len(), source, abbreviate(&missing));
+        }
+        matched
+    }
+}
+
+/// The next ID in a series: an ID ending in a number after a "." or ":"
+/// has it incremented, e.g. exon:T1:1 becomes exon:T1:2, and any other ID
+/// gets ".1" appended.
+pub fn next_id(id: &str) -> String {
+    lazy_static! {
+        static ref NUMBERED: Regex = Regex::new(r"^(.*[.:])([0-9]+)$").unwrap();
+    }
+    match NUMBERED.captures(id).and_then(|caps| caps[2].parse::<u64>().ok().map(|n| (caps[1].to_string(), n))) {
+        Some((prefix, n)) => format!("{}{}", prefix, n+1),
+        None => format!("{}.1", id),
+    }
+}
+
+// order rows by seqname and start
+fn by_position(rows: &[Record], a: usize, b: usize) -> Ordering {
+    rows[a].seqname.as_bytes().cmp(rows[b].seqname.as_bytes()).
+        then_with(|| rows[a].start.cmp(&rows[b].start))
+}
+
+// join names for a message, listing only the first few
+fn abbreviate(names: &[String]) -> String {
+    const MAX_NAMES: usize = 10;
+    if names.len() > MAX_NAMES {
+        format!("{}, ... ({} more)", names[..MAX_NAMES].join(", "), names.len()-MAX_NAMES)
+    } else {
+        names.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse GFF3 text from a temporary file, without caching
+    fn parse_gff(name: &str, text: &str) -> IndexedAnnotation {
+        let file = std::env::temp_dir().join(format!("{}_{}.gff3", name, std::process::id()));
+        let file = file.to_str().unwrap();
+        std::fs::write(file, text.replace("  ", "\t")).unwrap();
+        let annot = IndexedAnnotation::parse_file(file, "gff", "gene", "mRNA", &None, &None, true).unwrap();
+        std::fs::remove_file(file).unwrap();
+        annot
+    }
+
+    // parse GTF text from a temporary file, without caching
+    fn parse_gtf(name: &str, text: &str) -> IndexedAnnotation {
+        let file = std::env::temp_dir().join(format!("{}_{}.gtf", name, std::process::id()));
+        let file = file.to_str().unwrap();
+        std::fs::write(file, text.replace("  ", "\t")).unwrap();
+        let annot = IndexedAnnotation::parse_file(file, "gtf", "gene", "transcript", &None, &None, true).unwrap();
+        std::fs::remove_file(file).unwrap();
+        annot
+    }
+
+    fn rows_with_id(annot: &IndexedAnnotation, id: &str) -> Vec<usize> {
+        (0..annot.rows.len()).filter(|row| annot.rows[*row].attributes.get("ID").map(|i| i == id).unwrap_or(false)).collect()
+    }
+
+    #[test]
+    fn multi_row_parent() {
+        let annot = parse_gff("multi_row_parent", concat!(
+            "##gff-version 3\n",
+            "chr1  .  gene  1  1000  .  +  .  ID=g1\n",
+            "chr1  .  mRNA  1  400  .  +  .  ID=t1;Parent=g1\n",
+            "chr1  .  mRNA  600  1000  .  +  .  ID=t1;Parent=g1\n",
+            "chr1  .  exon  1  100  .  +  .  ID=e1;Parent=t1\n",
+            "chr1  .  exon  300  400  .  +  .  ID=e2;Parent=t1\n",
+            "chr1  .  exon  600  700  .  +  .  ID=e3;Parent=t1\n",
+            "chr1  .  exon  900  1000  .  +  .  ID=e4;Parent=t1\n"));
+        let transcript_rows = rows_with_id(&annot, "t1");
+        assert_eq!(transcript_rows.len(), 2);
+        let exon_rows = ["e1", "e2", "e3", "e4"].iter().map(|id| rows_with_id(&annot, id)[0]).collect::<Vec<_>>();
+        // every exon is a child of both rows of the transcript
+        for transcript_row in &transcript_rows {
+            assert_eq!(annot.row2children[transcript_row], exon_rows);
+            assert_eq!(annot.segments(*transcript_row), transcript_rows);
+        }
+        for exon_row in &exon_rows {
+            assert_eq!(annot.row2parents[exon_row], transcript_rows);
+        }
+
+        // the views list the transcript once, with all of its exons
+        let types = FeatureTypes::new(&["gene".to_string()], &["mRNA".to_string()], &["exon".to_string()], &[]);
+        let genes = annot.genes(&types);
+        assert_eq!(genes.len(), 1);
+        let transcripts = annot.transcripts(&genes[0], &types);
+        assert_eq!(transcripts.len(), 1);
+        assert_eq!(annot.exons(&transcripts[0], &types).iter().map(|e| e.row).collect::<Vec<_>>(), exon_rows);
+        let mut gtf = Vec::new();
+        annot.write_gtf(&mut gtf, &[]).unwrap();
+        let gtf = String::from_utf8(gtf).unwrap();
+        assert_eq!(gtf.lines().filter(|l| l.split('\t').nth(2) == Some("exon")).count(), 4);
+    }
+
+    #[test]
+    fn gtf_round_trip() {
+        let annot = parse_gtf("gtf_round_trip", concat!(
+            "chr1  t  gene  100  1000  .  +  .  gene_id \"g1\"; gene_name \"say \\\"hi\\\"\";\n",
+            "chr1  t  transcript  100  1000  .  +  .  gene_id \"g1\"; transcript_id \"t1\"; tag \"basic\"; tag \"CCDS\";\n",
+            "chr1  t  exon  100  200  .  +  .  gene_id \"g1\"; transcript_id \"t1\"; note \"back\\\\slash; \\\"quoted\\\"\";\n",
+            "chr1  t  exon  500  1000  .  +  .  gene_id \"g1\"; transcript_id \"t1\";\n",
+            "chr1  t  transcript  150  900  .  +  .  gene_id \"g1\"; transcript_id \"t2\";\n",
+            "chr1  t  exon  150  200  .  +  .  gene_id \"g1\"; transcript_id \"t2\";\n",
+            "chr1  t  exon  800  900  .  +  .  gene_id \"g1\"; transcript_id \"t2\";\n"));
+        let write = |annot: &IndexedAnnotation| {
+            let mut gtf = Vec::new();
+            annot.write_gtf(&mut gtf, &[]).unwrap();
+            String::from_utf8(gtf).unwrap()
+        };
+        let gtf = write(&annot);
+        let annot = parse_gtf("gtf_round_trip2", &gtf);
+        // escaped values and repeated tags survive the round trip
+        let row = |feature_type: &str, start: u64| annot.rows.iter().
+            find(|r| r.feature_type == feature_type && r.start == start).unwrap();
+        assert_eq!(row("gene", 100).attributes["gene_name"], "say \"hi\"");
+        assert_eq!(row("transcript", 100).attributes.get_all("tag"), &[Sym::new("basic"), Sym::new("CCDS")]);
+        assert_eq!(row("exon", 100).attributes["note"], "back\\slash; \"quoted\"");
+        assert_eq!(write(&annot), gtf);
+        // each transcript is written with its features, not interleaved
+        // with the other transcript's by position
+        let order = gtf.lines().map(|l| {
+            let record = Record::from_row(0, l, "gtf", &HashMap::new()).unwrap();
+            (record.feature_type.to_string(), record.start,
+                record.attributes.get("transcript_id").map(|t| t.to_string()).unwrap_or_default())
+        }).collect::<Vec<_>>();
+        let line = |feature_type: &str, start: u64, transcript_id: &str| (feature_type.to_string(), start, transcript_id.to_string());
+        assert_eq!(order, vec![
+            line("gene", 100, ""),
+            line("transcript", 100, "t1"),
+            line("exon", 100, "t1"),
+            line("exon", 500, "t1"),
+            line("transcript", 150, "t2"),
+            line("exon", 150, "t2"),
+            line("exon", 800, "t2"),
+        ]);
+    }
+
+    #[test]
+    fn gff_round_trip() {
+        let text = concat!(
+            "##gff-version 3\n",
+            "chr1  .  gene  1  1000  .  +  .  ID=g1;Name=A%2CB\n",
+            "chr1  .  mRNA  1  1000  .  +  .  ID=t1;Parent=g1\n",
+            "chr1  .  mRNA  1  800  .  +  .  ID=t2;Parent=g1\n",
+            "chr1  .  exon  1  100  .  +  .  ID=e1;Parent=t1,t2;Note=a%2Cb%3Bc%3Dd%25,second;Dbxref=x:1,y:2\n",
+            "chr1  .  exon  700  800  .  +  .  ID=e2;Parent=t1,t2\n");
+        let annot = parse_gff("gff_round_trip", text);
+        let write = |annot: &IndexedAnnotation| {
+            let mut gff = Vec::new();
+            annot.write_gff(&mut gff, true, &[]).unwrap();
+            String::from_utf8(gff).unwrap()
+        };
+        let check = |annot: &IndexedAnnotation| {
+            let e1 = rows_with_id(annot, "e1")[0];
+            let transcripts = ["t1", "t2"].iter().map(|t| rows_with_id(annot, t)[0]).collect::<Vec<_>>();
+            assert_eq!(annot.row2parents[&e1], transcripts);
+            let attributes = &annot.rows[e1].attributes;
+            assert_eq!(attributes.get_all("Parent"), &[Sym::new("t1"), Sym::new("t2")]);
+            // encoded separators are part of a value, commas split values
+            assert_eq!(attributes.get_all("Note"), &[Sym::new("a,b;c=d%"), Sym::new("second")]);
+            assert_eq!(attributes.get_all("Dbxref"), &[Sym::new("x:1"), Sym::new("y:2")]);
+            assert_eq!(annot.rows[rows_with_id(annot, "g1")[0]].attributes["Name"], "A,B");
+        };
+        check(&annot);
+        let gff = write(&annot);
+        assert!(gff.contains("Parent=t1,t2;Note=a%2Cb%3Bc%3Dd%25,second;Dbxref=x:1,y:2"), "{}", gff);
+        let annot = parse_gff("gff_round_trip2", &gff);
+        check(&annot);
+        assert_eq!(write(&annot), gff);
+    }
+
+    #[test]
+    fn transcript_with_stray_exon() {
+        let annot = parse_gff("transcript_with_stray_exon", concat!(
+            "##gff-version 3\n",
+            "chr1  .  gene  1  1000  .  +  .  ID=g1\n",
+            "chr1  .  mRNA  1  1000  .  +  .  ID=t1;Parent=g1\n",
+            "chr1  .  exon  1  100  .  +  .  Parent=t1\n",
+            "chr1  .  exon  900  1000  .  +  .  Parent=t1\n",
+            "chr1  .  mRNA  1  1000  .  +  .  ID=t2;Parent=g1\n",
+            "chr1  .  exon  1  100  .  +  .  Parent=t2\n",
+            "chr1  .  exon  500  600  .  -  .  Parent=t2\n",
+            "chr1  .  exon  900  1000  .  +  .  Parent=t2\n"));
+        // t2 is skipped instead of being read as a transcript without its middle exon
+        let types = FeatureTypes::new(&["gene".to_string()], &["mRNA".to_string()], &["exon".to_string()], &[]);
+        let genes = annot.genes(&types);
+        let transcripts = annot.transcripts(&genes[0], &types);
+        assert_eq!(transcripts.len(), 1);
+        assert_eq!(transcripts[0].record.attributes.get("ID").map(|i| i.as_str()), Some("t1"));
+        assert_eq!(annot.introns(&transcripts[0], &types), vec![100..899]);
+    }
+
+    #[test]
+    fn add_record_multi_row_parent() {
+        let mut annot = parse_gff("add_record_multi_row_parent", concat!(
+            "##gff-version 3\n",
+            "chr1  .  mRNA  1  400  .  +  .  ID=t1\n",
+            "chr1  .  mRNA  600  1000  .  +  .  ID=t1\n"));
+        let mut exon = Record::new();
+        exon.seqname = Sym::new("chr1");
+        exon.feature_type = Sym::new("exon");
+        exon.start = 600;
+        exon.end = 700;
+        exon.strand = Strand::Forward;
+        exon.attributes.insert("ID", "e1");
+        exon.attributes.insert("Parent", "t1");
+        let exon_row = annot.add_record(exon).unwrap();
+        assert_eq!(annot.row2parents[&exon_row], vec![0, 1]);
+        assert_eq!(annot.row2children[&0], vec![exon_row]);
+        assert_eq!(annot.row2children[&1], vec![exon_row]);
+
+        // a segment added later shares the children of the others
+        let mut segment = annot.rows[1].clone();
+        segment.start = 1200;
+        segment.end = 1300;
+        let segment_row = annot.add_record(segment).unwrap();
+        assert_eq!(annot.segments(0), vec![0, 1, segment_row]);
+        assert_eq!(annot.row2children[&segment_row], vec![exon_row]);
+        assert_eq!(annot.row2parents[&exon_row], vec![0, 1, segment_row]);
+    }
+
+    #[test]
+    fn filter_rows() {
+        let mut annot = parse_gff("filter_rows", concat!(
+            "##gff-version 3\n",
+            "chr1  .  gene  1  1000  .  +  .  ID=g1;gene_biotype=protein_coding\n",
+            "chr1  .  mRNA  1  1000  .  +  .  ID=t1;Parent=g1;tag=basic\n",
+            "chr1  .  exon  1  100  .  +  .  ID=e1;Parent=t1\n",
+            "chr1  .  mRNA  1  1000  .  +  .  ID=t2;Parent=g1\n",
+            "chr1  .  exon  900  1000  .  +  .  ID=e2;Parent=t2\n",
+            "chr1  .  gene  2000  3000  .  +  .  ID=g2;gene_biotype=pseudogene\n",
+            "chr1  .  exon  2000  3000  .  +  .  ID=e3;Parent=g2;tag=basic\n"));
+        let filter = Filter::parse("gene_biotype=protein_coding and tag=basic").unwrap();
+        let mut kept = annot.filter_rows(&filter).into_iter().collect::<Vec<_>>();
+        kept.sort();
+        // the gene passes with only its transcript that passes
+        assert_eq!(kept, vec![0, 1, 2]);
+        assert_eq!(annot.rows.len(), 7);
+        let removed = annot.retain_features(&filter).unwrap();
+        assert_eq!(removed.iter().map(|r| r.attributes.get("ID").unwrap().to_string()).collect::<Vec<_>>(), vec!["t2", "e2", "g2", "e3"]);
+    }
+
+    #[test]
+    fn strand_regions() {
+        let annot = parse_gff("strand_regions", concat!(
+            "##gff-version 3\n",
+            "chr1  .  exon  1  100  .  +  .  ID=a\n",
+            "chr1  .  exon  50  150  .  +  .  ID=b\n",
+            "chr1  .  exon  151  200  .  +  .  ID=c\n",
+            "chr1  .  exon  181  300  .  -  .  ID=d\n",
+            "chr1  .  exon  400  500  .  -  .  ID=e\n",
+            "chr1  .  exon  450  460  .  +  .  ID=f\n",
+            "chr1  .  exon  600  700  .  .  .  ID=g\n",
+            "chr2  .  exon  10  20  .  +  .  ID=h\n",
+            "chr2  .  exon  21  30  .  -  .  ID=i\n"));
+        let regions = annot.strand_regions();
+        // overlapping and adjacent features on a strand are merged, the
+        // other strand is cut out, and unstranded features are ignored
+        assert_eq!(regions["chr1"], vec![(0..180, b'+'), (200..300, b'-'), (399..449, b'-'), (460..500, b'-')]);
+        assert_eq!(regions["chr2"], vec![(9..20, b'+'), (20..30, b'-')]);
+        assert_eq!(regions.len(), 2);
+    }
+
+    #[test]
+    fn remove_features_in_one_batch() {
+        let text = concat!(
+            "##gff-version 3\n",
+            "chr1  .  gene  1  1000  .  +  .  ID=g1\n",
+            "chr1  .  mRNA  1  1000  .  +  .  ID=t1;Parent=g1\n",
+            "chr1  .  exon  1  100  .  +  .  ID=e1;Parent=t1\n",
+            "chr1  .  exon  900  1000  .  +  .  ID=e2;Parent=t1\n",
+            "chr1  .  gene  2000  3000  .  +  .  ID=g2\n",
+            "chr1  .  mRNA  2000  3000  .  +  .  ID=t2;Parent=g2\n",
+            "chr1  .  exon  2000  3000  .  +  .  ID=e3;Parent=t2,t1\n");
+        let mut annot = parse_gff("remove_features_in_one_batch", text);
+        let original = annot.rows.iter().map(|r| r.to_gff().unwrap()).collect::<Vec<_>>();
+        // add a copy of each transcript, then remove both copies
+        let mut new_rows = Vec::new();
+        for id in &["t1", "t2"] {
+            let mut transcript = annot.rows[annot.id2row[&Sym::new(id)]].clone();
+            let new_id = annot.unique_id(id);
+            transcript.attributes.insert("ID", &new_id);
+            new_rows.push(annot.add_record(transcript).unwrap());
+            let mut exon = annot.rows[rows_with_id(&annot, "e1")[0]].clone();
+            exon.attributes.insert("ID", &annot.unique_id("e1"));
+            exon.attributes.insert("Parent", &new_id);
+            annot.add_record(exon).unwrap();
+        }
+        assert_eq!(annot.rows.len(), original.len()+4);
+        let removed = annot.remove_features(&new_rows).unwrap();
+        assert_eq!(removed.len(), 4);
+        assert_eq!(annot.rows.iter().map(|r| r.to_gff().unwrap()).collect::<Vec<_>>(), original);
+        // e3 lost neither of its parents, and the tree has the new row numbers
+        let e3 = rows_with_id(&annot, "e3")[0];
+        assert_eq!(annot.row2parents[&e3], vec![annot.id2row[&Sym::new("t1")], annot.id2row[&Sym::new("t2")]]);
+        let mut found = annot.tree["chr1"].find(0..u64::MAX).map(|e| *e.data()).collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(found, (0..annot.rows.len()).collect::<Vec<_>>());
+    }
 }
@@ -0,0 +1,436 @@
+//! Interval overlap indexes. `SortedIntervals` keeps its intervals in a
+//! single array sorted by start, with each element also acting as a node of
+//! an implicit binary tree augmented with the maximum end of its subtree
+//! (the layout used by cgranges). Compared to a tree of separately allocated
+//! nodes it is built with one sort, uses less memory, and its queries and
+//! ordered iteration walk contiguous memory.
+use std::ops::Range;
+use std::iter::Peekable;
+use bio::data_structures::interval_tree::{IntervalTree, IntervalTreeIterator};
+
+/// An interval and its data, as returned by queries on an `IntervalIndex`.
+#[derive(Debug)]
+pub struct Entry<'a, T> {
+    interval: &'a Range<u64>,
+    data: &'a T,
+}
+
+impl<'a, T> Clone for Entry<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<'a, T> Copy for Entry<'a, T> {}
+
+impl<'a, T> Entry<'a, T> {
+    pub fn interval(&self) -> &'a Range<u64> {
+        self.interval
+    }
+    pub fn data(&self) -> &'a T {
+        self.data
+    }
+}
+
+/// An index of 0-based half-open intervals that can be queried for overlaps.
+pub trait IntervalIndex<T> {
+    type Find<'a>: Iterator<Item=Entry<'a, T>> where Self: 'a, T: 'a;
+    /// The entries overlapping `range`.
+    fn find(&self, range: Range<u64>) -> Self::Find<'_>;
+}
+
+/// An immutable interval index built from all of its intervals at once.
+/// Queries and iteration return entries sorted by start, and entries with
+/// the same start in the order they were given.
+///
+/// Intervals can also be inserted one at a time. They are kept in a small
+/// sorted list beside the index and merged into it once the list grows
+/// past the square root of the index size, so inserting is cheap but a
+/// built index is faster to query.
+#[derive(Clone, Debug)]
+pub struct SortedIntervals<T> {
+    // sorted by start
+    entries: Vec<(Range<u64>, T)>,
+    // the maximum end of the implicit subtree of each entry
+    max_end: Vec<u64>,
+    // the level of the root of the implicit tree
+    root_level: u32,
+    // inserted entries that are not yet in the index, sorted by start
+    added: Vec<(Range<u64>, T)>,
+}
+
+impl<T> Default for SortedIntervals<T> {
+    fn default() -> Self {
+        SortedIntervals::new(Vec::new())
+    }
+}
+
+impl<T> SortedIntervals<T> {
+    pub fn new(mut entries: Vec<(Range<u64>, T)>) -> SortedIntervals<T> {
+        // a stable sort keeps entries with equal starts in order
+        entries.sort_by_key(|(range, _)| range.start);
+        let mut index = SortedIntervals {
+            entries: entries,
+            max_end: Vec::new(),
+            root_level: 0,
+            added: Vec::new(),
+        };
+        index.augment();
+        index
+    }
+
+    // compute the maximum subtree ends bottom up. The node at index i has
+    // level k if the lowest k bits of i are set, and its children are at
+    // i-2^(k-1) and i+2^(k-1). Right children past the end of the array
+    // only stand for their left descendants.
+    fn augment(&mut self) {
+        let n = self.entries.len();
+        self.max_end = self.entries.iter().map(|(range, _)| range.end).collect();
+        self.root_level = 0;
+        while (1usize << (self.root_level+1)) - 1 < n {
+            self.root_level += 1;
+        }
+        for level in 1..=self.root_level {
+            let half = 1usize << (level-1);
+            let mut i = (1usize << level) - 1;
+            while i < n {
+                let mut max_end = std::cmp::max(self.max_end[i], self.max_end[i-half]);
+                if let Some(right) = self.subtree_max_end(i+half, level-1) {
+                    max_end = std::cmp::max(max_end, right);
+                }
+                self.max_end[i] = max_end;
+                i += 1usize << (level+1);
+            }
+        }
+    }
+
+    // the maximum end of the subtree at node i of the given level, which
+    // may be past the end of the array
+    fn subtree_max_end(&self, mut i: usize, mut level: u32) -> Option<u64> {
+        while i >= self.entries.len() {
+            if level == 0 { return None }
+            level -= 1;
+            i -= 1usize << level;
+        }
+        Some(self.max_end[i])
+    }
+
+    /// Insert an interval. Entries with the same start are kept in the
+    /// order they were inserted.
+    pub fn insert(&mut self, range: Range<u64>, data: T) {
+        let pos = self.added.partition_point(|(r, _)| r.start <= range.start);
+        self.added.insert(pos, (range, data));
+        if self.added.len() > 16 && self.added.len().pow(2) > self.entries.len() {
+            let entries = std::mem::take(&mut self.entries);
+            let added = std::mem::take(&mut self.added);
+            self.entries = merge_by_start(entries.into_iter(), added.into_iter()).collect();
+            self.augment();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.added.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// All entries in order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { inner: merge_by_start(
+            self.entries.iter().map(entry as EntryFn<'_, T>),
+            self.added.iter().map(entry as EntryFn<'_, T>)) }
+    }
+
+    /// The entries overlapping `range`, in order.
+    pub fn find(&self, range: Range<u64>) -> Find<'_, T> {
+        let mut overlaps = Overlaps {
+            entries: &self.entries,
+            max_end: &self.max_end,
+            range: range.clone(),
+            stack: [(0, 0, false); 64],
+            len: 0,
+            scan: 0..0,
+        };
+        if !self.entries.is_empty() {
+            overlaps.push((1usize << self.root_level) - 1, self.root_level, false);
+        }
+        let added = AddedOverlaps { added: &self.added, pos: 0, range: range };
+        Find { inner: merge_by_start(overlaps, added) }
+    }
+}
+
+impl<T> std::iter::FromIterator<(Range<u64>, T)> for SortedIntervals<T> {
+    fn from_iter<I: IntoIterator<Item=(Range<u64>, T)>>(iter: I) -> Self {
+        SortedIntervals::new(iter.into_iter().collect())
+    }
+}
+
+impl<T> IntervalIndex<T> for SortedIntervals<T> {
+    type Find<'a> = Find<'a, T> where T: 'a;
+    fn find(&self, range: Range<u64>) -> Find<'_, T> {
+        SortedIntervals::find(self, range)
+    }
+}
+
+impl<T> IntervalIndex<T> for IntervalTree<u64, T> {
+    type Find<'a> = std::iter::Map<IntervalTreeIterator<'a, u64, T>, fn(bio::data_structures::interval_tree::Entry<'a, u64, T>) -> Entry<'a, T>> where T: 'a;
+    fn find(&self, range: Range<u64>) -> Self::Find<'_> {
+        IntervalTree::find(self, range).map(|e| Entry { interval: e.interval(), data: e.data() })
+    }
+}
+
+type EntryFn<'a, T> = fn(&'a (Range<u64>, T)) -> Entry<'a, T>;
+type EntryIter<'a, T> = std::iter::Map<std::slice::Iter<'a, (Range<u64>, T)>, EntryFn<'a, T>>;
+
+fn entry<T>((range, data): &(Range<u64>, T)) -> Entry<'_, T> {
+    Entry { interval: range, data: data }
+}
+
+// empty intervals never overlap anything, as in bio's IntervalTree
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < a.end && b.start < b.end && a.start < b.end && b.start < a.end
+}
+
+// merge two iterators that are each sorted by start, taking from the first
+// when the starts are equal
+fn merge_by_start<E: HasStart, I: Iterator<Item=E>, J: Iterator<Item=E>>(a: I, b: J) -> MergeByStart<I, J> {
+    MergeByStart { a: a.peekable(), b: b.peekable() }
+}
+
+trait HasStart {
+    fn start(&self) -> u64;
+}
+
+impl<T> HasStart for (Range<u64>, T) {
+    fn start(&self) -> u64 {
+        self.0.start
+    }
+}
+
+impl<'a, T> HasStart for Entry<'a, T> {
+    fn start(&self) -> u64 {
+        self.interval.start
+    }
+}
+
+struct MergeByStart<I: Iterator, J: Iterator> {
+    a: Peekable<I>,
+    b: Peekable<J>,
+}
+
+impl<E: HasStart, I: Iterator<Item=E>, J: Iterator<Item=E>> Iterator for MergeByStart<I, J> {
+    type Item = E;
+    fn next(&mut self) -> Option<E> {
+        match (self.a.peek(), self.b.peek()) {
+            (Some(a), Some(b)) if b.start() < a.start() => self.b.next(),
+            (Some(_), _) => self.a.next(),
+            (None, _) => self.b.next(),
+        }
+    }
+}
+
+/// An iterator over all entries of a `SortedIntervals`, in order.
+pub struct Iter<'a, T> {
+    inner: MergeByStart<EntryIter<'a, T>, EntryIter<'a, T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = Entry<'a, T>;
+    fn next(&mut self) -> Option<Entry<'a, T>> {
+        self.inner.next()
+    }
+}
+
+/// An iterator over the entries of a `SortedIntervals` that overlap a
+/// range, in order.
+pub struct Find<'a, T> {
+    inner: MergeByStart<Overlaps<'a, T>, AddedOverlaps<'a, T>>,
+}
+
+impl<'a, T> Iterator for Find<'a, T> {
+    type Item = Entry<'a, T>;
+    fn next(&mut self) -> Option<Entry<'a, T>> {
+        self.inner.next()
+    }
+}
+
+// an in-order walk of the implicit tree that skips the subtrees that end
+// before the range, and stops at the first entry that starts after it.
+// Small subtrees are scanned in order instead of walked.
+struct Overlaps<'a, T> {
+    entries: &'a [(Range<u64>, T)],
+    max_end: &'a [u64],
+    range: Range<u64>,
+    // the nodes still to visit, with whether their left subtree was visited
+    stack: [(usize, u32, bool); 64],
+    len: usize,
+    // the entries of the small subtree being scanned
+    scan: Range<usize>,
+}
+
+impl<'a, T> Overlaps<'a, T> {
+    fn push(&mut self, i: usize, level: u32, left_done: bool) {
+        self.stack[self.len] = (i, level, left_done);
+        self.len += 1;
+    }
+}
+
+// the level of subtrees that are scanned instead of walked
+const SCAN_LEVEL: u32 = 3;
+
+impl<'a, T> Iterator for Overlaps<'a, T> {
+    type Item = Entry<'a, T>;
+    fn next(&mut self) -> Option<Entry<'a, T>> {
+        loop {
+            while self.scan.start < self.scan.end {
+                let (ref interval, ref data) = self.entries[self.scan.start];
+                self.scan.start += 1;
+                if interval.start >= self.range.end {
+                    // every later entry starts after the range too
+                    self.len = 0;
+                    self.scan = 0..0;
+                    return None;
+                }
+                if overlaps(interval, &self.range) {
+                    return Some(Entry { interval: interval, data: data });
+                }
+            }
+            if self.len == 0 {
+                return None;
+            }
+            self.len -= 1;
+            let (i, level, left_done) = self.stack[self.len];
+            if i < self.entries.len() && !left_done && self.max_end[i] <= self.range.start {
+                continue;
+            }
+            if level <= SCAN_LEVEL && !left_done {
+                self.scan = i+1 - (1usize << level)..std::cmp::min(i + (1usize << level), self.entries.len());
+                continue;
+            }
+            if i >= self.entries.len() {
+                // only the left subtree of a node past the end exists
+                self.push(i - (1usize << (level-1)), level-1, false);
+                continue;
+            }
+            if !left_done {
+                self.push(i, level, true);
+                self.push(i - (1usize << (level-1)), level-1, false);
+                continue;
+            }
+            let (ref interval, ref data) = self.entries[i];
+            if interval.start >= self.range.end {
+                self.len = 0;
+                return None;
+            }
+            self.push(i + (1usize << (level-1)), level-1, false);
+            if overlaps(interval, &self.range) {
+                return Some(Entry { interval: interval, data: data });
+            }
+        }
+    }
+}
+
+// a scan of the inserted entries that are not yet in the index
+struct AddedOverlaps<'a, T> {
+    added: &'a [(Range<u64>, T)],
+    pos: usize,
+    range: Range<u64>,
+}
+
+impl<'a, T> Iterator for AddedOverlaps<'a, T> {
+    type Item = Entry<'a, T>;
+    fn next(&mut self) -> Option<Entry<'a, T>> {
+        while let Some((interval, data)) = self.added.get(self.pos) {
+            if interval.start >= self.range.end { break }
+            self.pos += 1;
+            if overlaps(interval, &self.range) {
+                return Some(Entry { interval: interval, data: data });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a small deterministic random number generator
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+        // short and long intervals, some of them empty
+        fn range(&mut self) -> Range<u64> {
+            let start = self.next(10_000);
+            let len = match self.next(10) {
+                0 => 0,
+                1 => self.next(5_000),
+                _ => self.next(100),
+            };
+            start..start+len
+        }
+    }
+
+    // the overlapping entries of all entries in insertion order, sorted by
+    // start with ties in insertion order
+    fn brute_force(all: &[(Range<u64>, usize)], range: &Range<u64>) -> Vec<(Range<u64>, usize)> {
+        let mut found = all.iter().filter(|(r, _)| overlaps(r, range)).cloned().collect::<Vec<_>>();
+        found.sort_by_key(|(r, _)| r.start);
+        found
+    }
+
+    fn check(index: &SortedIntervals<usize>, all: &[(Range<u64>, usize)], random: &mut Random) {
+        let mut queries = (0..50).map(|_| random.range()).collect::<Vec<_>>();
+        queries.push(0..u64::MAX);
+        queries.push(5_000..5_000);
+        for query in queries {
+            let found = index.find(query.clone()).map(|e| (e.interval().clone(), *e.data())).collect::<Vec<_>>();
+            assert_eq!(found, brute_force(all, &query), "query {:?} of {} entries", query, all.len());
+        }
+        let mut sorted = all.to_vec();
+        sorted.sort_by_key(|(r, _)| r.start);
+        assert_eq!(index.iter().map(|e| (e.interval().clone(), *e.data())).collect::<Vec<_>>(), sorted);
+        assert_eq!(index.len(), all.len());
+    }
+
+    #[test]
+    fn find_matches_brute_force() {
+        let mut random = Random(0x2545f4914f6cdd1d);
+        // sizes around the complete trees and the scanned subtrees
+        for n in (0..70).chain([127, 128, 129, 255, 1000, 4095].iter().cloned()) {
+            let all = (0..n).map(|i| (random.range(), i)).collect::<Vec<_>>();
+            let index = SortedIntervals::new(all.clone());
+            check(&index, &all, &mut random);
+        }
+    }
+
+    #[test]
+    fn find_after_inserts() {
+        let mut random = Random(42);
+        for n in [0, 1, 10, 100, 1000].iter().cloned() {
+            let mut all = (0..n).map(|i| (random.range(), i)).collect::<Vec<_>>();
+            let mut index = SortedIntervals::new(all.clone());
+            let mut merges = 0;
+            for i in n..n+500 {
+                let range = random.range();
+                all.push((range.clone(), i));
+                let indexed = index.entries.len();
+                index.insert(range, i);
+                if index.entries.len() != indexed {
+                    merges += 1;
+                    assert!(index.added.is_empty());
+                }
+                if i % 7 == 0 || index.added.is_empty() {
+                    check(&index, &all, &mut random);
+                }
+            }
+            assert!(merges > 0, "no merges after inserts into {} entries", n);
+            check(&index, &all, &mut random);
+        }
+    }
+}
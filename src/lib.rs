@@ -2,40 +2,139 @@ use std::vec::Vec;
 use std::ops::Range;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::io::{BufReader, BufRead, BufWriter, Write};
+use std::io::{stdin, stdout};
+use flate2::bufread::MultiGzDecoder;
 use linked_hash_map::LinkedHashMap;
 use rust_htslib::bam::record::Cigar;
 use rust_htslib::bam::record::CigarStringView;
+use std::ffi::CString;
+use std::str::FromStr;
 use rust_htslib::bam::Read;
 use rust_htslib::bam::IndexedReader;
+use rust_htslib::bam::Reader;
+use rust_htslib::bam::record::Record;
+use rust_htslib::htslib;
 use anyhow::{Result, anyhow};
-use duct::cmd;
+use serde::Serialize;
 
 pub mod indexed_annotation;
+pub mod faidx;
+pub mod annotation_cache;
+pub mod annotation_formats;
+pub mod lint;
+pub mod chrom_alias;
+pub mod symbol;
+pub mod interval_index;
+pub mod feature_filter;
+pub mod bbi;
+pub mod bigbed;
+pub mod bigwig;
 
-pub mod power_set {
-    pub struct PowerSet<'a, T: 'a> {
-        source: &'a [T],
-        position: usize
-    }
-    
-    impl<'a, T> PowerSet<'a, T> where T: Clone {
-        pub fn new(source: &'a [T]) -> PowerSet<'a, T> {
-            PowerSet { source: source, position: 0 }
+pub mod interval_scheduling {
+    use std::ops::Range;
+
+    /// Find the set of non-overlapping half-open intervals with the largest
+    /// total weight using weighted interval scheduling. Ties are broken in
+    /// favor of the smaller set. The chosen intervals are returned sorted by
+    /// start.
+    pub fn max_weight_intervals(intervals: &[(Range<usize>, i64)]) -> Vec<Range<usize>> {
+        let mut sorted = intervals.to_vec();
+        sorted.sort_by(|a, b| a.0.end.cmp(&b.0.end).then_with(|| a.0.start.cmp(&b.0.start)));
+        // a score is the total weight, then minus the number of intervals, so
+        // that of two sets with the same weight the smaller one scores higher
+        let add = |score: (i64, i64), weight: i64| (score.0 + weight, score.1 - 1);
+        // best[j] is the best score using only the first j intervals
+        let mut best = vec![(0i64, 0i64); sorted.len()+1];
+        // prev[j] is the number of intervals that end before interval j starts
+        let mut prev = vec![0usize; sorted.len()];
+        for (j, (range, weight)) in sorted.iter().enumerate() {
+            prev[j] = sorted[..j].partition_point(|(r, _)| r.end <= range.start);
+            best[j+1] = std::cmp::max(best[j], add(best[prev[j]], *weight));
+        }
+        // trace back the chosen intervals
+        let mut chosen = Vec::new();
+        let mut j = sorted.len();
+        while j > 0 {
+            if add(best[prev[j-1]], sorted[j-1].1) > best[j-1] {
+                chosen.push(sorted[j-1].0.clone());
+                j = prev[j-1];
+            }
+            else {
+                j -= 1;
+            }
         }
+        chosen.reverse();
+        chosen
     }
 
-    impl<'a, T> Iterator for PowerSet<'a, T> where T: Clone {
-        type Item = Vec<T>;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-        fn next(&mut self) -> Option<Self::Item> {
-            if 2usize.pow(self.source.len() as u32) <= self.position {
-                None
-            } else {
-                let res = self.source.iter().enumerate().filter(|&(i, _)| (self.position >> i) % 2 == 1)
-                                                        .map(|(_, element)| element.clone()).collect();
-                self.position = self.position + 1;
-                Some(res)
+        fn weight(intervals: &[(Range<usize>, i64)], chosen: &[Range<usize>]) -> i64 {
+            chosen.iter().map(|c| intervals.iter().find(|(r, _)| r == c).unwrap().1).sum()
+        }
+
+        #[test]
+        fn chosen_intervals() {
+            // disjoint intervals are all chosen
+            let intervals = vec![(10..20, 1), (0..5, 2), (30..40, 3)];
+            assert_eq!(max_weight_intervals(&intervals), vec![0..5, 10..20, 30..40]);
+            // nested intervals: the outer one wins only if it outweighs the inner ones
+            let intervals = vec![(0..100, 5), (10..20, 3), (30..40, 3)];
+            assert_eq!(max_weight_intervals(&intervals), vec![10..20, 30..40]);
+            let intervals = vec![(0..100, 7), (10..20, 3), (30..40, 3)];
+            assert_eq!(max_weight_intervals(&intervals), vec![0..100]);
+            // equal weight: the smaller set wins, whichever way round
+            let intervals = vec![(0..100, 6), (10..20, 3), (30..40, 3)];
+            assert_eq!(max_weight_intervals(&intervals), vec![0..100]);
+            let intervals = vec![(10..20, 3), (30..40, 3), (15..35, 6)];
+            assert_eq!(max_weight_intervals(&intervals), vec![15..35]);
+            // touching endpoints do not overlap
+            let intervals = vec![(0..10, 1), (10..20, 1), (20..30, 1)];
+            assert_eq!(max_weight_intervals(&intervals), vec![0..10, 10..20, 20..30]);
+            let intervals = vec![(0..11, 1), (10..20, 1)];
+            assert_eq!(max_weight_intervals(&intervals).len(), 1);
+            // intervals that add nothing are left out
+            let intervals = vec![(0..10, 2), (20..30, 0), (40..50, -1)];
+            assert_eq!(max_weight_intervals(&intervals), vec![0..10]);
+            assert!(max_weight_intervals(&[]).is_empty());
+        }
+
+        #[test]
+        fn brute_force() {
+            // a small linear congruential generator keeps the inputs reproducible
+            let mut seed = 12345u64;
+            let mut random = |n: u64| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (seed >> 33) % n
+            };
+            for _ in 0..500 {
+                let mut intervals = Vec::<(Range<usize>, i64)>::new();
+                for _ in 0..random(9) {
+                    let start = random(20) as usize;
+                    let range = start..start + 1 + random(8) as usize;
+                    // distinct ranges, so that a chosen range has one weight
+                    if !intervals.iter().any(|(r, _)| *r == range) {
+                        intervals.push((range, random(7) as i64 - 1));
+                    }
+                }
+                let n = intervals.len();
+                // the best score over every non-overlapping subset
+                let mut expected = (0i64, 0i64);
+                for subset in 0..1usize<<n {
+                    let members = (0..n).filter(|i| subset & 1<<i != 0).map(|i| &intervals[i]).collect::<Vec<_>>();
+                    let overlaps = members.iter().any(|(a, _)| members.iter().any(|(b, _)|
+                        !std::ptr::eq(a, b) && a.start < b.end && b.start < a.end));
+                    if !overlaps {
+                        let score = (members.iter().map(|(_, w)| w).sum::<i64>(), -(members.len() as i64));
+                        expected = std::cmp::max(expected, score);
+                    }
+                }
+                let chosen = max_weight_intervals(&intervals);
+                assert!(chosen.windows(2).all(|w| w[0].end <= w[1].start), "{:?}", intervals);
+                assert_eq!((weight(&intervals, &chosen), -(chosen.len() as i64)), expected, "{:?}", intervals);
             }
         }
     }
@@ -67,23 +166,109 @@ pub fn cigar2exons(cigar: &CigarStringView, pos: u64) -> Result<Vec<Range<u64>>>
     Ok(exons)
 }
 
-pub fn read_sizes_file(sizes_file: &str, chrmap: &HashMap<String,String>) -> Result<LinkedHashMap<String,u64>> {
+/// Open a text input file for reading, where "-" means stdin. Gzip and BGZF
+/// compressed input is detected from the magic bytes and decompressed.
+pub fn open_input(file: &str) -> Result<Box<dyn BufRead>> {
+    let mut input: Box<dyn BufRead> = 
+        if file == "-" { Box::new(BufReader::new(stdin())) } 
+        else { Box::new(BufReader::new(File::open(file)?)) };
+    // BGZF is a series of gzip members, so both share the gzip magic bytes
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    let is_gzip = input.fill_buf()?.starts_with(GZIP_MAGIC);
+    if is_gzip {
+        input = Box::new(BufReader::new(MultiGzDecoder::new(input)));
+    }
+    Ok(input)
+}
+
+/// A BGZF compressed output file, written through htslib so that it can be
+/// indexed with tabix or faidx.
+pub struct BgzfWriter {
+    fp: *mut htslib::BGZF,
+}
+
+impl BgzfWriter {
+    pub fn create(file: &str) -> Result<BgzfWriter> {
+        let path = CString::new(file.as_bytes())?;
+        let mode = CString::new("w")?;
+        let fp = unsafe { htslib::bgzf_open(path.as_ptr(), mode.as_ptr()) };
+        if fp.is_null() {
+            return Err(anyhow!("Could not open BGZF file {} for writing", file));
+        }
+        Ok(BgzfWriter { fp: fp })
+    }
+}
+
+impl Write for BgzfWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = unsafe { htslib::bgzf_write(self.fp, buf.as_ptr() as *const _, buf.len() as _) };
+        if written < 0 {
+            return Err(std::io::Error::other("Could not write to BGZF file"));
+        }
+        Ok(written as usize)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        if unsafe { htslib::bgzf_flush(self.fp) } < 0 {
+            return Err(std::io::Error::other("Could not flush BGZF file"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BgzfWriter {
+    fn drop(&mut self) {
+        // writes the final empty block that marks the end of the file
+        unsafe { htslib::bgzf_close(self.fp) };
+    }
+}
+
+/// Open an output file for writing, where "-" means stdout. If `bgzip` is
+/// set, the file is BGZF compressed.
+pub fn create_output(file: &str, bgzip: bool) -> Result<BufWriter<Box<dyn Write>>> {
+    Ok(BufWriter::new(
+        if bgzip {
+            if file == "-" { return Err(anyhow!("Can't write BGZF compressed output to stdout")) }
+            Box::new(BgzfWriter::create(file)?)
+        }
+        else if file == "-" { Box::new(stdout()) }
+        else { Box::new(File::create(file)?) }))
+}
+
+/// Index a BGZF compressed GFF3, GTF, BED or genePred file with tabix,
+/// writing a .tbi index next to it. The file must be sorted by sequence
+/// name and start position.
+pub fn tabix_index(file: &str, format: &str) -> Result<()> {
+    let conf = match format {
+        "gff" | "gtf" => unsafe { htslib::tbx_conf_gff },
+        "bed" => unsafe { htslib::tbx_conf_bed },
+        // chrom, txStart and txEnd are columns 2, 4 and 5, 0-based like BED
+        "genepred" => htslib::tbx_conf_t { preset: 0x10000, sc: 2, bc: 4, ec: 5, meta_char: b'#' as i32, line_skip: 0 },
+        _ => return Err(anyhow!("Can't index {} files with tabix", format)),
+    };
+    let path = CString::new(file.as_bytes())?;
+    if unsafe { htslib::tbx_index_build(path.as_ptr(), 0, &conf) } != 0 {
+        return Err(anyhow!("Could not build the tabix index of {}", file));
+    }
+    Ok(())
+}
+
+/// Read a chromosome sizes file, with its chr names matched to the
+/// annotation's seqnames.
+pub fn read_sizes_file(sizes_file: &str, annot: &indexed_annotation::IndexedAnnotation) -> Result<LinkedHashMap<String,u64>> {
     let mut refs = HashMap::<String,u64>::new();
-    let f = File::open(&sizes_file)?;
-    let mut file = BufReader::new(&f);
+    let mut file = open_input(sizes_file)?;
     let mut buf = String::new();
     while file.read_line(&mut buf)? > 0 {
         {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
             let cols: Vec<&str> = line.split('\t').collect();
             if let Some(chr) = cols.get(0) {
-                let chr = String::from(*chr);
-                let chr = chrmap.get(&chr).unwrap_or(&chr);
+                let chr = annot.map_seqname(chr);
                 if let Some(size) = cols.get(1) {
                     if let Ok(size) = size.parse::<u64>() {
-                        refs.insert(chr.clone(), size);
+                        refs.insert(chr, size);
                     }
                     else {
-                        anyhow!("Could not parse size \"{}\" for chr \"{}\" from line \"{}\" of file \"{}\"", size, chr, line, sizes_file);
+                        return Err(anyhow!("Could not parse size \"{}\" for chr \"{}\" from line \"{}\" of file \"{}\"", size, chr, line, sizes_file));
                     }
                 }
             }
@@ -100,7 +285,9 @@ pub fn read_sizes_file(sizes_file: &str, chrmap: &HashMap<String,String>) -> Res
     Ok(sorted_refs)
 }
 
-pub fn get_bam_refs(bamfile: &str, chrmap: &HashMap<String,String>) -> Result<LinkedHashMap<String,u64>> {
+/// Get the reference sequence lengths of a bam file, with its chr names
+/// matched to the annotation's seqnames.
+pub fn get_bam_refs(bamfile: &str, annot: &indexed_annotation::IndexedAnnotation) -> Result<LinkedHashMap<String,u64>> {
     let mut refs = LinkedHashMap::<String,u64>::new();
     let bam = IndexedReader::from_path(bamfile)?;
     let header = bam.header();
@@ -108,27 +295,383 @@ pub fn get_bam_refs(bamfile: &str, chrmap: &HashMap<String,String>) -> Result<Li
     for target_name in target_names {
         let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
         let target_len = header.target_len(tid).ok_or(anyhow!("NoneError"))? as u64;
-        let target_name = String::from(std::str::from_utf8(target_name)?);
-        let chr = chrmap.get(&target_name).unwrap_or(&target_name);
-        refs.insert(chr.clone(), target_len);
+        let chr = annot.map_seqname(std::str::from_utf8(target_name)?);
+        refs.insert(chr, target_len);
     }
     Ok(refs)
 }
 
-pub fn get_bam_total_reads(bamfiles: &[String]) -> Result<u64> {
+/// Map the annotation's seqnames to the target ids of a bam file, matching
+/// the bam file's chr names through the chrmap and chr name aliases. Warns
+/// about annotation seqnames that are missing from the bam file, and fails
+/// if two of the bam file's chr names match the same seqname.
+pub fn get_tidmap(bamfile: &str, annot: &indexed_annotation::IndexedAnnotation) -> Result<HashMap<String,u32>> {
+    let bam = IndexedReader::from_path(bamfile)?;
+    let header = bam.header();
+    let mut tids = HashMap::<String,u32>::new();
+    for target_name in header.target_names() {
+        let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
+        tids.insert(String::from(std::str::from_utf8(target_name)?), tid);
+    }
+    let mut tidmap = HashMap::<String,u32>::new();
+    let mut chr2target = HashMap::<String,String>::new();
+    for (target_name, chr) in annot.match_seqnames(tids.keys(), bamfile) {
+        // two bam sequences that alias the same seqname would silently
+        // lose the reads of one of them
+        if let Some(other) = chr2target.insert(chr.clone(), target_name.clone()) {
+            let mut names = [other, target_name];
+            names.sort();
+            return Err(anyhow!("The sequences {} and {} of {} both match the annotation seqname {}",
+                names[0], names[1], bamfile, chr));
+        }
+        tidmap.insert(chr, tids[&target_name]);
+    }
+    Ok(tidmap)
+}
+
+/// Which alignments count as reads, both for per-feature counts and for the
+/// total read count used as the RPKM denominator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadCount {
+    /// All mapped records, as reported by the bam index
+    Mapped,
+    /// Mapped records that are not secondary or supplementary
+    Primary,
+    /// Primary records with a single reported alignment (NH:i:1)
+    Unique,
+    /// Primary records, counting each read pair once
+    Fragments,
+}
+
+impl FromStr for ReadCount {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<ReadCount> {
+        match s {
+            "mapped" => Ok(ReadCount::Mapped),
+            "primary" => Ok(ReadCount::Primary),
+            "unique" => Ok(ReadCount::Unique),
+            "fragments" => Ok(ReadCount::Fragments),
+            _ => Err(anyhow!("Invalid read count type \"{}\": must be one of mapped, primary, unique, fragments", s)),
+        }
+    }
+}
+
+/// The parts of an alignment that decide whether it counts as a read.
+pub trait Alignment {
+    /// The SAM flags
+    fn flags(&self) -> u16;
+    /// The number of reported alignments of the read, from the NH tag
+    fn hit_count(&self) -> Option<i64>;
+}
+
+impl Alignment for Record {
+    fn flags(&self) -> u16 {
+        Record::flags(self)
+    }
+    fn hit_count(&self) -> Option<i64> {
+        self.aux(b"NH").map(|nh| nh.integer())
+    }
+}
+
+const FLAG_PAIRED: u16 = 0x1;
+const FLAG_UNMAPPED: u16 = 0x4;
+const FLAG_MATE_UNMAPPED: u16 = 0x8;
+const FLAG_FIRST_IN_TEMPLATE: u16 = 0x40;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+impl ReadCount {
+    /// Does this alignment count as a read?
+    pub fn includes<A: Alignment>(self, read: &A) -> bool {
+        let flags = read.flags();
+        if flags & FLAG_UNMAPPED != 0 { return false }
+        let primary = flags & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) == 0;
+        match self {
+            ReadCount::Mapped => true,
+            ReadCount::Primary => primary,
+            ReadCount::Unique => primary && read.hit_count() == Some(1),
+            ReadCount::Fragments => primary,
+        }
+    }
+
+    /// Is this alignment the one that represents its fragment in the total?
+    /// Paired reads are counted through read 1, or through read 2 if read 1
+    /// is unmapped.
+    fn counts_fragment<A: Alignment>(self, read: &A) -> bool {
+        let flags = read.flags();
+        self.includes(read) &&
+            (self != ReadCount::Fragments ||
+                flags & FLAG_PAIRED == 0 ||
+                flags & FLAG_FIRST_IN_TEMPLATE != 0 ||
+                flags & FLAG_MATE_UNMAPPED != 0)
+    }
+
+    /// Count the reads of a stream of alignments, counting each fragment
+    /// once for `Fragments`.
+    pub fn count_reads<A, E>(self, reads: impl IntoIterator<Item=std::result::Result<A, E>>) -> Result<u64>
+        where A: Alignment, E: std::error::Error + Send + Sync + 'static
+    {
+        let mut total = 0u64;
+        for read in reads {
+            if self.counts_fragment(&read?) {
+                total += 1;
+            }
+        }
+        Ok(total)
+    }
+}
+
+/// Read the total mapped record count from the bam index metadata.
+fn get_bam_index_mapped(bamfile: &str) -> Result<u64> {
+    // a plain reader, so that the index is only loaded once, below
+    let bam = Reader::from_path(bamfile)?;
+    let path = CString::new(bamfile.as_bytes())?;
+    let idx = unsafe { htslib::sam_index_load(bam.htsfile(), path.as_ptr()) };
+    if idx.is_null() {
+        return Err(anyhow!("Could not load the index for bam file {}", bamfile));
+    }
+    let mut total = 0u64;
+    for tid in 0..bam.header().target_count() {
+        let mut mapped = 0u64;
+        let mut unmapped = 0u64;
+        // targets without any reads have no stats
+        if unsafe { htslib::hts_idx_get_stat(idx, tid as i32, &mut mapped, &mut unmapped) } == 0 {
+            total += mapped;
+        }
+    }
+    unsafe { htslib::hts_idx_destroy(idx) };
+    Ok(total)
+}
+
+/// Get the total number of reads in a set of bam files. Mapped record counts
+/// come from the bam index; the other read types require a scan of each file.
+pub fn get_bam_total_reads(bamfiles: &[String], read_count: ReadCount) -> Result<u64> {
     let mut total_reads = 0u64;
     for bamfile in bamfiles {
-        let stdout = cmd!("samtools","idxstats",bamfile).read()?;
-        for line in stdout.lines() {
-            let cols: Vec<&str> = line.split('\t').collect();
-            if let Some(reads_str) = cols.get(2) {
-                if let Ok(reads) = reads_str.parse::<u64>() {
-                    total_reads += reads;
+        if read_count == ReadCount::Mapped {
+            total_reads += get_bam_index_mapped(bamfile)?;
+        }
+        else {
+            let mut bam = Reader::from_path(bamfile)?;
+            total_reads += read_count.count_reads(bam.records())?;
+        }
+    }
+    Ok(total_reads)
+}
+
+/// The default number of strand-informative reads to sample when inferring
+/// strandedness.
+pub const STRAND_SAMPLE_READS: u64 = 200_000;
+/// The default fraction of sampled fragments that must agree for a library
+/// to be called stranded.
+pub const STRAND_THRESHOLD: f64 = 0.8;
+/// Fewer informative reads than this and the library is called unstranded.
+pub const STRAND_MIN_READS: u64 = 100;
+// how many strand regions to spread the sample across
+const STRAND_SAMPLE_REGIONS: usize = 2000;
+
+/// Which read of a pair indicates the transcript strand.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strandedness {
+    Read1,
+    Read2,
+    Unstranded,
+}
+
+/// Counts of reads aligned on the same strand (sense) or the opposite strand
+/// (antisense) as the strand-unambiguous annotated regions they start in.
+#[derive(Clone, Debug, Serialize)]
+pub struct StrandReport {
+    pub bamfile: String,
+    pub read1_sense: u64,
+    pub read1_antisense: u64,
+    pub read2_sense: u64,
+    pub read2_antisense: u64,
+    pub read1_fraction_sense: f64,
+    pub read2_fraction_sense: f64,
+    pub threshold: f64,
+    pub strandedness: Strandedness,
+}
+
+impl StrandReport {
+    /// Classify a library from its read counts, indexed by read 2 and by
+    /// antisense: counts[0][0] is read 1 sense, counts[1][1] read 2 antisense.
+    pub fn from_counts(bamfile: &str, counts: [[u64; 2]; 2], threshold: f64) -> StrandReport {
+        let fraction = |sense: u64, antisense: u64| {
+            if sense + antisense == 0 { 0.0 } else { sense as f64 / (sense + antisense) as f64 }
+        };
+        // read 2 of a pair is on the opposite strand of its fragment
+        let fragment_sense = counts[0][0] + counts[1][1];
+        let fragment_antisense = counts[0][1] + counts[1][0];
+        let fragment_fraction = fraction(fragment_sense, fragment_antisense);
+        let strandedness =
+            if fragment_sense + fragment_antisense < STRAND_MIN_READS { Strandedness::Unstranded }
+            else if fragment_fraction >= threshold { Strandedness::Read1 }
+            else if fragment_fraction <= 1.0 - threshold { Strandedness::Read2 }
+            else { Strandedness::Unstranded };
+        StrandReport {
+            bamfile: bamfile.to_string(),
+            read1_sense: counts[0][0],
+            read1_antisense: counts[0][1],
+            read2_sense: counts[1][0],
+            read2_antisense: counts[1][1],
+            read1_fraction_sense: fraction(counts[0][0], counts[0][1]),
+            read2_fraction_sense: fraction(counts[1][0], counts[1][1]),
+            threshold,
+            strandedness,
+        }
+    }
+
+    /// Classify one read number: 's' for sense, 'r' for antisense or 'u' for
+    /// unstranded, as used by the bam2bedgraph --split_strand codes.
+    pub fn read_strand(&self, read_number: u8) -> char {
+        let (sense, antisense, fraction) = if read_number == 2 {
+            (self.read2_sense, self.read2_antisense, self.read2_fraction_sense)
+        } else {
+            (self.read1_sense, self.read1_antisense, self.read1_fraction_sense)
+        };
+        if sense + antisense < STRAND_MIN_READS { 'u' }
+        else if fraction >= self.threshold { 's' }
+        else if fraction <= 1.0 - self.threshold { 'r' }
+        else { 'u' }
+    }
+
+    /// The bamstrand value used by the analysis tools: Some(true) if read 1
+    /// indicates strand, Some(false) if read 2 does, None if unstranded.
+    pub fn read1strand(&self) -> Option<bool> {
+        match self.strandedness {
+            Strandedness::Read1 => Some(true),
+            Strandedness::Read2 => Some(false),
+            Strandedness::Unstranded => None,
+        }
+    }
+}
+
+/// Check the --autostrand sample size and threshold. A threshold of 0.5 or
+/// less would call a library stranded in both orientations at once.
+pub fn check_strand_options(sample_reads: u64, threshold: f64) -> Result<()> {
+    if !(threshold > 0.5 && threshold <= 1.0) {
+        return Err(anyhow!("The strand threshold must be above 0.5 and at most 1, got {}", threshold));
+    }
+    if sample_reads < STRAND_MIN_READS {
+        return Err(anyhow!("The strand sample must have at least {} reads, got {}", STRAND_MIN_READS, sample_reads));
+    }
+    Ok(())
+}
+
+pub fn infer_library_strandedness(bamfile: &str, annot: &indexed_annotation::IndexedAnnotation) -> Result<StrandReport> {
+    infer_library_strandedness_with(bamfile, annot, STRAND_SAMPLE_READS, STRAND_THRESHOLD)
+}
+
+/// Infer the strandedness of a bam file by sampling primary alignments that
+/// start in the strand-unambiguous regions of the annotation. The sampled
+/// regions are spread out over the whole annotation.
+pub fn infer_library_strandedness_with(
+    bamfile: &str,
+    annot: &indexed_annotation::IndexedAnnotation,
+    sample_reads: u64,
+    threshold: f64)
+    -> Result<StrandReport>
+{
+    check_strand_options(sample_reads, threshold)?;
+    let mut bam = IndexedReader::from_path(bamfile)?;
+    let mut tidmap = HashMap::<String,u32>::new();
+    {   let header = bam.header();
+        for target_name in header.target_names() {
+            let tid = header.tid(target_name).ok_or(anyhow!("NoneError"))?;
+            let chr = annot.map_seqname(std::str::from_utf8(target_name)?);
+            tidmap.insert(chr, tid);
+        }
+    }
+    let mut regions = Vec::<(u32, Range<u64>, u8)>::new();
+    let mut strand_regions = annot.strand_regions().into_iter().collect::<Vec<_>>();
+    strand_regions.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    for (chr, list) in strand_regions {
+        if let Some(tid) = tidmap.get(&chr) {
+            for (range, strand) in list {
+                regions.push((*tid, range, strand));
+            }
+        }
+    }
+
+    let mut counts = [[0u64; 2]; 2];
+    let stride = std::cmp::max(1, regions.len() / STRAND_SAMPLE_REGIONS);
+    'sample:
+    for offset in 0..stride {
+        for (tid, range, strand) in regions.iter().skip(offset).step_by(stride) {
+            bam.fetch(*tid, range.start as u32, range.end as u32)?;
+            for read in bam.records() {
+                let read = read?;
+                // only count reads that start in this region, so each is counted once
+                if !ReadCount::Primary.includes(&read) ||
+                    (read.pos() as u64) < range.start ||
+                    (read.pos() as u64) >= range.end
+                {
+                    continue;
+                }
+                let read2 = if read.is_last_in_template() { 1 } else { 0 };
+                let antisense = if read.is_reverse() == (*strand == b'-') { 0 } else { 1 };
+                counts[read2][antisense] += 1;
+                if counts[0][0] + counts[0][1] + counts[1][0] + counts[1][1] >= sample_reads {
+                    break 'sample;
                 }
             }
         }
     }
-    Ok(total_reads)
+
+    Ok(StrandReport::from_counts(bamfile, counts, threshold))
+}
+
+/// Write strand reports as JSON if the file name ends in .json, otherwise as TSV.
+pub fn write_strand_reports(reports: &[StrandReport], file: &str) -> Result<()> {
+    let output: BufWriter<Box<dyn Write>> = BufWriter::new(
+        if file == "-" { Box::new(stdout()) }
+        else { Box::new(File::create(file)?) });
+    if file.to_lowercase().ends_with(".json") {
+        let mut output = output;
+        serde_json::to_writer_pretty(&mut output, reports)?;
+        writeln!(output)?;
+    }
+    else {
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .quote_style(csv::QuoteStyle::Necessary)
+            .from_writer(output);
+        for report in reports {
+            wtr.serialize(report)?;
+        }
+        wtr.flush()?;
+    }
+    Ok(())
+}
+
+/// Infer the strandedness of every bam file with an unknown strand (None in
+/// `bamstrand`) and fill in its strand, optionally writing out a report.
+pub fn assign_bam_strands(
+    bamfiles: &[String],
+    bamstrand: &mut [Option<bool>],
+    annot: &indexed_annotation::IndexedAnnotation,
+    report_file: &Option<String>,
+    sample_reads: u64,
+    threshold: f64)
+    -> Result<()>
+{
+    check_strand_options(sample_reads, threshold)?;
+    let mut reports = Vec::<StrandReport>::new();
+    for (i, bamfile) in bamfiles.iter().enumerate() {
+        if bamstrand[i].is_none() {
+            let report = infer_library_strandedness_with(bamfile, annot, sample_reads, threshold)?;
+            eprintln!("Inferred strandedness of {}: {:?} (read1 sense {:.3}, read2 sense {:.3})",
+                bamfile, report.strandedness, report.read1_fraction_sense, report.read2_fraction_sense);
+            bamstrand[i] = report.read1strand();
+            reports.push(report);
+        }
+    }
+    if let Some(report_file) = report_file {
+        write_strand_reports(&reports, report_file)?;
+    }
+    Ok(())
 }
 
 pub fn get_gene_name(row: usize, annot: &indexed_annotation::IndexedAnnotation) -> Option<String> {
@@ -154,3 +697,147 @@ pub fn get_name(row: usize, annot: &indexed_annotation::IndexedAnnotation) -> Op
     name.map(|n| n.to_string())
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an alignment with only flags and an NH tag
+    struct Read(u16, Option<i64>);
+
+    impl Alignment for Read {
+        fn flags(&self) -> u16 { self.0 }
+        fn hit_count(&self) -> Option<i64> { self.1 }
+    }
+
+    fn count(read_count: ReadCount, reads: &[(u16, Option<i64>)]) -> u64 {
+        let reads = reads.iter().map(|(flags, nh)| Ok::<_, std::fmt::Error>(Read(*flags, *nh)));
+        read_count.count_reads(reads).unwrap()
+    }
+
+    #[test]
+    fn read_counts() {
+        let reads = [
+            (0x0, Some(1)),     // single-end unique
+            (0x10, Some(2)),    // single-end multimapper
+            (0x4, None),        // unmapped
+            (0x100, Some(2)),   // secondary
+            (0x800, Some(1)),   // supplementary
+            (0x63, Some(1)),    // read 1 of a pair
+            (0x93, Some(1)),    // its read 2
+            (0x89, Some(1)),    // read 2 of a pair whose read 1 is unmapped
+            (0x45, None),       // that unmapped read 1
+            (0x143, Some(2)),   // secondary read 1 of a pair
+        ];
+        assert_eq!(count(ReadCount::Mapped, &reads), 8);
+        assert_eq!(count(ReadCount::Primary, &reads), 5);
+        assert_eq!(count(ReadCount::Unique, &reads), 4);
+        assert_eq!(count(ReadCount::Fragments, &reads), 4);
+        assert_eq!(count(ReadCount::Fragments, &[]), 0);
+        // read errors are passed on
+        let error = vec![Ok(Read(0, None)), Err(std::fmt::Error)];
+        assert!(ReadCount::Mapped.count_reads(error).is_err());
+        assert_eq!("unique".parse::<ReadCount>().unwrap(), ReadCount::Unique);
+        assert!("all".parse::<ReadCount>().is_err());
+    }
+
+    #[test]
+    fn strand_classification() {
+        // counts are [[read1 sense, read1 antisense], [read2 sense, read2 antisense]]
+        let report = StrandReport::from_counts("a.bam", [[900, 100], [50, 950]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Read1);
+        assert_eq!(report.read1strand(), Some(true));
+        assert_eq!((report.read_strand(1), report.read_strand(2)), ('s', 'r'));
+        assert!((report.read1_fraction_sense - 0.9).abs() < 1e-12);
+        assert!((report.read2_fraction_sense - 0.05).abs() < 1e-12);
+
+        let report = StrandReport::from_counts("a.bam", [[100, 900], [0, 0]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Read2);
+        assert_eq!(report.read1strand(), Some(false));
+        assert_eq!((report.read_strand(1), report.read_strand(2)), ('r', 'u'));
+
+        let report = StrandReport::from_counts("a.bam", [[500, 500], [500, 500]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Unstranded);
+        assert_eq!(report.read1strand(), None);
+        assert_eq!(report.read_strand(1), 'u');
+
+        // the threshold itself counts as stranded
+        let report = StrandReport::from_counts("a.bam", [[80, 20], [0, 0]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Read1);
+        let report = StrandReport::from_counts("a.bam", [[79, 21], [0, 0]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Unstranded);
+        let report = StrandReport::from_counts("a.bam", [[79, 21], [0, 0]], 0.75);
+        assert_eq!(report.strandedness, Strandedness::Read1);
+
+        // too few reads, however one-sided
+        let report = StrandReport::from_counts("a.bam", [[STRAND_MIN_READS-1, 0], [0, 0]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Unstranded);
+        assert_eq!(report.read_strand(1), 'u');
+        let report = StrandReport::from_counts("a.bam", [[0, 0], [0, 0]], 0.8);
+        assert_eq!(report.strandedness, Strandedness::Unstranded);
+        assert_eq!(report.read1_fraction_sense, 0.0);
+    }
+
+    #[test]
+    fn strand_options() {
+        assert!(check_strand_options(STRAND_SAMPLE_READS, STRAND_THRESHOLD).is_ok());
+        assert!(check_strand_options(STRAND_SAMPLE_READS, 1.0).is_ok());
+        assert!(check_strand_options(STRAND_MIN_READS, 0.51).is_ok());
+        assert!(check_strand_options(STRAND_SAMPLE_READS, 0.5).is_err());
+        assert!(check_strand_options(STRAND_SAMPLE_READS, 0.2).is_err());
+        assert!(check_strand_options(STRAND_SAMPLE_READS, 1.01).is_err());
+        assert!(check_strand_options(STRAND_SAMPLE_READS, f64::NAN).is_err());
+        assert!(check_strand_options(STRAND_MIN_READS-1, STRAND_THRESHOLD).is_err());
+    }
+
+    #[test]
+    fn strand_reports() {
+        let reports = vec![
+            StrandReport::from_counts("a.bam", [[900, 100], [50, 950]], 0.8),
+            StrandReport::from_counts("b.bam", [[500, 500], [500, 500]], 0.8),
+        ];
+        let dir = std::env::temp_dir();
+        let json = dir.join(format!("strand_reports_{}.json", std::process::id())).to_str().unwrap().to_string();
+        write_strand_reports(&reports, &json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+        std::fs::remove_file(&json).unwrap();
+        assert_eq!(value[0]["bamfile"], "a.bam");
+        assert_eq!(value[0]["read2_antisense"], 950);
+        assert_eq!(value[0]["strandedness"], "read1");
+        assert_eq!(value[1]["strandedness"], "unstranded");
+
+        let tsv = dir.join(format!("strand_reports_{}.tsv", std::process::id())).to_str().unwrap().to_string();
+        write_strand_reports(&reports, &tsv).unwrap();
+        let text = std::fs::read_to_string(&tsv).unwrap();
+        std::fs::remove_file(&tsv).unwrap();
+        let lines = text.lines().map(|l| l.split('\t').collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], vec!["bamfile", "read1_sense", "read1_antisense", "read2_sense", "read2_antisense",
+            "read1_fraction_sense", "read2_fraction_sense", "threshold", "strandedness"]);
+        assert_eq!(lines[1][0], "a.bam");
+        assert_eq!(lines[1][8], "read1");
+        assert_eq!(lines[2][8], "unstranded");
+    }
+
+    #[test]
+    fn assign_strands() {
+        let dir = std::env::temp_dir();
+        let gff = dir.join(format!("assign_strands_{}.gff", std::process::id())).to_str().unwrap().to_string();
+        std::fs::write(&gff, "chr1\tt\tmRNA\t1\t100\t.\t+\t.\tID=t1\n").unwrap();
+        let annot = indexed_annotation::IndexedAnnotation::from_file(&gff, "gff", "gene", "mRNA", &None, &None).unwrap();
+        std::fs::remove_file(&gff).unwrap();
+        let report = dir.join(format!("assign_strands_{}.json", std::process::id())).to_str().unwrap().to_string();
+
+        // bam files with a known strand are not opened, so they need not exist
+        let bamfiles = vec!["missing1.bam".to_string(), "missing2.bam".to_string()];
+        let mut bamstrand = vec![Some(true), Some(false)];
+        assign_bam_strands(&bamfiles, &mut bamstrand, &annot, &Some(report.clone()), STRAND_SAMPLE_READS, STRAND_THRESHOLD).unwrap();
+        assert_eq!(bamstrand, vec![Some(true), Some(false)]);
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+        std::fs::remove_file(&report).unwrap();
+        assert_eq!(value, serde_json::json!([]));
+
+        // an invalid threshold is an error even if nothing needs inferring
+        assert!(assign_bam_strands(&bamfiles, &mut bamstrand, &annot, &None, STRAND_SAMPLE_READS, 0.5).is_err());
+    }
+}
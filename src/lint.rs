@@ -0,0 +1,257 @@
+//! Validation of GFF3 and GTF annotation files.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::io::stdout;
+use serde::Serialize;
+use anyhow::Result;
+
+use crate::open_input;
+use crate::indexed_annotation::{Record, FeatureTypes, is_comment};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// A single problem found on a line of an annotation file.
+#[derive(Clone, Debug, Serialize)]
+pub struct LintIssue {
+    pub line: usize,
+    pub severity: Severity,
+    pub check: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LintReport {
+    pub file: String,
+    pub lines: usize,
+    pub records: usize,
+    pub errors: usize,
+    pub warnings: usize,
+    pub passed: bool,
+    pub issues: Vec<LintIssue>,
+}
+
+impl LintReport {
+    /// Write the report as JSON if the file name ends in .json, otherwise
+    /// write the issues as a tab-delimited table. "-" writes to stdout.
+    pub fn write(&self, file: &str) -> Result<()> {
+        let mut output: BufWriter<Box<dyn Write>> = BufWriter::new(
+            if file == "-" { Box::new(stdout()) }
+            else { Box::new(File::create(file)?) });
+        if file.to_lowercase().ends_with(".json") {
+            serde_json::to_writer_pretty(&mut output, self)?;
+            writeln!(output)?;
+        }
+        else {
+            let mut writer = csv::WriterBuilder::new().
+                delimiter(b'\t').
+                quote_style(csv::QuoteStyle::Necessary).
+                from_writer(output);
+            writer.write_record(["line", "severity", "check", "message"])?;
+            for issue in &self.issues {
+                writer.write_record(&[
+                    issue.line.to_string(),
+                    issue.severity.to_string(),
+                    issue.check.clone(),
+                    issue.message.clone()])?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn error(issues: &mut Vec<LintIssue>, line: usize, check: &str, message: String) {
+    issues.push(LintIssue { line, severity: Severity::Error, check: check.to_string(), message });
+}
+
+fn warning(issues: &mut Vec<LintIssue>, line: usize, check: &str, message: String) {
+    issues.push(LintIssue { line, severity: Severity::Warning, check: check.to_string(), message });
+}
+
+// a group of child records and the records of their parent feature
+struct Group {
+    name: String,
+    parents: Vec<usize>,
+    children: Vec<usize>,
+}
+
+/// Check an annotation file for malformed lines and inconsistent feature
+/// hierarchies. `filetype` is either "gff" or "gtf". `types` names the
+/// exon and CDS types, and the GTF gene and transcript types.
+pub fn lint_file(annotfile: &str, filetype: &str, types: &FeatureTypes) -> Result<LintReport> {
+    let mut issues = Vec::<LintIssue>::new();
+    // (line number, record) for each well-formed line
+    let mut records = Vec::<(usize, Record)>::new();
+    let mut file = open_input(annotfile)?;
+    let mut buf = String::new();
+    let mut lineno = 0;
+    let chrmap = HashMap::new();
+    while file.read_line(&mut buf)? > 0 {
+        lineno += 1;
+        {   let line = buf.trim_end_matches('\n').trim_end_matches('\r');
+            // the rest of a GFF3 file is sequence
+            if filetype == "gff" && line.starts_with("##FASTA") { break }
+            if !is_comment(line) {
+                let problems = Record::check_row(line, filetype);
+                if problems.is_empty() {
+                    let record = Record::from_row(records.len(), line, filetype, &chrmap)?;
+                    if types.is_cds(&record.feature_type) && record.frame == "." {
+                        warning(&mut issues, lineno, "cds_phase", "CDS feature has no phase".to_string());
+                    }
+                    records.push((lineno, record));
+                }
+                for (check, message) in problems {
+                    error(&mut issues, lineno, check, message);
+                }
+            }
+        }
+        buf.clear();
+    }
+
+    let mut groups = Vec::<Group>::new();
+    match filetype {
+        "gff" => {
+            let mut ids = HashMap::<&str, Vec<usize>>::new();
+            for (i, (_, record)) in records.iter().enumerate() {
+                if let Some(id) = record.attributes.get("ID") {
+                    ids.entry(id.as_str()).or_default().push(i);
+                }
+            }
+            // lines sharing an ID are one feature only if they agree on
+            // seqname, type and strand
+            for rows in ids.values() {
+                let first = &records[rows[0]].1;
+                for row in &rows[1..] {
+                    let (line, record) = &records[*row];
+                    if record.seqname != first.seqname ||
+                        record.feature_type != first.feature_type ||
+                        record.strand != first.strand
+                    {
+                        error(&mut issues, *line, "duplicate_id", format!("ID {} was already used on line {}",
+                            record.attributes["ID"], records[rows[0]].0));
+                    }
+                }
+            }
+            let mut children = HashMap::<&str, Vec<usize>>::new();
+            for (i, (line, record)) in records.iter().enumerate() {
+                for p in record.attributes.get_all("Parent") {
+                    if ids.contains_key(p.as_str()) {
+                        children.entry(p.as_str()).or_default().push(i);
+                    }
+                    else {
+                        error(&mut issues, *line, "dangling_parent", format!("Parent {} was not found", p));
+                    }
+                }
+            }
+            let mut parent_ids = children.keys().cloned().collect::<Vec<_>>();
+            parent_ids.sort_by_key(|p| ids[p][0]);
+            for p in parent_ids {
+                groups.push(Group { name: p.to_string(), parents: ids[p].clone(), children: children[p].clone() });
+            }
+        }
+        "gtf" => {
+            let mut transcripts = HashMap::<&str, Group>::new();
+            let mut genes = HashMap::<&str, Group>::new();
+            for (i, (line, record)) in records.iter().enumerate() {
+                let gene_id = record.attributes.get("gene_id");
+                let transcript_id = record.attributes.get("transcript_id");
+                if gene_id.is_none() {
+                    error(&mut issues, *line, "missing_attribute", "Missing gene_id attribute".to_string());
+                }
+                if transcript_id.is_none() && !types.is_gene(&record.feature_type) {
+                    error(&mut issues, *line, "missing_attribute", "Missing transcript_id attribute".to_string());
+                }
+                if let Some(gene_id) = gene_id {
+                    let group = genes.entry(gene_id.as_str()).or_insert_with(||
+                        Group { name: gene_id.to_string(), parents: Vec::new(), children: Vec::new() });
+                    if types.is_gene(&record.feature_type) { group.parents.push(i) }
+                    else if types.is_transcript(&record.feature_type) { group.children.push(i) }
+                }
+                if let Some(transcript_id) = transcript_id {
+                    let group = transcripts.entry(transcript_id.as_str()).or_insert_with(||
+                        Group { name: transcript_id.to_string(), parents: Vec::new(), children: Vec::new() });
+                    if types.is_transcript(&record.feature_type) { group.parents.push(i) }
+                    else { group.children.push(i) }
+                }
+            }
+            let mut gtf_groups = genes.into_values().
+                chain(transcripts.into_values()).
+                collect::<Vec<_>>();
+            gtf_groups.sort_by_key(|g| g.parents.iter().chain(g.children.iter()).min().cloned());
+            groups.append(&mut gtf_groups);
+        }
+        _ => (),
+    }
+
+    for group in &groups {
+        // compare children to their parent, or to the first child if the
+        // parent has no record
+        let reference = match group.parents.first().or_else(|| group.children.first()) {
+            Some(reference) => &records[*reference].1,
+            None => continue,
+        };
+        let start = group.parents.iter().map(|p| records[*p].1.start).min();
+        let end = group.parents.iter().map(|p| records[*p].1.end).max();
+        for child in &group.children {
+            let (line, record) = &records[*child];
+            if record.seqname != reference.seqname {
+                error(&mut issues, *line, "mixed_seqname", format!("Feature is on {} but {} is on {}",
+                    record.seqname, group.name, reference.seqname));
+            }
+            else if record.strand != reference.strand {
+                error(&mut issues, *line, "mixed_strand", format!("Feature is on strand {} but {} is on strand {}",
+                    record.strand, group.name, reference.strand));
+            }
+            else if let (Some(start), Some(end)) = (start, end) {
+                if record.start < start || end < record.end {
+                    error(&mut issues, *line, "outside_parent", format!("Feature {}..{} is outside of parent {} at {}..{}",
+                        record.start, record.end, group.name, start, end));
+                }
+            }
+        }
+        // every CDS must be within an exon of the same transcript
+        let exons = group.children.iter().
+            map(|c| &records[*c].1).
+            filter(|r| types.is_exon(&r.feature_type)).
+            collect::<Vec<_>>();
+        if !exons.is_empty() {
+            for child in &group.children {
+                let (line, record) = &records[*child];
+                if types.is_cds(&record.feature_type) &&
+                    !exons.iter().any(|e| e.seqname == record.seqname && e.start <= record.start && record.end <= e.end)
+                {
+                    error(&mut issues, *line, "cds_outside_exon", format!("CDS {}..{} of {} is not within an exon",
+                        record.start, record.end, group.name));
+                }
+            }
+        }
+    }
+
+    issues.sort_by_key(|i| i.line);
+    let errors = issues.iter().filter(|i| i.severity == Severity::Error).count();
+    let warnings = issues.iter().filter(|i| i.severity == Severity::Warning).count();
+    Ok(LintReport {
+        file: annotfile.to_string(),
+        lines: lineno,
+        records: records.len(),
+        errors,
+        warnings,
+        passed: errors == 0,
+        issues,
+    })
+}
@@ -0,0 +1,194 @@
+//! Interned strings. An annotation repeats a few seqnames, sources,
+//! feature types and attribute keys, and gene and transcript IDs, on many
+//! rows, and interning stores each distinct string only once.
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref SYMBOLS: RwLock<HashSet<&'static str>> = RwLock::new(HashSet::new());
+}
+
+/// An interned string, which is as cheap to copy as a reference. Since
+/// each distinct string is interned once, symbols are compared and hashed
+/// by address instead of by their contents. They are still ordered by
+/// their contents.
+#[derive(Clone, Copy)]
+pub struct Sym(&'static str);
+
+impl Sym {
+    /// Intern a string. Interned strings are leaked: they are never freed
+    /// and stay in one table shared by the whole process, so each distinct
+    /// string read, such as every gene and transcript ID of every
+    /// annotation, takes memory until the process exits. Interning takes a
+    /// global lock, which is only held for writing by strings that are new.
+    pub fn new(s: &str) -> Sym {
+        if let Some(sym) = SYMBOLS.read().unwrap_or_else(|e| e.into_inner()).get(s) {
+            return Sym(sym);
+        }
+        let mut symbols = SYMBOLS.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(sym) = symbols.get(s) {
+            return Sym(sym);
+        }
+        let sym: &'static str = Box::leak(s.to_string().into_boxed_str());
+        symbols.insert(sym);
+        Sym(sym)
+    }
+
+    /// The symbol of a string if it has been interned, without interning
+    /// it, for looking strings up in maps keyed by symbols.
+    pub fn get(s: &str) -> Option<Sym> {
+        SYMBOLS.read().unwrap_or_else(|e| e.into_inner()).get(s).map(|sym| Sym(sym))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Default for Sym {
+    fn default() -> Sym {
+        Sym::new("")
+    }
+}
+
+impl PartialEq for Sym {
+    fn eq(&self, other: &Sym) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Sym {}
+
+impl Hash for Sym {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0.as_ptr() as usize).hash(state)
+    }
+}
+
+impl PartialOrd for Sym {
+    fn partial_cmp(&self, other: &Sym) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sym {
+    fn cmp(&self, other: &Sym) -> Ordering {
+        if self == other { Ordering::Equal } else { self.0.cmp(other.0) }
+    }
+}
+
+impl Deref for Sym {
+    type Target = str;
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl AsRef<str> for Sym {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl fmt::Display for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+impl fmt::Debug for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+}
+
+impl From<&str> for Sym {
+    fn from(s: &str) -> Sym {
+        Sym::new(s)
+    }
+}
+
+impl From<String> for Sym {
+    fn from(s: String) -> Sym {
+        Sym::new(&s)
+    }
+}
+
+impl From<&String> for Sym {
+    fn from(s: &String) -> Sym {
+        Sym::new(s)
+    }
+}
+
+impl From<&Sym> for Sym {
+    fn from(s: &Sym) -> Sym {
+        *s
+    }
+}
+
+impl From<Sym> for String {
+    fn from(s: Sym) -> String {
+        s.0.to_string()
+    }
+}
+
+impl PartialEq<str> for Sym {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Sym {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Sym {
+    fn eq(&self, other: &String) -> bool {
+        self.0 == other.as_str()
+    }
+}
+
+impl PartialEq<Sym> for str {
+    fn eq(&self, other: &Sym) -> bool {
+        self == other.0
+    }
+}
+
+impl PartialEq<Sym> for &str {
+    fn eq(&self, other: &Sym) -> bool {
+        *self == other.0
+    }
+}
+
+impl PartialEq<Sym> for String {
+    fn eq(&self, other: &Sym) -> bool {
+        self.as_str() == other.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interned_once() {
+        let a = Sym::new("symbol_test_a");
+        let b = Sym::from(String::from("symbol_test_a"));
+        assert_eq!(a, b);
+        assert!(std::ptr::eq(a.as_str(), b.as_str()));
+        assert_eq!(Sym::default(), Sym::new(""));
+        assert!(Sym::new("symbol_test_b") > a);
+        assert_eq!(a, "symbol_test_a");
+        // looking a string up doesn't intern it
+        assert!(Sym::get("symbol_test_c").is_none());
+        assert!(Sym::get("symbol_test_c").is_none());
+        assert_eq!(Sym::get("symbol_test_a"), Some(a));
+    }
+}